@@ -1,14 +1,22 @@
 use akula::{
+    accessors::chain,
     binutil::AkulaDataDir,
+    hexbytes,
     kv::{tables, traits::*},
     models::*,
-    stagedsync::stages::*,
+    stagedsync::{stages::*, sync_status::sync_status},
+    txpool::{TxPool, TxPoolConfig},
+    Buffer,
 };
+use anyhow::format_err;
 use async_trait::async_trait;
-use ethereum_types::{Address, U256};
+use bytes::Bytes;
+use ethereum_types::{Address, H256, U256};
 use jsonrpsee::{core::RpcResult, http_server::HttpServerBuilder, proc_macros::rpc};
+use serde::{Deserialize, Serialize};
 use std::{future::pending, net::SocketAddr, sync::Arc};
 use structopt::StructOpt;
+use tokio::sync::Mutex;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
 #[derive(StructOpt)]
@@ -27,6 +35,36 @@ pub trait EthApi {
     async fn block_number(&self) -> RpcResult<BlockNumber>;
     #[method(name = "getBalance")]
     async fn get_balance(&self, address: Address, block_number: BlockNumber) -> RpcResult<U256>;
+    #[method(name = "sendRawTransaction")]
+    async fn send_raw_transaction(&self, transaction: RawTransaction) -> RpcResult<H256>;
+    #[method(name = "syncing")]
+    async fn syncing(&self) -> RpcResult<SyncingStatus>;
+}
+
+/// Transaction in the encoding it's hashed in, e.g. a typed transaction isn't wrapped into an RLP string.
+#[derive(Deserialize)]
+pub struct RawTransaction(#[serde(with = "hexbytes")] Bytes);
+
+/// `false` only if every stage has reached the chain tip, e.g. for a load balancer health check.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SyncingStatus {
+    NotSyncing(bool),
+    Syncing(SyncingProgress),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncingProgress {
+    pub current_block: BlockNumber,
+    pub highest_block: BlockNumber,
+    pub stages: Vec<StageProgress>,
+}
+
+#[derive(Serialize)]
+pub struct StageProgress {
+    pub stage_name: String,
+    pub block_number: BlockNumber,
 }
 
 pub struct EthApiServerImpl<DB>
@@ -34,6 +72,8 @@ where
     DB: KV,
 {
     db: Arc<DB>,
+    /// Created on the first submitted transaction, on top of the latest executed block.
+    txpool: Mutex<Option<TxPool>>,
 }
 
 #[async_trait]
@@ -61,6 +101,75 @@ where
         .map(|acc| acc.balance)
         .unwrap_or_else(U256::zero))
     }
+
+    async fn send_raw_transaction(&self, transaction: RawTransaction) -> RpcResult<H256> {
+        let transaction =
+            MessageWithSignature::trie_decode(&transaction.0).map_err(anyhow::Error::from)?;
+        let hash = transaction.hash();
+
+        let tx = self.db.begin().await?;
+        let head_number = tx
+            .get(tables::SyncStage, EXECUTION)
+            .await?
+            .unwrap_or(BlockNumber(0));
+        let head_hash = chain::canonical_hash::read(&tx, head_number)
+            .await?
+            .ok_or_else(|| format_err!("No canonical block {}", head_number))?;
+        let head = chain::header::read(&tx, head_hash, head_number)
+            .await?
+            .ok_or_else(|| format_err!("No header of the block {}", head_number))?;
+        // the plain state is of the latest executed block
+        let state = Buffer::new(&tx, BlockNumber(0), None);
+
+        let mut txpool = self.txpool.lock().await;
+        if txpool.is_none() {
+            let genesis_hash = chain::canonical_hash::read(&tx, BlockNumber(0))
+                .await?
+                .ok_or_else(|| format_err!("Genesis block absent"))?;
+            let chain_spec = tx.get(tables::Config, genesis_hash).await?.ok_or_else(|| {
+                format_err!("No chain config for genesis block {:?}", genesis_hash)
+            })?;
+            *txpool = Some(TxPool::new(TxPoolConfig::default(), chain_spec, &head));
+        }
+        let txpool = txpool.as_mut().unwrap();
+        if txpool.head_hash() != head_hash {
+            txpool.set_head(&head, &state).await?;
+        }
+
+        txpool
+            .add_transaction(&state, transaction)
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        Ok(hash)
+    }
+
+    async fn syncing(&self) -> RpcResult<SyncingStatus> {
+        let tx = self.db.begin().await?;
+        // only the stages which have ever run, e.g. not the Erigon conversion of a downloading node
+        let stages = all_progress(&tx)
+            .await?
+            .into_iter()
+            .filter_map(|(stage_id, progress)| progress.map(|_| stage_id))
+            .collect::<Vec<_>>();
+        let status = sync_status(&tx, &stages, None).await?;
+
+        if !status.is_syncing {
+            return Ok(SyncingStatus::NotSyncing(false));
+        }
+        Ok(SyncingStatus::Syncing(SyncingProgress {
+            current_block: status.current_block,
+            highest_block: status.highest_block,
+            stages: status
+                .stages
+                .into_iter()
+                .map(|(stage_id, block_number)| StageProgress {
+                    stage_name: stage_id.0.to_string(),
+                    block_number,
+                })
+                .collect(),
+        }))
+    }
 }
 
 #[tokio::main]
@@ -87,7 +196,13 @@ async fn main() -> anyhow::Result<()> {
     )?);
 
     let server = HttpServerBuilder::default().build(opt.listen_address)?;
-    let _server_handle = server.start(EthApiServerImpl { db }.into_rpc())?;
+    let _server_handle = server.start(
+        EthApiServerImpl {
+            db,
+            txpool: Default::default(),
+        }
+        .into_rpc(),
+    )?;
 
     pending().await
 }
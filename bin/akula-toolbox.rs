@@ -8,6 +8,7 @@ use akula::{
     models::*,
     stagedsync::{self},
     stages::*,
+    StageId,
 };
 use anyhow::{bail, ensure, format_err, Context};
 use bytes::Bytes;
@@ -36,6 +37,13 @@ pub enum OptCommand {
         csv: bool,
     },
 
+    /// Print per-table database statistics
+    DbTableStats {
+        /// Whether to print CSV
+        #[structopt(long)]
+        csv: bool,
+    },
+
     /// Query database
     DbQuery {
         #[structopt(long)]
@@ -64,6 +72,12 @@ pub enum OptCommand {
         table: String,
     },
 
+    /// Copy the database with compaction into another directory, the node must be stopped
+    DbCompact {
+        #[structopt(long, parse(from_os_str))]
+        output: PathBuf,
+    },
+
     /// Execute Block Hashes stage
     Blockhashes,
 
@@ -77,6 +91,31 @@ pub enum OptCommand {
     ReadBlock {
         block_number: BlockNumber,
     },
+
+    /// Print the progress of all the stages
+    StageProgress,
+
+    /// Set the progress of a stage to run it again from the block, the node must be stopped.
+    /// Refused for the stages applying the blocks on top of their data, like the execution
+    SetStageProgress {
+        #[structopt(long)]
+        stage: StageId,
+        #[structopt(long)]
+        block_number: BlockNumber,
+        /// Lower the later stages along
+        #[structopt(long)]
+        cascade: bool,
+    },
+
+    /// Clear the progress of a stage to run it again from the genesis, the node must be stopped.
+    /// Refused for the stages applying the blocks on top of their data, like the execution
+    ClearStage {
+        #[structopt(long)]
+        stage: StageId,
+        /// Clear the later stages along
+        #[structopt(long)]
+        cascade: bool,
+    },
 }
 
 #[derive(StructOpt)]
@@ -109,10 +148,9 @@ async fn blockhashes(data_dir: AkulaDataDir) -> anyhow::Result<()> {
 
     let mut staged_sync = stagedsync::StagedSync::new();
     staged_sync.push(BlockHashes);
-    staged_sync.run(&env).await?;
+    staged_sync.run(&env).await
 }
 
-#[allow(unreachable_code)]
 async fn header_download(data_dir: AkulaDataDir, opts: HeaderDownloadOpts) -> anyhow::Result<()> {
     let chains_config = akula::sentry::chain_config::ChainsConfig::new()?;
     let chain_config = chains_config.get(&opts.chain_name)?;
@@ -132,14 +170,14 @@ async fn header_download(data_dir: AkulaDataDir, opts: HeaderDownloadOpts) -> an
 
     let stage = akula::stages::HeaderDownload::new(
         chain_config,
-        opts.downloader_opts.headers_mem_limit(),
+        &opts.downloader_opts.headers_downloader_options(),
         opts.downloader_opts.headers_batch_size,
         sentry.clone(),
         sentry_status_provider,
     )?;
 
     std::fs::create_dir_all(&data_dir.0)?;
-    let db = akula::kv::new_database(&data_dir.chain_data_dir())?;
+    let db = akula::kv::new_database(&data_dir.chain_data_dir(), &Default::default())?;
 
     let mut staged_sync = stagedsync::StagedSync::new();
     staged_sync.push(stage);
@@ -184,6 +222,74 @@ async fn table_sizes(data_dir: AkulaDataDir, csv: bool) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn db_compact(data_dir: AkulaDataDir, output: PathBuf) -> anyhow::Result<()> {
+    info!(
+        "Compacting {} into {}",
+        data_dir.chain_data_dir().display(),
+        output.display()
+    );
+    let report = akula::kv::compact_database(&data_dir.chain_data_dir(), &output)?;
+    info!(
+        "Compacted: {} -> {}",
+        bytesize::ByteSize::b(report.size_before),
+        bytesize::ByteSize::b(report.size_after)
+    );
+    Ok(())
+}
+
+async fn table_stats(data_dir: AkulaDataDir, csv: bool) -> anyhow::Result<()> {
+    let env = akula::kv::mdbx::Environment::<mdbx::NoWriteMap>::open_ro(
+        mdbx::Environment::new(),
+        &data_dir.chain_data_dir(),
+        Default::default(),
+    )?;
+    let mut stats = env.begin().await?.table_stats()?;
+    stats.sort_by_key(|(_, stat)| stat.total_bytes);
+
+    let mut out = Vec::new();
+    if csv {
+        out.push("Table,Entries,Depth,Branch pages,Leaf pages,Overflow pages,Size".to_string());
+        for (table, stat) in &stats {
+            out.push(format!(
+                "{},{},{},{},{},{},{}",
+                table,
+                stat.entries,
+                stat.depth,
+                stat.branch_pages,
+                stat.leaf_pages,
+                stat.overflow_pages,
+                stat.total_bytes
+            ));
+        }
+    } else {
+        out.push(format!(
+            "{:<32} {:>14} {:>5} {:>12} {:>12} {:>12} {:>12}",
+            "Table", "Entries", "Depth", "Branch", "Leaf", "Overflow", "Size"
+        ));
+        for (table, stat) in &stats {
+            out.push(format!(
+                "{:<32} {:>14} {:>5} {:>12} {:>12} {:>12} {:>12}",
+                table,
+                stat.entries,
+                stat.depth,
+                stat.branch_pages,
+                stat.leaf_pages,
+                stat.overflow_pages,
+                bytesize::ByteSize::b(stat.total_bytes).to_string()
+            ));
+        }
+        out.push(format!(
+            "TOTAL: {}",
+            bytesize::ByteSize::b(stats.into_iter().map(|(_, stat)| stat.total_bytes).sum())
+        ));
+    }
+
+    for line in out {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
 async fn db_query(data_dir: AkulaDataDir, table: String, key: Bytes) -> anyhow::Result<()> {
     let env = akula::kv::mdbx::Environment::<mdbx::NoWriteMap>::open_ro(
         mdbx::Environment::new(),
@@ -370,6 +476,59 @@ async fn read_block(data_dir: AkulaDataDir, block_num: BlockNumber) -> anyhow::R
     Ok(())
 }
 
+async fn stage_progress(data_dir: AkulaDataDir) -> anyhow::Result<()> {
+    let env = akula::kv::mdbx::Environment::<mdbx::NoWriteMap>::open_ro(
+        mdbx::Environment::new(),
+        &data_dir.chain_data_dir(),
+        CHAINDATA_TABLES.clone(),
+    )?;
+
+    let tx = env.begin().await?;
+    for (stage_id, progress) in stagedsync::stages::all_progress(&tx).await? {
+        match progress {
+            Some(progress) => println!("{}: {}", stage_id, progress),
+            None => println!("{}: -", stage_id),
+        }
+    }
+
+    Ok(())
+}
+
+async fn set_stage_progress(
+    data_dir: AkulaDataDir,
+    stage_id: StageId,
+    block_number: Option<BlockNumber>,
+    cascade: bool,
+) -> anyhow::Result<()> {
+    let env = akula::kv::mdbx::Environment::<mdbx::NoWriteMap>::open_rw(
+        mdbx::Environment::new(),
+        &data_dir.chain_data_dir(),
+        CHAINDATA_TABLES.clone(),
+    )?;
+
+    let tx = env.begin_mutable().await?;
+    let dependents = if let Some(block_number) = block_number {
+        stagedsync::stages::set_stage_progress(&tx, stage_id, block_number, cascade).await?
+    } else {
+        stagedsync::stages::clear_stage(&tx, stage_id, cascade).await?
+    };
+    tx.commit().await?;
+
+    if !dependents.is_empty() {
+        info!(
+            "The later stages {} {}",
+            dependents.iter().join(", "),
+            if cascade {
+                "were reset along"
+            } else {
+                "are still ahead, use --cascade to reset them along"
+            }
+        );
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt: Opt = Opt::from_args();
@@ -389,7 +548,9 @@ async fn main() -> anyhow::Result<()> {
 
     match opt.command {
         OptCommand::DbStats { csv } => table_sizes(opt.data_dir, csv).await?,
+        OptCommand::DbCompact { output } => db_compact(opt.data_dir, output).await?,
         OptCommand::Blockhashes => blockhashes(opt.data_dir).await?,
+        OptCommand::DbTableStats { csv } => table_stats(opt.data_dir, csv).await?,
         OptCommand::DbQuery { table, key } => db_query(opt.data_dir, table, key).await?,
         OptCommand::DbWalk {
             table,
@@ -399,6 +560,15 @@ async fn main() -> anyhow::Result<()> {
         OptCommand::CheckEqual { db1, db2, table } => check_table_eq(db1, db2, table).await?,
         OptCommand::HeaderDownload { opts } => header_download(opt.data_dir, opts).await?,
         OptCommand::ReadBlock { block_number } => read_block(opt.data_dir, block_number).await?,
+        OptCommand::StageProgress => stage_progress(opt.data_dir).await?,
+        OptCommand::SetStageProgress {
+            stage,
+            block_number,
+            cascade,
+        } => set_stage_progress(opt.data_dir, stage, Some(block_number), cascade).await?,
+        OptCommand::ClearStage { stage, cascade } => {
+            set_stage_progress(opt.data_dir, stage, None, cascade).await?
+        }
     }
 
     Ok(())
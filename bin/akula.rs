@@ -1,25 +1,31 @@
 use akula::{
     binutil::AkulaDataDir,
-    downloader::sentry_status_provider::SentryStatusProvider,
+    downloader::{
+        sentry_status_provider::SentryStatusProvider, Checkpoints, HeaderDownloaderOptions,
+    },
     kv::{
         tables::{self, ErasedTable},
         traits::*,
     },
     models::*,
     sentry::{
-        sentry_client_connector::SentryClientConnectorImpl,
+        chain_config::ChainConfig, message_decoder::MAX_DECOMPRESSED_PAYLOAD_SIZE,
+        sentry_client::RequestTimeouts, sentry_client_connector::SentryClientConnectorImpl,
         sentry_client_reactor::SentryClientReactor,
     },
     stagedsync::{self, stage::*, stages::FINISH},
     stages::*,
-    version_string, StageId,
+    version_string, BufferConfig, StageId,
 };
 use anyhow::bail;
 use async_trait::async_trait;
 use rayon::prelude::*;
 use std::{
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use structopt::StructOpt;
@@ -47,6 +53,10 @@ pub struct Opt {
     )]
     pub chain_name: String,
 
+    /// Path to a Geth-style genesis.json of a private chain, instead of --chain.
+    #[structopt(long, parse(from_os_str))]
+    pub genesis: Option<PathBuf>,
+
     /// Sentry GRPC service URL
     #[structopt(
         long = "sentry.api.addr",
@@ -55,6 +65,26 @@ pub struct Opt {
     )]
     pub sentry_api_addr: akula::sentry::sentry_address::SentryAddress,
 
+    /// Comma-separated IDs of the peers to send the requests to first, e.g. own well-connected nodes.
+    #[structopt(long = "sentry.preferred-peers", use_delimiter = true)]
+    pub sentry_preferred_peers: Vec<akula::sentry::sentry_client::PeerId>,
+
+    /// Seconds to wait for the block headers before requesting them again, growing with the retries.
+    #[structopt(long = "sentry.get-block-headers-timeout", default_value = "5")]
+    pub sentry_get_block_headers_timeout_secs: u64,
+
+    /// Seconds to wait for the block bodies before requesting them again, growing with the retries.
+    #[structopt(long = "sentry.get-block-bodies-timeout", default_value = "15")]
+    pub sentry_get_block_bodies_timeout_secs: u64,
+
+    /// Decompress the snappy-compressed payloads of the messages received from the sentry.
+    #[structopt(long = "sentry.snappy-payloads")]
+    pub sentry_snappy_payloads: bool,
+
+    /// Reject the payloads decompressing above this size (bytes), and penalize their peers.
+    #[structopt(long = "sentry.snappy-max-payload-size")]
+    pub sentry_snappy_max_payload_size: Option<usize>,
+
     /// Last block where to sync to.
     #[structopt(long)]
     pub max_block: Option<BlockNumber>,
@@ -75,13 +105,188 @@ pub struct Opt {
     #[structopt(long, env)]
     pub execution_exit_after_batch: bool,
 
+    /// Verify the state root at the end of every execution batch. Keeps the whole state in memory,
+    /// so it's only for the test and development chains, and fails on a larger state.
+    #[structopt(long, env)]
+    pub execution_verify_state_root: bool,
+
+    /// Size execution batches by the recent block gas limits instead of the fixed batch size.
+    #[structopt(long, env)]
+    pub execution_adaptive_batch: bool,
+
+    /// Number of blocks in an adaptive execution batch.
+    #[structopt(long, default_value = "100000")]
+    pub execution_adaptive_batch_blocks: u64,
+
+    /// Size execution batches by the measured throughput, so that they take about the target time.
+    #[structopt(long, env)]
+    pub execution_batch_auto_tune: bool,
+
+    /// Target time to execute an auto-tuned batch in (seconds).
+    #[structopt(long, default_value = "60")]
+    pub execution_batch_target_secs: u64,
+
+    /// Lower bound of the auto-tuned execution batch size (Ggas).
+    #[structopt(long, default_value = "1")]
+    pub execution_min_batch_size: u64,
+
+    /// Upper bound of the auto-tuned execution batch size (Ggas).
+    #[structopt(long, default_value = "5000")]
+    pub execution_max_batch_size: u64,
+
+    /// Recover the senders of this many blocks ahead of the execution in parallel. Disabled if 0.
+    #[structopt(long, default_value = "0")]
+    pub execution_sender_lookahead: u64,
+
+    /// Threads to recover the senders ahead of the execution, the number of CPUs if 0.
+    #[structopt(long, default_value = "0")]
+    pub execution_sender_lookahead_threads: usize,
+
+    /// Senders for the execution: trust the stored ones, verify them, or recover them again.
+    #[structopt(long, default_value = "trust")]
+    pub execution_sender_recovery: SenderRecoveryMode,
+
+    /// Keep the recovered senders of the unwound blocks, and reuse them if the blocks come back.
+    #[structopt(long, env)]
+    pub sender_cache: bool,
+
+    /// Log the execution progress every this many seconds.
+    #[structopt(long, default_value = "30")]
+    pub execution_log_every_secs: u64,
+
+    /// Log the execution progress every this many blocks instead of by time.
+    #[structopt(long)]
+    pub execution_log_every_blocks: Option<u64>,
+
+    /// Log the execution progress as the fields of an event, e.g. for a JSON log pipeline.
+    #[structopt(long)]
+    pub execution_structured_progress_log: bool,
+
+    /// Number of the recent progress messages the smoothed execution Mgas/sec is averaged over.
+    #[structopt(long, default_value = "10")]
+    pub execution_throughput_window: usize,
+
+    /// Estimate the execution progress by the cumulative "gas" or "transactions".
+    #[structopt(long, default_value = "gas")]
+    pub execution_progress_unit: ProgressUnit,
+
+    /// Refuse to unwind the execution by more blocks than this.
+    #[structopt(long)]
+    pub execution_max_unwind_depth: Option<u64>,
+
+    /// Let the first execution unwind exceed the maximum depth.
+    #[structopt(long)]
+    pub execution_force_deep_unwind: bool,
+
+    /// Commit the execution unwind every this many blocks, so that an interrupted one resumes.
+    #[structopt(long, default_value = "10000")]
+    pub execution_unwind_batch_size: u64,
+
+    /// Write the state buffered by the execution to the database once it takes this many MiB,
+    /// to bound the memory used by the large batches. Unbounded by default.
+    #[structopt(long)]
+    pub execution_buffer_flush_mb: Option<usize>,
+
+    /// Also write the execution history once it takes this many MiB, even if less than
+    /// the execution history batch size of gas has been executed since.
+    #[structopt(long)]
+    pub execution_history_flush_mb: Option<usize>,
+
+    /// Check that the canonical headers link to each other before executing them.
+    #[structopt(long)]
+    pub execution_verify_canonical: bool,
+
+    /// Number of blocks to index the transaction hashes of between commits.
+    #[structopt(long, default_value = "100000")]
+    pub tx_lookup_batch_blocks: u64,
+
+    /// Prune the history older than the most recent blocks.
+    #[structopt(long, env)]
+    pub prune: bool,
+
+    /// Number of the most recent blocks to keep the history for when pruning,
+    /// at least the maximum unwind and reorg depths. The unwinds below fail.
+    #[structopt(long, default_value = "90000")]
+    pub prune_keep_blocks: u64,
+
+    /// Number of blocks of history to prune between commits.
+    #[structopt(long, default_value = "10000")]
+    pub prune_batch_blocks: u64,
+
+    /// Prune the canonical headers older than the most recent blocks, except for the checkpoints.
+    #[structopt(long, env)]
+    pub prune_headers: bool,
+
+    /// Number of the most recent headers to keep when pruning, at least 256.
+    #[structopt(long, default_value = "90000")]
+    pub prune_headers_keep_blocks: u64,
+
+    /// Number of blocks of headers to prune between commits.
+    #[structopt(long, default_value = "10000")]
+    pub prune_headers_batch_blocks: u64,
+
     /// Exit Akula after sync is complete and there's no progress.
     #[structopt(long, env)]
     pub exit_after_sync: bool,
 
+    /// Sync all stages up to this block and exit.
+    #[structopt(long)]
+    pub exit_at: Option<BlockNumber>,
+
     /// Delay applied at the terminating stage.
     #[structopt(long, default_value = "2000")]
     pub delay_after_sync: u64,
+
+    /// Execute a stage again up to this many times in a row if it fails with a network error.
+    #[structopt(long, default_value = "3")]
+    pub stage_max_retries: usize,
+
+    /// Delay before the first stage retry, doubled for each next one (ms).
+    #[structopt(long, default_value = "1000")]
+    pub stage_retry_backoff: u64,
+
+    /// Fail instead of unwinding or switching to a downloaded branch deeper than this many blocks,
+    /// e.g. 1024 on mainnet.
+    /// Too low a limit stops the sync at the genuine deep reorgs until restarted with a higher one.
+    #[structopt(long)]
+    pub max_reorg_depth: Option<u64>,
+
+    /// Log as JSON lines with the progress in separate fields, e.g. for log aggregation.
+    #[structopt(long = "log.json")]
+    pub log_json: bool,
+
+    /// Upper limit of the database size (GiB).
+    #[structopt(long = "db.max-size", default_value = "4096")]
+    pub db_max_size_gb: u64,
+
+    /// How much the database file grows by at once (GiB).
+    #[structopt(long = "db.growth-step", default_value = "4")]
+    pub db_growth_step_gb: u64,
+
+    /// Maximum number of simultaneous database readers.
+    #[structopt(long = "db.max-readers")]
+    pub db_max_readers: Option<u64>,
+
+    /// When the commits are flushed to the disk: durable, no-meta-sync, safe-no-sync or utterly-no-sync.
+    /// Anything but durable is faster, but can lose the recent commits on a system crash.
+    #[structopt(long = "db.durability", default_value = "durable")]
+    pub db_durability: akula::kv::Durability,
+
+    #[structopt(subcommand)]
+    pub command: Option<OptCommand>,
+}
+
+#[derive(StructOpt)]
+pub enum OptCommand {
+    /// Import the blocks from an RLP file, e.g. made by `geth export`, and sync them
+    /// without the header download, so that no sentry is needed.
+    Import {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Commit every this many imported blocks.
+        #[structopt(long, default_value = "10000")]
+        commit_every: u64,
+    },
 }
 
 #[derive(Debug)]
@@ -170,6 +375,7 @@ where
         Ok(ExecOutput::Progress {
             stage_progress: highest_block,
             done: true,
+            exhausted: true,
             must_commit: highest_block > original_highest_block,
         })
     }
@@ -393,6 +599,7 @@ where
         Ok(ExecOutput::Progress {
             stage_progress: highest_block,
             done,
+            exhausted: done,
             must_commit: highest_block > original_highest_block,
         })
     }
@@ -465,6 +672,7 @@ where
                 ExecOutput::Progress {
                     stage_progress: prev_stage,
                     done: true,
+                    exhausted: true,
                     must_commit: true,
                 }
             } else {
@@ -478,6 +686,7 @@ where
                 ExecOutput::Progress {
                     stage_progress: prev_stage,
                     done: true,
+                    exhausted: true,
                     must_commit: true,
                 }
             },
@@ -498,7 +707,6 @@ where
     }
 }
 
-#[allow(unreachable_code)]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt: Opt = Opt::from_args();
@@ -513,14 +721,49 @@ async fn main() -> anyhow::Result<()> {
         EnvFilter::from_default_env()
     };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .with((!opt.log_json).then(|| tracing_subscriber::fmt::layer().with_target(false)))
+        .with(
+            opt.log_json
+                .then(|| tracing_subscriber::fmt::layer().json().with_target(false)),
+        )
         .with(env_filter)
         .init();
 
     info!("Starting Akula ({})", version_string());
 
+    // the unwinds can't revert the blocks with pruned history
+    if opt.prune {
+        for (max_depth, flag) in [
+            (
+                opt.execution_max_unwind_depth,
+                "--execution-max-unwind-depth",
+            ),
+            (opt.max_reorg_depth, "--max-reorg-depth"),
+        ] {
+            if let Some(max_depth) = max_depth {
+                if opt.prune_keep_blocks < max_depth {
+                    bail!(
+                        "--prune-keep-blocks {} is less than {} {}",
+                        opt.prune_keep_blocks,
+                        flag,
+                        max_depth
+                    );
+                }
+            }
+        }
+    }
+
+    let geth_genesis = opt
+        .genesis
+        .as_ref()
+        .map(|path| {
+            Ok::<_, anyhow::Error>(serde_json::from_slice::<akula::genesis::GethGenesis>(
+                &std::fs::read(path)?,
+            )?)
+        })
+        .transpose()?;
     let chains_config = akula::sentry::chain_config::ChainsConfig::new()?;
-    let chain_config = chains_config.get(&opt.chain_name)?;
+    let mut chain_config = chains_config.get(&opt.chain_name)?;
 
     // database setup
     let erigon_db = if let Some(erigon_data_dir) = opt.erigon_data_dir {
@@ -537,10 +780,50 @@ async fn main() -> anyhow::Result<()> {
 
     std::fs::create_dir_all(&opt.data_dir.0)?;
     let akula_chain_data_dir = opt.data_dir.chain_data_dir();
-    let db = akula::kv::new_database(&akula_chain_data_dir)?;
+    let db_config = akula::kv::DatabaseConfig {
+        max_size: byte_unit::n_gib_bytes!(opt.db_max_size_gb as u128)
+            .try_into()
+            .unwrap_or(usize::MAX),
+        growth_step: Some(
+            byte_unit::n_gib_bytes!(opt.db_growth_step_gb as u128)
+                .try_into()
+                .unwrap_or(usize::MAX),
+        ),
+        max_readers: opt.db_max_readers,
+        durability: opt.db_durability,
+    };
+    let db = akula::kv::new_database(&akula_chain_data_dir, &db_config)?;
     async {
         let txn = db.begin_mutable().await?;
-        if akula::genesis::initialize_genesis(&txn, chain_config.chain_spec().clone()).await? {
+        if let Some(geth_genesis) = &geth_genesis {
+            let initialized = akula::genesis::initialize_geth_genesis(&txn, geth_genesis).await?;
+            let genesis_block_hash = txn
+                .get(tables::CanonicalHeader, BlockNumber(0))
+                .await?
+                .ok_or_else(|| anyhow::format_err!("genesis block not found"))?;
+            if !initialized {
+                let expected_genesis_block_hash = akula::genesis::geth_genesis_header(geth_genesis)
+                    .await?
+                    .hash();
+                if genesis_block_hash != expected_genesis_block_hash {
+                    bail!(
+                        "Database is initialized with genesis {:?}, but --genesis is {:?}",
+                        genesis_block_hash,
+                        expected_genesis_block_hash
+                    );
+                }
+            }
+            info!("Private chain genesis {:?}", genesis_block_hash);
+            chain_config = ChainConfig::with_genesis_block_hash(
+                geth_genesis.chain_spec()?,
+                genesis_block_hash,
+            );
+            if initialized {
+                txn.commit().await?;
+            }
+        } else if akula::genesis::initialize_genesis(&txn, chain_config.chain_spec().clone())
+            .await?
+        {
             txn.commit().await?;
         }
 
@@ -549,30 +832,86 @@ async fn main() -> anyhow::Result<()> {
     .instrument(span!(Level::INFO, "", " Genesis initialization "))
     .await?;
 
+    async {
+        let txn = db.begin_mutable().await?;
+        if migrate_tx_lookup(&txn).await? {
+            info!("Transaction lookup dropped to be rebuilt with the transaction indices");
+        }
+        txn.commit().await
+    }
+    .instrument(span!(Level::INFO, "", " Migrations "))
+    .await?;
+
+    let import = match &opt.command {
+        Some(OptCommand::Import { path, commit_every }) => Some((path.clone(), *commit_every)),
+        None => None,
+    };
+    if let Some((import_path, commit_every)) = &import {
+        async {
+            let imported_blocks = akula::import::import_rlp(
+                &db,
+                &mut std::fs::File::open(import_path)?,
+                *commit_every,
+            )
+            .await?;
+            info!(
+                "Imported {} blocks, {} were there already",
+                imported_blocks.imported, imported_blocks.duplicates
+            );
+
+            Ok::<_, anyhow::Error>(())
+        }
+        .instrument(span!(Level::INFO, "", " Import "))
+        .await?;
+    }
+
+    let checkpoints = Checkpoints::for_chain(&chain_config.chain_name());
     let sentry_status_provider = SentryStatusProvider::new(chain_config.clone());
     // staged sync setup
     let mut staged_sync = stagedsync::StagedSync::new();
     // staged_sync.set_min_progress_to_commit_after_stage(2);
+    staged_sync
+        .set_max_retries(opt.stage_max_retries)
+        .set_retry_backoff(Duration::from_millis(opt.stage_retry_backoff))
+        .set_target_block(opt.exit_at)
+        .set_max_reorg_depth(opt.max_reorg_depth);
     if let Some(erigon_db) = erigon_db.clone() {
         staged_sync.push(ConvertHeaders {
             db: erigon_db,
             max_block: opt.max_block,
         });
-    } else {
+    } else if import.is_none() {
         // sentry setup
+        let mut sentry_connector = SentryClientConnectorImpl::new(opt.sentry_api_addr.clone());
+        if opt.sentry_snappy_payloads {
+            sentry_connector.set_snappy_payloads(Some(
+                opt.sentry_snappy_max_payload_size
+                    .unwrap_or(MAX_DECOMPRESSED_PAYLOAD_SIZE),
+            ));
+        }
         let mut sentry_reactor = SentryClientReactor::new(
-            Box::new(SentryClientConnectorImpl::new(opt.sentry_api_addr.clone())),
+            Box::new(sentry_connector),
             sentry_status_provider.current_status_stream(),
         );
+        sentry_reactor.set_request_timeouts(RequestTimeouts {
+            get_block_headers: Duration::from_secs(opt.sentry_get_block_headers_timeout_secs),
+            get_block_bodies: Duration::from_secs(opt.sentry_get_block_bodies_timeout_secs),
+        });
+        sentry_reactor.set_preferred_peers(opt.sentry_preferred_peers.clone());
         sentry_reactor.start()?;
 
-        staged_sync.push(HeaderDownload::new(
+        let mut header_download = HeaderDownload::new(
             chain_config,
-            opt.downloader_opts.headers_mem_limit(),
+            &HeaderDownloaderOptions {
+                max_reorg_depth: opt.max_reorg_depth,
+                ..opt.downloader_opts.headers_downloader_options()
+            },
             opt.downloader_opts.headers_batch_size,
             sentry_reactor.into_shared(),
             sentry_status_provider,
-        )?);
+        )?;
+        header_download.set_peer_allowlist(opt.downloader_opts.headers_peer_allowlist());
+        staged_sync.push(header_download);
     }
     staged_sync.push(BlockHashes);
     if let Some(erigon_db) = erigon_db {
@@ -581,7 +920,10 @@ async fn main() -> anyhow::Result<()> {
         // also add body download stage here
     }
     staged_sync.push(CumulativeIndex);
-    staged_sync.push(SenderRecovery);
+    staged_sync.push(SenderRecovery {
+        cache_senders: opt.sender_cache,
+    });
+    let prune_from = Arc::new(AtomicU64::new(0));
     staged_sync.push(Execution {
         batch_size: opt.execution_batch_size.saturating_mul(1_000_000_000_u64),
         history_batch_size: opt
@@ -590,10 +932,66 @@ async fn main() -> anyhow::Result<()> {
         exit_after_batch: opt.execution_exit_after_batch,
         batch_until: None,
         commit_every: None,
-        prune_from: BlockNumber(0),
+        prune_from: prune_from.clone(),
+        verify_state_root: opt.execution_verify_state_root,
+        adaptive_batch: opt.execution_adaptive_batch,
+        adaptive_batch_blocks: opt.execution_adaptive_batch_blocks,
+        log_every: Duration::from_secs(opt.execution_log_every_secs),
+        log_every_blocks: opt.execution_log_every_blocks,
+        sender_lookahead: opt.execution_sender_lookahead,
+        sender_lookahead_threads: opt.execution_sender_lookahead_threads,
+        sender_lookahead_pool: Default::default(),
+        sender_recovery: opt.execution_sender_recovery,
+        structured_progress_log: opt.execution_structured_progress_log,
+        throughput_window: opt.execution_throughput_window,
+        header_cache: None,
+        verify_canonical: opt.execution_verify_canonical,
+        batch_auto_tune: if opt.execution_batch_auto_tune {
+            Some(BatchAutoTune::new(
+                Duration::from_secs(opt.execution_batch_target_secs),
+                opt.execution_min_batch_size
+                    .saturating_mul(1_000_000_000_u64),
+                opt.execution_max_batch_size
+                    .saturating_mul(1_000_000_000_u64),
+            ))
+        } else {
+            None
+        },
+        progress_unit: opt.execution_progress_unit,
+        max_unwind_depth: opt.execution_max_unwind_depth,
+        force_deep_unwind: Arc::new(AtomicBool::new(opt.execution_force_deep_unwind)),
+        unwind_batch_size: opt.execution_unwind_batch_size,
+        track_touched: false,
+        touched: Default::default(),
+        state_root_cache: Default::default(),
+        buffer_config: BufferConfig {
+            flush_threshold: opt
+                .execution_buffer_flush_mb
+                .map(|flush_mb| flush_mb * 1024 * 1024),
+            history_flush_threshold: opt
+                .execution_history_flush_mb
+                .map(|flush_mb| flush_mb * 1024 * 1024),
+        },
     });
     staged_sync.push(HashState::new(None));
     staged_sync.push(Interhashes::new(None));
+    staged_sync.push(TxLookup {
+        batch_size: opt.tx_lookup_batch_blocks,
+    });
+    if opt.prune {
+        staged_sync.push(Prune {
+            keep_blocks: opt.prune_keep_blocks,
+            batch_size: opt.prune_batch_blocks,
+            prune_from: Some(prune_from),
+        });
+    }
+    if opt.prune_headers {
+        staged_sync.push(PruneHeaders {
+            keep_headers: opt.prune_headers_keep_blocks,
+            batch_size: opt.prune_headers_batch_blocks,
+            checkpoints,
+        });
+    }
     staged_sync.push(TerminatingStage {
         max_block: opt.max_block,
         exit_after_sync: opt.exit_after_sync,
@@ -14,6 +14,7 @@ use anyhow::{bail, ensure, format_err};
 use bytes::Bytes;
 use educe::Educe;
 use ethereum_types::*;
+use evmodin::Revision;
 use maplit::*;
 use once_cell::sync::Lazy;
 use serde::{de, Deserialize};
@@ -724,7 +725,10 @@ async fn transaction_test(testdata: TransactionTest) -> anyhow::Result<()> {
             (Ok(txn), t) => {
                 let config = &NETWORK_CONFIG[&key.parse().unwrap()];
 
-                if let Err(e) = pre_validate_transaction(txn, config.params.chain_id, None) {
+                let eip155 =
+                    config.collect_block_spec(BlockNumber(0)).revision >= Revision::Spurious;
+                if let Err(e) = pre_validate_transaction(txn, config.params.chain_id, eip155, None)
+                {
                     match t {
                         TransactionTestResult::Correct { hash, sender } => {
                             return Err(anyhow::Error::new(e).context(format!(
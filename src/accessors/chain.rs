@@ -1,9 +1,13 @@
+use super::header_cache::HeaderCache;
 use crate::{
+    consensus::ValidationError,
+    crypto::{ordered_trie_root, TrieEncode},
     kv::{tables, traits::*},
     models::*,
 };
+use anyhow::format_err;
 use ethereum_types::{Address, H256, U256};
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 use tracing::*;
 
 pub mod canonical_hash {
@@ -58,6 +62,24 @@ pub mod header {
 
         tx.get(tables::Header, (number, hash)).await
     }
+
+    /// Same as `read`, but looks the header up in the cache first if there's one.
+    pub async fn read_cached<'db, Tx: Transaction<'db>>(
+        tx: &Tx,
+        cache: Option<&HeaderCache>,
+        hash: H256,
+        number: impl Into<BlockNumber>,
+    ) -> anyhow::Result<Option<BlockHeader>> {
+        if let Some(header) = cache.and_then(|cache| cache.get(hash)) {
+            return Ok(Some(header));
+        }
+
+        let header = read(tx, hash, number).await?;
+        if let (Some(cache), Some(header)) = (cache, &header) {
+            cache.put(hash, header.clone());
+        }
+        Ok(header)
+    }
 }
 
 pub mod tx {
@@ -253,6 +275,55 @@ pub mod block_body {
 
         Ok(None)
     }
+
+    /// Keeps only the trie encodings of the transactions as they are decoded, instead of materializing the whole body,
+    /// and writes the transactions and the storage body once they match the transactions_root of the header.
+    /// Nothing is written for a mismatching body, so the rows at its tx ids are left alone.
+    pub async fn write_stream<'db, RwTx, S>(
+        tx: &RwTx,
+        header: &BlockHeader,
+        base_tx_id: impl Into<TxIndex>,
+        ommers: Vec<BlockHeader>,
+        mut transactions: S,
+    ) -> anyhow::Result<BodyForStorage>
+    where
+        RwTx: MutableTransaction<'db>,
+        S: Stream<Item = anyhow::Result<MessageWithSignature>> + Unpin,
+    {
+        let base_tx_id = base_tx_id.into();
+        let hash = header.hash();
+        trace!("Streaming body for block {}/{:?}", header.number, hash);
+
+        let mut encoded_transactions = Vec::new();
+        while let Some(transaction) = transactions.try_next().await? {
+            encoded_transactions.push(transaction.trie_encode());
+        }
+
+        let expected_transactions_root = ordered_trie_root(&encoded_transactions);
+        if header.transactions_root != expected_transactions_root {
+            return Err(ValidationError::WrongTransactionsRoot {
+                expected: expected_transactions_root,
+                got: header.transactions_root,
+            }
+            .into());
+        }
+
+        let tx_amount = encoded_transactions.len();
+        let mut cursor = tx.mutable_cursor(tables::BlockTransaction).await?;
+        for (i, encoded_transaction) in encoded_transactions.into_iter().enumerate() {
+            let transaction = MessageWithSignature::trie_decode(&encoded_transaction)?;
+            cursor.put(base_tx_id + i as u64, transaction).await?;
+        }
+
+        let body = BodyForStorage {
+            base_tx_id,
+            tx_amount,
+            uncles: ommers,
+        };
+        super::storage_body::write(tx, hash, header.number, &body).await?;
+
+        Ok(body)
+    }
 }
 
 pub mod td {
@@ -268,40 +339,76 @@ pub mod td {
 
         tx.get(tables::HeadersTotalDifficulty, (number, hash)).await
     }
+
+    pub async fn write<'db, RwTx: MutableTransaction<'db>>(
+        tx: &RwTx,
+        hash: H256,
+        number: impl Into<BlockNumber>,
+        total_difficulty: U256,
+    ) -> anyhow::Result<()> {
+        let number = number.into();
+        trace!("Writing total difficulty at block {}/{:?}", number, hash);
+
+        tx.set(
+            tables::HeadersTotalDifficulty,
+            (number, hash),
+            total_difficulty,
+        )
+        .await
+    }
+
+    /// Total difficulty of the header, accumulated on top of its parent's.
+    /// The genesis has its own difficulty.
+    pub async fn read_parent_and_accumulate<'db, Tx: Transaction<'db>>(
+        tx: &Tx,
+        header: &BlockHeader,
+    ) -> anyhow::Result<U256> {
+        if header.number == BlockNumber(0) {
+            return Ok(header.difficulty);
+        }
+
+        let parent_number = BlockNumber(header.number.0 - 1);
+        let parent_total_difficulty = read(tx, header.parent_hash, parent_number)
+            .await?
+            .ok_or_else(|| {
+                format_err!(
+                    "Missing total difficulty of the parent {}/{:?}",
+                    parent_number,
+                    header.parent_hash
+                )
+            })?;
+
+        Ok(parent_total_difficulty + header.difficulty)
+    }
 }
 
 pub mod tl {
     use super::*;
 
+    /// Block number and index within the block of the transaction.
     pub async fn read<'db, Tx: Transaction<'db>>(
         tx: &Tx,
         tx_hash: H256,
-    ) -> anyhow::Result<Option<BlockNumber>> {
-        trace!("Reading Block number for a tx_hash {:?}", tx_hash);
+    ) -> anyhow::Result<Option<(BlockNumber, u64)>> {
+        trace!("Reading location of tx_hash {:?}", tx_hash);
 
-        Ok(tx
-            .get(tables::BlockTransactionLookup, tx_hash)
-            .await?
-            .map(|b| b.0))
+        tx.get(tables::BlockTransactionLookup, tx_hash).await
     }
 
     pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
         tx: &'tx RwTx,
         hashed_tx_data: H256,
         block_number: BlockNumber,
+        index: u64,
     ) -> anyhow::Result<()> {
         trace!("Writing tx_lookup for hash {}", hashed_tx_data);
 
-        let mut cursor = tx
-            .mutable_cursor(tables::BlockTransactionLookup)
-            .await
-            .unwrap();
-        cursor
-            .put(hashed_tx_data, block_number.into())
-            .await
-            .unwrap();
-
-        Ok(())
+        tx.set(
+            tables::BlockTransactionLookup,
+            hashed_tx_data,
+            (block_number, index),
+        )
+        .await
     }
 }
 
@@ -311,8 +418,7 @@ mod tests {
     use crate::kv::{new_mem_database, traits::MutableKV};
     use bytes::Bytes;
 
-    #[tokio::test]
-    async fn accessors() {
+    fn transactions() -> [MessageWithSignature; 2] {
         let tx1 = MessageWithSignature {
             message: Message::Legacy {
                 chain_id: None,
@@ -339,7 +445,12 @@ mod tests {
             signature: MessageSignature::new(true, H256::repeat_byte(6), H256::repeat_byte(9))
                 .unwrap(),
         };
-        let txs = [tx1, tx2];
+        [tx1, tx2]
+    }
+
+    #[tokio::test]
+    async fn accessors() {
+        let txs = transactions();
 
         let sender1 = Address::random();
         let sender2 = Address::random();
@@ -381,4 +492,118 @@ mod tests {
         assert_eq!(txs, *recovered_txs);
         assert_eq!(senders, *recovered_senders);
     }
+
+    #[tokio::test]
+    async fn write_stream() {
+        let txs = transactions();
+        let header = BlockHeader {
+            number: 1.into(),
+            transactions_root: Block::transactions_root(&txs),
+            ..BlockHeader::empty()
+        };
+
+        let db = new_mem_database().unwrap();
+        let rwtx = db.begin_mutable().await.unwrap();
+        let rwtx = &rwtx;
+
+        let body = block_body::write_stream(
+            rwtx,
+            &header,
+            1,
+            vec![],
+            tokio_stream::iter(txs.clone().into_iter().map(Ok)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            body,
+            BodyForStorage {
+                base_tx_id: 1.into(),
+                tx_amount: 2,
+                uncles: vec![],
+            }
+        );
+        assert_eq!(
+            storage_body::read(rwtx, header.hash(), 1).await.unwrap(),
+            Some(body)
+        );
+        assert_eq!(txs, *tx::read(rwtx, 1, 2).await.unwrap());
+
+        let invalid_header = BlockHeader {
+            number: 2.into(),
+            transactions_root: EMPTY_ROOT,
+            ..BlockHeader::empty()
+        };
+        let error = block_body::write_stream(
+            rwtx,
+            &invalid_header,
+            3,
+            vec![],
+            tokio_stream::iter(txs.clone().into_iter().map(Ok)),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ValidationError>(),
+            Some(ValidationError::WrongTransactionsRoot { .. })
+        ));
+        assert!(storage_body::read(rwtx, invalid_header.hash(), 2)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(tx::read(rwtx, 3, 2).await.unwrap().is_empty());
+        assert_eq!(txs, *tx::read(rwtx, 1, 2).await.unwrap());
+
+        // a mismatching body over the tx ids of another one leaves its transactions alone
+        let reordered_txs = txs.iter().rev().cloned().collect::<Vec<_>>();
+        block_body::write_stream(
+            rwtx,
+            &invalid_header,
+            1,
+            vec![],
+            tokio_stream::iter(reordered_txs.into_iter().map(Ok)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(txs, *tx::read(rwtx, 1, 2).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn cached_header() {
+        let db = new_mem_database().unwrap();
+        let rwtx = db.begin_mutable().await.unwrap();
+
+        let header = BlockHeader {
+            number: BlockNumber(1),
+            ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+        };
+        let hash = header.hash();
+        rwtx.set(tables::Header, (BlockNumber(1), hash), header.clone())
+            .await
+            .unwrap();
+
+        let cache = HeaderCache::new(16);
+        for _ in 0..2 {
+            assert_eq!(
+                header::read_cached(&rwtx, Some(&cache), hash, 1)
+                    .await
+                    .unwrap(),
+                Some(header.clone())
+            );
+        }
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 1);
+
+        // invalidated along with the unwind of the header
+        rwtx.del(tables::Header, (BlockNumber(1), hash), None)
+            .await
+            .unwrap();
+        cache.invalidate_above(BlockNumber(0));
+        assert_eq!(
+            header::read_cached(&rwtx, Some(&cache), hash, 1)
+                .await
+                .unwrap(),
+            None
+        );
+    }
 }
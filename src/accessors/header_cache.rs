@@ -0,0 +1,116 @@
+use crate::models::*;
+use ethereum_types::H256;
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeaderCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Recently read headers by hash, consulted by `chain::header::read_cached` before the database.
+/// Shared between the readers, e.g. the RPC handlers tracing the same blocks.
+///
+/// A hash always identifies the same header, but the headers above an unwind point
+/// might be deleted, so they have to be invalidated along.
+#[derive(Debug)]
+pub struct HeaderCache {
+    inner: Mutex<LruCache<H256, BlockHeader>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl HeaderCache {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            inner: Mutex::new(LruCache::new(cap)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, hash: H256) -> Option<BlockHeader> {
+        let header = self.inner.lock().get(&hash).cloned();
+        if header.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        header
+    }
+
+    pub fn put(&self, hash: H256, header: BlockHeader) {
+        self.inner.lock().put(hash, header);
+    }
+
+    /// Drops the headers above the block, e.g. on an unwind or a reorg to it.
+    pub fn invalidate_above(&self, block_number: BlockNumber) {
+        let mut inner = self.inner.lock();
+        let invalidated = inner
+            .iter()
+            .filter(|(_, header)| header.number > block_number)
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+        for hash in invalidated {
+            inner.pop(&hash);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> HeaderCacheStats {
+        HeaderCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(number: u64) -> BlockHeader {
+        BlockHeader {
+            number: BlockNumber(number),
+            ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+        }
+    }
+
+    #[test]
+    fn lru() {
+        let cache = HeaderCache::new(2);
+        for number in 1..=3 {
+            let header = header(number);
+            cache.put(header.hash(), header);
+        }
+
+        // the least recently used one is evicted
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(header(1).hash()), None);
+        assert_eq!(cache.get(header(3).hash()), Some(header(3)));
+        assert_eq!(cache.stats(), HeaderCacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn invalidate_above() {
+        let cache = HeaderCache::new(10);
+        for number in 1..=5 {
+            let header = header(number);
+            cache.put(header.hash(), header);
+        }
+
+        cache.invalidate_above(BlockNumber(3));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(header(4).hash()), None);
+        assert_eq!(cache.get(header(3).hash()), Some(header(3)));
+    }
+}
@@ -1,2 +1,3 @@
 pub mod chain;
+pub mod header_cache;
 pub mod state;
@@ -1,6 +1,7 @@
 use crate::{
     kv::{tables, traits::*},
     models::*,
+    stagedsync::stages::ensure_history_available,
 };
 use ethereum_types::*;
 
@@ -83,6 +84,70 @@ pub mod storage {
     }
 }
 
+/// Account as of the end of `block_number`, reconstructed from the current state
+/// by reverting the changesets of the newer blocks.
+/// Unlike `account::read`, this doesn't need the history indexes,
+/// but visits every newer block with account changes.
+/// Fails if the changesets of the newer blocks are pruned.
+pub async fn account_at<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    address: Address,
+    block_number: BlockNumber,
+) -> anyhow::Result<Option<Account>> {
+    ensure_history_available(tx, block_number).await?;
+
+    let mut cursor = tx.cursor_dup_sort(tables::AccountChangeSet).await?;
+    let mut entry = cursor.seek(block_number + 1).await?;
+    while let Some((changed_block, _)) = entry {
+        // the earliest change after the block has the value we're looking for
+        if let Some(change) = cursor.seek_both_range(changed_block, address).await? {
+            if change.address == address {
+                return Ok(change.account);
+            }
+        }
+        entry = cursor.seek(changed_block + 1).await?;
+    }
+
+    tx.get(tables::Account, address).await
+}
+
+/// Storage value as of the end of `block_number`, see `account_at`.
+pub async fn storage_at<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    address: Address,
+    location: U256,
+    block_number: BlockNumber,
+) -> anyhow::Result<U256> {
+    ensure_history_available(tx, block_number).await?;
+
+    let location = crate::u256_to_h256(location);
+
+    let mut cursor = tx.cursor_dup_sort(tables::StorageChangeSet).await?;
+    let mut entry = cursor.seek(block_number + 1).await?;
+    while let Some((key, _)) = entry {
+        let changed_block = key.block_number;
+        if let Some(change) = cursor
+            .seek_both_range(
+                tables::StorageChangeKey {
+                    block_number: changed_block,
+                    address,
+                },
+                location,
+            )
+            .await?
+        {
+            if change.location == location {
+                return Ok(change.value);
+            }
+        }
+        entry = cursor.seek(changed_block + 1).await?;
+    }
+
+    Ok(crate::read_account_storage(tx, address, location)
+        .await?
+        .unwrap_or_default())
+}
+
 pub mod history_index {
     use super::*;
     use crate::kv::tables::BitmapKey;
@@ -119,14 +184,266 @@ pub mod history_index {
     }
 }
 
+/// Changesets of a block range in ascending block order, blocks without changes are skipped.
+pub mod changeset {
+    use super::*;
+    use async_stream::try_stream;
+    use futures_core::Stream;
+    use tokio::pin;
+    use tokio_stream::StreamExt;
+
+    pub fn walk_account_changes<'tx: 'cur, 'cur, C>(
+        cursor: &'cur mut C,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> impl Stream<Item = anyhow::Result<(BlockNumber, tables::AccountChange)>> + 'cur
+    where
+        C: Cursor<'tx, tables::AccountChangeSet>,
+    {
+        try_stream! {
+            let walker = walk(cursor, Some(from));
+            pin!(walker);
+            while let Some((block_number, change)) = walker.try_next().await? {
+                if block_number > to {
+                    break;
+                }
+
+                yield (block_number, change);
+            }
+        }
+    }
+
+    pub fn walk_storage_changes<'tx: 'cur, 'cur, C>(
+        cursor: &'cur mut C,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> impl Stream<Item = anyhow::Result<(tables::StorageChangeKey, tables::StorageChange)>> + 'cur
+    where
+        C: Cursor<'tx, tables::StorageChangeSet>,
+    {
+        try_stream! {
+            let walker = walk(cursor, Some(from));
+            pin!(walker);
+            while let Some((key, change)) = walker.try_next().await? {
+                if key.block_number > to {
+                    break;
+                }
+
+                yield (key, change);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use crate::{
         h256_to_u256,
         kv::{new_mem_database, tables},
+        stagedsync::stages::{HistoryPrunedError, PRUNED_HISTORY},
     };
     use hex_literal::hex;
+    use tokio::pin;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn state_at_block() {
+        let db = new_mem_database().unwrap();
+        let txn = db.begin_mutable().await.unwrap();
+
+        let created: Address = hex!("c000000000000000000000000000000000000001").into();
+        let destructed: Address = hex!("d000000000000000000000000000000000000002").into();
+        let account = |nonce| Account {
+            nonce,
+            ..Default::default()
+        };
+        let location = U256::from(0x13);
+        let slot = H256::from_low_u64_be(0x13);
+
+        // `created` is created at block 1 and updated at block 3,
+        // `destructed` self-destructs at block 2
+        for (block_number, address, initial) in [
+            (1, created, None),
+            (2, destructed, Some(account(7))),
+            (3, created, Some(account(1))),
+        ] {
+            txn.set(
+                tables::AccountChangeSet,
+                BlockNumber(block_number),
+                tables::AccountChange {
+                    address,
+                    account: initial,
+                },
+            )
+            .await
+            .unwrap();
+        }
+        txn.set(tables::Account, created, account(2)).await.unwrap();
+
+        // the slot is set at block 2 and updated at block 4
+        for (block_number, initial) in [(2, 0_u64), (4, 5)] {
+            txn.set(
+                tables::StorageChangeSet,
+                tables::StorageChangeKey {
+                    block_number: BlockNumber(block_number),
+                    address: created,
+                },
+                tables::StorageChange {
+                    location: slot,
+                    value: initial.into(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+        txn.set(tables::Storage, created, (slot, 9_u64.into()))
+            .await
+            .unwrap();
+
+        for (block_number, expected_created, expected_destructed, expected_value) in [
+            (0, None, Some(account(7)), 0_u64),
+            (1, Some(account(1)), Some(account(7)), 0),
+            (2, Some(account(1)), None, 5),
+            (3, Some(account(2)), None, 5),
+            (4, Some(account(2)), None, 9),
+            (10, Some(account(2)), None, 9),
+        ] {
+            let block_number = BlockNumber(block_number);
+            assert_eq!(
+                super::account_at(&txn, created, block_number)
+                    .await
+                    .unwrap(),
+                expected_created,
+                "block {}",
+                block_number
+            );
+            assert_eq!(
+                super::account_at(&txn, destructed, block_number)
+                    .await
+                    .unwrap(),
+                expected_destructed,
+                "block {}",
+                block_number
+            );
+            assert_eq!(
+                super::storage_at(&txn, created, location, block_number)
+                    .await
+                    .unwrap(),
+                U256::from(expected_value),
+                "block {}",
+                block_number
+            );
+        }
+
+        // the changesets of the blocks 1 and 2 are pruned
+        PRUNED_HISTORY
+            .save_progress(&txn, BlockNumber(3))
+            .await
+            .unwrap();
+        assert!(super::account_at(&txn, created, BlockNumber(2))
+            .await
+            .is_ok());
+        for error in [
+            super::account_at(&txn, created, BlockNumber(1))
+                .await
+                .unwrap_err(),
+            super::storage_at(&txn, created, location, BlockNumber(1))
+                .await
+                .unwrap_err(),
+        ] {
+            assert!(matches!(
+                error.downcast_ref::<HistoryPrunedError>(),
+                Some(HistoryPrunedError {
+                    block_number: BlockNumber(1),
+                    pruned_below: BlockNumber(3),
+                })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn changesets_in_range() {
+        let db = new_mem_database().unwrap();
+        let txn = db.begin_mutable().await.unwrap();
+
+        let address1 = hex!("b000000000000000000000000000000000000001").into();
+        let address2 = hex!("b000000000000000000000000000000000000002").into();
+        let location = H256::from_low_u64_be(7);
+
+        for (block_number, address) in [
+            (1, address1),
+            (2, address2),
+            (2, address1),
+            (4, address1),
+            (6, address2),
+        ] {
+            let block_number = BlockNumber(block_number);
+            txn.set(
+                tables::AccountChangeSet,
+                block_number,
+                tables::AccountChange {
+                    address,
+                    account: None,
+                },
+            )
+            .await
+            .unwrap();
+            txn.set(
+                tables::StorageChangeSet,
+                tables::StorageChangeKey {
+                    block_number,
+                    address,
+                },
+                tables::StorageChange {
+                    location,
+                    value: block_number.0.into(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let mut cursor = txn.cursor(tables::AccountChangeSet).await.unwrap();
+        let walker =
+            super::changeset::walk_account_changes(&mut cursor, BlockNumber(2), BlockNumber(5));
+        pin!(walker);
+        let mut account_changes = vec![];
+        while let Some((block_number, change)) = walker.try_next().await.unwrap() {
+            account_changes.push((block_number, change.address));
+        }
+        assert_eq!(
+            account_changes,
+            vec![
+                (BlockNumber(2), address1),
+                (BlockNumber(2), address2),
+                (BlockNumber(4), address1)
+            ]
+        );
+
+        let mut cursor = txn.cursor(tables::StorageChangeSet).await.unwrap();
+        let walker =
+            super::changeset::walk_storage_changes(&mut cursor, BlockNumber(3), BlockNumber(6));
+        pin!(walker);
+        let mut storage_changes = vec![];
+        while let Some((key, change)) = walker.try_next().await.unwrap() {
+            storage_changes.push((key.block_number, key.address, change.value));
+        }
+        assert_eq!(
+            storage_changes,
+            vec![
+                (BlockNumber(4), address1, 4.into()),
+                (BlockNumber(6), address2, 6.into())
+            ]
+        );
+
+        // no changes in the range
+        let mut cursor = txn.cursor(tables::AccountChangeSet).await.unwrap();
+        let walker =
+            super::changeset::walk_account_changes(&mut cursor, BlockNumber(7), BlockNumber(10));
+        pin!(walker);
+        assert!(walker.try_next().await.unwrap().is_none());
+    }
 
     #[tokio::test]
     async fn read_storage() {
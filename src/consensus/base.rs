@@ -8,13 +8,19 @@ use std::time::SystemTime;
 #[derive(Debug)]
 pub struct ConsensusEngineBase {
     chain_id: ChainId,
+    eip155_block: Option<BlockNumber>,
     eip1559_block: Option<BlockNumber>,
 }
 
 impl ConsensusEngineBase {
-    pub fn new(chain_id: ChainId, eip1559_block: Option<BlockNumber>) -> Self {
+    pub fn new(
+        chain_id: ChainId,
+        eip155_block: Option<BlockNumber>,
+        eip1559_block: Option<BlockNumber>,
+    ) -> Self {
         Self {
             chain_id,
+            eip155_block,
             eip1559_block,
         }
     }
@@ -159,48 +165,12 @@ impl ConsensusEngineBase {
         header.beneficiary
     }
 
-    // https://eips.ethereum.org/EIPS/eip-1559
     fn expected_base_fee_per_gas(
         &self,
         header: &BlockHeader,
         parent: &BlockHeader,
     ) -> Option<U256> {
-        if let Some(fork_block) = self.eip1559_block {
-            if header.number >= fork_block {
-                if header.number == fork_block {
-                    return Some(param::INITIAL_BASE_FEE.into());
-                }
-
-                let parent_gas_target = parent.gas_limit / param::ELASTICITY_MULTIPLIER;
-
-                let parent_base_fee_per_gas = parent.base_fee_per_gas.unwrap();
-
-                if parent.gas_used == parent_gas_target {
-                    return Some(parent_base_fee_per_gas);
-                }
-
-                if parent.gas_used > parent_gas_target {
-                    let gas_used_delta = parent.gas_used - parent_gas_target;
-                    let base_fee_per_gas_delta = std::cmp::max(
-                        U256::one(),
-                        parent_base_fee_per_gas * U256::from(gas_used_delta)
-                            / U256::from(parent_gas_target)
-                            / U256::from(param::BASE_FEE_MAX_CHANGE_DENOMINATOR),
-                    );
-                    return Some(parent_base_fee_per_gas + base_fee_per_gas_delta);
-                } else {
-                    let gas_used_delta = parent_gas_target - parent.gas_used;
-                    let base_fee_per_gas_delta = parent_base_fee_per_gas
-                        * U256::from(gas_used_delta)
-                        / U256::from(parent_gas_target)
-                        / U256::from(param::BASE_FEE_MAX_CHANGE_DENOMINATOR);
-
-                    return Some(parent_base_fee_per_gas.saturating_sub(base_fee_per_gas_delta));
-                }
-            }
-        }
-
-        None
+        expected_base_fee_per_gas(header, parent, self.eip1559_block)
     }
 
     pub async fn pre_validate_block(
@@ -269,18 +239,121 @@ impl ConsensusEngineBase {
             }
         }
 
+        let eip155 = self
+            .eip155_block
+            .map_or(false, |eip155_block| block.header.number >= eip155_block);
         for txn in &block.transactions {
-            pre_validate_transaction(txn, self.chain_id, block.header.base_fee_per_gas)?;
+            pre_validate_transaction(txn, self.chain_id, eip155, block.header.base_fee_per_gas)?;
         }
 
         Ok(())
     }
 }
 
+// https://eips.ethereum.org/EIPS/eip-1559
+pub fn expected_base_fee_per_gas(
+    header: &BlockHeader,
+    parent: &BlockHeader,
+    eip1559_block: Option<BlockNumber>,
+) -> Option<U256> {
+    if let Some(fork_block) = eip1559_block {
+        if header.number >= fork_block {
+            if header.number == fork_block {
+                return Some(param::INITIAL_BASE_FEE.into());
+            }
+
+            let parent_gas_target = parent.gas_limit / param::ELASTICITY_MULTIPLIER;
+
+            let parent_base_fee_per_gas = parent.base_fee_per_gas.unwrap();
+
+            if parent.gas_used == parent_gas_target {
+                return Some(parent_base_fee_per_gas);
+            }
+
+            if parent.gas_used > parent_gas_target {
+                let gas_used_delta = parent.gas_used - parent_gas_target;
+                let base_fee_per_gas_delta = std::cmp::max(
+                    U256::one(),
+                    parent_base_fee_per_gas * U256::from(gas_used_delta)
+                        / U256::from(parent_gas_target)
+                        / U256::from(param::BASE_FEE_MAX_CHANGE_DENOMINATOR),
+                );
+                return Some(parent_base_fee_per_gas + base_fee_per_gas_delta);
+            } else {
+                let gas_used_delta = parent_gas_target - parent.gas_used;
+                let base_fee_per_gas_delta = parent_base_fee_per_gas * U256::from(gas_used_delta)
+                    / U256::from(parent_gas_target)
+                    / U256::from(param::BASE_FEE_MAX_CHANGE_DENOMINATOR);
+
+                return Some(parent_base_fee_per_gas.saturating_sub(base_fee_per_gas_delta));
+            }
+        }
+    }
+
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::res::chainspec::MAINNET;
+    use hex_literal::hex;
+
+    #[test]
+    fn validate_replay_protection() {
+        // https://eips.ethereum.org/EIPS/eip-155, v = 37
+        let txn = rlp::decode::<MessageWithSignature>(&hex!(
+            "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000"
+            "8025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f"
+            "761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+        ))
+        .unwrap()
+        .message;
+        assert_eq!(txn.chain_id(), Some(ChainId(1)));
+
+        assert_eq!(
+            pre_validate_transaction(&txn, MAINNET.params.chain_id, true, None),
+            Ok(())
+        );
+        // not valid before Spurious Dragon
+        assert_eq!(
+            pre_validate_transaction(&txn, MAINNET.params.chain_id, false, None),
+            Err(ValidationError::WrongChainId)
+        );
+        // replayed on another chain
+        assert_eq!(
+            pre_validate_transaction(&txn, ChainId(3), true, None),
+            Err(ValidationError::WrongChainId)
+        );
+
+        // v = 27/28 is valid both before and after
+        let unprotected = match txn {
+            Message::Legacy {
+                nonce,
+                gas_price,
+                gas_limit,
+                action,
+                value,
+                input,
+                ..
+            } => Message::Legacy {
+                chain_id: None,
+                nonce,
+                gas_price,
+                gas_limit,
+                action,
+                value,
+                input,
+            },
+            _ => unreachable!(),
+        };
+        for eip155 in [false, true] {
+            assert_eq!(
+                pre_validate_transaction(&unprotected, MAINNET.params.chain_id, eip155, None),
+                Ok(())
+            );
+        }
+    }
 
     #[test]
     fn validate_max_fee_per_gas() {
@@ -327,6 +400,7 @@ mod tests {
             let res = pre_validate_transaction(
                 &txn,
                 MAINNET.params.chain_id,
+                true,
                 Some(base_fee_per_gas.into()),
             );
 
@@ -22,6 +22,7 @@ impl Ethash {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         chain_id: ChainId,
+        eip155_block: Option<BlockNumber>,
         eip1559_block: Option<BlockNumber>,
         duration_limit: u64,
         block_reward: BTreeMap<BlockNumber, U256>,
@@ -31,7 +32,7 @@ impl Ethash {
         skip_pow_verification: bool,
     ) -> Self {
         Self {
-            base: ConsensusEngineBase::new(chain_id, eip1559_block),
+            base: ConsensusEngineBase::new(chain_id, eip155_block, eip1559_block),
             duration_limit,
             block_reward,
             homestead_formula,
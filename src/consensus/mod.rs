@@ -2,7 +2,7 @@ mod base;
 mod blockchain;
 mod ethash;
 
-pub use self::{blockchain::*, ethash::*};
+pub use self::{base::expected_base_fee_per_gas, blockchain::*, ethash::*};
 use crate::{
     models::{Block, BlockHeader, *},
     State,
@@ -154,15 +154,24 @@ impl Display for ValidationError {
 
 impl std::error::Error for ValidationError {}
 
+/// `eip155` is whether the replay protection is active, i.e. since Spurious Dragon.
+/// The chain id encoded in `v` of a legacy transaction can't be there before it.
+/// The legacy transactions without one are still valid after the fork,
+/// as EIP-155 only adds the protected ones, and mainnet has plenty of unprotected ones since.
 pub fn pre_validate_transaction(
     txn: &Message,
     canonical_chain_id: ChainId,
+    eip155: bool,
     base_fee_per_gas: Option<U256>,
 ) -> Result<(), ValidationError> {
     if let Some(chain_id) = txn.chain_id() {
         if chain_id != canonical_chain_id {
             return Err(ValidationError::WrongChainId);
         }
+
+        if !eip155 && matches!(txn, Message::Legacy { .. }) {
+            return Err(ValidationError::WrongChainId);
+        }
     }
 
     if let Some(base_fee_per_gas) = base_fee_per_gas {
@@ -190,6 +199,7 @@ pub fn engine_factory(chain_config: ChainSpec) -> anyhow::Result<Box<dyn Consens
             skip_pow_verification,
         } => Box::new(Ethash::new(
             chain_config.params.chain_id,
+            chain_config.upgrades.spurious,
             chain_config.consensus.eip1559_block,
             duration_limit,
             block_reward,
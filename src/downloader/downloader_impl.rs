@@ -1,12 +1,21 @@
 use super::sentry_status_provider::SentryStatusProvider;
 use crate::{
-    downloader::headers::downloader::{DownloaderReport, DownloaderRunState},
+    downloader::headers::{
+        downloader::{
+            DownloaderCancelSignal, DownloaderOptions, DownloaderReport, DownloaderRunState,
+        },
+        health::DownloaderHealth,
+    },
     kv,
     models::BlockNumber,
-    sentry::{chain_config::ChainConfig, sentry_client_reactor::SentryClientReactorShared},
+    sentry::{
+        chain_config::ChainConfig, sentry_client::PeerId,
+        sentry_client_reactor::SentryClientReactorShared,
+    },
 };
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 use tokio::sync::Mutex;
+use tracing::*;
 
 #[derive(Debug)]
 pub struct Downloader {
@@ -17,12 +26,12 @@ pub struct Downloader {
 impl Downloader {
     pub fn new(
         chain_config: ChainConfig,
-        mem_limit: usize,
+        options: &DownloaderOptions,
         sentry: SentryClientReactorShared,
         sentry_status_provider: SentryStatusProvider,
     ) -> anyhow::Result<Self> {
         let headers_downloader =
-            super::headers::downloader::Downloader::new(chain_config, mem_limit, sentry)?;
+            super::headers::downloader::Downloader::new(chain_config, options, sentry)?;
 
         let instance = Self {
             headers_downloader,
@@ -31,12 +40,22 @@ impl Downloader {
         Ok(instance)
     }
 
+    /// Only download from these peers.
+    pub fn set_peer_allowlist(&mut self, peer_allowlist: Option<HashSet<PeerId>>) {
+        self.headers_downloader.set_peer_allowlist(peer_allowlist);
+    }
+
+    pub fn health(&self) -> DownloaderHealth {
+        self.headers_downloader.health()
+    }
+
     pub async fn run<'downloader, 'db: 'downloader, RwTx: kv::traits::MutableTransaction<'db>>(
         &'downloader self,
         db_transaction: &'downloader RwTx,
         start_block_num: BlockNumber,
         max_blocks_count: usize,
         previous_run_state: Option<DownloaderRunState>,
+        cancel: Option<DownloaderCancelSignal>,
     ) -> anyhow::Result<DownloaderReport> {
         self.sentry_status_provider.update(db_transaction).await?;
 
@@ -44,7 +63,7 @@ impl Downloader {
         ui_system.start()?;
         let ui_system = Arc::new(Mutex::new(ui_system));
 
-        let report = self
+        let result = self
             .headers_downloader
             .run::<RwTx>(
                 db_transaction,
@@ -52,11 +71,16 @@ impl Downloader {
                 max_blocks_count,
                 previous_run_state,
                 ui_system.clone(),
+                cancel,
             )
-            .await?;
+            .await;
 
-        ui_system.try_lock()?.stop().await?;
+        // stop the UI on every path, otherwise the terminal is left in a broken state;
+        // a failure to stop it must not hide the result of the download
+        if let Err(error) = ui_system.lock().await.stop().await {
+            warn!("Failed to stop the downloader UI: {:?}", error);
+        }
 
-        Ok(report)
+        result
     }
 }
@@ -1,7 +1,11 @@
 use crate::{
     downloader::{
-        headers::downloader::DownloaderReport, sentry_status_provider::SentryStatusProvider,
-        Downloader,
+        headers::{
+            downloader::{DownloaderCancelSignal, DownloaderReport},
+            header_slices::HEADER_SLICE_SIZE,
+        },
+        sentry_status_provider::SentryStatusProvider,
+        Downloader, HeaderDownloaderOptions,
     },
     kv,
     kv::traits::*,
@@ -13,6 +17,7 @@ use crate::{
         sentry_client_reactor::{SentryClientReactor, SentryClientReactorShared},
     },
 };
+use std::time::Duration;
 
 fn make_chain_config() -> chain_config::ChainConfig {
     let chains_config = chain_config::ChainsConfig::new().unwrap();
@@ -20,6 +25,21 @@ fn make_chain_config() -> chain_config::ChainConfig {
     chains_config.get(chain_name).unwrap()
 }
 
+fn make_downloader_options() -> HeaderDownloaderOptions {
+    HeaderDownloaderOptions {
+        mem_limit: byte_unit::n_mib_bytes!(50) as usize,
+        max_in_flight_requests: None,
+        hard_mem_limit: None,
+        max_total_retries: None,
+        linear_ranges_count: 1,
+        flush_threshold: 1,
+        verify_seal: false,
+        notify_interval: Duration::ZERO,
+        window: None,
+        max_reorg_depth: None,
+    }
+}
+
 fn make_sentry_reactor(
     sentry: SentryClientMock,
     current_status_stream: sentry_client_connector::StatusStream,
@@ -32,6 +52,7 @@ fn make_sentry_reactor(
 async fn run_downloader(
     downloader: Downloader,
     sentry: SentryClientReactorShared,
+    cancel: Option<DownloaderCancelSignal>,
 ) -> anyhow::Result<DownloaderReport> {
     {
         sentry.write().await.start()?;
@@ -41,7 +62,7 @@ async fn run_downloader(
     let db_transaction = db.begin_mutable().await?;
 
     let report = downloader
-        .run(&db_transaction, BlockNumber(0), 100_000, None)
+        .run(&db_transaction, BlockNumber(0), 100_000, None, cancel)
         .await?;
 
     db_transaction.commit().await?;
@@ -69,10 +90,99 @@ async fn noop() {
     let sentry_reactor = make_sentry_reactor(sentry, status_provider.current_status_stream());
     let downloader = Downloader::new(
         chain_config,
-        byte_unit::n_mib_bytes!(50) as usize,
+        &make_downloader_options(),
         sentry_reactor.clone(),
         status_provider,
     )
     .unwrap();
-    run_downloader(downloader, sentry_reactor).await.unwrap();
+    run_downloader(downloader, sentry_reactor, None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn cancelled() {
+    let sentry = SentryClientMock::new();
+
+    let chain_config = make_chain_config();
+    let status_provider = SentryStatusProvider::new(chain_config.clone());
+    let sentry_reactor = make_sentry_reactor(sentry, status_provider.current_status_stream());
+    let downloader = Downloader::new(
+        chain_config,
+        &make_downloader_options(),
+        sentry_reactor.clone(),
+        status_provider,
+    )
+    .unwrap();
+
+    let (cancel_sender, cancel) = tokio::sync::watch::channel(false);
+    cancel_sender.send(true).unwrap();
+
+    let report = run_downloader(downloader, sentry_reactor, Some(cancel))
+        .await
+        .unwrap();
+    assert!(report.is_cancelled);
+    assert_eq!(report.final_block_num, BlockNumber(0));
+    assert!(report.final_block_num < report.target_final_block_num);
+}
+
+#[tokio::test]
+async fn max_total_retries() {
+    let sentry = SentryClientMock::new_unresponsive();
+
+    let chain_config = make_chain_config();
+    let status_provider = SentryStatusProvider::new(chain_config.clone());
+    let sentry_reactor = make_sentry_reactor(sentry, status_provider.current_status_stream());
+    let options = HeaderDownloaderOptions {
+        max_total_retries: Some(0),
+        ..make_downloader_options()
+    };
+    let downloader = Downloader::new(
+        chain_config,
+        &options,
+        sentry_reactor.clone(),
+        status_provider,
+    )
+    .unwrap();
+
+    let report = run_downloader(downloader, sentry_reactor, None)
+        .await
+        .unwrap();
+    assert!(report.is_retry_limit_exceeded);
+    assert!(!report.is_cancelled);
+    assert_eq!(report.final_block_num, BlockNumber(0));
+}
+
+#[tokio::test]
+async fn window() {
+    let sentry = SentryClientMock::new();
+
+    let chain_config = make_chain_config();
+    let status_provider = SentryStatusProvider::new(chain_config.clone());
+    let sentry_reactor = make_sentry_reactor(sentry, status_provider.current_status_stream());
+    let window = 10 * HEADER_SLICE_SIZE;
+    let options = HeaderDownloaderOptions {
+        window: Some(window),
+        ..make_downloader_options()
+    };
+    let downloader = Downloader::new(
+        chain_config,
+        &options,
+        sentry_reactor.clone(),
+        status_provider,
+    )
+    .unwrap();
+
+    let (cancel_sender, cancel) = tokio::sync::watch::channel(false);
+    cancel_sender.send(true).unwrap();
+
+    let report = run_downloader(downloader, sentry_reactor, Some(cancel))
+        .await
+        .unwrap();
+    assert!(report.is_cancelled);
+    // the slices before the window are not even created
+    assert_eq!(
+        report.final_block_num,
+        BlockNumber(report.target_final_block_num.0 - window as u64)
+    );
 }
@@ -0,0 +1,394 @@
+use crate::{
+    accessors,
+    kv::{tables, traits::*},
+    models::*,
+    stagedsync::stage::ReorgTooDeepError,
+};
+use anyhow::format_err;
+use ethereum_types::{H256, U256};
+use std::cmp::Ordering;
+use tracing::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    pub number: BlockNumber,
+    pub hash: H256,
+    pub total_difficulty: U256,
+}
+
+impl ChainTip {
+    /// Heavier by the total difficulty, the tie is broken by the lower hash,
+    /// so that all the nodes choose the same tip regardless of the arrival order.
+    fn cmp_weight(&self, other: &Self) -> Ordering {
+        self.total_difficulty
+            .cmp(&other.total_difficulty)
+            .then_with(|| other.hash.cmp(&self.hash))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeadUpdate {
+    /// The header is on a lighter branch.
+    Unchanged,
+    /// The header extends the canonical chain.
+    Extended(ChainTip),
+    /// The header's branch became canonical,
+    /// the old canonical headers above the common ancestor are not canonical anymore.
+    Reorg {
+        common_ancestor: BlockNumber,
+        old_head: ChainTip,
+        new_head: ChainTip,
+    },
+}
+
+/// Inserts the headers near the tip, where several competing branches might arrive,
+/// and keeps the heaviest of the tips canonical.
+#[derive(Debug)]
+pub struct ForkChoice {
+    head: ChainTip,
+    /// The tips of the non-canonical branches.
+    side_tips: Vec<ChainTip>,
+    max_side_tips: usize,
+    max_reorg_depth: Option<u64>,
+}
+
+impl ForkChoice {
+    /// Starts from the last saved canonical header.
+    pub async fn load<'db, Tx: Transaction<'db>>(
+        tx: &Tx,
+        max_side_tips: usize,
+    ) -> anyhow::Result<Self> {
+        let hash = tx
+            .get(tables::LastHeader, Default::default())
+            .await?
+            .ok_or_else(|| format_err!("No last header"))?;
+        let number = accessors::chain::header_number::read(tx, hash)
+            .await?
+            .ok_or_else(|| format_err!("Missing number of the last header {:?}", hash))?;
+        let total_difficulty = accessors::chain::td::read(tx, hash, number)
+            .await?
+            .ok_or_else(|| format_err!("Missing total difficulty of the last header {}", number))?;
+
+        Ok(Self {
+            head: ChainTip {
+                number,
+                hash,
+                total_difficulty,
+            },
+            side_tips: vec![],
+            max_side_tips,
+            max_reorg_depth: None,
+        })
+    }
+
+    /// Refuse to switch to a branch which forks off deeper than this below the head,
+    /// see `StagedSync::set_max_reorg_depth`.
+    pub fn set_max_reorg_depth(&mut self, max_reorg_depth: Option<u64>) {
+        self.max_reorg_depth = max_reorg_depth;
+    }
+
+    pub fn head(&self) -> ChainTip {
+        self.head
+    }
+
+    pub fn side_tips(&self) -> &[ChainTip] {
+        &self.side_tips
+    }
+
+    /// Saves the header, which parent must be saved already,
+    /// and makes its branch canonical if it's the heaviest one.
+    pub async fn insert_header<'db, RwTx: MutableTransaction<'db>>(
+        &mut self,
+        tx: &RwTx,
+        header: BlockHeader,
+    ) -> anyhow::Result<HeadUpdate> {
+        let total_difficulty =
+            accessors::chain::td::read_parent_and_accumulate(tx, &header).await?;
+        let tip = ChainTip {
+            number: header.number,
+            hash: header.hash(),
+            total_difficulty,
+        };
+        let parent_hash = header.parent_hash;
+
+        tx.set(tables::Header, (tip.number, tip.hash), header)
+            .await?;
+        tx.set(tables::HeaderNumber, tip.hash, tip.number).await?;
+        accessors::chain::td::write(tx, tip.hash, tip.number, total_difficulty).await?;
+
+        if parent_hash == self.head.hash {
+            accessors::chain::canonical_hash::write(tx, tip.number, tip.hash).await?;
+            tx.set(tables::LastHeader, Default::default(), tip.hash)
+                .await?;
+            self.head = tip;
+            return Ok(HeadUpdate::Extended(tip));
+        }
+
+        self.side_tips
+            .retain(|side_tip| side_tip.hash != parent_hash);
+
+        if tip.cmp_weight(&self.head) != Ordering::Greater {
+            self.push_side_tip(tip);
+            return Ok(HeadUpdate::Unchanged);
+        }
+
+        let common_ancestor = match self.make_canonical(tx, tip).await {
+            Ok(common_ancestor) => common_ancestor,
+            Err(error) => {
+                // e.g. too deep, the branch stays on the side
+                self.push_side_tip(tip);
+                return Err(error);
+            }
+        };
+        let old_head = self.head;
+        self.head = tip;
+        self.push_side_tip(old_head);
+
+        info!(
+            "Reorg from {}/{:?} to {}/{:?} at the common ancestor {}",
+            old_head.number, old_head.hash, tip.number, tip.hash, common_ancestor
+        );
+
+        Ok(HeadUpdate::Reorg {
+            common_ancestor,
+            old_head,
+            new_head: tip,
+        })
+    }
+
+    fn push_side_tip(&mut self, tip: ChainTip) {
+        self.side_tips.push(tip);
+        if self.side_tips.len() > self.max_side_tips {
+            self.side_tips.sort_by(|a, b| b.cmp_weight(a));
+            self.side_tips.truncate(self.max_side_tips);
+        }
+    }
+
+    /// Points the canonical hashes to the branch of the tip, returns the common ancestor.
+    /// Nothing is written if the reorg is too deep.
+    async fn make_canonical<'db, RwTx: MutableTransaction<'db>>(
+        &self,
+        tx: &RwTx,
+        tip: ChainTip,
+    ) -> anyhow::Result<BlockNumber> {
+        let mut branch = vec![];
+        let mut number = tip.number;
+        let mut hash = tip.hash;
+        loop {
+            if accessors::chain::canonical_hash::read(tx, number).await? == Some(hash) {
+                break;
+            }
+            branch.push((number, hash));
+
+            if number.0 == 0 {
+                return Err(format_err!(
+                    "Branch of {:?} has a different genesis",
+                    tip.hash
+                ));
+            }
+            hash = accessors::chain::header::read(tx, hash, number)
+                .await?
+                .ok_or_else(|| format_err!("Missing header {}/{:?}", number, hash))?
+                .parent_hash;
+            number.0 -= 1;
+        }
+        let common_ancestor = number;
+
+        if let Some(max_reorg_depth) = self.max_reorg_depth {
+            if self.head.number.saturating_sub(*common_ancestor) > max_reorg_depth {
+                return Err(ReorgTooDeepError {
+                    head: self.head.number,
+                    unwind_to: common_ancestor,
+                    max_reorg_depth,
+                }
+                .into());
+            }
+        }
+
+        for (number, hash) in branch {
+            accessors::chain::canonical_hash::write(tx, number, hash).await?;
+        }
+
+        // the old branch might have been longer
+        for number in tip.number.0 + 1..=self.head.number.0 {
+            tx.del(tables::CanonicalHeader, BlockNumber(number), None)
+                .await?;
+        }
+        tx.set(tables::LastHeader, Default::default(), tip.hash)
+            .await?;
+
+        Ok(common_ancestor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::new_mem_database;
+
+    fn child(parent: &BlockHeader, difficulty: u64, extra: u8) -> BlockHeader {
+        BlockHeader {
+            number: BlockNumber(parent.number.0 + 1),
+            parent_hash: parent.hash(),
+            difficulty: difficulty.into(),
+            extra_data: vec![extra].into(),
+            ..parent.clone()
+        }
+    }
+
+    async fn write_genesis<'db, RwTx: MutableTransaction<'db>>(tx: &RwTx) -> BlockHeader {
+        let genesis = BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT);
+        tx.set(
+            tables::Header,
+            (BlockNumber(0), genesis.hash()),
+            genesis.clone(),
+        )
+        .await
+        .unwrap();
+        tx.set(tables::HeaderNumber, genesis.hash(), BlockNumber(0))
+            .await
+            .unwrap();
+        accessors::chain::td::write(tx, genesis.hash(), BlockNumber(0), genesis.difficulty)
+            .await
+            .unwrap();
+        accessors::chain::canonical_hash::write(tx, BlockNumber(0), genesis.hash())
+            .await
+            .unwrap();
+        tx.set(tables::LastHeader, Default::default(), genesis.hash())
+            .await
+            .unwrap();
+        genesis
+    }
+
+    #[tokio::test]
+    async fn competing_tips() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let genesis = write_genesis(&tx).await;
+
+        let mut fork_choice = ForkChoice::load(&tx, 4).await.unwrap();
+        assert_eq!(fork_choice.head().hash, genesis.hash());
+
+        // a long light branch
+        let a1 = child(&genesis, 10, 0xa);
+        let a2 = child(&a1, 10, 0xa);
+        assert!(matches!(
+            fork_choice.insert_header(&tx, a1.clone()).await.unwrap(),
+            HeadUpdate::Extended(_)
+        ));
+        assert!(matches!(
+            fork_choice.insert_header(&tx, a2.clone()).await.unwrap(),
+            HeadUpdate::Extended(_)
+        ));
+
+        // a competing lighter header stays on the side
+        let b1 = child(&genesis, 15, 0xb);
+        assert_eq!(
+            fork_choice.insert_header(&tx, b1.clone()).await.unwrap(),
+            HeadUpdate::Unchanged
+        );
+        assert_eq!(fork_choice.side_tips().len(), 1);
+        assert_eq!(
+            accessors::chain::canonical_hash::read(&tx, BlockNumber(1))
+                .await
+                .unwrap(),
+            Some(a1.hash())
+        );
+
+        // the short branch becomes heavier
+        let b2 = child(&b1, 10, 0xb);
+        let update = fork_choice.insert_header(&tx, b2.clone()).await.unwrap();
+        assert_eq!(
+            update,
+            HeadUpdate::Reorg {
+                common_ancestor: BlockNumber(0),
+                old_head: ChainTip {
+                    number: BlockNumber(2),
+                    hash: a2.hash(),
+                    total_difficulty: 20.into(),
+                },
+                new_head: ChainTip {
+                    number: BlockNumber(2),
+                    hash: b2.hash(),
+                    total_difficulty: 25.into(),
+                },
+            }
+        );
+        for (number, hash) in [(BlockNumber(1), b1.hash()), (BlockNumber(2), b2.hash())] {
+            assert_eq!(
+                accessors::chain::canonical_hash::read(&tx, number)
+                    .await
+                    .unwrap(),
+                Some(hash)
+            );
+        }
+        assert_eq!(
+            tx.get(tables::LastHeader, Default::default())
+                .await
+                .unwrap(),
+            Some(b2.hash())
+        );
+        assert_eq!(fork_choice.side_tips()[0].hash, a2.hash());
+
+        // the old branch catches up with an equal weight, the lower hash wins
+        let a3 = child(&a2, 5, 0xa);
+        let update = fork_choice.insert_header(&tx, a3.clone()).await.unwrap();
+        if a3.hash() < b2.hash() {
+            assert!(matches!(update, HeadUpdate::Reorg { .. }));
+            assert_eq!(fork_choice.head().hash, a3.hash());
+        } else {
+            assert_eq!(update, HeadUpdate::Unchanged);
+            assert_eq!(fork_choice.head().hash, b2.hash());
+        }
+    }
+
+    #[tokio::test]
+    async fn max_reorg_depth() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let genesis = write_genesis(&tx).await;
+        let mut fork_choice = ForkChoice::load(&tx, 4).await.unwrap();
+        fork_choice.set_max_reorg_depth(Some(1));
+
+        let a1 = child(&genesis, 10, 0xa);
+        let a2 = child(&a1, 10, 0xa);
+        for header in [a1.clone(), a2.clone()] {
+            fork_choice.insert_header(&tx, header).await.unwrap();
+        }
+
+        // a heavier branch forking off 2 blocks below the head
+        let b1 = child(&genesis, 100, 0xb);
+        let error = fork_choice
+            .insert_header(&tx, b1.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ReorgTooDeepError>(),
+            Some(ReorgTooDeepError {
+                head: BlockNumber(2),
+                unwind_to: BlockNumber(0),
+                max_reorg_depth: 1,
+            })
+        ));
+
+        // the canonical chain is untouched, and the branch is kept on the side
+        assert_eq!(fork_choice.head().hash, a2.hash());
+        assert_eq!(fork_choice.side_tips().len(), 1);
+        assert_eq!(fork_choice.side_tips()[0].hash, b1.hash());
+        assert_eq!(
+            accessors::chain::canonical_hash::read(&tx, BlockNumber(1))
+                .await
+                .unwrap(),
+            Some(a1.hash())
+        );
+
+        // a shallow reorg is fine
+        let c2 = child(&a1, 100, 0xc);
+        assert!(matches!(
+            fork_choice.insert_header(&tx, c2).await.unwrap(),
+            HeadUpdate::Reorg { .. }
+        ));
+    }
+}
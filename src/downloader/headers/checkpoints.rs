@@ -0,0 +1,174 @@
+use super::header_slices::{HeaderSlice, InvalidReason};
+use crate::models::BlockNumber;
+use ethereum_types::H256;
+use hex_literal::hex;
+
+/// Hashes of the mainnet blocks every 960000 blocks, taken from the preverified hashes.
+const MAINNET_CHECKPOINTS: &[(u64, [u8; 32])] = &[
+    (
+        0,
+        hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"),
+    ),
+    (
+        960000,
+        hex!("4d365bcb682707bc2a8692c231957e64b67f80608e32b3b12a59792cc88f36f5"),
+    ),
+    (
+        1920000,
+        hex!("4985f5ca3d2afbec36529aa96f74de3cc10a2a4a6c44f2157a57d2c6059a11bb"),
+    ),
+    (
+        2880000,
+        hex!("41f90aac37aef027f8729b6f25ce577804511575e8af92dca2a93dd47350b0b9"),
+    ),
+    (
+        3840000,
+        hex!("d33be1a9e035458a2d56c03b3b4233618acdf6c1b37eea6952b71ac6f3ede034"),
+    ),
+    (
+        4800000,
+        hex!("047d5b3aba8e85b61c1ae9eb8ab96bb2b0e1dc6bfc780fd58d5132dd0adbb728"),
+    ),
+    (
+        5760000,
+        hex!("037ba428e39dbff8f22b1b6d5e386cb7401fa3ccb91b9375e47dd2b149fc8557"),
+    ),
+    (
+        6720000,
+        hex!("5ce57baf296882598b1f058e8ba66f63f0548445a3ab69a1dce4685f5096a21a"),
+    ),
+    (
+        7680000,
+        hex!("02a43423cb3c57bd0a3fa44cea38b5c8d4dc00b5f4c33deb2499d9428e048bad"),
+    ),
+    (
+        8640000,
+        hex!("ad0316ebdd45036920b837f5483ea9ad2d0a33131053adae2138d6e6b66d693d"),
+    ),
+    (
+        9600000,
+        hex!("caeb3b764b55780425634d6a6a6b5ca1c1459eda0b83fb40aac1a8da011e045c"),
+    ),
+    (
+        10560000,
+        hex!("3de4f1d014845a9fa33ce9639fe0473e8c6431fcc17fdc983145f22a14e7aa65"),
+    ),
+    (
+        11520000,
+        hex!("9106736da1c9ec16cd03b1680cd55f935dc920835f3efe3efc587e6be43ac753"),
+    ),
+    (
+        12480000,
+        hex!("016bf7a6dc8aabdf646d659a1488ab0570900fd9394996a3b38b25d09c959266"),
+    ),
+    (
+        13440000,
+        hex!("2bddc455df955dffe038a9784d879d439c3325bdb2b0ef54ee242ba9875db93f"),
+    ),
+];
+
+/// Known canonical hashes at some heights, so that the peers of an eclipse attack
+/// can't feed a different chain during the initial sync, like the checkpoints of Geth.
+#[derive(Clone, Debug, Default)]
+pub struct Checkpoints {
+    /// Sorted by the block number.
+    hashes: Vec<(BlockNumber, H256)>,
+}
+
+impl Checkpoints {
+    pub fn new(mut hashes: Vec<(BlockNumber, H256)>) -> Self {
+        hashes.sort_by_key(|(block_num, _)| *block_num);
+        hashes.dedup_by_key(|(block_num, _)| *block_num);
+        Self { hashes }
+    }
+
+    /// The embedded checkpoints of the chain, or none if the chain has none.
+    pub fn for_chain(chain_name: &str) -> Self {
+        match chain_name.to_lowercase().as_str() {
+            "mainnet" | "ethereum" => Self::new(
+                MAINNET_CHECKPOINTS
+                    .iter()
+                    .map(|(block_num, hash)| (BlockNumber(*block_num), H256(*hash)))
+                    .collect(),
+            ),
+            _ => Self::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn get(&self, block_num: BlockNumber) -> Option<H256> {
+        self.hashes
+            .binary_search_by_key(&block_num, |(block_num, _)| *block_num)
+            .ok()
+            .map(|index| self.hashes[index].1)
+    }
+
+    /// The checkpoints within [start_block_num, end_block_num).
+    fn in_range(
+        &self,
+        start_block_num: BlockNumber,
+        end_block_num: BlockNumber,
+    ) -> &[(BlockNumber, H256)] {
+        let start = self
+            .hashes
+            .partition_point(|(block_num, _)| *block_num < start_block_num);
+        let end = self
+            .hashes
+            .partition_point(|(block_num, _)| *block_num < end_block_num);
+        &self.hashes[start..end]
+    }
+
+    /// Verify that the headers of the slice at the checkpoint heights have the checkpoint hashes.
+    /// The hashes of the headers must be prepared.
+    pub fn verify_slice(&self, slice: &HeaderSlice) -> Result<(), InvalidReason> {
+        let headers = match &slice.headers {
+            Some(headers) => headers,
+            None => return Ok(()),
+        };
+        let end_block_num = BlockNumber(slice.start_block_num.0 + headers.len() as u64);
+        for (block_num, expected) in self.in_range(slice.start_block_num, end_block_num) {
+            let header = &headers[(block_num.0 - slice.start_block_num.0) as usize];
+            if header.number() != *block_num {
+                // the block numbers are verified separately
+                continue;
+            }
+            let got = header.hash();
+            if got != *expected {
+                return Err(InvalidReason::CheckpointMismatch {
+                    block_num: *block_num,
+                    expected: *expected,
+                    got,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let checkpoints = Checkpoints::for_chain("mainnet");
+        assert_eq!(
+            checkpoints.get(BlockNumber(0)),
+            Some(H256(hex!(
+                "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+            )))
+        );
+        assert_eq!(checkpoints.get(BlockNumber(1)), None);
+        assert!(checkpoints.get(BlockNumber(1_920_000)).is_some());
+        assert_eq!(
+            checkpoints
+                .in_range(BlockNumber(1), BlockNumber(1_920_000))
+                .len(),
+            1
+        );
+        assert!(Checkpoints::for_chain("ropsten").is_empty());
+    }
+}
@@ -3,18 +3,79 @@ use crate::{
         headers::{
             downloader_linear, downloader_preverified,
             header_slices::align_block_num_to_slice_start,
+            health::{check_health, DownloaderHealth, HEALTH_INTERVAL},
+            peer_latencies::{PeerLatencies, PeerLatency},
         },
         ui_system::UISystemShared,
     },
     kv,
     models::BlockNumber,
-    sentry::{chain_config::ChainConfig, messages::BlockHashAndNumber, sentry_client_reactor::*},
+    sentry::{
+        chain_config::ChainConfig, messages::BlockHashAndNumber, sentry_client::PeerId,
+        sentry_client_reactor::*,
+    },
+};
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
+use tokio::sync::watch;
+
+/// A cancellation signal for a downloader run.
+/// The run stops as soon as `true` is sent.
+pub type DownloaderCancelSignal = watch::Receiver<bool>;
+
+/// Resolves when the cancellation is requested, or never if there's no signal.
+pub(super) async fn wait_cancelled(cancel: &mut Option<DownloaderCancelSignal>) {
+    if let Some(receiver) = cancel {
+        loop {
+            if *receiver.borrow() {
+                return;
+            }
+            if receiver.changed().await.is_err() {
+                // the sender is gone, nobody can cancel anymore
+                break;
+            }
+        }
+    }
+    std::future::pending::<()>().await
+}
+
+pub(super) fn is_cancelled(cancel: &Option<DownloaderCancelSignal>) -> bool {
+    cancel.as_ref().map_or(false, |receiver| *receiver.borrow())
+}
+
+/// The limits and the tuning of the header download.
+#[derive(Clone, Debug)]
+pub struct DownloaderOptions {
+    /// Memory for the slices downloaded in parallel.
+    pub mem_limit: usize,
+    /// How many slices can be requested simultaneously, unlimited if None.
+    pub max_in_flight_requests: Option<usize>,
+    /// Stop requesting while the downloaded headers take more memory than this.
+    pub hard_mem_limit: Option<usize>,
+    /// Abort after this many slice request retries in total.
+    pub max_total_retries: Option<u64>,
+    /// Ranges of the linear download, downloaded in parallel.
+    pub linear_ranges_count: usize,
+    /// Save the verified slices in batches of this many.
+    pub flush_threshold: usize,
+    /// Verify the seals of the linear headers with the consensus engine.
+    pub verify_seal: bool,
+    /// Notify the stages of the slice status changes at most once per this interval.
+    pub notify_interval: Duration,
+    /// Only download the last `window` preverified headers, and the ones after them.
+    pub window: Option<usize>,
+    /// Refuse to switch to a downloaded branch which forks off deeper than this below the head.
+    pub max_reorg_depth: Option<u64>,
+}
 
 #[derive(Debug)]
 pub struct Downloader {
     downloader_preverified: downloader_preverified::DownloaderPreverified,
     downloader_linear: downloader_linear::DownloaderLinear,
+    peer_latencies: Arc<PeerLatencies>,
     genesis_block_hash: ethereum_types::H256,
 }
 
@@ -22,6 +83,15 @@ pub struct DownloaderReport {
     pub final_block_num: BlockNumber,
     pub target_final_block_num: BlockNumber,
     pub run_state: DownloaderRunState,
+    /// The run was interrupted by the cancellation signal,
+    /// final_block_num reflects the partial progress.
+    pub is_cancelled: bool,
+    /// The run was aborted, because the slices were retried more than max_total_retries times in total.
+    /// final_block_num reflects the partial progress.
+    pub is_retry_limit_exceeded: bool,
+    /// The fork choice switched to a heavier branch forking off at this block,
+    /// so the canonical headers above it are not the ones saved by the previous runs.
+    pub reorg_common_ancestor: Option<BlockNumber>,
 }
 
 #[derive(Clone, Debug)]
@@ -32,26 +102,53 @@ pub struct DownloaderRunState {
 impl Downloader {
     pub fn new(
         chain_config: ChainConfig,
-        mem_limit: usize,
+        options: &DownloaderOptions,
         sentry: SentryClientReactorShared,
     ) -> anyhow::Result<Self> {
-        let downloader_preverified = downloader_preverified::DownloaderPreverified::new(
+        let mut downloader_preverified = downloader_preverified::DownloaderPreverified::new(
             chain_config.chain_name(),
-            mem_limit,
+            options,
             sentry.clone(),
         )?;
 
-        let downloader_linear =
-            downloader_linear::DownloaderLinear::new(chain_config.clone(), mem_limit, sentry);
+        let mut downloader_linear =
+            downloader_linear::DownloaderLinear::new(chain_config.clone(), options, sentry);
+
+        let peer_latencies = Arc::new(PeerLatencies::default());
+        downloader_preverified.set_peer_latencies(peer_latencies.clone());
+        downloader_linear.set_peer_latencies(peer_latencies.clone());
 
         let instance = Self {
             downloader_preverified,
             downloader_linear,
+            peer_latencies,
             genesis_block_hash: chain_config.genesis_block_hash(),
         };
         Ok(instance)
     }
 
+    /// Only download from these peers, e.g. the trusted ones in a restricted environment.
+    /// The slices no allowed peer has stay Empty, and are requested again later.
+    pub fn set_peer_allowlist(&mut self, peer_allowlist: Option<HashSet<PeerId>>) {
+        let peer_allowlist = peer_allowlist.map(Arc::new);
+        self.downloader_preverified
+            .set_peer_allowlist(peer_allowlist.clone());
+        self.downloader_linear.set_peer_allowlist(peer_allowlist);
+    }
+
+    /// Header request latencies of the peers, the fastest first, for diagnostics.
+    pub fn peer_latencies(&self) -> Vec<(PeerId, PeerLatency)> {
+        self.peer_latencies.stats()
+    }
+
+    /// Whether the current run is making progress, for the liveness monitoring.
+    /// Doesn't block the run.
+    pub fn health(&self) -> DownloaderHealth {
+        let mut header_slices = self.downloader_preverified.active_header_slices().get();
+        header_slices.extend(self.downloader_linear.active_header_slices().get());
+        check_health(&header_slices, HEALTH_INTERVAL, SystemTime::now())
+    }
+
     async fn linear_start_block_id<
         'downloader,
         'db: 'downloader,
@@ -88,6 +185,8 @@ impl Downloader {
         Ok(linear_start_block_id)
     }
 
+    /// Downloads the headers from start_block_num, or only the last `window` preverified ones if it's set,
+    /// and then the rest of them linked one after another.
     pub async fn run<'downloader, 'db: 'downloader, RwTx: kv::traits::MutableTransaction<'db>>(
         &'downloader self,
         db_transaction: &'downloader RwTx,
@@ -95,6 +194,7 @@ impl Downloader {
         max_blocks_count: usize,
         previous_run_state: Option<DownloaderRunState>,
         ui_system: UISystemShared,
+        cancel: Option<DownloaderCancelSignal>,
     ) -> anyhow::Result<DownloaderReport> {
         let preverified_report = self
             .downloader_preverified
@@ -103,9 +203,29 @@ impl Downloader {
                 start_block_num,
                 max_blocks_count,
                 ui_system.clone(),
+                cancel.clone(),
             )
             .await?;
 
+        if preverified_report.is_cancelled
+            || is_cancelled(&cancel)
+            || preverified_report.is_retry_limit_exceeded
+        {
+            let estimated_top_block_num = preverified_report
+                .estimated_top_block_num
+                .or_else(|| previous_run_state.and_then(|state| state.estimated_top_block_num));
+            return Ok(DownloaderReport {
+                final_block_num: preverified_report.final_block_num,
+                target_final_block_num: preverified_report.target_final_block_num,
+                run_state: DownloaderRunState {
+                    estimated_top_block_num,
+                },
+                is_cancelled: preverified_report.is_cancelled || is_cancelled(&cancel),
+                is_retry_limit_exceeded: preverified_report.is_retry_limit_exceeded,
+                reorg_common_ancestor: None,
+            });
+        }
+
         let linear_start_block_id = self
             .linear_start_block_id(db_transaction, preverified_report.final_block_num)
             .await?;
@@ -122,6 +242,7 @@ impl Downloader {
                 linear_estimated_top_block_num,
                 linear_max_blocks_count,
                 ui_system,
+                cancel,
             )
             .await?;
 
@@ -131,6 +252,9 @@ impl Downloader {
             run_state: DownloaderRunState {
                 estimated_top_block_num: Some(linear_report.estimated_top_block_num),
             },
+            is_cancelled: linear_report.is_cancelled,
+            is_retry_limit_exceeded: linear_report.is_retry_limit_exceeded,
+            reorg_common_ancestor: linear_report.reorg_common_ancestor,
         };
 
         Ok(report)
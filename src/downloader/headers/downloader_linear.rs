@@ -1,13 +1,27 @@
 use super::{
-    fetch_receive_stage::FetchReceiveStage, fetch_request_stage::FetchRequestStage, header_slices,
-    header_slices::HeaderSlices, penalize_stage::PenalizeStage, refill_stage::RefillStage,
-    retry_stage::RetryStage, save_stage::SaveStage,
-    top_block_estimate_stage::TopBlockEstimateStage, verify_stage_linear::VerifyStageLinear,
-    verify_stage_linear_link::VerifyStageLinearLink, HeaderSlicesView,
+    checkpoints::Checkpoints,
+    fetch_receive_stage::FetchReceiveStage,
+    fetch_request_stage::FetchRequestStage,
+    header::BlockHeader,
+    header_slices,
+    header_slices::HeaderSlices,
+    health::ActiveHeaderSlices,
+    peer_batch_sizes::{PeerBatchSizes, MAX_PEER_BATCH_SIZE},
+    peer_latencies::PeerLatencies,
+    penalize_stage::PenalizeStage,
+    refill_stage::RefillStage,
+    retry_stage::RetryStage,
+    save_stage::{self, SaveStage},
+    status_notifier::StatusNotifier,
+    top_block_estimate_stage::TopBlockEstimateStage,
+    verify_stage_linear::VerifyStageLinear,
+    verify_stage_linear_link::VerifyStageLinearLink,
+    HeaderSlicesView,
 };
 use crate::{
     downloader::{
         headers::{
+            downloader::{wait_cancelled, DownloaderCancelSignal, DownloaderOptions},
             header_slices::align_block_num_to_slice_start,
             stage_stream::{make_stage_stream, StageStream},
         },
@@ -15,39 +29,93 @@ use crate::{
     },
     kv,
     models::BlockNumber,
-    sentry::{chain_config::ChainConfig, messages::BlockHashAndNumber, sentry_client_reactor::*},
+    sentry::{
+        chain_config::ChainConfig, messages::BlockHashAndNumber, sentry_client::PeerId,
+        sentry_client_reactor::*,
+    },
+    stagedsync::stage::ReorgTooDeepError,
 };
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 use tokio_stream::{StreamExt, StreamMap};
 use tracing::*;
 
+/// How many competing branches the fork choice of the saved headers tracks.
+const FORK_CHOICE_MAX_SIDE_TIPS: usize = 16;
+
+/// How many slices each range after the one being linked downloads ahead.
+const LOOKAHEAD_PREFETCH_SLICES: usize = 4;
+
 #[derive(Debug)]
 pub struct DownloaderLinear {
     chain_config: ChainConfig,
     mem_limit: usize,
+    max_in_flight_requests: Option<usize>,
+    hard_mem_limit: Option<usize>,
+    max_total_retries: Option<u64>,
+    ranges_count: usize,
+    flush_threshold: usize,
+    verify_seal: bool,
+    notify_interval: Duration,
+    max_reorg_depth: Option<u64>,
     sentry: SentryClientReactorShared,
+    active_header_slices: ActiveHeaderSlices,
+    peer_batch_sizes: Arc<PeerBatchSizes>,
+    peer_latencies: Arc<PeerLatencies>,
+    peer_allowlist: Option<Arc<HashSet<PeerId>>>,
+    checkpoints: Arc<Checkpoints>,
 }
 
 pub struct DownloaderLinearReport {
     pub loaded_count: usize,
     pub final_block_num: BlockNumber,
     pub target_final_block_num: BlockNumber,
+    pub is_cancelled: bool,
+    pub is_retry_limit_exceeded: bool,
     pub estimated_top_block_num: BlockNumber,
+    pub reorg_common_ancestor: Option<BlockNumber>,
 }
 
 impl DownloaderLinear {
     pub fn new(
         chain_config: ChainConfig,
-        mem_limit: usize,
+        options: &DownloaderOptions,
         sentry: SentryClientReactorShared,
     ) -> Self {
+        let checkpoints = Arc::new(Checkpoints::for_chain(&chain_config.chain_name()));
         Self {
             chain_config,
-            mem_limit,
+            mem_limit: options.mem_limit,
+            max_in_flight_requests: options.max_in_flight_requests,
+            hard_mem_limit: options.hard_mem_limit,
+            max_total_retries: options.max_total_retries,
+            ranges_count: options.linear_ranges_count,
+            flush_threshold: options.flush_threshold,
+            verify_seal: options.verify_seal,
+            notify_interval: options.notify_interval,
+            max_reorg_depth: options.max_reorg_depth,
             sentry,
+            active_header_slices: ActiveHeaderSlices::default(),
+            peer_batch_sizes: Arc::new(PeerBatchSizes::new(MAX_PEER_BATCH_SIZE)),
+            peer_latencies: Arc::default(),
+            peer_allowlist: None,
+            checkpoints,
         }
     }
 
+    pub fn active_header_slices(&self) -> &ActiveHeaderSlices {
+        &self.active_header_slices
+    }
+
+    /// Shares the latencies with the other downloaders.
+    pub fn set_peer_latencies(&mut self, peer_latencies: Arc<PeerLatencies>) {
+        self.peer_latencies = peer_latencies;
+    }
+
+    /// Only download from these peers.
+    pub fn set_peer_allowlist(&mut self, peer_allowlist: Option<Arc<HashSet<PeerId>>>) {
+        self.peer_allowlist = peer_allowlist;
+    }
+
     async fn estimate_top_block_num(
         &self,
         start_block_num: BlockNumber,
@@ -72,6 +140,7 @@ impl DownloaderLinear {
         estimated_top_block_num: Option<BlockNumber>,
         max_blocks_count: usize,
         ui_system: UISystemShared,
+        mut cancel: Option<DownloaderCancelSignal>,
     ) -> anyhow::Result<DownloaderLinearReport> {
         let start_block_num = start_block_id.number;
 
@@ -100,18 +169,67 @@ impl DownloaderLinear {
                 loaded_count: 0,
                 final_block_num: start_block_num,
                 target_final_block_num,
+                is_cancelled: false,
+                is_retry_limit_exceeded: false,
                 estimated_top_block_num,
+                reorg_common_ancestor: None,
             });
         }
 
-        let header_slices = Arc::new(HeaderSlices::new(
-            self.mem_limit,
-            start_block_num,
-            final_block_num,
-        ));
+        // skip the slices saved by a previous run,
+        // but the last of them, because the next slice is linked to its hash
+        let saved_prefix_end =
+            save_stage::saved_prefix_end(db_transaction, start_block_num, final_block_num).await?;
+        let start_block_id = if saved_prefix_end.0
+            > start_block_num.0 + header_slices::HEADER_SLICE_SIZE as u64
+        {
+            let number = BlockNumber(saved_prefix_end.0 - header_slices::HEADER_SLICE_SIZE as u64);
+            let hash = db_transaction
+                .get(kv::tables::CanonicalHeader, number)
+                .await?
+                .ok_or_else(|| anyhow::format_err!("header {} hash not found", number.0))?;
+            debug!(
+                "DownloaderLinear: headers until {} are already saved",
+                number.0
+            );
+            BlockHashAndNumber { number, hash }
+        } else {
+            start_block_id
+        };
+
+        // The ranges are downloaded in parallel, but only the range being linked
+        // can be verified and saved, so it takes most of the memory.
+        // The ranges after it only prefetch a few slices until it's their turn.
+        let ranges = split_into_ranges(start_block_id.number, final_block_num, self.ranges_count);
+        let ranges_count = ranges.len();
+        let (linked_mem_limit, lookahead_mem_limit) = split_mem_limit(self.mem_limit, ranges_count);
+        let mut header_slices_ranges = Vec::with_capacity(ranges_count);
+        for (range, &(range_start_block_num, range_final_block_num)) in ranges.iter().enumerate() {
+            header_slices_ranges.push(Arc::new(HeaderSlices::new(
+                if range == 0 {
+                    linked_mem_limit
+                } else {
+                    lookahead_mem_limit
+                },
+                range_start_block_num,
+                range_final_block_num,
+            )?));
+        }
+        if ranges_count > 1 {
+            debug!(
+                "DownloaderLinear: downloading {} ranges in parallel: {:?}",
+                ranges_count, ranges
+            );
+        }
         let sentry = self.sentry.clone();
 
-        let header_slices_view = HeaderSlicesView::new(header_slices.clone(), "DownloaderLinear");
+        let _active_header_slices_scopes = header_slices_ranges
+            .iter()
+            .map(|header_slices| self.active_header_slices.register(header_slices.clone()))
+            .collect::<Vec<_>>();
+
+        let header_slices_view =
+            HeaderSlicesView::new(header_slices_ranges.clone(), "DownloaderLinear");
         let _header_slices_view_scope =
             UISystemViewScope::new(&ui_system, Box::new(header_slices_view));
 
@@ -122,69 +240,264 @@ impl DownloaderLinear {
         // although most of the time only one of the stages is actively running,
         // while the others are waiting for the status updates or timeouts.
 
-        let fetch_request_stage = FetchRequestStage::new(
-            header_slices.clone(),
-            sentry.clone(),
-            header_slices::HEADER_SLICE_SIZE,
-        );
-        let fetch_receive_stage = FetchReceiveStage::new(header_slices.clone(), sentry.clone());
-        let retry_stage = RetryStage::new(header_slices.clone());
-        let verify_stage = VerifyStageLinear::new(
-            header_slices.clone(),
-            header_slices::HEADER_SLICE_SIZE,
-            self.chain_config.clone(),
-        );
+        let mut stream = StreamMap::<(usize, &str), StageStream>::new();
+        let mut can_proceed_checks = Vec::with_capacity(ranges_count);
+        let mut reorg_checks = Vec::with_capacity(ranges_count);
+        for (range, header_slices) in header_slices_ranges.iter().enumerate() {
+            let mut fetch_request_stage = FetchRequestStage::new(
+                header_slices.clone(),
+                sentry.clone(),
+                header_slices::HEADER_SLICE_SIZE,
+                self.max_in_flight_requests.map(|max_in_flight_requests| {
+                    (max_in_flight_requests + ranges_count - 1) / ranges_count
+                }),
+                // the windows split the memory, any range can become the linked one
+                self.hard_mem_limit,
+            );
+            fetch_request_stage.set_peer_batch_sizes(self.peer_batch_sizes.clone());
+            fetch_request_stage.set_peer_latencies(self.peer_latencies.clone());
+            if let Some(peer_allowlist) = &self.peer_allowlist {
+                fetch_request_stage.set_peer_allowlist(peer_allowlist.clone());
+            }
+            let mut fetch_receive_stage =
+                FetchReceiveStage::new(header_slices.clone(), sentry.clone());
+            fetch_receive_stage.set_peer_batch_sizes(self.peer_batch_sizes.clone());
+            fetch_receive_stage.set_peer_latencies(self.peer_latencies.clone());
+            if let Some(peer_allowlist) = &self.peer_allowlist {
+                fetch_receive_stage.set_peer_allowlist(peer_allowlist.clone());
+            }
+            let mut retry_stage = RetryStage::new(header_slices.clone(), sentry.clone());
+            retry_stage.set_peer_batch_sizes(self.peer_batch_sizes.clone());
+            let verify_stage = VerifyStageLinear::new(
+                header_slices.clone(),
+                header_slices::HEADER_SLICE_SIZE,
+                self.chain_config.clone(),
+                self.verify_seal,
+                self.checkpoints.clone(),
+            )?;
+            let penalize_stage = PenalizeStage::new(header_slices.clone(), sentry.clone());
+            let mut save_stage =
+                SaveStage::<RwTx>::new(header_slices.clone(), self.flush_threshold, db_transaction);
+            // unlike the preverified ones, these headers might be on a competing branch
+            save_stage.set_fork_choice(Some(FORK_CHOICE_MAX_SIDE_TIPS));
+            save_stage.set_max_reorg_depth(self.max_reorg_depth);
+            let refill_stage = RefillStage::new(header_slices.clone());
+
+            can_proceed_checks.push(fetch_receive_stage.can_proceed_check());
+            reorg_checks.push(save_stage.reorg_check());
+
+            stream.insert(
+                (range, "fetch_request_stage"),
+                make_stage_stream(fetch_request_stage),
+            );
+            stream.insert(
+                (range, "fetch_receive_stage"),
+                make_stage_stream(fetch_receive_stage),
+            );
+            stream.insert((range, "retry_stage"), make_stage_stream(retry_stage));
+            stream.insert((range, "verify_stage"), make_stage_stream(verify_stage));
+            stream.insert((range, "penalize_stage"), make_stage_stream(penalize_stage));
+            stream.insert((range, "save_stage"), make_stage_stream(save_stage));
+            stream.insert((range, "refill_stage"), make_stage_stream(refill_stage));
+        }
+
+        // a single stage links all the ranges in order, including the joints between them
         let verify_link_stage = VerifyStageLinearLink::new(
-            header_slices.clone(),
+            header_slices_ranges.clone(),
             self.chain_config.clone(),
-            start_block_num,
+            start_block_id.number,
             start_block_id.hash,
         );
-        let penalize_stage = PenalizeStage::new(header_slices.clone(), sentry.clone());
-        let save_stage = SaveStage::<RwTx>::new(header_slices.clone(), db_transaction);
-        let refill_stage = RefillStage::new(header_slices.clone());
-
-        let can_proceed = fetch_receive_stage.can_proceed_check();
-
-        let mut stream = StreamMap::<&str, StageStream>::new();
-        stream.insert(
-            "fetch_request_stage",
-            make_stage_stream(fetch_request_stage),
-        );
         stream.insert(
-            "fetch_receive_stage",
-            make_stage_stream(fetch_receive_stage),
+            (0, "verify_link_stage"),
+            make_stage_stream(verify_link_stage),
         );
-        stream.insert("retry_stage", make_stage_stream(retry_stage));
-        stream.insert("verify_stage", make_stage_stream(verify_stage));
-        stream.insert("verify_link_stage", make_stage_stream(verify_link_stage));
-        stream.insert("penalize_stage", make_stage_stream(penalize_stage));
-        stream.insert("save_stage", make_stage_stream(save_stage));
-        stream.insert("refill_stage", make_stage_stream(refill_stage));
-
-        while let Some((key, result)) = stream.next().await {
-            if result.is_err() {
-                error!("Downloader headers {} failure: {:?}", key, result);
+
+        let mut status_notifier =
+            StatusNotifier::new(header_slices_ranges.clone(), self.notify_interval);
+
+        let mut linked_range = 0;
+        let mut is_cancelled = false;
+        let mut is_retry_limit_exceeded = false;
+        loop {
+            let (key, result) = tokio::select! {
+                biased;
+                _ = wait_cancelled(&mut cancel) => {
+                    info!("DownloaderLinear: cancelled");
+                    is_cancelled = true;
+                    break;
+                }
+                _ = status_notifier.wait_deferred() => {
+                    status_notifier.notify_deferred();
+                    continue;
+                }
+                item = stream.next() => match item {
+                    Some(item) => item,
+                    None => break,
+                },
+            };
+
+            if let Err(error) = result {
+                error!("Downloader headers {:?} failure: {:?}", key, error);
+                // downloading again would hit the same branch, it needs a manual intervention
+                if error.is::<ReorgTooDeepError>() {
+                    return Err(error);
+                }
                 break;
             }
 
-            if !can_proceed() {
+            if !can_proceed_checks.iter().all(|can_proceed| can_proceed()) {
                 break;
             }
-            if header_slices.is_empty_at_final_position() {
+            if header_slices_ranges
+                .iter()
+                .all(|header_slices| header_slices.is_empty_at_final_position())
+            {
                 break;
             }
 
-            header_slices.notify_status_watchers();
+            // the next range takes over the window of the range which is linked and saved
+            while linked_range + 1 < ranges_count
+                && header_slices_ranges[linked_range].is_empty_at_final_position()
+            {
+                linked_range += 1;
+                let header_slices = &header_slices_ranges[linked_range];
+                header_slices.set_mem_limit(linked_mem_limit);
+                header_slices.refill();
+                debug!(
+                    "DownloaderLinear: linking the range from {}",
+                    header_slices.min_block_num().0
+                );
+            }
+            if let Some(max_total_retries) = self.max_total_retries {
+                let total_retries = header_slices_ranges
+                    .iter()
+                    .map(|header_slices| header_slices.total_retries())
+                    .sum::<u64>();
+                if total_retries > max_total_retries {
+                    error!(
+                        "DownloaderLinear: giving up after {} retries (max {})",
+                        total_retries, max_total_retries
+                    );
+                    is_retry_limit_exceeded = true;
+                    break;
+                }
+            }
+
+            status_notifier.notify();
         }
 
+        // the headers are saved contiguously up to the first unfinished range
+        let saved_block_num = header_slices_ranges
+            .iter()
+            .find(|header_slices| !header_slices.is_empty_at_final_position())
+            .map_or(final_block_num, |header_slices| {
+                header_slices.min_block_num()
+            });
+
         let report = DownloaderLinearReport {
-            loaded_count: (header_slices.min_block_num().0 - start_block_num.0) as usize,
-            final_block_num: header_slices.min_block_num(),
+            loaded_count: (saved_block_num.0 - start_block_num.0) as usize,
+            final_block_num: saved_block_num,
             target_final_block_num,
+            is_cancelled,
+            is_retry_limit_exceeded,
             estimated_top_block_num,
+            reorg_common_ancestor: reorg_checks
+                .iter()
+                .filter_map(|reorg_check| reorg_check())
+                .min(),
         };
 
         Ok(report)
     }
 }
+
+/// The memory of the range being linked and of each look-ahead range:
+/// the look-ahead ranges get up to LOOKAHEAD_PREFETCH_SLICES each, and the linked range gets the rest.
+fn split_mem_limit(mem_limit: usize, ranges_count: usize) -> (usize, usize) {
+    let slice_mem = std::mem::size_of::<BlockHeader>() * header_slices::HEADER_SLICE_SIZE;
+    if ranges_count <= 1 {
+        return (mem_limit, 0);
+    }
+    let lookahead_mem_limit = std::cmp::max(
+        slice_mem,
+        std::cmp::min(
+            mem_limit / ranges_count,
+            slice_mem * LOOKAHEAD_PREFETCH_SLICES,
+        ),
+    );
+    let linked_mem_limit = std::cmp::max(
+        slice_mem,
+        mem_limit.saturating_sub(lookahead_mem_limit * (ranges_count - 1)),
+    );
+    (linked_mem_limit, lookahead_mem_limit)
+}
+
+/// Splits the blocks into up to `ranges_count` adjacent ranges of whole slices, as even as possible.
+fn split_into_ranges(
+    start_block_num: BlockNumber,
+    final_block_num: BlockNumber,
+    ranges_count: usize,
+) -> Vec<(BlockNumber, BlockNumber)> {
+    let slice_size = header_slices::HEADER_SLICE_SIZE as u64;
+    let slices_count = (final_block_num.0 - start_block_num.0) / slice_size;
+    let ranges_count = std::cmp::max(1, std::cmp::min(ranges_count as u64, slices_count));
+
+    let mut ranges = Vec::new();
+    let mut range_start_block_num = start_block_num;
+    for range in 0..ranges_count {
+        let range_slices_count =
+            slices_count / ranges_count + u64::from(range < slices_count % ranges_count);
+        let range_final_block_num =
+            BlockNumber(range_start_block_num.0 + range_slices_count * slice_size);
+        ranges.push((range_start_block_num, range_final_block_num));
+        range_start_block_num = range_final_block_num;
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_into_ranges_evenly() {
+        let slice_size = header_slices::HEADER_SLICE_SIZE as u64;
+        let block_num = |slices: u64| BlockNumber(slices * slice_size);
+
+        assert_eq!(
+            split_into_ranges(block_num(0), block_num(10), 1),
+            vec![(block_num(0), block_num(10))]
+        );
+        assert_eq!(
+            split_into_ranges(block_num(2), block_num(12), 4),
+            vec![
+                (block_num(2), block_num(5)),
+                (block_num(5), block_num(8)),
+                (block_num(8), block_num(10)),
+                (block_num(10), block_num(12)),
+            ]
+        );
+        // no empty ranges
+        assert_eq!(
+            split_into_ranges(block_num(0), block_num(2), 4),
+            vec![(block_num(0), block_num(1)), (block_num(1), block_num(2))]
+        );
+    }
+
+    #[test]
+    fn split_mem_limit_for_lookahead() {
+        let slice_mem = std::mem::size_of::<BlockHeader>() * header_slices::HEADER_SLICE_SIZE;
+
+        assert_eq!(split_mem_limit(slice_mem * 100, 1), (slice_mem * 100, 0));
+        assert_eq!(
+            split_mem_limit(slice_mem * 100, 3),
+            (
+                slice_mem * (100 - 2 * LOOKAHEAD_PREFETCH_SLICES),
+                slice_mem * LOOKAHEAD_PREFETCH_SLICES
+            )
+        );
+        // at least a slice each
+        assert_eq!(split_mem_limit(slice_mem, 3), (slice_mem, slice_mem));
+    }
+}
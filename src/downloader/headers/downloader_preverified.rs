@@ -1,24 +1,35 @@
 use super::{
-    fetch_receive_stage::FetchReceiveStage, fetch_request_stage::FetchRequestStage, header_slices,
-    header_slices::HeaderSlices, penalize_stage::PenalizeStage,
-    preverified_hashes_config::PreverifiedHashesConfig, refill_stage::RefillStage,
-    retry_stage::RetryStage, save_stage::SaveStage,
+    fetch_receive_stage::FetchReceiveStage,
+    fetch_request_stage::FetchRequestStage,
+    header_slices,
+    header_slices::HeaderSlices,
+    health::ActiveHeaderSlices,
+    peer_batch_sizes::{PeerBatchSizes, MAX_PEER_BATCH_SIZE},
+    peer_latencies::PeerLatencies,
+    penalize_stage::PenalizeStage,
+    preverified_hashes_config::PreverifiedHashesConfig,
+    refill_stage::RefillStage,
+    retry_stage::RetryStage,
+    save_stage::{self, SaveStage},
+    status_notifier::StatusNotifier,
     top_block_estimate_stage::TopBlockEstimateStage,
-    verify_stage_preverified::VerifyStagePreverified, HeaderSlicesView,
+    verify_stage_preverified::VerifyStagePreverified,
+    HeaderSlicesView,
 };
 use crate::{
     downloader::{
         headers::{
-            header_slices::align_block_num_to_slice_start,
+            downloader::{wait_cancelled, DownloaderCancelSignal, DownloaderOptions},
+            header_slices::{align_block_num_to_slice_start, window_start_block_num},
             stage_stream::{make_stage_stream, StageStream},
         },
         ui_system::{UISystemShared, UISystemViewScope},
     },
     kv,
     models::BlockNumber,
-    sentry::sentry_client_reactor::*,
+    sentry::{sentry_client::PeerId, sentry_client_reactor::*},
 };
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 use tokio_stream::{StreamExt, StreamMap};
 use tracing::*;
 
@@ -26,32 +37,68 @@ use tracing::*;
 pub struct DownloaderPreverified {
     preverified_hashes_config: PreverifiedHashesConfig,
     mem_limit: usize,
+    max_in_flight_requests: Option<usize>,
+    hard_mem_limit: Option<usize>,
+    max_total_retries: Option<u64>,
+    flush_threshold: usize,
+    notify_interval: Duration,
+    window: Option<usize>,
     sentry: SentryClientReactorShared,
+    active_header_slices: ActiveHeaderSlices,
+    peer_batch_sizes: Arc<PeerBatchSizes>,
+    peer_latencies: Arc<PeerLatencies>,
+    peer_allowlist: Option<Arc<HashSet<PeerId>>>,
 }
 
 pub struct DownloaderPreverifiedReport {
     pub loaded_count: usize,
     pub final_block_num: BlockNumber,
     pub target_final_block_num: BlockNumber,
+    pub is_cancelled: bool,
+    pub is_retry_limit_exceeded: bool,
     pub estimated_top_block_num: Option<BlockNumber>,
 }
 
 impl DownloaderPreverified {
     pub fn new(
         chain_name: String,
-        mem_limit: usize,
+        options: &DownloaderOptions,
         sentry: SentryClientReactorShared,
     ) -> anyhow::Result<Self> {
         let preverified_hashes_config = PreverifiedHashesConfig::new(&chain_name)?;
 
         let instance = Self {
             preverified_hashes_config,
-            mem_limit,
+            mem_limit: options.mem_limit,
+            max_in_flight_requests: options.max_in_flight_requests,
+            hard_mem_limit: options.hard_mem_limit,
+            max_total_retries: options.max_total_retries,
+            flush_threshold: options.flush_threshold,
+            notify_interval: options.notify_interval,
+            window: options.window,
             sentry,
+            active_header_slices: ActiveHeaderSlices::default(),
+            peer_batch_sizes: Arc::new(PeerBatchSizes::new(MAX_PEER_BATCH_SIZE)),
+            peer_latencies: Arc::default(),
+            peer_allowlist: None,
         };
         Ok(instance)
     }
 
+    pub fn active_header_slices(&self) -> &ActiveHeaderSlices {
+        &self.active_header_slices
+    }
+
+    /// Shares the latencies with the other downloaders.
+    pub fn set_peer_latencies(&mut self, peer_latencies: Arc<PeerLatencies>) {
+        self.peer_latencies = peer_latencies;
+    }
+
+    /// Only download from these peers.
+    pub fn set_peer_allowlist(&mut self, peer_allowlist: Option<Arc<HashSet<PeerId>>>) {
+        self.peer_allowlist = peer_allowlist;
+    }
+
     fn target_final_block_num(&self) -> BlockNumber {
         let slice_size = header_slices::HEADER_SLICE_SIZE as u64;
         BlockNumber((self.preverified_hashes_config.hashes.len() as u64 - 1) * slice_size)
@@ -63,9 +110,15 @@ impl DownloaderPreverified {
         start_block_num: BlockNumber,
         max_blocks_count: usize,
         ui_system: UISystemShared,
+        mut cancel: Option<DownloaderCancelSignal>,
     ) -> anyhow::Result<DownloaderPreverifiedReport> {
         let start_block_num = align_block_num_to_slice_start(start_block_num);
         let target_final_block_num = self.target_final_block_num();
+        // skip the headers before the window, they are verified independently of each other
+        let start_block_num = match self.window {
+            Some(window) => window_start_block_num(start_block_num, target_final_block_num, window),
+            None => start_block_num,
+        };
         let final_block_num = BlockNumber(std::cmp::min(
             target_final_block_num.0,
             align_block_num_to_slice_start(BlockNumber(
@@ -79,6 +132,8 @@ impl DownloaderPreverified {
                 loaded_count: 0,
                 final_block_num: start_block_num,
                 target_final_block_num,
+                is_cancelled: false,
+                is_retry_limit_exceeded: false,
                 estimated_top_block_num: None,
             });
         }
@@ -87,11 +142,15 @@ impl DownloaderPreverified {
             self.mem_limit,
             start_block_num,
             final_block_num,
-        ));
+        )?);
+        // a previous run might have saved some of the slices already
+        save_stage::mark_saved_slices(&header_slices, db_transaction).await?;
         let sentry = self.sentry.clone();
 
+        let _active_header_slices_scope = self.active_header_slices.register(header_slices.clone());
+
         let header_slices_view =
-            HeaderSlicesView::new(header_slices.clone(), "DownloaderPreverified");
+            HeaderSlicesView::new(vec![header_slices.clone()], "DownloaderPreverified");
         let _header_slices_view_scope =
             UISystemViewScope::new(&ui_system, Box::new(header_slices_view));
 
@@ -102,19 +161,33 @@ impl DownloaderPreverified {
         // although most of the time only one of the stages is actively running,
         // while the others are waiting for the status updates or timeouts.
 
-        let fetch_request_stage = FetchRequestStage::new(
+        let mut fetch_request_stage = FetchRequestStage::new(
             header_slices.clone(),
             sentry.clone(),
             header_slices::HEADER_SLICE_SIZE + 1,
+            self.max_in_flight_requests,
+            self.hard_mem_limit,
         );
-        let fetch_receive_stage = FetchReceiveStage::new(header_slices.clone(), sentry.clone());
-        let retry_stage = RetryStage::new(header_slices.clone());
+        fetch_request_stage.set_peer_batch_sizes(self.peer_batch_sizes.clone());
+        fetch_request_stage.set_peer_latencies(self.peer_latencies.clone());
+        if let Some(peer_allowlist) = &self.peer_allowlist {
+            fetch_request_stage.set_peer_allowlist(peer_allowlist.clone());
+        }
+        let mut fetch_receive_stage = FetchReceiveStage::new(header_slices.clone(), sentry.clone());
+        fetch_receive_stage.set_peer_batch_sizes(self.peer_batch_sizes.clone());
+        fetch_receive_stage.set_peer_latencies(self.peer_latencies.clone());
+        if let Some(peer_allowlist) = &self.peer_allowlist {
+            fetch_receive_stage.set_peer_allowlist(peer_allowlist.clone());
+        }
+        let mut retry_stage = RetryStage::new(header_slices.clone(), sentry.clone());
+        retry_stage.set_peer_batch_sizes(self.peer_batch_sizes.clone());
         let verify_stage = VerifyStagePreverified::new(
             header_slices.clone(),
             self.preverified_hashes_config.clone(),
         );
         let penalize_stage = PenalizeStage::new(header_slices.clone(), sentry.clone());
-        let save_stage = SaveStage::<RwTx>::new(header_slices.clone(), db_transaction);
+        let save_stage =
+            SaveStage::<RwTx>::new(header_slices.clone(), self.flush_threshold, db_transaction);
         let refill_stage = RefillStage::new(header_slices.clone());
         let top_block_estimate_stage = TopBlockEstimateStage::new(sentry.clone());
 
@@ -141,7 +214,29 @@ impl DownloaderPreverified {
             make_stage_stream(top_block_estimate_stage),
         );
 
-        while let Some((key, result)) = stream.next().await {
+        let mut status_notifier =
+            StatusNotifier::new(vec![header_slices.clone()], self.notify_interval);
+
+        let mut is_cancelled = false;
+        let mut is_retry_limit_exceeded = false;
+        loop {
+            let (key, result) = tokio::select! {
+                biased;
+                _ = wait_cancelled(&mut cancel) => {
+                    info!("DownloaderPreverified: cancelled");
+                    is_cancelled = true;
+                    break;
+                }
+                _ = status_notifier.wait_deferred() => {
+                    status_notifier.notify_deferred();
+                    continue;
+                }
+                item = stream.next() => match item {
+                    Some(item) => item,
+                    None => break,
+                },
+            };
+
             if result.is_err() {
                 error!("Downloader headers {} failure: {:?}", key, result);
                 break;
@@ -153,14 +248,27 @@ impl DownloaderPreverified {
             if header_slices.is_empty_at_final_position() {
                 break;
             }
+            if let Some(max_total_retries) = self.max_total_retries {
+                let total_retries = header_slices.total_retries();
+                if total_retries > max_total_retries {
+                    error!(
+                        "DownloaderPreverified: giving up after {} retries (max {})",
+                        total_retries, max_total_retries
+                    );
+                    is_retry_limit_exceeded = true;
+                    break;
+                }
+            }
 
-            header_slices.notify_status_watchers();
+            status_notifier.notify();
         }
 
         let report = DownloaderPreverifiedReport {
             loaded_count: (header_slices.min_block_num().0 - start_block_num.0) as usize,
             final_block_num: header_slices.min_block_num(),
             target_final_block_num,
+            is_cancelled,
+            is_retry_limit_exceeded,
             estimated_top_block_num: estimated_top_block_num_provider(),
         };
 
@@ -1,18 +1,25 @@
 use super::{
     header::BlockHeader,
     header_slices,
-    header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices},
+    header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices, InvalidReason},
+    peer_batch_sizes::PeerBatchSizes,
+    peer_latencies::PeerLatencies,
 };
-use crate::sentry::{
-    messages::{BlockHeadersMessage, EthMessageId, Message},
-    sentry_client::PeerId,
-    sentry_client_reactor::*,
+use crate::{
+    models::{self, HeaderDecodeError},
+    sentry::{
+        messages::{BlockHeadersMessage, EthMessageId, Message},
+        sentry_client::PeerId,
+        sentry_client_reactor::*,
+    },
 };
 use futures_core::Stream;
 use std::{
+    collections::HashSet,
     ops::DerefMut,
     pin::Pin,
     sync::{atomic::*, Arc},
+    time,
 };
 use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
@@ -31,6 +38,9 @@ pub struct FetchReceiveStage {
     sentry: SentryClientReactorShared,
     is_over: Arc<AtomicBool>,
     message_stream: Mutex<Option<BlockHeadersMessageStream>>,
+    peer_batch_sizes: Option<Arc<PeerBatchSizes>>,
+    peer_latencies: Option<Arc<PeerLatencies>>,
+    peer_allowlist: Option<Arc<HashSet<PeerId>>>,
 }
 
 impl FetchReceiveStage {
@@ -40,9 +50,27 @@ impl FetchReceiveStage {
             sentry,
             is_over: Arc::new(false.into()),
             message_stream: Mutex::new(None),
+            peer_batch_sizes: None,
+            peer_latencies: None,
+            peer_allowlist: None,
         }
     }
 
+    /// Grow the batches of the peers which send the slices.
+    pub fn set_peer_batch_sizes(&mut self, peer_batch_sizes: Arc<PeerBatchSizes>) {
+        self.peer_batch_sizes = Some(peer_batch_sizes);
+    }
+
+    /// Measure the latencies of the peers which send the slices.
+    pub fn set_peer_latencies(&mut self, peer_latencies: Arc<PeerLatencies>) {
+        self.peer_latencies = Some(peer_latencies);
+    }
+
+    /// Ignore the slices sent by the other peers.
+    pub fn set_peer_allowlist(&mut self, peer_allowlist: Arc<HashSet<PeerId>>) {
+        self.peer_allowlist = Some(peer_allowlist);
+    }
+
     pub async fn execute(&self) -> anyhow::Result<()> {
         debug!("FetchReceiveStage: start");
         let mut message_stream = self.message_stream.try_lock()?;
@@ -63,7 +91,24 @@ impl FetchReceiveStage {
     fn on_headers_message(&self, message_from_peer: BlockHeadersMessageFromPeer) {
         debug!("FetchReceiveStage: received a headers slice");
 
+        if let Some(peer_allowlist) = &self.peer_allowlist {
+            let from_peer_id = message_from_peer.from_peer_id;
+            if !from_peer_id.map_or(false, |peer_id| peer_allowlist.contains(&peer_id)) {
+                debug!(
+                    "FetchReceiveStage ignores a headers slice from a peer not allowed: {:?}",
+                    from_peer_id
+                );
+                return;
+            }
+        }
+
         let headers = message_from_peer.message.headers;
+
+        if let Some(error) = message_from_peer.message.malformed {
+            self.on_malformed_headers_message(headers, error, message_from_peer.from_peer_id);
+            return;
+        }
+
         if headers.len() < header_slices::HEADER_SLICE_SIZE {
             warn!(
                 "FetchReceiveStage got a headers slice of a smaller size: {}",
@@ -97,14 +142,58 @@ impl FetchReceiveStage {
         }
     }
 
+    fn on_malformed_headers_message(
+        &self,
+        headers: Vec<models::BlockHeader>,
+        error: HeaderDecodeError,
+        from_peer_id: Option<PeerId>,
+    ) {
+        // the slice can only be identified if at least the first header is fine
+        let start_block_num = match headers.first() {
+            Some(header) => header.number,
+            None => {
+                warn!(
+                    "FetchReceiveStage ignores a malformed headers slice from {:?}: {}",
+                    from_peer_id, error
+                );
+                return;
+            }
+        };
+
+        if let Some(slice_lock) = self.header_slices.find_by_start_block_num(start_block_num) {
+            let mut slice = slice_lock.write();
+            if slice.status == HeaderSliceStatus::Waiting {
+                warn!(
+                    "FetchReceiveStage got a malformed headers slice starting at {:?} from {:?}: {}",
+                    start_block_num, from_peer_id, error
+                );
+                slice.headers = Some(headers.into_iter().map(BlockHeader::from).collect());
+                slice.from_peer_id = from_peer_id;
+                slice.invalid_reason = Some(InvalidReason::MalformedRlp(error));
+                self.header_slices
+                    .set_slice_status(slice.deref_mut(), HeaderSliceStatus::Invalid);
+            }
+        }
+    }
+
     fn update_slice(
         &self,
         slice: &mut HeaderSlice,
         headers: Vec<BlockHeader>,
         from_peer_id: Option<PeerId>,
     ) {
+        if let (Some(peer_batch_sizes), Some(peer_id)) = (&self.peer_batch_sizes, from_peer_id) {
+            peer_batch_sizes.on_received(peer_id);
+        }
         slice.headers = Some(headers);
         slice.from_peer_id = from_peer_id;
+        slice.requested_peer_id = None;
+        slice.received_time = Some(time::Instant::now());
+        if let (Some(peer_latencies), Some(peer_id), Some(round_trip)) =
+            (&self.peer_latencies, from_peer_id, slice.round_trip())
+        {
+            peer_latencies.on_received(peer_id, round_trip);
+        }
         self.header_slices
             .set_slice_status(slice, HeaderSliceStatus::Downloaded);
     }
@@ -1,18 +1,21 @@
 use crate::{
     downloader::headers::{
         header_slice_status_watch::HeaderSliceStatusWatch,
-        header_slices::{HeaderSliceStatus, HeaderSlices},
+        header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices},
+        peer_batch_sizes::PeerBatchSizes,
+        peer_latencies::PeerLatencies,
     },
     models::BlockNumber,
     sentry::{
         block_id,
         messages::{GetBlockHeadersMessage, GetBlockHeadersMessageParams, Message},
-        sentry_client::PeerFilter,
+        sentry_client::{PeerFilter, PeerId},
         sentry_client_reactor::*,
     },
 };
 use parking_lot::RwLockUpgradableReadGuard;
 use std::{
+    collections::{HashMap, HashSet},
     ops::{ControlFlow, DerefMut},
     sync::{atomic::*, Arc},
     time,
@@ -24,8 +27,17 @@ pub struct FetchRequestStage {
     header_slices: Arc<HeaderSlices>,
     sentry: SentryClientReactorShared,
     slice_size: usize,
+    max_in_flight_requests: Option<usize>,
+    hard_mem_limit: Option<usize>,
     pending_watch: HeaderSliceStatusWatch,
+    in_flight_watch: HeaderSliceStatusWatch,
+    saved_watch: HeaderSliceStatusWatch,
     last_request_id: AtomicU64,
+    peer_batch_sizes: Option<Arc<PeerBatchSizes>>,
+    peer_latencies: Option<Arc<PeerLatencies>>,
+    /// Sorted, so that the peers take turns.
+    peer_allowlist: Option<Vec<PeerId>>,
+    next_allowed_peer: AtomicUsize,
 }
 
 impl FetchRequestStage {
@@ -33,24 +45,108 @@ impl FetchRequestStage {
         header_slices: Arc<HeaderSlices>,
         sentry: SentryClientReactorShared,
         slice_size: usize,
+        max_in_flight_requests: Option<usize>,
+        hard_mem_limit: Option<usize>,
     ) -> Self {
         Self {
             header_slices: header_slices.clone(),
             sentry,
             slice_size,
+            max_in_flight_requests,
+            hard_mem_limit,
             pending_watch: HeaderSliceStatusWatch::new(
                 HeaderSliceStatus::Empty,
-                header_slices,
+                header_slices.clone(),
                 "FetchRequestStage",
             ),
+            in_flight_watch: HeaderSliceStatusWatch::new(
+                HeaderSliceStatus::Waiting,
+                header_slices.clone(),
+                "FetchRequestStage in flight",
+            ),
+            saved_watch: HeaderSliceStatusWatch::new(
+                HeaderSliceStatus::Saved,
+                header_slices,
+                "FetchRequestStage saved",
+            ),
             last_request_id: 0.into(),
+            peer_batch_sizes: None,
+            peer_latencies: None,
+            peer_allowlist: None,
+            next_allowed_peer: 0.into(),
         }
     }
 
+    /// Request batches of slices from the peers which responded before,
+    /// in addition to the random peers.
+    pub fn set_peer_batch_sizes(&mut self, peer_batch_sizes: Arc<PeerBatchSizes>) {
+        self.peer_batch_sizes = Some(peer_batch_sizes);
+    }
+
+    /// Don't request batches from the slowest peers.
+    pub fn set_peer_latencies(&mut self, peer_latencies: Arc<PeerLatencies>) {
+        self.peer_latencies = Some(peer_latencies);
+    }
+
+    /// Only request the slices from these peers, in turns, instead of the random ones.
+    /// Nothing is requested if the set is empty.
+    pub fn set_peer_allowlist(&mut self, peer_allowlist: Arc<HashSet<PeerId>>) {
+        let mut peer_ids = peer_allowlist.iter().copied().collect::<Vec<_>>();
+        peer_ids.sort();
+        self.peer_allowlist = Some(peer_ids);
+    }
+
+    fn is_allowed(&self, peer_id: PeerId) -> bool {
+        self.peer_allowlist
+            .as_ref()
+            .map_or(true, |peer_allowlist| peer_allowlist.contains(&peer_id))
+    }
+
+    /// The next of the allowed peers, or None to request from a random peer.
+    fn next_allowed_peer(&self) -> Option<PeerId> {
+        let peer_allowlist = self.peer_allowlist.as_ref()?;
+        let index = self.next_allowed_peer.fetch_add(1, Ordering::SeqCst);
+        Some(peer_allowlist[index % peer_allowlist.len()])
+    }
+
     pub async fn execute(&mut self) -> anyhow::Result<()> {
         debug!("FetchRequestStage: start");
         self.pending_watch.wait().await?;
 
+        // over the memory cap: request the first empty slice only, and wait until some of the slices
+        // are saved and forgotten, reset, or received
+        if self.is_over_hard_mem_limit() {
+            debug!(
+                "FetchRequestStage: headers take {} bytes, waiting for memory",
+                self.header_slices.estimated_memory_bytes()
+            );
+            {
+                let sentry = self.sentry.read().await;
+                self.request_first_empty(&sentry)?;
+            }
+            let pending_count = self.pending_watch.pending_count();
+            let in_flight_count = self.in_flight_watch.pending_count();
+            let saved_count = self.saved_watch.pending_count();
+            tokio::select! {
+                result = self.pending_watch.wait_while(pending_count) => result?,
+                result = self.in_flight_watch.wait_while(in_flight_count) => result?,
+                result = self.saved_watch.wait_while(saved_count) => result?,
+            }
+            return Ok(());
+        }
+
+        // at capacity: wait until some of the requests are received or retried
+        if let Some(max_in_flight_requests) = self.max_in_flight_requests {
+            let in_flight_count = self.in_flight_watch.pending_count();
+            if in_flight_count >= max_in_flight_requests {
+                debug!(
+                    "FetchRequestStage: {} requests in flight, waiting for capacity",
+                    in_flight_count
+                );
+                self.in_flight_watch.wait_while(in_flight_count).await?;
+            }
+        }
+
         debug!(
             "FetchRequestStage: requesting {} slices",
             self.pending_watch.pending_count()
@@ -74,16 +170,47 @@ impl FetchRequestStage {
         Ok(())
     }
 
+    fn is_over_hard_mem_limit(&self) -> bool {
+        self.hard_mem_limit.map_or(false, |hard_mem_limit| {
+            self.header_slices.estimated_memory_bytes() >= hard_mem_limit
+        })
+    }
+
     fn request_pending(&self, sentry: &SentryClientReactor) -> anyhow::Result<()> {
+        if self.is_over_hard_mem_limit() {
+            return self.request_first_empty(sentry);
+        }
+        // the slices stay Empty until some peer is allowed
+        if matches!(&self.peer_allowlist, Some(peer_allowlist) if peer_allowlist.is_empty()) {
+            return Ok(());
+        }
+
+        let mut capacity = self.max_in_flight_requests.map(|max_in_flight_requests| {
+            max_in_flight_requests.saturating_sub(self.header_slices.in_flight_count())
+        });
+
+        if let Some(peer_batch_sizes) = &self.peer_batch_sizes {
+            if !self.request_peer_batches(peer_batch_sizes, &mut capacity, sentry)? {
+                return Ok(());
+            }
+        }
+
         let result = self.header_slices.try_fold((), |_, slice_lock| {
             let slice = slice_lock.upgradable_read();
             if slice.status == HeaderSliceStatus::Empty {
+                if capacity == Some(0) {
+                    return ControlFlow::Break(Ok(()));
+                }
+
                 let request_id = self.last_request_id.fetch_add(1, Ordering::SeqCst);
 
                 let block_num = slice.start_block_num;
                 let limit = self.slice_size as u64;
+                let requested_peer_id = self.next_allowed_peer();
+                let peer_filter =
+                    requested_peer_id.map_or(PeerFilter::Random(1), PeerFilter::PeerId);
 
-                let result = self.request(request_id, block_num, limit, sentry);
+                let result = self.request(request_id, block_num, limit, peer_filter, sentry);
                 match result {
                     Err(error) => match error.downcast_ref::<SendMessageError>() {
                         Some(SendMessageError::SendQueueFull) => {
@@ -98,8 +225,13 @@ impl FetchRequestStage {
                     Ok(_) => {
                         let mut slice = RwLockUpgradableReadGuard::upgrade(slice);
                         slice.request_time = Some(time::Instant::now());
+                        slice.received_time = None;
+                        slice.requested_peer_id = requested_peer_id;
                         self.header_slices
                             .set_slice_status(slice.deref_mut(), HeaderSliceStatus::Waiting);
+                        if let Some(capacity) = capacity.as_mut() {
+                            *capacity -= 1;
+                        }
                     }
                 }
             }
@@ -113,11 +245,121 @@ impl FetchRequestStage {
         }
     }
 
+    /// Over the hard memory limit, requests the first empty slice, one at a time:
+    /// it may be the gap which keeps the downloaded slices above it from being saved and forgotten.
+    fn request_first_empty(&self, sentry: &SentryClientReactor) -> anyhow::Result<()> {
+        if self.header_slices.in_flight_count() > 0 {
+            return Ok(());
+        }
+        if matches!(&self.peer_allowlist, Some(peer_allowlist) if peer_allowlist.is_empty()) {
+            return Ok(());
+        }
+
+        if let Some(slice_lock) = self.header_slices.first_empty_slice() {
+            let mut slice = slice_lock.write();
+            self.request_slice(slice.deref_mut(), self.next_allowed_peer(), sentry)?;
+        }
+        Ok(())
+    }
+
+    /// Tops up the slices requested from every known peer to its batch size.
+    /// Returns false if the send queue is full.
+    fn request_peer_batches(
+        &self,
+        peer_batch_sizes: &PeerBatchSizes,
+        capacity: &mut Option<usize>,
+        sentry: &SentryClientReactor,
+    ) -> anyhow::Result<bool> {
+        let mut requested_counts = HashMap::<PeerId, usize>::new();
+        self.header_slices.for_each(|slice_lock| {
+            let slice = slice_lock.read();
+            if let (HeaderSliceStatus::Waiting, Some(peer_id)) =
+                (slice.status, slice.requested_peer_id)
+            {
+                *requested_counts.entry(peer_id).or_default() += 1;
+            }
+        });
+
+        let slow_peers = self
+            .peer_latencies
+            .as_ref()
+            .map(|peer_latencies| peer_latencies.slow_peers())
+            .unwrap_or_default();
+
+        for (peer_id, batch_size) in peer_batch_sizes.batches() {
+            if slow_peers.contains(&peer_id) || !self.is_allowed(peer_id) {
+                continue;
+            }
+            let requested_count = requested_counts.get(&peer_id).copied().unwrap_or(0);
+            let count = std::cmp::min(
+                batch_size.saturating_sub(requested_count),
+                capacity.unwrap_or(usize::MAX),
+            );
+            if count == 0 {
+                continue;
+            }
+
+            for slice_lock in self
+                .header_slices
+                .find_batch_by_status(HeaderSliceStatus::Empty, count)
+            {
+                let mut slice = slice_lock.write();
+                if !self.request_slice(slice.deref_mut(), Some(peer_id), sentry)? {
+                    return Ok(false);
+                }
+                if let Some(capacity) = capacity.as_mut() {
+                    *capacity -= 1;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Requests the slice from the peer, or a random one if None.
+    /// Returns false if the send queue is full.
+    fn request_slice(
+        &self,
+        slice: &mut HeaderSlice,
+        peer_id: Option<PeerId>,
+        sentry: &SentryClientReactor,
+    ) -> anyhow::Result<bool> {
+        if slice.status != HeaderSliceStatus::Empty {
+            return Ok(true);
+        }
+
+        let request_id = self.last_request_id.fetch_add(1, Ordering::SeqCst);
+        let limit = self.slice_size as u64;
+        let result = self.request(
+            request_id,
+            slice.start_block_num,
+            limit,
+            peer_id.map_or(PeerFilter::Random(1), PeerFilter::PeerId),
+            sentry,
+        );
+        if let Err(error) = result {
+            return match error.downcast_ref::<SendMessageError>() {
+                Some(SendMessageError::SendQueueFull) => {
+                    debug!("FetchRequestStage: request send queue is full");
+                    Ok(false)
+                }
+                _ => Err(error),
+            };
+        }
+
+        slice.request_time = Some(time::Instant::now());
+        slice.received_time = None;
+        slice.requested_peer_id = peer_id;
+        self.header_slices
+            .set_slice_status(slice, HeaderSliceStatus::Waiting);
+        Ok(true)
+    }
+
     fn request(
         &self,
         request_id: u64,
         block_num: BlockNumber,
         limit: u64,
+        peer_filter: PeerFilter,
         sentry: &SentryClientReactor,
     ) -> anyhow::Result<()> {
         let message = GetBlockHeadersMessage {
@@ -129,7 +371,7 @@ impl FetchRequestStage {
                 reverse: 0,
             },
         };
-        sentry.try_send_message(Message::GetBlockHeaders(message), PeerFilter::Random(1))
+        sentry.try_send_message_to_preferred(Message::GetBlockHeaders(message), peer_filter)
     }
 }
 
@@ -139,3 +381,334 @@ impl super::stage::Stage for FetchRequestStage {
         FetchRequestStage::execute(self).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        downloader::{
+            headers::{
+                header::BlockHeader, header_slices::HEADER_SLICE_SIZE,
+                save_stage::mark_saved_slices,
+            },
+            sentry_status_provider::SentryStatusProvider,
+        },
+        kv::{self, tables, traits::*},
+        models::{self, PartialHeader, EMPTY_LIST_HASH, EMPTY_ROOT},
+        sentry::{
+            chain_config::ChainsConfig, sentry_client_connector::SentryClientConnectorTest,
+            sentry_client_mock::SentryClientMock,
+        },
+    };
+
+    #[tokio::test]
+    async fn hard_mem_limit_requests_first_empty_slice() {
+        let slices_count = 4;
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * slices_count,
+                BlockNumber(0),
+                BlockNumber((HEADER_SLICE_SIZE * slices_count) as u64),
+            )
+            .unwrap(),
+        );
+
+        // the first slice is downloaded and holds its headers
+        {
+            let slice_lock = header_slices.first_empty_slice().unwrap();
+            let mut slice = slice_lock.write();
+            let header = BlockHeader::from(models::BlockHeader::new(
+                PartialHeader::empty(),
+                EMPTY_LIST_HASH,
+                EMPTY_ROOT,
+            ));
+            slice.headers = Some(vec![header; HEADER_SLICE_SIZE]);
+            header_slices.set_slice_status(slice.deref_mut(), HeaderSliceStatus::Downloaded);
+        }
+        let memory_bytes = header_slices.estimated_memory_bytes();
+        assert!(memory_bytes >= std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE);
+
+        let chain_config = ChainsConfig::new().unwrap().get("mainnet").unwrap();
+        let status_provider = SentryStatusProvider::new(chain_config);
+        let sentry_connector = Box::new(SentryClientConnectorTest::new(Box::new(
+            SentryClientMock::new(),
+        )));
+        let sentry =
+            SentryClientReactor::new(sentry_connector, status_provider.current_status_stream())
+                .into_shared();
+
+        let capped_stage = FetchRequestStage::new(
+            header_slices.clone(),
+            sentry.clone(),
+            HEADER_SLICE_SIZE,
+            None,
+            Some(memory_bytes),
+        );
+        // over the cap, only the slice after the downloaded one is requested, so that it can be saved
+        capped_stage.request_pending(&*sentry.read().await).unwrap();
+        assert_eq!(header_slices.in_flight_count(), 1);
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Empty),
+            slices_count - 2
+        );
+        assert_eq!(
+            header_slices
+                .find_by_status(HeaderSliceStatus::Waiting)
+                .unwrap()
+                .read()
+                .start_block_num,
+            BlockNumber(HEADER_SLICE_SIZE as u64)
+        );
+
+        // and one at a time
+        capped_stage.request_pending(&*sentry.read().await).unwrap();
+        assert_eq!(header_slices.in_flight_count(), 1);
+    }
+
+    async fn request_repeatedly(
+        stage: &FetchRequestStage,
+        sentry: &SentryClientReactorShared,
+        times: usize,
+        window: usize,
+    ) {
+        for _ in 0..times {
+            let capacity_future = sentry.read().await.reserve_capacity_in_send_queue();
+            capacity_future.await.unwrap();
+            stage.request_pending(&*sentry.read().await).unwrap();
+            assert!(stage.header_slices.in_flight_count() <= window);
+        }
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_within_window() {
+        let slices_count = 8;
+        let window = 4;
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * slices_count,
+                BlockNumber(0),
+                BlockNumber((HEADER_SLICE_SIZE * slices_count) as u64),
+            )
+            .unwrap(),
+        );
+
+        let chain_config = ChainsConfig::new().unwrap().get("mainnet").unwrap();
+        let status_provider = SentryStatusProvider::new(chain_config);
+        let sentry_connector = Box::new(SentryClientConnectorTest::new(Box::new(
+            SentryClientMock::new(),
+        )));
+        let sentry =
+            SentryClientReactor::new(sentry_connector, status_provider.current_status_stream())
+                .into_shared();
+        sentry.write().await.start().unwrap();
+
+        let stage = FetchRequestStage::new(
+            header_slices.clone(),
+            sentry.clone(),
+            HEADER_SLICE_SIZE,
+            Some(window),
+            None,
+        );
+
+        // all the slices fit into memory, but only the window is requested
+        request_repeatedly(&stage, &sentry, slices_count, window).await;
+        assert_eq!(header_slices.in_flight_count(), window);
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Empty),
+            slices_count - window
+        );
+
+        // a received slice makes room for the next request
+        {
+            let slice_lock = header_slices
+                .find_by_status(HeaderSliceStatus::Waiting)
+                .unwrap();
+            let mut slice = slice_lock.write();
+            header_slices.set_slice_status(slice.deref_mut(), HeaderSliceStatus::Downloaded);
+        }
+        request_repeatedly(&stage, &sentry, slices_count, window).await;
+        assert_eq!(header_slices.in_flight_count(), window);
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Empty),
+            slices_count - window - 1
+        );
+
+        sentry.write().await.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_slices_are_not_requested() {
+        let slices_count = 4;
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * slices_count,
+                BlockNumber(0),
+                BlockNumber((HEADER_SLICE_SIZE * slices_count) as u64),
+            )
+            .unwrap(),
+        );
+
+        // the first half is saved by a previous run
+        let db = kv::new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        for block_num in 0..(HEADER_SLICE_SIZE * slices_count / 2) as u64 {
+            tx.set(
+                tables::CanonicalHeader,
+                BlockNumber(block_num),
+                ethereum_types::H256::from_low_u64_be(block_num + 1),
+            )
+            .await
+            .unwrap();
+        }
+        // a slice with a gap is downloaded again
+        tx.set(
+            tables::CanonicalHeader,
+            BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+            ethereum_types::H256::zero(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(mark_saved_slices(&header_slices, &tx).await.unwrap(), 2);
+
+        let chain_config = ChainsConfig::new().unwrap().get("mainnet").unwrap();
+        let status_provider = SentryStatusProvider::new(chain_config);
+        let sentry_connector = Box::new(SentryClientConnectorTest::new(Box::new(
+            SentryClientMock::new(),
+        )));
+        let sentry =
+            SentryClientReactor::new(sentry_connector, status_provider.current_status_stream())
+                .into_shared();
+
+        let stage = FetchRequestStage::new(
+            header_slices.clone(),
+            sentry.clone(),
+            HEADER_SLICE_SIZE,
+            None,
+            None,
+        );
+        // the send queue takes a single request, it goes to the first slice to download
+        stage.request_pending(&*sentry.read().await).unwrap();
+        assert_eq!(header_slices.in_flight_count(), 1);
+        assert_eq!(
+            header_slices.clone_statuses(),
+            vec![
+                HeaderSliceStatus::Saved,
+                HeaderSliceStatus::Saved,
+                HeaderSliceStatus::Waiting,
+                HeaderSliceStatus::Empty,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_requested_from_known_peers() {
+        let slices_count = 6;
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * slices_count,
+                BlockNumber(0),
+                BlockNumber((HEADER_SLICE_SIZE * slices_count) as u64),
+            )
+            .unwrap(),
+        );
+
+        let chain_config = ChainsConfig::new().unwrap().get("mainnet").unwrap();
+        let status_provider = SentryStatusProvider::new(chain_config);
+        let sentry_connector = Box::new(SentryClientConnectorTest::new(Box::new(
+            SentryClientMock::new(),
+        )));
+        let sentry =
+            SentryClientReactor::new(sentry_connector, status_provider.current_status_stream())
+                .into_shared();
+        sentry.write().await.start().unwrap();
+
+        // the peer responded twice before
+        let peer_id = PeerId::from_low_u64_be(1);
+        let peer_batch_sizes = Arc::new(PeerBatchSizes::new(8));
+        peer_batch_sizes.on_received(peer_id);
+        peer_batch_sizes.on_received(peer_id);
+
+        let mut stage = FetchRequestStage::new(
+            header_slices.clone(),
+            sentry.clone(),
+            HEADER_SLICE_SIZE,
+            None,
+            None,
+        );
+        stage.set_peer_batch_sizes(peer_batch_sizes);
+        request_repeatedly(&stage, &sentry, slices_count, slices_count).await;
+        assert_eq!(header_slices.in_flight_count(), slices_count);
+
+        // the rest went to random peers
+        let mut requested_peer_ids = Vec::new();
+        header_slices.for_each(|slice_lock| {
+            requested_peer_ids.push(slice_lock.read().requested_peer_id);
+        });
+        assert_eq!(
+            requested_peer_ids
+                .iter()
+                .filter(|requested_peer_id| **requested_peer_id == Some(peer_id))
+                .count(),
+            2
+        );
+        assert_eq!(requested_peer_ids[0], Some(peer_id));
+
+        sentry.write().await.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn requests_only_allowed_peers() {
+        let slices_count = 4;
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * slices_count,
+                BlockNumber(0),
+                BlockNumber((HEADER_SLICE_SIZE * slices_count) as u64),
+            )
+            .unwrap(),
+        );
+
+        let chain_config = ChainsConfig::new().unwrap().get("mainnet").unwrap();
+        let status_provider = SentryStatusProvider::new(chain_config);
+        let sentry_connector = Box::new(SentryClientConnectorTest::new(Box::new(
+            SentryClientMock::new(),
+        )));
+        let sentry =
+            SentryClientReactor::new(sentry_connector, status_provider.current_status_stream())
+                .into_shared();
+        sentry.write().await.start().unwrap();
+
+        // another peer responded before
+        let allowed_peer_id = PeerId::from_low_u64_be(1);
+        let other_peer_id = PeerId::from_low_u64_be(2);
+        let peer_batch_sizes = Arc::new(PeerBatchSizes::new(8));
+        peer_batch_sizes.on_received(other_peer_id);
+        peer_batch_sizes.on_received(other_peer_id);
+
+        // nobody is allowed
+        let mut stage = FetchRequestStage::new(
+            header_slices.clone(),
+            sentry.clone(),
+            HEADER_SLICE_SIZE,
+            None,
+            None,
+        );
+        stage.set_peer_batch_sizes(peer_batch_sizes.clone());
+        stage.set_peer_allowlist(Arc::new(HashSet::new()));
+        request_repeatedly(&stage, &sentry, slices_count, 0).await;
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Empty),
+            slices_count
+        );
+
+        stage.set_peer_allowlist(Arc::new([allowed_peer_id].into_iter().collect()));
+        request_repeatedly(&stage, &sentry, slices_count, slices_count).await;
+        assert_eq!(header_slices.in_flight_count(), slices_count);
+        header_slices.for_each(|slice_lock| {
+            assert_eq!(slice_lock.read().requested_peer_id, Some(allowed_peer_id));
+        });
+
+        sentry.write().await.stop().await.unwrap();
+    }
+}
@@ -58,6 +58,16 @@ impl BlockHeader {
         self.hash_cached
             .unwrap_or_else(|| Self::hash_compute(&self.rlp_repr()))
     }
+
+    /// Heap and inline bytes held by this header.
+    pub fn estimated_memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.header.extra_data.len()
+            + self
+                .rlp_repr_cached
+                .as_ref()
+                .map_or(0, |rlp_repr| rlp_repr.len())
+    }
 }
 
 impl From<models::BlockHeader> for BlockHeader {
@@ -1,6 +1,10 @@
-use super::header::BlockHeader;
+use super::{header::BlockHeader, header_slices::InvalidReason};
 use crate::{
-    consensus::difficulty::{canonical_difficulty, BlockDifficultyBombData},
+    chain::protocol_param::param,
+    consensus::{
+        difficulty::{canonical_difficulty, BlockDifficultyBombData},
+        expected_base_fee_per_gas, Consensus,
+    },
     models::{switch_is_active, BlockNumber, ChainSpec, SealVerificationParams, EMPTY_LIST_HASH},
 };
 
@@ -58,6 +62,31 @@ pub fn verify_link_difficulties(
     given_child_difficulty == expected_child_difficulty
 }
 
+/// Verify the EIP-1559 base fee after the fork activation.
+pub fn verify_link_base_fee(
+    child: &BlockHeader,
+    parent: &BlockHeader,
+    chain_spec: &ChainSpec,
+) -> bool {
+    let eip1559_block = chain_spec.consensus.eip1559_block;
+    let is_parent_after_fork =
+        eip1559_block.map_or(false, |fork_block| parent.number() >= fork_block);
+    if is_parent_after_fork && parent.header.base_fee_per_gas.is_none() {
+        return false;
+    }
+    // the base fee change is divided by the parent's gas target
+    if is_parent_after_fork
+        && (parent.header.gas_limit < chain_spec.params.min_gas_limit
+            || parent.header.gas_limit / param::ELASTICITY_MULTIPLIER == 0)
+    {
+        return false;
+    }
+
+    let expected_base_fee_per_gas =
+        expected_base_fee_per_gas(&child.header, &parent.header, eip1559_block);
+    child.header.base_fee_per_gas == expected_base_fee_per_gas
+}
+
 pub fn verify_link_pow(_child: &BlockHeader, _parent: &BlockHeader) -> bool {
     // TODO: verify_link_pow
     true
@@ -77,6 +106,39 @@ pub fn verify_slice_is_linked_by_parent_hash(headers: &[BlockHeader]) -> bool {
         .all(|(parent, child)| verify_link_by_parent_hash(child, parent))
 }
 
+/// Verify the structure of the headers inside the slice:
+/// block numbers are sequential, each header is linked to the previous one by the parent_hash,
+/// timestamps increase, and the gas used fits into the gas limit.
+pub fn verify_slice_internal(headers: &[BlockHeader]) -> Result<(), InvalidReason> {
+    for header in headers {
+        if header.header.gas_used > header.header.gas_limit {
+            return Err(InvalidReason::GasUsedAboveLimit {
+                block_num: header.number(),
+            });
+        }
+    }
+
+    for (parent, child) in enumerate_sequential_pairs(headers) {
+        if !verify_link_block_nums(child, parent) {
+            return Err(InvalidReason::BlockNumberGap {
+                expected: parent.number() + 1,
+                got: child.number(),
+            });
+        }
+        if !verify_link_by_parent_hash(child, parent) {
+            return Err(InvalidReason::ParentHashMismatch {
+                block_num: child.number(),
+            });
+        }
+        if !verify_link_timestamps(child, parent) {
+            return Err(InvalidReason::TimestampNotIncreasing {
+                block_num: child.number(),
+            });
+        }
+    }
+    Ok(())
+}
+
 /// Verify that block numbers start from the expected
 /// slice.start_block_num and increase sequentially.
 pub fn verify_slice_block_nums(headers: &[BlockHeader], start_block_num: BlockNumber) -> bool {
@@ -119,8 +181,188 @@ pub fn verify_slice_difficulties(headers: &[BlockHeader], chain_spec: &ChainSpec
         .all(|(parent, child)| verify_link_difficulties(child, parent, chain_spec))
 }
 
+/// Verify that base_fee_per_gas follows the EIP-1559 formula.
+pub fn verify_slice_base_fees(headers: &[BlockHeader], chain_spec: &ChainSpec) -> bool {
+    enumerate_sequential_pairs(headers)
+        .all(|(parent, child)| verify_link_base_fee(child, parent, chain_spec))
+}
+
 /// Verify the headers proof-of-work.
 pub fn verify_slice_pow(_headers: &[BlockHeader]) -> bool {
     // TODO: verify_slice_pow
     true
 }
+
+/// Verify the headers seals with the consensus engine.
+/// This is CPU-heavy, e.g. Ethash builds a light DAG for each header.
+pub async fn verify_slice_seals(headers: &[BlockHeader], engine: &dyn Consensus) -> bool {
+    for header in headers {
+        if engine.validate_seal(&header.header).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::engine_factory,
+        models::{self, PartialHeader},
+        res::chainspec::MAINNET,
+    };
+    use hex_literal::hex;
+
+    // mainnet block 1
+    fn block_1_header() -> BlockHeader {
+        let header = PartialHeader {
+            parent_hash: hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3")
+                .into(),
+            beneficiary: hex!("05a56e2d52c817161883f50c441c3228cfe54d9f").into(),
+            state_root: hex!("d67e4d450343046425ae4271474353857ab860dbc0a1dde64b41b5cd3a532bf3")
+                .into(),
+            receipts_root: hex!("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421")
+                .into(),
+            logs_bloom: Default::default(),
+            difficulty: 0x3ff800000_u64.into(),
+            number: BlockNumber(1),
+            gas_limit: 5000,
+            gas_used: 0,
+            timestamp: 0x55ba4224,
+            extra_data: hex!("476574682f76312e302e302f6c696e75782f676f312e342e32")
+                .to_vec()
+                .into(),
+            mix_hash: hex!("969b900de27b6ac6a67742365dd65f55a0526c41fd18e1b16f1a1215c2e66f59")
+                .into(),
+            nonce: hex!("539bd4979fef1ec4").into(),
+            base_fee_per_gas: None,
+        };
+        BlockHeader::from(models::BlockHeader::new(
+            header,
+            EMPTY_LIST_HASH,
+            hex!("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421").into(),
+        ))
+    }
+
+    fn make_chain(len: u64) -> Vec<BlockHeader> {
+        let mut headers = Vec::<BlockHeader>::new();
+        for number in 1..=len {
+            let header = models::BlockHeader {
+                parent_hash: headers
+                    .last()
+                    .map(|parent| parent.hash())
+                    .unwrap_or_default(),
+                number: BlockNumber(number),
+                timestamp: number * 15,
+                gas_limit: 5000,
+                ..models::BlockHeader::empty()
+            };
+            headers.push(BlockHeader::from(header));
+        }
+        headers
+    }
+
+    #[test]
+    fn verify_internal() {
+        let headers = make_chain(5);
+        assert_eq!(verify_slice_internal(&headers), Ok(()));
+        assert_eq!(verify_slice_internal(&[]), Ok(()));
+    }
+
+    #[test]
+    fn verify_internal_gap() {
+        let mut headers = make_chain(5);
+        headers.remove(2);
+        assert_eq!(
+            verify_slice_internal(&headers),
+            Err(InvalidReason::BlockNumberGap {
+                expected: BlockNumber(3),
+                got: BlockNumber(4),
+            })
+        );
+    }
+
+    #[test]
+    fn verify_internal_parent_hash_mismatch() {
+        let mut headers = make_chain(5);
+        headers[3].header.parent_hash =
+            hex!("ff00000000000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(
+            verify_slice_internal(&headers),
+            Err(InvalidReason::ParentHashMismatch {
+                block_num: BlockNumber(4),
+            })
+        );
+    }
+
+    #[test]
+    fn verify_internal_timestamps_and_gas() {
+        let mut headers = make_chain(3);
+        headers[2].header.timestamp = headers[1].timestamp();
+        assert_eq!(
+            verify_slice_internal(&headers),
+            Err(InvalidReason::TimestampNotIncreasing {
+                block_num: BlockNumber(3),
+            })
+        );
+
+        let mut headers = make_chain(3);
+        headers[1].header.gas_used = 5001;
+        assert_eq!(
+            verify_slice_internal(&headers),
+            Err(InvalidReason::GasUsedAboveLimit {
+                block_num: BlockNumber(2),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_seals() {
+        let engine = engine_factory(MAINNET.clone()).unwrap();
+
+        let header = block_1_header();
+        assert_eq!(
+            header.hash(),
+            hex!("88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6").into()
+        );
+        assert!(verify_slice_seals(&[header.clone()], engine.as_ref()).await);
+
+        let mut tampered_header = header;
+        tampered_header.header.nonce = hex!("539bd4979fef1ec5").into();
+        assert!(!verify_slice_seals(&[tampered_header], engine.as_ref()).await);
+    }
+
+    #[test]
+    fn base_fee_of_tiny_gas_limit() {
+        let london = MAINNET.consensus.eip1559_block.unwrap();
+        let header = |number, gas_limit, base_fee_per_gas| {
+            BlockHeader::from(models::BlockHeader {
+                number,
+                gas_limit,
+                gas_used: gas_limit,
+                base_fee_per_gas,
+                ..models::BlockHeader::empty()
+            })
+        };
+
+        let parent = header(london + 1, 30_000_000, Some(1_000_000_000.into()));
+        let child = header(
+            london + 2,
+            30_000_000,
+            expected_base_fee_per_gas(
+                &header(london + 2, 0, None).header,
+                &parent.header,
+                Some(london),
+            ),
+        );
+        assert!(verify_link_base_fee(&child, &parent, &MAINNET));
+
+        // a zero gas target would divide by zero
+        for gas_limit in [1, MAINNET.params.min_gas_limit - 1] {
+            let parent = header(london + 1, gas_limit, Some(1_000_000_000.into()));
+            let child = header(london + 2, gas_limit, Some(1_000_000_000.into()));
+            assert!(!verify_link_base_fee(&child, &parent, &MAINNET));
+        }
+    }
+}
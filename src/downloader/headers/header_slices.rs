@@ -1,8 +1,16 @@
 use super::header::BlockHeader;
-use crate::{models::BlockNumber, sentry::sentry_client::PeerId};
-use parking_lot::RwLock;
+use crate::{
+    crypto::keccak256,
+    models::{self, BlockNumber, HeaderDecodeError},
+    sentry::sentry_client::PeerId,
+};
+use anyhow::{bail, format_err};
+use ethereum_types::H256;
+use parking_lot::{Mutex, RwLock};
 use std::{
     collections::{HashMap, VecDeque},
+    fmt,
+    io::Read,
     sync::{
         atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
@@ -30,19 +38,125 @@ pub enum HeaderSliceStatus {
     Saved,
 }
 
+/// Why a slice became Invalid.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvalidReason {
+    MalformedRlp(HeaderDecodeError),
+    /// The block number is not the previous one + 1.
+    BlockNumberGap {
+        expected: BlockNumber,
+        got: BlockNumber,
+    },
+    /// The parent_hash is not the hash of the previous header.
+    ParentHashMismatch {
+        block_num: BlockNumber,
+    },
+    /// The timestamp is not after the previous header timestamp.
+    TimestampNotIncreasing {
+        block_num: BlockNumber,
+    },
+    /// The gas_used is above the gas_limit.
+    GasUsedAboveLimit {
+        block_num: BlockNumber,
+    },
+    /// The hash differs from the known canonical hash at this height.
+    CheckpointMismatch {
+        block_num: BlockNumber,
+        expected: H256,
+        got: H256,
+    },
+}
+
+/// Why two HeaderSlices can't be merged.
+#[derive(Debug, PartialEq)]
+pub enum MergeError {
+    /// The other buffer starts before the first one is final.
+    Overlap {
+        final_block_num: BlockNumber,
+        other_start_block_num: BlockNumber,
+    },
+    /// The blocks between the slices of the first buffer and the other buffer are missing.
+    Gap {
+        max_block_num: BlockNumber,
+        other_start_block_num: BlockNumber,
+    },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for MergeError {}
+
 pub struct HeaderSlice {
     pub start_block_num: BlockNumber,
     pub status: HeaderSliceStatus,
     pub headers: Option<Vec<BlockHeader>>,
     pub from_peer_id: Option<PeerId>,
+    /// The peer the slice was requested from, unless it was a random one.
+    pub requested_peer_id: Option<PeerId>,
     pub request_time: Option<time::Instant>,
+    pub received_time: Option<time::Instant>,
     pub request_attempt: u16,
+    pub invalid_reason: Option<InvalidReason>,
+    /// Checksum of the headers as they were Downloaded, see `verify_checksum`.
+    pub checksum: Option<u64>,
+}
+
+impl HeaderSlice {
+    /// Time between the last request and its response.
+    pub fn round_trip(&self) -> Option<time::Duration> {
+        self.received_time?
+            .checked_duration_since(self.request_time?)
+    }
+
+    /// Hash of the header hashes in order. The hashes are computed from the headers
+    /// rather than taken from the cache, so that any change of a header shows.
+    pub fn compute_checksum(&self) -> Option<u64> {
+        let hashes = self
+            .headers
+            .as_ref()?
+            .iter()
+            .flat_map(|header| header.header.hash().0)
+            .collect::<Vec<u8>>();
+        Some(keccak256(hashes).to_low_u64_be())
+    }
+
+    /// Fails if the headers changed since they were Downloaded,
+    /// which would be a bug of the stages in between.
+    pub fn verify_checksum(&self) -> anyhow::Result<()> {
+        if let Some(checksum) = self.checksum {
+            if self.compute_checksum() != Some(checksum) {
+                bail!(
+                    "the headers of the slice at {} changed after download",
+                    self.start_block_num
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
+/// Headers of the Invalid slices: (start_block_num, from_peer_id, headers).
+pub type InvalidLog = Arc<Mutex<Vec<(BlockNumber, PeerId, Vec<BlockHeader>)>>>;
+
 struct HeaderSliceStatusWatch {
     pub sender: watch::Sender<usize>,
     pub receiver: watch::Receiver<usize>,
     pub count: AtomicUsize,
+    /// Milliseconds since the UNIX epoch when the count last changed, or 0 if never.
+    pub last_change_millis: AtomicU64,
+}
+
+impl HeaderSliceStatusWatch {
+    fn touch(&self) {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        self.last_change_millis.store(now, ATOMIC_ORDERING);
+    }
 }
 
 /// The pre-verified headers are downloaded with a help of a HeaderSlices data structure.
@@ -54,10 +168,16 @@ struct HeaderSliceStatusWatch {
 /// HeaderSlice 2: headers 384-576
 pub struct HeaderSlices {
     slices: RwLock<VecDeque<Arc<RwLock<HeaderSlice>>>>,
-    max_slices: usize,
+    max_slices: AtomicUsize,
     max_block_num: AtomicU64,
-    final_block_num: BlockNumber,
+    final_block_num: AtomicU64,
     state_watches: HashMap<HeaderSliceStatus, HeaderSliceStatusWatch>,
+    verified_prefix_sender: watch::Sender<usize>,
+    verified_prefix_receiver: watch::Receiver<usize>,
+    completion_sender: watch::Sender<bool>,
+    completion_receiver: watch::Receiver<bool>,
+    total_retries: AtomicU64,
+    invalid_log: Option<InvalidLog>,
 }
 
 pub(super) const HEADER_SLICE_SIZE: usize = 192;
@@ -69,8 +189,8 @@ impl HeaderSlices {
         mem_limit: usize,
         start_block_num: BlockNumber,
         final_block_num: BlockNumber,
-    ) -> Self {
-        let max_slices = mem_limit / std::mem::size_of::<BlockHeader>() / HEADER_SLICE_SIZE;
+    ) -> anyhow::Result<Self> {
+        let max_slices = Self::max_slices_for_mem_limit(mem_limit);
 
         assert_eq!(
             (start_block_num.0 as usize) % HEADER_SLICE_SIZE,
@@ -84,32 +204,65 @@ impl HeaderSlices {
         );
 
         let total_block_num = final_block_num.0 as usize - start_block_num.0 as usize;
-        let max_slices = std::cmp::min(max_slices, total_block_num / HEADER_SLICE_SIZE);
+        if max_slices == 0 && total_block_num > 0 {
+            bail!(
+                "mem_limit too small, need at least {} bytes",
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE
+            );
+        }
+        // max_slices is kept to refill up to it if final_block_num is moved further
+        let initial_slices = std::cmp::min(max_slices, total_block_num / HEADER_SLICE_SIZE);
 
         let mut slices = VecDeque::new();
-        for i in 0..max_slices {
+        for i in 0..initial_slices {
             let slice = HeaderSlice {
                 start_block_num: BlockNumber(start_block_num.0 + (i * HEADER_SLICE_SIZE) as u64),
                 status: HeaderSliceStatus::Empty,
                 headers: None,
                 from_peer_id: None,
+                requested_peer_id: None,
                 request_time: None,
+                received_time: None,
                 request_attempt: 0,
+                invalid_reason: None,
+                checksum: None,
             };
             slices.push_back(Arc::new(RwLock::new(slice)));
         }
 
-        let max_block_num = start_block_num.0 + (max_slices * HEADER_SLICE_SIZE) as u64;
+        let max_block_num = start_block_num.0 + (initial_slices * HEADER_SLICE_SIZE) as u64;
 
-        let state_watches = Self::make_state_watches(max_slices);
+        let state_watches = Self::make_state_watches(initial_slices);
+        let (verified_prefix_sender, verified_prefix_receiver) = watch::channel(0);
+        let (completion_sender, completion_receiver) =
+            watch::channel(initial_slices == 0 && start_block_num >= final_block_num);
 
-        Self {
+        Ok(Self {
             slices: RwLock::new(slices),
-            max_slices,
+            max_slices: AtomicUsize::new(max_slices),
             max_block_num: AtomicU64::new(max_block_num),
-            final_block_num,
+            final_block_num: AtomicU64::new(final_block_num.0),
             state_watches,
-        }
+            verified_prefix_sender,
+            verified_prefix_receiver,
+            completion_sender,
+            completion_receiver,
+            total_retries: AtomicU64::new(0),
+            invalid_log: None,
+        })
+    }
+
+    fn max_slices_for_mem_limit(mem_limit: usize) -> usize {
+        mem_limit / std::mem::size_of::<BlockHeader>() / HEADER_SLICE_SIZE
+    }
+
+    /// Resizes the window of the slices in memory, the next refill appends up to it.
+    /// Shrinking it doesn't forget the slices which are in memory already.
+    pub fn set_mem_limit(&self, mem_limit: usize) {
+        self.max_slices.store(
+            std::cmp::max(1, Self::max_slices_for_mem_limit(mem_limit)),
+            ATOMIC_ORDERING,
+        );
     }
 
     fn make_state_watches(max_slices: usize) -> HashMap<HeaderSliceStatus, HeaderSliceStatusWatch> {
@@ -126,6 +279,7 @@ impl HeaderSlices {
                 sender,
                 receiver,
                 count: AtomicUsize::new(initial_count),
+                last_change_millis: AtomicU64::new(0),
             };
 
             state_watches.insert(id, channel);
@@ -133,6 +287,62 @@ impl HeaderSlices {
         state_watches
     }
 
+    /// Appends the slices of the other buffer, which must start where this one is final.
+    /// All the slices up to final_block_num must be in this buffer: it can't be refilled after the merge.
+    pub fn try_merge(self, other: HeaderSlices) -> Result<HeaderSlices, MergeError> {
+        let other_start_block_num = other.min_block_num();
+        let final_block_num = self.final_block_num();
+        if other_start_block_num < final_block_num {
+            return Err(MergeError::Overlap {
+                final_block_num,
+                other_start_block_num,
+            });
+        }
+        let max_block_num = self.max_block_num();
+        if other_start_block_num > max_block_num {
+            return Err(MergeError::Gap {
+                max_block_num,
+                other_start_block_num,
+            });
+        }
+
+        let total_retries = self.total_retries() + other.total_retries();
+        let mut slices = self.slices.into_inner();
+        slices.extend(other.slices.into_inner());
+
+        let state_watches = Self::make_state_watches(0);
+        for slice in &slices {
+            let status_watch = &state_watches[&slice.read().status];
+            status_watch.count.fetch_add(1, ATOMIC_ORDERING);
+        }
+        for status_watch in state_watches.values() {
+            let _ = status_watch
+                .sender
+                .send(status_watch.count.load(ATOMIC_ORDERING));
+        }
+
+        let merged = Self {
+            slices: RwLock::new(slices),
+            max_slices: AtomicUsize::new(
+                self.max_slices.into_inner() + other.max_slices.into_inner(),
+            ),
+            max_block_num: other.max_block_num,
+            final_block_num: other.final_block_num,
+            state_watches,
+            verified_prefix_sender: self.verified_prefix_sender,
+            verified_prefix_receiver: self.verified_prefix_receiver,
+            completion_sender: self.completion_sender,
+            completion_receiver: self.completion_receiver,
+            total_retries: AtomicU64::new(total_retries),
+            invalid_log: self.invalid_log.or(other.invalid_log),
+        };
+        let _ = merged
+            .verified_prefix_sender
+            .send(merged.verified_prefix_len());
+        merged.update_completion();
+        Ok(merged)
+    }
+
     pub fn clone_statuses(&self) -> Vec<HeaderSliceStatus> {
         self.slices
             .read()
@@ -141,6 +351,76 @@ impl HeaderSlices {
             .collect::<Vec<HeaderSliceStatus>>()
     }
 
+    /// The start block number and status of every slice in order.
+    pub fn status_map(&self) -> Vec<(BlockNumber, HeaderSliceStatus)> {
+        self.slices
+            .read()
+            .iter()
+            .map(|slice| {
+                let slice = slice.read();
+                (slice.start_block_num, slice.status)
+            })
+            .collect()
+    }
+
+    /// The statuses of the slices packed by STATUS_BITMAP_BITS for sending to another process,
+    /// see decode_status_bitmap. The slices are contiguous, so only the start of the first one
+    /// is encoded, as a u64 followed by the u32 number of the slices, big-endian.
+    pub fn status_bitmap(&self) -> Vec<u8> {
+        let slices = self.slices.read();
+        let start_block_num = slices.front().map_or_else(
+            || self.max_block_num(),
+            |slice| slice.read().start_block_num,
+        );
+
+        let len = STATUS_BITMAP_HEADER_LEN + (slices.len() * STATUS_BITMAP_BITS + 7) / 8;
+        let mut bitmap = Vec::with_capacity(len);
+        bitmap.extend_from_slice(&start_block_num.0.to_be_bytes());
+        bitmap.extend_from_slice(&(slices.len() as u32).to_be_bytes());
+        bitmap.resize(len, 0);
+        for (i, slice) in slices.iter().enumerate() {
+            let code = slice.read().status as u16;
+            let bit = i * STATUS_BITMAP_BITS;
+            let byte = STATUS_BITMAP_HEADER_LEN + bit / 8;
+            let shifted = code << (bit % 8);
+            bitmap[byte] |= shifted as u8;
+            if shifted > 0xff {
+                bitmap[byte + 1] |= (shifted >> 8) as u8;
+            }
+        }
+        bitmap
+    }
+
+    /// A snapshot of every slice for diagnostics:
+    /// (start_block_num, status, from_peer_id, request_attempt, time since the last request).
+    pub fn dump(
+        &self,
+    ) -> Vec<(
+        BlockNumber,
+        HeaderSliceStatus,
+        Option<PeerId>,
+        u16,
+        Option<time::Duration>,
+    )> {
+        let now = time::Instant::now();
+        self.slices
+            .read()
+            .iter()
+            .map(|slice| {
+                let slice = slice.read();
+                (
+                    slice.start_block_num,
+                    slice.status,
+                    slice.from_peer_id,
+                    slice.request_attempt,
+                    slice
+                        .request_time
+                        .map(|request_time| now.saturating_duration_since(request_time)),
+                )
+            })
+            .collect()
+    }
+
     pub fn for_each<F>(&self, f: F)
     where
         F: FnMut(&Arc<RwLock<HeaderSlice>>),
@@ -155,6 +435,98 @@ impl HeaderSlices {
         self.slices.read().iter().try_fold(init, f)
     }
 
+    /// Mark the slice as requested at the request_time after the request_attempt retries.
+    #[cfg(test)]
+    pub(crate) fn set_requested(
+        &self,
+        start_block_num: BlockNumber,
+        request_attempt: u16,
+        request_time: time::Instant,
+    ) {
+        let slice_lock = self
+            .find_by_start_block_num(start_block_num)
+            .expect("no slice at start_block_num");
+        let mut slice = slice_lock.write();
+        slice.request_attempt = request_attempt;
+        slice.request_time = Some(request_time);
+        self.set_slice_status(&mut slice, HeaderSliceStatus::Waiting);
+    }
+
+    /// Fills the slices from RLP-encoded headers in sequence instead of the sentry,
+    /// e.g. for the tests or an offline bootstrap.
+    /// Only the Empty slices the reader has all the headers of are set Downloaded,
+    /// the headers outside of them are skipped.
+    /// Returns the number of the filled slices.
+    pub fn fill_from_reader<R: Read>(&self, reader: &mut R) -> anyhow::Result<usize> {
+        let mut filled_count = 0;
+        let mut slice_headers = Vec::<models::BlockHeader>::with_capacity(HEADER_SLICE_SIZE);
+        let mut buffer = Vec::<u8>::new();
+        let mut chunk = vec![0u8; 1 << 16];
+        loop {
+            let read_count = reader.read(&mut chunk)?;
+            buffer.extend_from_slice(&chunk[..read_count]);
+
+            let mut offset = 0;
+            while offset < buffer.len() {
+                let header_len = match rlp::Rlp::new(&buffer[offset..]).payload_info() {
+                    Ok(info) => info.header_len + info.value_len,
+                    // the rest of the header is in the next chunk
+                    Err(rlp::DecoderError::RlpIsTooShort) if read_count > 0 => break,
+                    Err(error) => return Err(error.into()),
+                };
+                if offset + header_len > buffer.len() {
+                    if read_count == 0 {
+                        bail!("the last header is truncated");
+                    }
+                    break;
+                }
+                let header =
+                    rlp::decode::<models::BlockHeader>(&buffer[offset..offset + header_len])?;
+                offset += header_len;
+
+                let start_block_num = align_block_num_to_slice_start(header.number);
+                if slice_headers
+                    .first()
+                    .map_or(false, |first| first.number != start_block_num)
+                {
+                    filled_count += self.fill_slice(std::mem::take(&mut slice_headers)) as usize;
+                }
+                if slice_headers.is_empty() && header.number != start_block_num {
+                    // the slice of the header is incomplete
+                    continue;
+                }
+                slice_headers.push(header);
+            }
+            buffer.drain(..offset);
+
+            if read_count == 0 {
+                break;
+            }
+        }
+        filled_count += self.fill_slice(slice_headers) as usize;
+
+        Ok(filled_count)
+    }
+
+    fn fill_slice(&self, headers: Vec<models::BlockHeader>) -> bool {
+        if headers.len() != HEADER_SLICE_SIZE {
+            return false;
+        }
+        let slice_lock = match self.find_by_start_block_num(headers[0].number) {
+            Some(slice_lock) => slice_lock,
+            None => return false,
+        };
+        let mut slice = slice_lock.write();
+        if slice.status != HeaderSliceStatus::Empty {
+            return false;
+        }
+
+        slice.headers = Some(headers.into_iter().map(BlockHeader::from).collect());
+        slice.from_peer_id = None;
+        self.set_slice_status(&mut slice, HeaderSliceStatus::Downloaded);
+        true
+    }
+
     pub fn find_by_start_block_num(
         &self,
         start_block_num: BlockNumber,
@@ -174,6 +546,13 @@ impl HeaderSlices {
             .map(Arc::clone)
     }
 
+    /// The Empty slice with the lowest start_block_num.
+    /// Filling it first grows the contiguous prefix that can be saved.
+    pub fn first_empty_slice(&self) -> Option<Arc<RwLock<HeaderSlice>>> {
+        // the slices are ordered by start_block_num
+        self.find_by_status(HeaderSliceStatus::Empty)
+    }
+
     pub fn find_batch_by_status(
         &self,
         status: HeaderSliceStatus,
@@ -208,19 +587,25 @@ impl HeaderSlices {
                 cursor += 1;
             }
         }
+        drop(slices);
 
         let status_watch = &self.state_watches[&status];
         status_watch.count.fetch_sub(count, ATOMIC_ORDERING);
+        if count > 0 {
+            status_watch.touch();
+            self.update_completion();
+        }
     }
 
-    pub fn refill(&self) {
+    /// Appends the Empty slices up to max_slices or final_block_num, returns how many.
+    pub fn refill(&self) -> usize {
         let mut slices = self.slices.write();
         let initial_len = slices.len();
         let mut count = 0;
 
-        for _ in initial_len..self.max_slices {
+        for _ in initial_len..self.max_slices.load(ATOMIC_ORDERING) {
             let max_block_num = self.max_block_num();
-            if max_block_num >= self.final_block_num {
+            if max_block_num >= self.final_block_num() {
                 break;
             }
 
@@ -229,17 +614,27 @@ impl HeaderSlices {
                 status: HeaderSliceStatus::Empty,
                 headers: None,
                 from_peer_id: None,
+                requested_peer_id: None,
                 request_time: None,
+                received_time: None,
                 request_attempt: 0,
+                invalid_reason: None,
+                checksum: None,
             };
             slices.push_back(Arc::new(RwLock::new(slice)));
             self.max_block_num
                 .fetch_add(HEADER_SLICE_SIZE as u64, ATOMIC_ORDERING);
             count += 1;
         }
+        drop(slices);
 
         let status_watch = &self.state_watches[&HeaderSliceStatus::Empty];
         status_watch.count.fetch_add(count, ATOMIC_ORDERING);
+        if count > 0 {
+            status_watch.touch();
+            self.update_completion();
+        }
+        count
     }
 
     pub fn has_one_of_statuses(&self, statuses: &[HeaderSliceStatus]) -> bool {
@@ -255,12 +650,39 @@ impl HeaderSlices {
         }
 
         slice.status = status;
+        match status {
+            HeaderSliceStatus::Downloaded => slice.checksum = slice.compute_checksum(),
+            HeaderSliceStatus::Empty | HeaderSliceStatus::Waiting => slice.checksum = None,
+            _ => {}
+        }
 
         let old_status_watch = &self.state_watches[&old_status];
         let new_status_watch = &self.state_watches[&status];
 
         old_status_watch.count.fetch_sub(1, ATOMIC_ORDERING);
         new_status_watch.count.fetch_add(1, ATOMIC_ORDERING);
+        old_status_watch.touch();
+        new_status_watch.touch();
+    }
+
+    /// Keep the headers of the Invalid slices in the log when they are requeued.
+    /// Off by default, because the log grows without bound.
+    pub fn set_invalid_log(&mut self, invalid_log: Option<InvalidLog>) {
+        self.invalid_log = invalid_log;
+    }
+
+    /// Mark an Invalid slice as Empty to download it again, recording its headers to the invalid_log if set.
+    pub fn requeue_invalid(&self, slice: &mut HeaderSlice) {
+        let headers = slice.headers.take();
+        if let (Some(invalid_log), Some(from_peer_id), Some(headers)) =
+            (&self.invalid_log, slice.from_peer_id, headers)
+        {
+            invalid_log
+                .lock()
+                .push((slice.start_block_num, from_peer_id, headers));
+        }
+        slice.invalid_reason = None;
+        self.set_slice_status(slice, HeaderSliceStatus::Empty);
     }
 
     pub fn watch_status_changes(&self, status: HeaderSliceStatus) -> watch::Receiver<usize> {
@@ -273,6 +695,26 @@ impl HeaderSlices {
             let count = watch.count.load(ATOMIC_ORDERING);
             let _ = watch.sender.send(count);
         }
+        let _ = self.verified_prefix_sender.send(self.verified_prefix_len());
+    }
+
+    /// The number of contiguous Verified or Saved slices from the front.
+    pub fn verified_prefix_len(&self) -> usize {
+        self.slices
+            .read()
+            .iter()
+            .take_while(|slice| {
+                matches!(
+                    slice.read().status,
+                    HeaderSliceStatus::Verified | HeaderSliceStatus::Saved
+                )
+            })
+            .count()
+    }
+
+    /// Updated with verified_prefix_len on notify_status_watchers.
+    pub fn watch_verified_prefix_len(&self) -> watch::Receiver<usize> {
+        self.verified_prefix_receiver.clone()
     }
 
     pub fn count_slices_in_status(&self, status: HeaderSliceStatus) -> usize {
@@ -280,13 +722,101 @@ impl HeaderSlices {
         status_watch.count.load(ATOMIC_ORDERING)
     }
 
-    pub fn status_counters(&self) -> Vec<(HeaderSliceStatus, usize)> {
-        let mut counters = Vec::<(HeaderSliceStatus, usize)>::new();
-        for (status, watch) in &self.state_watches {
-            let count = watch.count.load(ATOMIC_ORDERING);
-            counters.push((*status, count));
+    /// When the count of the slices in the status last changed, if ever.
+    /// E.g. no Downloaded changes for a long time while some slices are Waiting means a stall.
+    pub fn last_change(&self, status: HeaderSliceStatus) -> Option<time::SystemTime> {
+        let status_watch = &self.state_watches[&status];
+        match status_watch.last_change_millis.load(ATOMIC_ORDERING) {
+            0 => None,
+            millis => Some(time::UNIX_EPOCH + time::Duration::from_millis(millis)),
+        }
+    }
+
+    /// Counts a request_attempt increment of any slice.
+    pub fn add_retry(&self) {
+        self.total_retries.fetch_add(1, ATOMIC_ORDERING);
+    }
+
+    /// The sum of request_attempt over all the slices ever held.
+    pub fn total_retries(&self) -> u64 {
+        self.total_retries.load(ATOMIC_ORDERING)
+    }
+
+    /// The number of slices requested from the peers and not received yet.
+    pub fn in_flight_count(&self) -> usize {
+        self.count_slices_in_status(HeaderSliceStatus::Waiting)
+    }
+
+    /// The memory taken by the headers which are currently held in the slices.
+    pub fn estimated_memory_bytes(&self) -> usize {
+        self.slices
+            .read()
+            .iter()
+            .map(|slice| {
+                slice.read().headers.as_ref().map_or(0, |headers| {
+                    headers
+                        .iter()
+                        .map(BlockHeader::estimated_memory_bytes)
+                        .sum()
+                })
+            })
+            .sum()
+    }
+
+    /// The median of the round trips of the slices which have received their headers.
+    pub fn median_round_trip(&self) -> Option<time::Duration> {
+        let mut round_trips = self
+            .slices
+            .read()
+            .iter()
+            .filter_map(|slice| slice.read().round_trip())
+            .collect::<Vec<_>>();
+        if round_trips.is_empty() {
+            return None;
+        }
+        round_trips.sort_unstable();
+
+        let middle = round_trips.len() / 2;
+        if round_trips.len() % 2 == 0 {
+            Some((round_trips[middle - 1] + round_trips[middle]) / 2)
+        } else {
+            Some(round_trips[middle])
         }
-        counters
+    }
+
+    /// Time to save the remaining slices at the rate of saving them,
+    /// or None if nothing is saved at this rate.
+    pub fn estimated_completion(&self, recent_rate_slices_per_sec: f64) -> Option<time::Duration> {
+        if !recent_rate_slices_per_sec.is_finite() || recent_rate_slices_per_sec <= 0.0 {
+            return None;
+        }
+
+        let not_refilled_block_count = self
+            .final_block_num()
+            .0
+            .saturating_sub(self.max_block_num().0);
+        let not_refilled_count = not_refilled_block_count as usize / HEADER_SLICE_SIZE;
+        // counted under the same lock, so that a concurrent refill or merge can't skew it
+        let not_saved_count = {
+            let slices = self.slices.read();
+            let saved_count = slices
+                .iter()
+                .filter(|slice| slice.read().status == HeaderSliceStatus::Saved)
+                .count();
+            slices.len().saturating_sub(saved_count)
+        };
+
+        let remaining_count = not_refilled_count + not_saved_count;
+        Some(time::Duration::from_secs_f64(
+            remaining_count as f64 / recent_rate_slices_per_sec,
+        ))
+    }
+
+    /// The counts of the slices by status, in the order of the statuses.
+    pub fn status_counters(&self) -> Vec<(HeaderSliceStatus, usize)> {
+        HeaderSliceStatus::iter()
+            .map(|status| (status, self.count_slices_in_status(status)))
+            .collect()
     }
 
     pub fn min_block_num(&self) -> BlockNumber {
@@ -301,15 +831,890 @@ impl HeaderSlices {
     }
 
     pub fn final_block_num(&self) -> BlockNumber {
+        BlockNumber(self.final_block_num.load(ATOMIC_ORDERING))
+    }
+
+    /// Moves final_block_num further as the chain tip advances,
+    /// so that refill() continues with the slices after the previous final_block_num.
+    pub fn set_final_block_num(&self, final_block_num: BlockNumber) -> anyhow::Result<()> {
+        if (final_block_num.0 as usize) % HEADER_SLICE_SIZE != 0 {
+            bail!(
+                "final_block_num {} must be at the slice boundary",
+                final_block_num.0
+            );
+        }
+        // compared and stored at once, so that the concurrent callers can't lower it
         self.final_block_num
+            .fetch_update(
+                ATOMIC_ORDERING,
+                ATOMIC_ORDERING,
+                |current_final_block_num| {
+                    (final_block_num.0 > current_final_block_num).then(|| final_block_num.0)
+                },
+            )
+            .map_err(|current_final_block_num| {
+                format_err!(
+                    "final_block_num {} must be greater than the current {}",
+                    final_block_num.0,
+                    current_final_block_num
+                )
+            })?;
+        self.update_completion();
+        Ok(())
+    }
+
+    /// Moves final_block_num to the new chain tip, and refills right away.
+    /// The slices beyond max_slices are added by the next refills, as the slices are removed.
+    /// Returns how many slices were added.
+    pub fn extend_to(&self, new_final_block_num: BlockNumber) -> anyhow::Result<usize> {
+        self.set_final_block_num(new_final_block_num)?;
+        Ok(self.refill())
     }
 
     pub fn is_empty_at_final_position(&self) -> bool {
-        (self.max_block_num() >= self.final_block_num) && self.slices.read().is_empty()
+        (self.max_block_num() >= self.final_block_num()) && self.slices.read().is_empty()
+    }
+
+    /// Flips to true when is_empty_at_final_position becomes true, i.e. all the slices
+    /// are saved and removed, and back to false if final_block_num is moved further.
+    pub fn watch_completion(&self) -> watch::Receiver<bool> {
+        self.completion_receiver.clone()
+    }
+
+    /// Called on every change of the slices or final_block_num, must not be under the slices lock.
+    fn update_completion(&self) {
+        let is_complete = self.is_empty_at_final_position();
+        if *self.completion_receiver.borrow() != is_complete {
+            let _ = self.completion_sender.send(is_complete);
+        }
     }
 }
 
+/// Bits per slice status in status_bitmap, all the 7 statuses fit.
+pub const STATUS_BITMAP_BITS: usize = 3;
+const STATUS_BITMAP_HEADER_LEN: usize = 12;
+
+/// Decodes HeaderSlices::status_bitmap into the start of the first slice and the statuses in order.
+pub fn decode_status_bitmap(
+    bitmap: &[u8],
+) -> anyhow::Result<(BlockNumber, Vec<HeaderSliceStatus>)> {
+    if bitmap.len() < STATUS_BITMAP_HEADER_LEN {
+        bail!("status bitmap too short: {} bytes", bitmap.len());
+    }
+    let start_block_num = BlockNumber(u64::from_be_bytes(bitmap[..8].try_into().unwrap()));
+    let count =
+        u32::from_be_bytes(bitmap[8..STATUS_BITMAP_HEADER_LEN].try_into().unwrap()) as usize;
+    let packed = &bitmap[STATUS_BITMAP_HEADER_LEN..];
+    if packed.len() != (count * STATUS_BITMAP_BITS + 7) / 8 {
+        bail!(
+            "status bitmap of {} slices must have {} bytes of statuses, got {}",
+            count,
+            (count * STATUS_BITMAP_BITS + 7) / 8,
+            packed.len()
+        );
+    }
+
+    let mut statuses = Vec::with_capacity(count);
+    for i in 0..count {
+        let bit = i * STATUS_BITMAP_BITS;
+        let byte = bit / 8;
+        let mut word = packed[byte] as u16;
+        if let Some(next) = packed.get(byte + 1) {
+            word |= (*next as u16) << 8;
+        }
+        let code = (word >> (bit % 8)) & ((1 << STATUS_BITMAP_BITS) - 1);
+        let status = HeaderSliceStatus::iter()
+            .nth(code as usize)
+            .ok_or_else(|| format_err!("invalid slice status {} at {}", code, i))?;
+        statuses.push(status);
+    }
+    Ok((start_block_num, statuses))
+}
+
 pub fn align_block_num_to_slice_start(num: BlockNumber) -> BlockNumber {
     let slice_size = HEADER_SLICE_SIZE as u64;
     BlockNumber(num.0 / slice_size * slice_size)
 }
+
+/// Start of the slices covering at least the last `window` blocks before `final_block_num`,
+/// but not before `start_block_num`.
+pub fn window_start_block_num(
+    start_block_num: BlockNumber,
+    final_block_num: BlockNumber,
+    window: usize,
+) -> BlockNumber {
+    let window = std::cmp::max(window, HEADER_SLICE_SIZE) as u64;
+    std::cmp::max(
+        start_block_num,
+        align_block_num_to_slice_start(BlockNumber(final_block_num.0.saturating_sub(window))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn try_merge() {
+        let make_header_slices = |start: usize, end: usize| {
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * (end - start),
+                BlockNumber((HEADER_SLICE_SIZE * start) as u64),
+                BlockNumber((HEADER_SLICE_SIZE * end) as u64),
+            )
+            .unwrap()
+        };
+
+        let first = make_header_slices(0, 2);
+        let slice_lock = first.first_empty_slice().unwrap();
+        first.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Verified);
+        first.add_retry();
+        let second = make_header_slices(2, 5);
+        let slice_lock = second.first_empty_slice().unwrap();
+        second.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Waiting);
+
+        let merged = first.try_merge(second).unwrap();
+        assert_eq!(
+            merged.clone_statuses(),
+            vec![
+                HeaderSliceStatus::Verified,
+                HeaderSliceStatus::Empty,
+                HeaderSliceStatus::Waiting,
+                HeaderSliceStatus::Empty,
+                HeaderSliceStatus::Empty,
+            ]
+        );
+        assert_eq!(merged.count_slices_in_status(HeaderSliceStatus::Empty), 3);
+        assert_eq!(merged.count_slices_in_status(HeaderSliceStatus::Waiting), 1);
+        assert_eq!(
+            merged.count_slices_in_status(HeaderSliceStatus::Verified),
+            1
+        );
+        assert_eq!(
+            *merged
+                .watch_status_changes(HeaderSliceStatus::Empty)
+                .borrow(),
+            3
+        );
+        assert_eq!(merged.verified_prefix_len(), 1);
+        assert_eq!(merged.total_retries(), 1);
+        assert_eq!(merged.min_block_num(), BlockNumber(0));
+        assert_eq!(
+            merged.max_block_num(),
+            BlockNumber((HEADER_SLICE_SIZE * 5) as u64)
+        );
+        assert_eq!(
+            merged.final_block_num(),
+            BlockNumber((HEADER_SLICE_SIZE * 5) as u64)
+        );
+
+        assert_eq!(
+            make_header_slices(0, 2)
+                .try_merge(make_header_slices(1, 3))
+                .err(),
+            Some(MergeError::Overlap {
+                final_block_num: BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+                other_start_block_num: BlockNumber(HEADER_SLICE_SIZE as u64),
+            })
+        );
+        assert_eq!(
+            make_header_slices(0, 2)
+                .try_merge(make_header_slices(3, 4))
+                .err(),
+            Some(MergeError::Gap {
+                max_block_num: BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+                other_start_block_num: BlockNumber((HEADER_SLICE_SIZE * 3) as u64),
+            })
+        );
+    }
+
+    #[test]
+    fn status_counters() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 3,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 3) as u64),
+        )
+        .unwrap();
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Verified);
+
+        let counters = header_slices.status_counters();
+        assert_eq!(
+            counters
+                .iter()
+                .map(|(status, _)| *status)
+                .collect::<Vec<_>>(),
+            HeaderSliceStatus::iter().collect::<Vec<_>>()
+        );
+        assert_eq!(counters[0], (HeaderSliceStatus::Empty, 2));
+        assert!(counters.contains(&(HeaderSliceStatus::Verified, 1)));
+        assert_eq!(counters.iter().map(|(_, count)| count).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn set_final_block_num_concurrently() {
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE,
+                BlockNumber(0),
+                BlockNumber(HEADER_SLICE_SIZE as u64),
+            )
+            .unwrap(),
+        );
+
+        let threads = (2..=64_u64)
+            .map(|slices| {
+                let header_slices = header_slices.clone();
+                std::thread::spawn(move || {
+                    // the lower ones fail once a higher one is set
+                    let _ = header_slices
+                        .set_final_block_num(BlockNumber(slices * HEADER_SLICE_SIZE as u64));
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(
+            header_slices.final_block_num(),
+            BlockNumber(64 * HEADER_SLICE_SIZE as u64)
+        );
+    }
+
+    #[test]
+    fn set_final_block_num() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 3,
+            BlockNumber(0),
+            BlockNumber(HEADER_SLICE_SIZE as u64),
+        )
+        .unwrap();
+        assert_eq!(header_slices.clone_statuses().len(), 1);
+
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Saved);
+        header_slices.remove(HeaderSliceStatus::Saved);
+        header_slices.refill();
+        assert!(header_slices.is_empty_at_final_position());
+
+        let final_block_num = BlockNumber((HEADER_SLICE_SIZE * 4) as u64);
+        header_slices.set_final_block_num(final_block_num).unwrap();
+        assert_eq!(header_slices.final_block_num(), final_block_num);
+        assert!(!header_slices.is_empty_at_final_position());
+
+        // refilled up to the mem_limit
+        header_slices.refill();
+        assert_eq!(
+            header_slices.clone_statuses(),
+            vec![HeaderSliceStatus::Empty; 3]
+        );
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Empty),
+            3
+        );
+        assert_eq!(
+            header_slices.min_block_num(),
+            BlockNumber(HEADER_SLICE_SIZE as u64)
+        );
+        assert_eq!(header_slices.max_block_num(), final_block_num);
+
+        // not aligned
+        assert!(header_slices
+            .set_final_block_num(BlockNumber(final_block_num.0 + 1))
+            .is_err());
+        // not greater
+        assert!(header_slices
+            .set_final_block_num(BlockNumber(HEADER_SLICE_SIZE as u64))
+            .is_err());
+        assert!(header_slices.set_final_block_num(final_block_num).is_err());
+        assert_eq!(header_slices.final_block_num(), final_block_num);
+    }
+
+    #[test]
+    fn extend_to() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 3,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+        )
+        .unwrap();
+        assert_eq!(header_slices.clone_statuses().len(), 2);
+
+        assert!(header_slices
+            .extend_to(BlockNumber((HEADER_SLICE_SIZE * 2) as u64))
+            .is_err());
+        assert!(header_slices
+            .extend_to(BlockNumber((HEADER_SLICE_SIZE * 5 + 1) as u64))
+            .is_err());
+
+        // only up to max_slices at once
+        let final_block_num = BlockNumber((HEADER_SLICE_SIZE * 6) as u64);
+        assert_eq!(header_slices.extend_to(final_block_num).unwrap(), 1);
+        assert_eq!(header_slices.final_block_num(), final_block_num);
+        assert_eq!(
+            header_slices.max_block_num(),
+            BlockNumber((HEADER_SLICE_SIZE * 3) as u64)
+        );
+
+        // the rest as the slices are removed
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Saved);
+        header_slices.remove(HeaderSliceStatus::Saved);
+        assert_eq!(header_slices.refill(), 1);
+        assert_eq!(
+            header_slices.min_block_num(),
+            BlockNumber(HEADER_SLICE_SIZE as u64)
+        );
+        assert_eq!(
+            header_slices.max_block_num(),
+            BlockNumber((HEADER_SLICE_SIZE * 4) as u64)
+        );
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Empty),
+            3
+        );
+    }
+
+    #[test]
+    fn set_mem_limit() {
+        let slice_mem = std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE;
+        let header_slices = HeaderSlices::new(
+            slice_mem,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 4) as u64),
+        )
+        .unwrap();
+        assert_eq!(header_slices.clone_statuses().len(), 1);
+        assert_eq!(header_slices.refill(), 0);
+
+        header_slices.set_mem_limit(slice_mem * 3);
+        assert_eq!(header_slices.refill(), 2);
+        assert_eq!(header_slices.clone_statuses().len(), 3);
+
+        // the slices in memory stay
+        header_slices.set_mem_limit(0);
+        assert_eq!(header_slices.clone_statuses().len(), 3);
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Saved);
+        header_slices.remove(HeaderSliceStatus::Saved);
+        assert_eq!(header_slices.refill(), 0);
+        assert_eq!(header_slices.clone_statuses().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn watch_completion() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+        )
+        .unwrap();
+        let mut completion = header_slices.watch_completion();
+        assert!(!*completion.borrow());
+
+        let save_and_remove = || {
+            let slice_lock = header_slices.first_empty_slice().unwrap();
+            header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Saved);
+            header_slices.remove(HeaderSliceStatus::Saved);
+        };
+
+        // drained, but not at the final position yet
+        save_and_remove();
+        assert!(!*completion.borrow());
+        header_slices.refill();
+        save_and_remove();
+        completion.changed().await.unwrap();
+        assert!(*completion.borrow());
+
+        header_slices
+            .extend_to(BlockNumber((HEADER_SLICE_SIZE * 3) as u64))
+            .unwrap();
+        completion.changed().await.unwrap();
+        assert!(!*completion.borrow());
+    }
+
+    #[test]
+    fn last_change() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 2,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+        )
+        .unwrap();
+        for status in HeaderSliceStatus::iter() {
+            assert_eq!(header_slices.last_change(status), None);
+        }
+
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Waiting);
+        let empty_changed = header_slices.last_change(HeaderSliceStatus::Empty).unwrap();
+        let waiting_changed = header_slices
+            .last_change(HeaderSliceStatus::Waiting)
+            .unwrap();
+        assert_eq!(
+            header_slices.last_change(HeaderSliceStatus::Downloaded),
+            None
+        );
+
+        std::thread::sleep(Duration::from_millis(2));
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Downloaded);
+        assert!(
+            header_slices
+                .last_change(HeaderSliceStatus::Waiting)
+                .unwrap()
+                > waiting_changed
+        );
+        assert!(header_slices
+            .last_change(HeaderSliceStatus::Downloaded)
+            .is_some());
+        assert_eq!(
+            header_slices.last_change(HeaderSliceStatus::Empty),
+            Some(empty_changed)
+        );
+
+        // removing no slices changes nothing
+        header_slices.remove(HeaderSliceStatus::Saved);
+        assert_eq!(header_slices.last_change(HeaderSliceStatus::Saved), None);
+    }
+
+    #[test]
+    fn first_empty_slice() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 5,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 5) as u64),
+        )
+        .unwrap();
+
+        let mut statuses = vec![
+            HeaderSliceStatus::Saved,
+            HeaderSliceStatus::Waiting,
+            HeaderSliceStatus::Empty,
+            HeaderSliceStatus::Downloaded,
+            HeaderSliceStatus::Empty,
+        ]
+        .into_iter();
+        header_slices.for_each(|slice_lock| {
+            let mut slice = slice_lock.write();
+            header_slices.set_slice_status(&mut slice, statuses.next().unwrap());
+        });
+
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        assert_eq!(
+            slice_lock.read().start_block_num,
+            BlockNumber((HEADER_SLICE_SIZE * 2) as u64)
+        );
+
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Waiting);
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        assert_eq!(
+            slice_lock.read().start_block_num,
+            BlockNumber((HEADER_SLICE_SIZE * 4) as u64)
+        );
+
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Waiting);
+        assert!(header_slices.first_empty_slice().is_none());
+    }
+
+    #[test]
+    fn dump() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 2,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+        )
+        .unwrap();
+        let peer_id = PeerId::repeat_byte(1);
+
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        {
+            let mut slice = slice_lock.write();
+            slice.from_peer_id = Some(peer_id);
+            slice.request_time = Some(time::Instant::now());
+            slice.request_attempt = 2;
+            header_slices.set_slice_status(&mut slice, HeaderSliceStatus::Waiting);
+        }
+
+        let dump = header_slices.dump();
+        assert_eq!(dump.len(), 2);
+
+        let (start_block_num, status, from_peer_id, request_attempt, age) = dump[0];
+        assert_eq!(start_block_num, BlockNumber(0));
+        assert_eq!(status, HeaderSliceStatus::Waiting);
+        assert_eq!(from_peer_id, Some(peer_id));
+        assert_eq!(request_attempt, 2);
+        assert!(age.is_some());
+
+        assert_eq!(
+            dump[1],
+            (
+                BlockNumber(HEADER_SLICE_SIZE as u64),
+                HeaderSliceStatus::Empty,
+                None,
+                0,
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn status_bitmap() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 10,
+            BlockNumber((HEADER_SLICE_SIZE * 5) as u64),
+            BlockNumber((HEADER_SLICE_SIZE * 15) as u64),
+        )
+        .unwrap();
+        let statuses = HeaderSliceStatus::iter()
+            .chain([HeaderSliceStatus::Saved, HeaderSliceStatus::Waiting])
+            .chain(HeaderSliceStatus::iter().take(1))
+            .collect::<Vec<_>>();
+        header_slices.for_each(|slice_lock| {
+            let mut slice = slice_lock.write();
+            let i = (slice.start_block_num.0 as usize / HEADER_SLICE_SIZE) - 5;
+            header_slices.set_slice_status(&mut slice, statuses[i]);
+        });
+
+        let bitmap = header_slices.status_bitmap();
+        // 30 bits of the statuses
+        assert_eq!(bitmap.len(), 12 + 4);
+        assert_eq!(
+            decode_status_bitmap(&bitmap).unwrap(),
+            (BlockNumber((HEADER_SLICE_SIZE * 5) as u64), statuses)
+        );
+
+        assert!(decode_status_bitmap(&bitmap[..bitmap.len() - 1]).is_err());
+        let mut invalid = bitmap;
+        // the last status code is 7
+        invalid[15] |= 0b0011_1000;
+        assert!(decode_status_bitmap(&invalid).is_err());
+    }
+
+    #[test]
+    fn window() {
+        let slice_size = HEADER_SLICE_SIZE as u64;
+        let final_block_num = BlockNumber(100 * slice_size);
+
+        let start_block_num =
+            window_start_block_num(BlockNumber(0), final_block_num, 3 * HEADER_SLICE_SIZE + 1);
+        assert_eq!(start_block_num, BlockNumber(96 * slice_size));
+        // at least one slice
+        assert_eq!(
+            window_start_block_num(BlockNumber(0), final_block_num, 0),
+            BlockNumber(99 * slice_size)
+        );
+        // already downloaded beyond the window start
+        assert_eq!(
+            window_start_block_num(BlockNumber(98 * slice_size), final_block_num, 1000),
+            BlockNumber(98 * slice_size)
+        );
+
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 100,
+            start_block_num,
+            final_block_num,
+        )
+        .unwrap();
+        let slices = header_slices.status_map();
+        assert_eq!(slices.len(), 4);
+        assert_eq!(slices[0].0, start_block_num);
+    }
+
+    #[test]
+    fn status_map() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 3,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 3) as u64),
+        )
+        .unwrap();
+
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Waiting);
+
+        let status_map = header_slices.status_map();
+        assert_eq!(status_map.len(), header_slices.clone_statuses().len());
+        assert!(status_map.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(
+            status_map,
+            vec![
+                (BlockNumber(0), HeaderSliceStatus::Waiting),
+                (
+                    BlockNumber(HEADER_SLICE_SIZE as u64),
+                    HeaderSliceStatus::Empty
+                ),
+                (
+                    BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+                    HeaderSliceStatus::Empty
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn requeue_invalid() {
+        let mut header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 2,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+        )
+        .unwrap();
+        let invalid_log = InvalidLog::default();
+        header_slices.set_invalid_log(Some(invalid_log.clone()));
+
+        let peer_id = PeerId::repeat_byte(1);
+        let headers = vec![BlockHeader::from(crate::models::BlockHeader::empty()); 2];
+
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        let mut slice = slice_lock.write();
+        slice.from_peer_id = Some(peer_id);
+        slice.headers = Some(headers.clone());
+        header_slices.set_slice_status(&mut slice, HeaderSliceStatus::Invalid);
+
+        header_slices.requeue_invalid(&mut slice);
+        assert_eq!(slice.status, HeaderSliceStatus::Empty);
+        assert!(slice.headers.is_none());
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Invalid),
+            0
+        );
+
+        let invalid_log = invalid_log.lock();
+        assert_eq!(invalid_log.len(), 1);
+        let (start_block_num, from_peer_id, logged_headers) = &invalid_log[0];
+        assert_eq!(*start_block_num, BlockNumber(0));
+        assert_eq!(*from_peer_id, peer_id);
+        assert_eq!(logged_headers.len(), headers.len());
+        assert_eq!(logged_headers[1].hash(), headers[1].hash());
+    }
+
+    #[test]
+    fn estimated_completion() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 3,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 6) as u64),
+        )
+        .unwrap();
+
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Saved);
+
+        // 2 slices are not saved, and 3 are not refilled yet
+        assert_eq!(
+            header_slices.estimated_completion(0.5),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(header_slices.estimated_completion(0.0), None);
+        assert_eq!(header_slices.estimated_completion(f64::NAN), None);
+    }
+
+    #[test]
+    fn verified_prefix_len() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 6,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 6) as u64),
+        )
+        .unwrap();
+        let verified_prefix_len = header_slices.watch_verified_prefix_len();
+        assert_eq!(header_slices.verified_prefix_len(), 0);
+
+        let set_verified = |index: usize| {
+            let slice_lock = header_slices
+                .find_by_start_block_num(BlockNumber((HEADER_SLICE_SIZE * index) as u64))
+                .unwrap();
+            header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Verified);
+            header_slices.notify_status_watchers();
+        };
+
+        for index in 0..3 {
+            set_verified(index);
+        }
+        assert_eq!(header_slices.verified_prefix_len(), 3);
+        assert_eq!(*verified_prefix_len.borrow(), 3);
+
+        // slice 3 is a gap
+        set_verified(4);
+        assert_eq!(header_slices.verified_prefix_len(), 3);
+        assert_eq!(*verified_prefix_len.borrow(), 3);
+
+        set_verified(3);
+        assert_eq!(*verified_prefix_len.borrow(), 5);
+    }
+
+    #[test]
+    fn mem_limit_too_small() {
+        let error = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>(),
+            BlockNumber(0),
+            BlockNumber(HEADER_SLICE_SIZE as u64),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "mem_limit too small, need at least {} bytes",
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE
+            )
+        );
+
+        // nothing to download, nothing to hold
+        assert!(HeaderSlices::new(0, BlockNumber(0), BlockNumber(0)).is_ok());
+    }
+
+    #[test]
+    fn round_trips() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 5,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 5) as u64),
+        )
+        .unwrap();
+        assert_eq!(header_slices.median_round_trip(), None);
+
+        let request_time = time::Instant::now();
+        let mut round_trips = vec![
+            Some(Duration::from_millis(300)),
+            None,
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(200)),
+        ]
+        .into_iter();
+        header_slices.for_each(|slice_lock| {
+            let mut slice = slice_lock.write();
+            if let Some(round_trip) = round_trips.next().flatten() {
+                slice.request_time = Some(request_time);
+                slice.received_time = Some(request_time + round_trip);
+                assert_eq!(slice.round_trip(), Some(round_trip));
+            } else {
+                // requested, but not received yet
+                slice.request_time = Some(request_time);
+                assert_eq!(slice.round_trip(), None);
+            }
+        });
+        assert_eq!(
+            header_slices.median_round_trip(),
+            Some(Duration::from_millis(200))
+        );
+
+        // the median of an even number of round trips is the mean of the middle ones
+        let last_slice = header_slices
+            .find_by_start_block_num(BlockNumber((HEADER_SLICE_SIZE * 4) as u64))
+            .unwrap();
+        {
+            let mut slice = last_slice.write();
+            slice.request_time = Some(request_time);
+            slice.received_time = Some(request_time + Duration::from_millis(700));
+        }
+        assert_eq!(
+            header_slices.median_round_trip(),
+            Some(Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    fn fill_from_reader() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 4,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 4) as u64),
+        )
+        .unwrap();
+        let slice_lock = header_slices
+            .find_by_start_block_num(BlockNumber(HEADER_SLICE_SIZE as u64))
+            .unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Waiting);
+
+        // the last slice is incomplete
+        let mut file = Vec::new();
+        for number in 0..(HEADER_SLICE_SIZE * 7 / 2) as u64 {
+            let header = models::BlockHeader {
+                number: BlockNumber(number),
+                ..models::BlockHeader::new(
+                    models::PartialHeader::empty(),
+                    models::EMPTY_LIST_HASH,
+                    models::EMPTY_ROOT,
+                )
+            };
+            file.extend_from_slice(&rlp::encode(&header));
+        }
+
+        assert_eq!(header_slices.fill_from_reader(&mut &file[..]).unwrap(), 2);
+        assert_eq!(
+            header_slices.clone_statuses(),
+            vec![
+                HeaderSliceStatus::Downloaded,
+                HeaderSliceStatus::Waiting,
+                HeaderSliceStatus::Downloaded,
+                HeaderSliceStatus::Empty,
+            ]
+        );
+        header_slices.for_each(|slice_lock| {
+            let slice = slice_lock.read();
+            if slice.status != HeaderSliceStatus::Downloaded {
+                assert!(slice.headers.is_none());
+                return;
+            }
+            assert_eq!(slice.from_peer_id, None);
+            let headers = slice.headers.as_ref().unwrap();
+            assert_eq!(headers.len(), HEADER_SLICE_SIZE);
+            for (i, header) in headers.iter().enumerate() {
+                assert_eq!(header.number().0, slice.start_block_num.0 + i as u64);
+            }
+        });
+
+        // a truncated header is an error
+        let file = &file[..file.len() - 1];
+        assert!(header_slices.fill_from_reader(&mut &file[..]).is_err());
+    }
+
+    #[test]
+    fn checksum() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE,
+            BlockNumber(0),
+            BlockNumber(HEADER_SLICE_SIZE as u64),
+        )
+        .unwrap();
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        let mut slice = slice_lock.write();
+        slice.headers = Some(
+            (0..HEADER_SLICE_SIZE as u64)
+                .map(|number| {
+                    BlockHeader::from(models::BlockHeader {
+                        number: BlockNumber(number),
+                        ..models::BlockHeader::empty()
+                    })
+                })
+                .collect(),
+        );
+        header_slices.set_slice_status(&mut slice, HeaderSliceStatus::Downloaded);
+        assert_eq!(slice.checksum, slice.compute_checksum());
+        assert!(slice.checksum.is_some());
+
+        // caching the hashes doesn't change the headers
+        for header in slice.headers.as_mut().unwrap() {
+            header.hash_prepare();
+        }
+        header_slices.set_slice_status(&mut slice, HeaderSliceStatus::Verified);
+        slice.verify_checksum().unwrap();
+
+        // detected despite the cached hash
+        slice.headers.as_mut().unwrap()[1].header.gas_limit = 1;
+        assert!(slice.verify_checksum().is_err());
+        slice.headers.as_mut().unwrap()[1].header.gas_limit = 0;
+        slice.verify_checksum().unwrap();
+
+        // reordered
+        slice.headers.as_mut().unwrap().swap(0, 1);
+        assert!(slice.verify_checksum().is_err());
+
+        header_slices.set_slice_status(&mut slice, HeaderSliceStatus::Empty);
+        assert_eq!(slice.checksum, None);
+    }
+}
@@ -0,0 +1,179 @@
+use super::header_slices::{HeaderSliceStatus, HeaderSlices};
+use parking_lot::Mutex;
+use std::{fmt, sync::Arc, time};
+use strum::IntoEnumIterator;
+
+/// A downloader without status transitions for this long is considered unhealthy.
+pub const HEALTH_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloaderHealth {
+    /// Some slice changed its status during the last interval.
+    pub has_recent_transitions: bool,
+    /// Slices are Waiting, but none got Downloaded during the last interval.
+    pub is_stalled: bool,
+    /// Counts of the slices in every status over the buffers of the current run.
+    pub status_counters: Vec<(HeaderSliceStatus, usize)>,
+    /// The downloader is idle, or making progress.
+    pub is_healthy: bool,
+}
+
+/// The buffers of the current run, so that the health can be checked from outside of it.
+#[derive(Clone, Default)]
+pub struct ActiveHeaderSlices(Arc<Mutex<Vec<Arc<HeaderSlices>>>>);
+
+impl fmt::Debug for ActiveHeaderSlices {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActiveHeaderSlices")
+            .field("count", &self.0.lock().len())
+            .finish()
+    }
+}
+
+impl ActiveHeaderSlices {
+    /// Tracks the buffer until the returned scope is dropped.
+    pub fn register(&self, header_slices: Arc<HeaderSlices>) -> ActiveHeaderSlicesScope {
+        self.0.lock().push(header_slices.clone());
+        ActiveHeaderSlicesScope {
+            active: self.clone(),
+            header_slices,
+        }
+    }
+
+    pub fn get(&self) -> Vec<Arc<HeaderSlices>> {
+        self.0.lock().clone()
+    }
+}
+
+pub struct ActiveHeaderSlicesScope {
+    active: ActiveHeaderSlices,
+    header_slices: Arc<HeaderSlices>,
+}
+
+impl Drop for ActiveHeaderSlicesScope {
+    fn drop(&mut self) {
+        self.active
+            .0
+            .lock()
+            .retain(|header_slices| !Arc::ptr_eq(header_slices, &self.header_slices));
+    }
+}
+
+fn changed_since(
+    header_slices: &[Arc<HeaderSlices>],
+    status: HeaderSliceStatus,
+    since: time::SystemTime,
+) -> bool {
+    header_slices.iter().any(|header_slices| {
+        header_slices
+            .last_change(status)
+            .map_or(false, |last_change| last_change >= since)
+    })
+}
+
+/// Only reads the atomic counters and timestamps of the buffers.
+pub fn check_health(
+    header_slices: &[Arc<HeaderSlices>],
+    interval: time::Duration,
+    now: time::SystemTime,
+) -> DownloaderHealth {
+    let since = now - interval;
+
+    let status_counters = HeaderSliceStatus::iter()
+        .map(|status| {
+            let count = header_slices
+                .iter()
+                .map(|header_slices| header_slices.count_slices_in_status(status))
+                .sum();
+            (status, count)
+        })
+        .collect::<Vec<_>>();
+    let count = |status| {
+        status_counters
+            .iter()
+            .find(|(counted_status, _)| *counted_status == status)
+            .map_or(0, |(_, count)| *count)
+    };
+
+    let has_recent_transitions =
+        HeaderSliceStatus::iter().any(|status| changed_since(header_slices, status, since));
+    let is_stalled = count(HeaderSliceStatus::Waiting) > 0
+        && !changed_since(header_slices, HeaderSliceStatus::Downloaded, since);
+    let is_idle = header_slices.is_empty();
+
+    DownloaderHealth {
+        has_recent_transitions,
+        is_stalled,
+        status_counters,
+        is_healthy: is_idle || (has_recent_transitions && !is_stalled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::header_slices::HEADER_SLICE_SIZE, *};
+    use crate::models::{BlockHeader, BlockNumber};
+
+    fn header_slices_with_waiting() -> Arc<HeaderSlices> {
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 4,
+                BlockNumber(0),
+                BlockNumber((HEADER_SLICE_SIZE * 4) as u64),
+            )
+            .unwrap(),
+        );
+        for slice_lock in header_slices.find_batch_by_status(HeaderSliceStatus::Empty, 3) {
+            header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Waiting);
+        }
+        header_slices
+    }
+
+    #[test]
+    fn stalled() {
+        let header_slices = header_slices_with_waiting();
+
+        // the requests were sent long ago, and nothing arrived since
+        let health = check_health(
+            &[header_slices],
+            HEALTH_INTERVAL,
+            time::SystemTime::now() + HEALTH_INTERVAL * 2,
+        );
+        assert!(!health.has_recent_transitions);
+        assert!(health.is_stalled);
+        assert!(!health.is_healthy);
+        assert!(health
+            .status_counters
+            .contains(&(HeaderSliceStatus::Waiting, 3)));
+    }
+
+    #[test]
+    fn progressing() {
+        let header_slices = header_slices_with_waiting();
+        let slice_lock = header_slices
+            .find_by_status(HeaderSliceStatus::Waiting)
+            .unwrap();
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Downloaded);
+
+        let health = check_health(&[header_slices], HEALTH_INTERVAL, time::SystemTime::now());
+        assert!(health.has_recent_transitions);
+        assert!(!health.is_stalled);
+        assert!(health.is_healthy);
+        assert!(health
+            .status_counters
+            .contains(&(HeaderSliceStatus::Downloaded, 1)));
+    }
+
+    #[test]
+    fn idle() {
+        let active = ActiveHeaderSlices::default();
+        {
+            let _scope = active.register(header_slices_with_waiting());
+            assert_eq!(active.get().len(), 1);
+        }
+        assert!(active.get().is_empty());
+
+        let health = check_health(&active.get(), HEALTH_INTERVAL, time::SystemTime::now());
+        assert!(health.is_healthy);
+    }
+}
@@ -1,13 +1,18 @@
 mod average_delta_counter;
+pub mod checkpoints;
 pub mod downloader;
 mod downloader_linear;
 mod downloader_preverified;
 mod header;
 mod header_slice_status_watch;
 pub mod header_slices;
+pub mod health;
 mod parallel;
+mod peer_batch_sizes;
+pub mod peer_latencies;
 pub mod stage;
 mod stage_stream;
+mod status_notifier;
 
 mod fetch_receive_stage;
 mod fetch_request_stage;
@@ -0,0 +1,83 @@
+use crate::sentry::sentry_client::PeerId;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+/// Slices requested at once from a single peer at most.
+pub const MAX_PEER_BATCH_SIZE: usize = 16;
+
+/// How many slices to keep requested from each of the peers which responded before.
+///
+/// Some peers time out on large requests, so the batch of a peer grows by one slice
+/// on every slice it sends, and halves on every timeout.
+#[derive(Debug)]
+pub struct PeerBatchSizes {
+    sizes: Mutex<HashMap<PeerId, usize>>,
+    max_batch_size: usize,
+}
+
+impl PeerBatchSizes {
+    pub fn new(max_batch_size: usize) -> Self {
+        Self {
+            sizes: Mutex::new(HashMap::new()),
+            max_batch_size: max_batch_size.max(1),
+        }
+    }
+
+    /// The batch size of the peer, or 0 if it never responded.
+    pub fn get(&self, peer_id: PeerId) -> usize {
+        self.sizes.lock().get(&peer_id).copied().unwrap_or(0)
+    }
+
+    /// The known peers with their batch sizes, the largest first.
+    pub fn batches(&self) -> Vec<(PeerId, usize)> {
+        let mut batches = self
+            .sizes
+            .lock()
+            .iter()
+            .map(|(peer_id, size)| (*peer_id, *size))
+            .collect::<Vec<_>>();
+        batches.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        batches
+    }
+
+    pub fn on_received(&self, peer_id: PeerId) {
+        let mut sizes = self.sizes.lock();
+        let size = sizes.entry(peer_id).or_insert(0);
+        *size = std::cmp::min(*size + 1, self.max_batch_size);
+    }
+
+    pub fn on_timeout(&self, peer_id: PeerId) {
+        if let Some(size) = self.sizes.lock().get_mut(&peer_id) {
+            *size = std::cmp::max(*size / 2, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grow_and_shrink() {
+        let peer_batch_sizes = PeerBatchSizes::new(8);
+        let peer_id = PeerId::from_low_u64_be(1);
+        assert_eq!(peer_batch_sizes.get(peer_id), 0);
+
+        for _ in 0..10 {
+            peer_batch_sizes.on_received(peer_id);
+        }
+        assert_eq!(peer_batch_sizes.get(peer_id), 8);
+
+        peer_batch_sizes.on_timeout(peer_id);
+        assert_eq!(peer_batch_sizes.get(peer_id), 4);
+        for _ in 0..3 {
+            peer_batch_sizes.on_timeout(peer_id);
+        }
+        // still asked for a slice at a time
+        assert_eq!(peer_batch_sizes.get(peer_id), 1);
+
+        // unknown peers stay unknown
+        peer_batch_sizes.on_timeout(PeerId::from_low_u64_be(2));
+        assert_eq!(peer_batch_sizes.batches(), vec![(peer_id, 1)]);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::sentry::sentry_client::PeerId;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+/// Weight of a new sample in the rolling average.
+const LATENCY_SMOOTHING: f64 = 0.2;
+/// The peers at or above this quantile of the average latencies are slow.
+const SLOW_PEERS_QUANTILE: f64 = 0.75;
+/// Below this many measured peers the quantile is meaningless, and no peer is slow.
+const MIN_MEASURED_PEERS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerLatency {
+    /// Rolling average of the time from Waiting to Downloaded.
+    pub average: Duration,
+    pub samples: u64,
+}
+
+/// Latencies of the header requests by peer, so that fewer requests are sent to the slow peers.
+/// Softer than penalizing: the slow peers still get the random requests.
+#[derive(Debug, Default)]
+pub struct PeerLatencies {
+    latencies: Mutex<HashMap<PeerId, PeerLatency>>,
+}
+
+impl PeerLatencies {
+    pub fn on_received(&self, peer_id: PeerId, latency: Duration) {
+        let mut latencies = self.latencies.lock();
+        let entry = latencies.entry(peer_id).or_insert(PeerLatency {
+            average: latency,
+            samples: 0,
+        });
+        entry.average =
+            entry.average.mul_f64(1_f64 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING);
+        entry.samples += 1;
+    }
+
+    /// The measured peers, the fastest first.
+    pub fn stats(&self) -> Vec<(PeerId, PeerLatency)> {
+        let mut stats = self
+            .latencies
+            .lock()
+            .iter()
+            .map(|(peer_id, latency)| (*peer_id, *latency))
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| a.1.average.cmp(&b.1.average).then(a.0.cmp(&b.0)));
+        stats
+    }
+
+    /// The peers in the slowest quantile.
+    /// The peers without samples yet are never slow, as if they had the average latency.
+    pub fn slow_peers(&self) -> HashSet<PeerId> {
+        let stats = self.stats();
+        if stats.len() < MIN_MEASURED_PEERS {
+            return HashSet::new();
+        }
+
+        let threshold = stats[(stats.len() as f64 * SLOW_PEERS_QUANTILE) as usize]
+            .1
+            .average;
+        // all equally fast
+        if threshold == stats[0].1.average {
+            return HashSet::new();
+        }
+
+        stats
+            .into_iter()
+            .filter(|(_, latency)| latency.average >= threshold)
+            .map(|(peer_id, _)| peer_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_peers() {
+        let peer_latencies = PeerLatencies::default();
+        let peer_ids = (1..=8).map(PeerId::from_low_u64_be).collect::<Vec<_>>();
+
+        // too few peers to tell
+        for peer_id in &peer_ids[..3] {
+            peer_latencies.on_received(*peer_id, Duration::from_millis(100));
+        }
+        peer_latencies.on_received(peer_ids[2], Duration::from_secs(5));
+        assert!(peer_latencies.slow_peers().is_empty());
+
+        for (i, peer_id) in peer_ids.iter().enumerate().skip(3) {
+            peer_latencies.on_received(*peer_id, Duration::from_millis(100 * (i as u64 + 1)));
+        }
+        let slow_peers = peer_latencies.slow_peers();
+        assert_eq!(
+            slow_peers,
+            [peer_ids[2], peer_ids[7]]
+                .into_iter()
+                .collect::<HashSet<_>>()
+        );
+
+        // the rolling average follows the recent samples
+        for _ in 0..20 {
+            peer_latencies.on_received(peer_ids[2], Duration::from_millis(100));
+        }
+        assert!(!peer_latencies.slow_peers().contains(&peer_ids[2]));
+        assert_eq!(peer_latencies.stats().last().unwrap().0, peer_ids[7]);
+
+        // an unmeasured peer isn't slow
+        assert!(!peer_latencies
+            .slow_peers()
+            .contains(&PeerId::from_low_u64_be(9)));
+    }
+}
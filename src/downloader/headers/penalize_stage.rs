@@ -54,6 +54,12 @@ impl PenalizeStage {
         self.header_slices.for_each(|slice_lock| {
             let slice = slice_lock.read();
             if slice.status == HeaderSliceStatus::Invalid {
+                if let Some(reason) = &slice.invalid_reason {
+                    debug!(
+                        "PenalizeStage: slice at {:?} is invalid: {:?}",
+                        slice.start_block_num, reason
+                    );
+                }
                 match slice.from_peer_id {
                     Some(from_peer_id) => { peers.insert(from_peer_id); }
                     None => warn!("PenalizeStage: got an invalid headers slice from an unknown peer starting at: {:?}", slice.start_block_num),
@@ -68,9 +74,7 @@ impl PenalizeStage {
             let slice = slice_lock.upgradable_read();
             if slice.status == HeaderSliceStatus::Invalid {
                 let mut slice = RwLockUpgradableReadGuard::upgrade(slice);
-                self.header_slices
-                    .set_slice_status(slice.deref_mut(), HeaderSliceStatus::Empty);
-                slice.headers = None;
+                self.header_slices.requeue_invalid(slice.deref_mut());
             }
         });
     }
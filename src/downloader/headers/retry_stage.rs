@@ -1,6 +1,13 @@
-use crate::downloader::headers::{
-    header_slice_status_watch::HeaderSliceStatusWatch,
-    header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices},
+use crate::{
+    downloader::headers::{
+        header_slice_status_watch::HeaderSliceStatusWatch,
+        header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices},
+        peer_batch_sizes::PeerBatchSizes,
+    },
+    sentry::{
+        messages::EthMessageId, sentry_client::RequestTimeouts,
+        sentry_client_reactor::SentryClientReactorShared,
+    },
 };
 use parking_lot::RwLockUpgradableReadGuard;
 use std::{ops::DerefMut, sync::Arc, time, time::Duration};
@@ -10,21 +17,30 @@ use tracing::*;
 /// Status is updated to Empty (the slice will be processed by the FetchRequestStage again).
 pub struct RetryStage {
     header_slices: Arc<HeaderSlices>,
+    sentry: SentryClientReactorShared,
     pending_watch: HeaderSliceStatusWatch,
+    peer_batch_sizes: Option<Arc<PeerBatchSizes>>,
 }
 
 impl RetryStage {
-    pub fn new(header_slices: Arc<HeaderSlices>) -> Self {
+    pub fn new(header_slices: Arc<HeaderSlices>, sentry: SentryClientReactorShared) -> Self {
         Self {
             header_slices: header_slices.clone(),
+            sentry,
             pending_watch: HeaderSliceStatusWatch::new(
                 HeaderSliceStatus::Waiting,
                 header_slices,
                 "RetryStage",
             ),
+            peer_batch_sizes: None,
         }
     }
 
+    /// Shrink the batches of the peers which time out.
+    pub fn set_peer_batch_sizes(&mut self, peer_batch_sizes: Arc<PeerBatchSizes>) {
+        self.peer_batch_sizes = Some(peer_batch_sizes);
+    }
+
     pub async fn execute(&mut self) -> anyhow::Result<()> {
         debug!("RetryStage: start");
         self.pending_watch.wait().await?;
@@ -32,7 +48,8 @@ impl RetryStage {
         // don't retry more often than once per 1 sec
         tokio::time::sleep(Duration::from_secs(1)).await;
 
-        let count = self.reset_pending()?;
+        let request_timeouts = self.sentry.read().await.request_timeouts().clone();
+        let count = self.reset_pending(&request_timeouts)?;
         if count > 0 {
             debug!("RetryStage: did reset {} slices for retry", count);
         }
@@ -40,17 +57,23 @@ impl RetryStage {
         Ok(())
     }
 
-    fn reset_pending(&self) -> anyhow::Result<usize> {
+    fn reset_pending(&self, request_timeouts: &RequestTimeouts) -> anyhow::Result<usize> {
         let now = time::Instant::now();
         let mut count: usize = 0;
         self.header_slices.for_each(|slice_lock| {
             let slice = slice_lock.upgradable_read();
             if (slice.status == HeaderSliceStatus::Waiting)
-                && RetryStage::is_waiting_timeout_expired(&slice, &now)
+                && RetryStage::is_waiting_timeout_expired(&slice, &now, request_timeouts)
             {
                 let mut slice = RwLockUpgradableReadGuard::upgrade(slice);
+                if let (Some(peer_batch_sizes), Some(peer_id)) =
+                    (&self.peer_batch_sizes, slice.requested_peer_id.take())
+                {
+                    peer_batch_sizes.on_timeout(peer_id);
+                }
                 slice.request_time = None;
                 slice.request_attempt += 1;
+                self.header_slices.add_retry();
                 self.header_slices
                     .set_slice_status(slice.deref_mut(), HeaderSliceStatus::Empty);
                 count += 1;
@@ -59,24 +82,20 @@ impl RetryStage {
         Ok(count)
     }
 
-    fn is_waiting_timeout_expired(slice: &HeaderSlice, now: &time::Instant) -> bool {
+    fn is_waiting_timeout_expired(
+        slice: &HeaderSlice,
+        now: &time::Instant,
+        request_timeouts: &RequestTimeouts,
+    ) -> bool {
         if slice.request_time.is_none() {
             return false;
         }
         let request_time = slice.request_time.unwrap();
         let elapsed = now.duration_since(request_time);
-        let timeout = RetryStage::timeout_for_attempt(slice.request_attempt);
+        let timeout =
+            request_timeouts.timeout(EthMessageId::GetBlockHeaders, slice.request_attempt);
         elapsed > timeout
     }
-
-    fn timeout_for_attempt(attempt: u16) -> Duration {
-        match attempt {
-            0 => Duration::from_secs(5),
-            1 => Duration::from_secs(10),
-            2 => Duration::from_secs(15),
-            _ => Duration::from_secs(30),
-        }
-    }
 }
 
 #[async_trait::async_trait]
@@ -85,3 +104,60 @@ impl super::stage::Stage for RetryStage {
         RetryStage::execute(self).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        downloader::headers::{header::BlockHeader, header_slices::HEADER_SLICE_SIZE},
+        models::BlockNumber,
+    };
+
+    #[test]
+    fn waiting_timeout_backoff() {
+        let header_slices = HeaderSlices::new(
+            std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 3,
+            BlockNumber(0),
+            BlockNumber((HEADER_SLICE_SIZE * 3) as u64),
+        )
+        .unwrap();
+
+        let request_time = time::Instant::now();
+        for (i, request_attempt) in [0, 1, 3].into_iter().enumerate() {
+            header_slices.set_requested(
+                BlockNumber((HEADER_SLICE_SIZE * i) as u64),
+                request_attempt,
+                request_time,
+            );
+        }
+        assert_eq!(
+            header_slices.count_slices_in_status(HeaderSliceStatus::Waiting),
+            3
+        );
+
+        let request_timeouts = RequestTimeouts::default();
+        let expired_slices = |elapsed: Duration| -> Vec<bool> {
+            let now = request_time + elapsed;
+            let mut expired = Vec::new();
+            header_slices.for_each(|slice_lock| {
+                expired.push(RetryStage::is_waiting_timeout_expired(
+                    &slice_lock.read(),
+                    &now,
+                    &request_timeouts,
+                ));
+            });
+            expired
+        };
+
+        assert_eq!(expired_slices(Duration::from_secs(4)), vec![false; 3]);
+        assert_eq!(
+            expired_slices(Duration::from_secs(6)),
+            vec![true, false, false]
+        );
+        assert_eq!(
+            expired_slices(Duration::from_secs(11)),
+            vec![true, true, false]
+        );
+        assert_eq!(expired_slices(Duration::from_secs(31)), vec![true; 3]);
+    }
+}
@@ -1,14 +1,20 @@
 use super::{
     header::BlockHeader,
     header_slice_status_watch::HeaderSliceStatusWatch,
-    header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices},
+    header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices, HEADER_SLICE_SIZE},
 };
 use crate::{
+    accessors,
+    downloader::fork_choice::{ForkChoice, HeadUpdate},
     kv,
-    kv::{tables::HeaderKey, traits::MutableTransaction},
+    kv::{
+        tables::HeaderKey,
+        traits::{MutableTransaction, Transaction},
+    },
+    models::BlockNumber,
 };
 use anyhow::format_err;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::{
     ops::{ControlFlow, DerefMut},
     sync::Arc,
@@ -16,15 +22,29 @@ use std::{
 use tracing::*;
 
 /// Saves slices into the database, and sets Saved status.
+///
+/// The verified slices are flushed in batches of flush_threshold slices,
+/// then the RefillStage frees their memory.
 pub struct SaveStage<'tx, RwTx> {
     header_slices: Arc<HeaderSlices>,
     pending_watch: HeaderSliceStatusWatch,
     remaining_count: usize,
+    flush_threshold: usize,
     db_transaction: &'tx RwTx,
+    fork_choice_max_side_tips: Option<usize>,
+    fork_choice_max_reorg_depth: Option<u64>,
+    /// Loaded from the last saved header when the first header is saved.
+    fork_choice: Option<ForkChoice>,
+    /// The lowest common ancestor of the reorgs done by the fork choice.
+    reorg_common_ancestor: Arc<Mutex<Option<BlockNumber>>>,
 }
 
 impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
-    pub fn new(header_slices: Arc<HeaderSlices>, db_transaction: &'tx RwTx) -> Self {
+    pub fn new(
+        header_slices: Arc<HeaderSlices>,
+        flush_threshold: usize,
+        db_transaction: &'tx RwTx,
+    ) -> Self {
         Self {
             header_slices: header_slices.clone(),
             pending_watch: HeaderSliceStatusWatch::new(
@@ -33,10 +53,39 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
                 "SaveStage",
             ),
             remaining_count: 0,
+            flush_threshold,
             db_transaction,
+            fork_choice_max_side_tips: None,
+            fork_choice_max_reorg_depth: None,
+            fork_choice: None,
+            reorg_common_ancestor: Arc::default(),
         }
     }
 
+    /// Insert the headers with the fork choice, keeping up to max_side_tips competing tips,
+    /// so that the heaviest branch is canonical, instead of overwriting the canonical headers.
+    /// The database must have the header the slices link to saved as the last one.
+    pub fn set_fork_choice(&mut self, max_side_tips: Option<usize>) {
+        self.fork_choice_max_side_tips = max_side_tips;
+        self.fork_choice = None;
+    }
+
+    /// Fail instead of switching to a branch which forks off deeper than this below the head,
+    /// see `ForkChoice::set_max_reorg_depth`.
+    pub fn set_max_reorg_depth(&mut self, max_reorg_depth: Option<u64>) {
+        self.fork_choice_max_reorg_depth = max_reorg_depth;
+        if let Some(fork_choice) = &mut self.fork_choice {
+            fork_choice.set_max_reorg_depth(max_reorg_depth);
+        }
+    }
+
+    /// The lowest common ancestor of the branches the fork choice has switched from,
+    /// None if the saved headers only extended the canonical chain.
+    pub fn reorg_check(&self) -> impl Fn() -> Option<BlockNumber> {
+        let reorg_common_ancestor = self.reorg_common_ancestor.clone();
+        move || *reorg_common_ancestor.lock()
+    }
+
     pub async fn execute(&mut self) -> anyhow::Result<()> {
         debug!("SaveStage: start");
 
@@ -48,6 +97,15 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
 
         let pending_count = self.pending_watch.pending_count();
 
+        if !self.is_flush_needed() {
+            debug!(
+                "SaveStage: waiting for {} verified slices to flush",
+                self.flush_threshold
+            );
+            self.remaining_count = pending_count;
+            return Ok(());
+        }
+
         debug!("SaveStage: saving {} slices", pending_count);
         let saved_count = self.save_pending_monotonic(pending_count).await?;
         debug!("SaveStage: saved {} slices", saved_count);
@@ -58,6 +116,25 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
         Ok(())
     }
 
+    /// There are at least flush_threshold Verified slices following the Saved ones at the front,
+    /// or all the slices are Verified or Saved, so the buffer can't progress without a flush.
+    fn is_flush_needed(&self) -> bool {
+        let mut verified_count: usize = 0;
+        let result =
+            self.header_slices
+                .try_fold((), |_, slice_lock| match slice_lock.read().status {
+                    HeaderSliceStatus::Saved => ControlFlow::Continue(()),
+                    HeaderSliceStatus::Verified => {
+                        verified_count += 1;
+                        ControlFlow::Continue(())
+                    }
+                    _ => ControlFlow::Break(()),
+                });
+        let is_all_verified = matches!(result, ControlFlow::Continue(()));
+
+        (verified_count > 0) && ((verified_count >= self.flush_threshold) || is_all_verified)
+    }
+
     async fn save_pending_monotonic(&mut self, pending_count: usize) -> anyhow::Result<usize> {
         let mut saved_count: usize = 0;
         for _ in 0..pending_count {
@@ -82,7 +159,7 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
     }
 
     // this is kept for performance comparison with save_pending_monotonic
-    async fn save_pending_all(&self, _pending_count: usize) -> anyhow::Result<usize> {
+    async fn save_pending_all(&mut self, _pending_count: usize) -> anyhow::Result<usize> {
         let mut saved_count: usize = 0;
         while let Some(slice_lock) = self
             .header_slices
@@ -94,10 +171,11 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
         Ok(saved_count)
     }
 
-    async fn save_slice(&self, slice_lock: Arc<RwLock<HeaderSlice>>) -> anyhow::Result<()> {
+    async fn save_slice(&mut self, slice_lock: Arc<RwLock<HeaderSlice>>) -> anyhow::Result<()> {
         // take out the headers, and unlock the slice while save_slice is in progress
         let headers = {
             let mut slice = slice_lock.write();
+            slice.verify_checksum()?;
             slice.headers.take().ok_or_else(|| {
                 format_err!("SaveStage: inconsistent state - Verified slice has no headers")
             })?
@@ -115,12 +193,55 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
         Ok(())
     }
 
-    async fn save_headers(&self, headers: &[BlockHeader]) -> anyhow::Result<()> {
-        let tx = &self.db_transaction;
+    async fn save_headers(&mut self, headers: &[BlockHeader]) -> anyhow::Result<()> {
+        let tx = self.db_transaction;
         for header_ref in headers {
             // this clone happens mostly on the stack (except extra_data)
             let header = header_ref.clone();
-            self.save_header(header, tx).await?;
+            if let Some(max_side_tips) = self.fork_choice_max_side_tips {
+                self.insert_header(header, max_side_tips, tx).await?;
+            } else {
+                self.save_header(header, tx).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn insert_header(
+        &mut self,
+        header: BlockHeader,
+        max_side_tips: usize,
+        tx: &RwTx,
+    ) -> anyhow::Result<()> {
+        // e.g. the first slice of a run overlaps the saved headers
+        if accessors::chain::canonical_hash::read(tx, header.number()).await? == Some(header.hash())
+        {
+            return Ok(());
+        }
+
+        let fork_choice = match self.fork_choice.take() {
+            Some(fork_choice) => fork_choice,
+            None => {
+                let mut fork_choice = ForkChoice::load(tx, max_side_tips).await?;
+                fork_choice.set_max_reorg_depth(self.fork_choice_max_reorg_depth);
+                fork_choice
+            }
+        };
+        let update = self
+            .fork_choice
+            .insert(fork_choice)
+            .insert_header(tx, header.header)
+            .await?;
+
+        if let HeadUpdate::Reorg {
+            common_ancestor, ..
+        } = update
+        {
+            let mut reorg_common_ancestor = self.reorg_common_ancestor.lock();
+            *reorg_common_ancestor =
+                Some(reorg_common_ancestor.map_or(common_ancestor, |block_num| {
+                    std::cmp::min(block_num, common_ancestor)
+                }));
         }
         Ok(())
     }
@@ -129,7 +250,8 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
         let block_num = header.number();
         let header_hash = header.hash();
         let header_key: HeaderKey = (block_num, header_hash);
-        let total_difficulty = header.difficulty();
+        let total_difficulty =
+            accessors::chain::td::read_parent_and_accumulate(tx, &header.header).await?;
 
         // saving a precomputed RLP representation
         tx.set(HeaderTableWithBytes, header_key, header.rlp_repr())
@@ -138,12 +260,7 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
             .await?;
         tx.set(kv::tables::CanonicalHeader, block_num, header_hash)
             .await?;
-        tx.set(
-            kv::tables::HeadersTotalDifficulty,
-            header_key,
-            total_difficulty,
-        )
-        .await?;
+        accessors::chain::td::write(tx, header_hash, block_num, total_difficulty).await?;
         tx.set(kv::tables::LastHeader, Default::default(), header_hash)
             .await?;
 
@@ -151,6 +268,61 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> SaveStage<'tx, RwTx> {
     }
 }
 
+/// Whether all the headers of the slice starting at start_block_num are saved as canonical.
+async fn is_slice_saved<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    start_block_num: BlockNumber,
+) -> anyhow::Result<bool> {
+    for block_num in start_block_num.0..start_block_num.0 + HEADER_SLICE_SIZE as u64 {
+        if tx
+            .get(kv::tables::CanonicalHeader, BlockNumber(block_num))
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Sets Saved status to the Empty slices which headers are already in the database,
+/// so that only the gaps are downloaded.
+/// Returns the number of such slices.
+pub async fn mark_saved_slices<'db, Tx: Transaction<'db>>(
+    header_slices: &HeaderSlices,
+    tx: &Tx,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for slice_lock in header_slices.find_batch_by_status(HeaderSliceStatus::Empty, usize::MAX) {
+        let start_block_num = slice_lock.read().start_block_num;
+        if is_slice_saved(tx, start_block_num).await? {
+            let mut slice = slice_lock.write();
+            header_slices.set_slice_status(slice.deref_mut(), HeaderSliceStatus::Saved);
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        debug!("SaveStage: {} slices are already saved", count);
+        header_slices.notify_status_watchers();
+    }
+    Ok(count)
+}
+
+/// The end of the slices from start_block_num up to final_block_num
+/// which headers are all already in the database.
+pub async fn saved_prefix_end<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    start_block_num: BlockNumber,
+    final_block_num: BlockNumber,
+) -> anyhow::Result<BlockNumber> {
+    let mut block_num = start_block_num;
+    while block_num < final_block_num && is_slice_saved(tx, block_num).await? {
+        block_num.0 += HEADER_SLICE_SIZE as u64;
+    }
+    Ok(block_num)
+}
+
 #[derive(Debug)]
 struct HeaderTableWithBytes;
 
@@ -171,3 +343,299 @@ impl<'tx, 'db: 'tx, RwTx: MutableTransaction<'db>> super::stage::Stage for SaveS
         SaveStage::<RwTx>::execute(self).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::{new_mem_database, traits::MutableKV},
+        models::{self, PartialHeader, EMPTY_LIST_HASH, EMPTY_ROOT},
+        stagedsync::stage::ReorgTooDeepError,
+    };
+
+    /// A chain of headers from the genesis, with the difficulty of block n being n + 1.
+    fn chained_headers(count: usize) -> Vec<BlockHeader> {
+        let mut headers = Vec::<BlockHeader>::with_capacity(count);
+        for number in 0..count as u64 {
+            let mut header = PartialHeader::empty();
+            header.number = BlockNumber(number);
+            header.difficulty = (number + 1).into();
+            if let Some(parent) = headers.last() {
+                header.parent_hash = parent.hash();
+            }
+            headers.push(BlockHeader::from(models::BlockHeader::new(
+                header,
+                EMPTY_LIST_HASH,
+                EMPTY_ROOT,
+            )));
+        }
+        headers
+    }
+
+    /// The same heights with a double difficulty, forking off the parent of the first header.
+    fn heavier_fork(headers: &[BlockHeader]) -> Vec<BlockHeader> {
+        let mut fork_headers = Vec::<BlockHeader>::with_capacity(headers.len());
+        for header in headers {
+            let mut fork_header = header.header.clone();
+            fork_header.difficulty += fork_header.difficulty;
+            fork_header.parent_hash = fork_headers
+                .last()
+                .map_or(header.header.parent_hash, |parent| parent.hash());
+            fork_headers.push(BlockHeader::from(fork_header));
+        }
+        fork_headers
+    }
+
+    fn set_verified(header_slices: &HeaderSlices, index: usize) {
+        let headers =
+            chained_headers(HEADER_SLICE_SIZE * (index + 1)).split_off(HEADER_SLICE_SIZE * index);
+        set_verified_headers(header_slices, index, headers);
+    }
+
+    fn set_verified_headers(header_slices: &HeaderSlices, index: usize, headers: Vec<BlockHeader>) {
+        let start_block_num = BlockNumber((HEADER_SLICE_SIZE * index) as u64);
+        let slice_lock = header_slices
+            .find_by_start_block_num(start_block_num)
+            .unwrap();
+        let mut slice = slice_lock.write();
+        slice.headers = Some(headers);
+        header_slices.set_slice_status(slice.deref_mut(), HeaderSliceStatus::Verified);
+        drop(slice);
+        header_slices.notify_status_watchers();
+    }
+
+    #[tokio::test]
+    async fn flush_threshold() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE * 3,
+                BlockNumber(0),
+                BlockNumber((HEADER_SLICE_SIZE * 3) as u64),
+            )
+            .unwrap(),
+        );
+        let mut stage = SaveStage::new(header_slices.clone(), 2, &tx);
+
+        // below the threshold
+        set_verified(&header_slices, 0);
+        stage.execute().await.unwrap();
+        assert_eq!(
+            header_slices.clone_statuses(),
+            vec![
+                HeaderSliceStatus::Verified,
+                HeaderSliceStatus::Empty,
+                HeaderSliceStatus::Empty,
+            ]
+        );
+        assert!(tx
+            .get(kv::tables::CanonicalHeader, BlockNumber(0))
+            .await
+            .unwrap()
+            .is_none());
+
+        set_verified(&header_slices, 1);
+        stage.execute().await.unwrap();
+        assert_eq!(
+            header_slices.clone_statuses(),
+            vec![
+                HeaderSliceStatus::Saved,
+                HeaderSliceStatus::Saved,
+                HeaderSliceStatus::Empty,
+            ]
+        );
+        assert!(is_slice_saved(&tx, BlockNumber(0)).await.unwrap());
+        assert!(is_slice_saved(&tx, BlockNumber(HEADER_SLICE_SIZE as u64))
+            .await
+            .unwrap());
+
+        // the RefillStage frees the flushed slices
+        header_slices.remove(HeaderSliceStatus::Saved);
+        assert_eq!(
+            header_slices.clone_statuses(),
+            vec![HeaderSliceStatus::Empty]
+        );
+
+        // the last slice is flushed below the threshold
+        set_verified(&header_slices, 2);
+        stage.execute().await.unwrap();
+        assert_eq!(
+            header_slices.clone_statuses(),
+            vec![HeaderSliceStatus::Saved]
+        );
+        assert!(
+            is_slice_saved(&tx, BlockNumber((HEADER_SLICE_SIZE * 2) as u64))
+                .await
+                .unwrap()
+        );
+
+        // the total difficulty accumulates across the slices
+        let headers = chained_headers(HEADER_SLICE_SIZE * 3);
+        for number in [0, HEADER_SLICE_SIZE, HEADER_SLICE_SIZE * 3 - 1] {
+            let header = &headers[number];
+            let blocks = number as u64 + 1;
+            assert_eq!(
+                accessors::chain::td::read(&tx, header.hash(), header.number())
+                    .await
+                    .unwrap(),
+                Some((blocks * (blocks + 1) / 2).into())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_parent_total_difficulty() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE,
+                BlockNumber(HEADER_SLICE_SIZE as u64),
+                BlockNumber((HEADER_SLICE_SIZE * 2) as u64),
+            )
+            .unwrap(),
+        );
+        let mut stage = SaveStage::new(header_slices.clone(), 1, &tx);
+
+        // the headers before the slice were never saved
+        set_verified(&header_slices, 1);
+        assert!(stage.execute().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fork_choice() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        let slice_mem = std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE;
+        let slice_end = |index: usize| BlockNumber((HEADER_SLICE_SIZE * index) as u64);
+
+        // the slice before the fork
+        let header_slices =
+            Arc::new(HeaderSlices::new(slice_mem, slice_end(0), slice_end(1)).unwrap());
+        let mut stage = SaveStage::new(header_slices.clone(), 1, &tx);
+        set_verified(&header_slices, 0);
+        stage.execute().await.unwrap();
+
+        let headers = chained_headers(HEADER_SLICE_SIZE * 2);
+        let fork_headers = heavier_fork(&headers[HEADER_SLICE_SIZE..]);
+
+        for (branch, branch_headers) in [&headers[HEADER_SLICE_SIZE..], &fork_headers[..]]
+            .into_iter()
+            .enumerate()
+        {
+            let header_slices =
+                Arc::new(HeaderSlices::new(slice_mem, slice_end(1), slice_end(2)).unwrap());
+            let mut stage = SaveStage::new(header_slices.clone(), 1, &tx);
+            stage.set_fork_choice(Some(4));
+            set_verified_headers(&header_slices, 1, branch_headers.to_vec());
+            stage.execute().await.unwrap();
+            assert_eq!(
+                header_slices.clone_statuses(),
+                vec![HeaderSliceStatus::Saved],
+                "branch {}",
+                branch
+            );
+
+            // the fork replaces the canonical headers above the slice before it
+            let reorg_check = stage.reorg_check();
+            if branch == 0 {
+                assert_eq!(reorg_check(), None);
+            } else {
+                assert_eq!(reorg_check(), Some(BlockNumber(slice_end(1).0 - 1)));
+            }
+        }
+
+        // the heavier branch is canonical, and the other one is still there
+        let last = HEADER_SLICE_SIZE - 1;
+        for (number, hash) in [
+            (slice_end(1), fork_headers[0].hash()),
+            (fork_headers[last].number(), fork_headers[last].hash()),
+        ] {
+            assert_eq!(
+                accessors::chain::canonical_hash::read(&tx, number)
+                    .await
+                    .unwrap(),
+                Some(hash)
+            );
+        }
+        let old_tip = &headers[HEADER_SLICE_SIZE * 2 - 1];
+        assert!(
+            accessors::chain::header::read(&tx, old_tip.hash(), old_tip.number())
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            tx.get(kv::tables::LastHeader, Default::default())
+                .await
+                .unwrap(),
+            Some(fork_headers[last].hash())
+        );
+    }
+
+    #[tokio::test]
+    async fn max_reorg_depth() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        let slice_mem = std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE;
+        let slice_end = |index: usize| BlockNumber((HEADER_SLICE_SIZE * index) as u64);
+
+        let headers = chained_headers(HEADER_SLICE_SIZE * 2);
+        let header_slices =
+            Arc::new(HeaderSlices::new(slice_mem, slice_end(0), slice_end(1)).unwrap());
+        let mut stage = SaveStage::new(header_slices.clone(), 1, &tx);
+        set_verified(&header_slices, 0);
+        stage.execute().await.unwrap();
+
+        let fork_headers = heavier_fork(&headers[HEADER_SLICE_SIZE..]);
+        for (branch, branch_headers) in [&headers[HEADER_SLICE_SIZE..], &fork_headers[..]]
+            .into_iter()
+            .enumerate()
+        {
+            let header_slices =
+                Arc::new(HeaderSlices::new(slice_mem, slice_end(1), slice_end(2)).unwrap());
+            let mut stage = SaveStage::new(header_slices.clone(), 1, &tx);
+            stage.set_fork_choice(Some(4));
+            stage.set_max_reorg_depth(Some(HEADER_SLICE_SIZE as u64 - 1));
+            set_verified_headers(&header_slices, 1, branch_headers.to_vec());
+            let result = stage.execute().await;
+            if branch == 0 {
+                result.unwrap();
+                continue;
+            }
+
+            // the fork point is a whole slice below the head
+            let error = result.unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<ReorgTooDeepError>(),
+                Some(ReorgTooDeepError {
+                    head,
+                    unwind_to,
+                    max_reorg_depth,
+                }) if head.0 == slice_end(2).0 - 1
+                    && unwind_to.0 == slice_end(1).0 - 1
+                    && *max_reorg_depth == HEADER_SLICE_SIZE as u64 - 1
+            ));
+        }
+
+        // the canonical chain is untouched
+        let last = &headers[HEADER_SLICE_SIZE * 2 - 1];
+        for header in [&headers[HEADER_SLICE_SIZE], last] {
+            assert_eq!(
+                accessors::chain::canonical_hash::read(&tx, header.number())
+                    .await
+                    .unwrap(),
+                Some(header.hash())
+            );
+        }
+        assert_eq!(
+            tx.get(kv::tables::LastHeader, Default::default())
+                .await
+                .unwrap(),
+            Some(last.hash())
+        );
+    }
+}
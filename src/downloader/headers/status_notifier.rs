@@ -0,0 +1,128 @@
+use super::header_slices::HeaderSlices;
+use std::{sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+/// Coalesces the status notifications of the header slices:
+/// the watchers are notified at most once per interval,
+/// and a notification requested in between is deferred until the interval passes,
+/// so that the final counts are always delivered.
+pub struct StatusNotifier {
+    header_slices: Vec<Arc<HeaderSlices>>,
+    interval: Duration,
+    last_notified: Option<Instant>,
+    is_deferred: bool,
+    notifications_count: usize,
+}
+
+impl StatusNotifier {
+    pub fn new(header_slices: Vec<Arc<HeaderSlices>>, interval: Duration) -> Self {
+        Self {
+            header_slices,
+            interval,
+            last_notified: None,
+            is_deferred: false,
+            notifications_count: 0,
+        }
+    }
+
+    fn next_notify_time(&self) -> Option<Instant> {
+        self.last_notified
+            .map(|last_notified| last_notified + self.interval)
+    }
+
+    /// Notifies the watchers now if the interval has passed since the last notification,
+    /// otherwise defers the notification.
+    pub fn notify(&mut self) {
+        match self.next_notify_time() {
+            Some(next_notify_time) if Instant::now() < next_notify_time => {
+                self.is_deferred = true;
+            }
+            _ => self.notify_now(),
+        }
+    }
+
+    /// Resolves when the deferred notification is due, or never if there's none.
+    pub async fn wait_deferred(&self) {
+        match self.next_notify_time() {
+            Some(next_notify_time) if self.is_deferred => {
+                tokio::time::sleep_until(next_notify_time).await
+            }
+            _ => std::future::pending::<()>().await,
+        }
+    }
+
+    /// Delivers the deferred notification, if any.
+    pub fn notify_deferred(&mut self) {
+        if self.is_deferred {
+            self.notify_now();
+        }
+    }
+
+    fn notify_now(&mut self) {
+        for header_slices in &self.header_slices {
+            header_slices.notify_status_watchers();
+        }
+        self.last_notified = Some(Instant::now());
+        self.is_deferred = false;
+        self.notifications_count += 1;
+    }
+
+    pub fn notifications_count(&self) -> usize {
+        self.notifications_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::header_slices::{HeaderSliceStatus, HEADER_SLICE_SIZE},
+        *,
+    };
+    use crate::models::{BlockHeader, BlockNumber};
+
+    #[tokio::test]
+    async fn coalesced() {
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE,
+                BlockNumber(0),
+                BlockNumber(HEADER_SLICE_SIZE as u64),
+            )
+            .unwrap(),
+        );
+        let verified_count = header_slices.watch_status_changes(HeaderSliceStatus::Verified);
+        let mut notifier =
+            StatusNotifier::new(vec![header_slices.clone()], Duration::from_millis(50));
+
+        let slice_lock = header_slices
+            .find_by_start_block_num(BlockNumber(0))
+            .unwrap();
+        for i in 0..1000 {
+            let status = if i % 2 == 0 {
+                HeaderSliceStatus::Verified
+            } else {
+                HeaderSliceStatus::Empty
+            };
+            header_slices.set_slice_status(&mut slice_lock.write(), status);
+            notifier.notify();
+        }
+        header_slices.set_slice_status(&mut slice_lock.write(), HeaderSliceStatus::Verified);
+        notifier.notify();
+
+        // a burst is much shorter than the interval
+        assert!(notifier.notifications_count() <= 2);
+
+        // the settled count is delivered by the deferred notification
+        let _ = tokio::time::timeout(Duration::from_millis(100), notifier.wait_deferred()).await;
+        notifier.notify_deferred();
+        assert_eq!(*verified_count.borrow(), 1);
+        assert!(notifier.notifications_count() <= 3);
+
+        // nothing is deferred anymore
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), notifier.wait_deferred())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -10,16 +10,18 @@ use std::{
     sync::Arc,
 };
 
+/// Shows the ranges of HeaderSlices as one, the progress is of the first range.
 pub struct HeaderSlicesView {
-    header_slices: Arc<HeaderSlices>,
+    header_slices_ranges: Vec<Arc<HeaderSlices>>,
     phase_name: String,
     speed_counter: RefCell<AverageDeltaCounter>,
 }
 
 impl HeaderSlicesView {
-    pub fn new(header_slices: Arc<HeaderSlices>, phase_name: &str) -> Self {
+    pub fn new(header_slices_ranges: Vec<Arc<HeaderSlices>>, phase_name: &str) -> Self {
+        assert!(!header_slices_ranges.is_empty());
         Self {
-            header_slices,
+            header_slices_ranges,
             phase_name: String::from(phase_name),
             speed_counter: RefCell::new(AverageDeltaCounter::new(60)),
         }
@@ -29,11 +31,34 @@ impl HeaderSlicesView {
 impl UIView for HeaderSlicesView {
     fn draw(&self) -> anyhow::Result<()> {
         let phase_name = &self.phase_name;
-        let min_block_num = self.header_slices.min_block_num();
-        let max_block_num = self.header_slices.max_block_num();
-        let final_block_num = self.header_slices.final_block_num();
-        let counters = self.header_slices.status_counters();
-        let statuses = self.header_slices.clone_statuses();
+        let ranges = &self.header_slices_ranges;
+        let min_block_num = ranges[0].min_block_num();
+        let max_block_num = ranges
+            .iter()
+            .map(|header_slices| header_slices.max_block_num())
+            .max()
+            .unwrap();
+        let final_block_num = ranges[ranges.len() - 1].final_block_num();
+        let mut counters = ranges[0].status_counters();
+        for header_slices in &ranges[1..] {
+            for (counter, (_, count)) in counters.iter_mut().zip(header_slices.status_counters()) {
+                counter.1 += count;
+            }
+        }
+        let in_flight_count = ranges
+            .iter()
+            .map(|header_slices| header_slices.in_flight_count())
+            .sum::<usize>();
+        let memory_size = bytesize::ByteSize::b(
+            ranges
+                .iter()
+                .map(|header_slices| header_slices.estimated_memory_bytes() as u64)
+                .sum(),
+        );
+        let statuses = ranges
+            .iter()
+            .flat_map(|header_slices| header_slices.clone_statuses())
+            .collect::<Vec<_>>();
 
         // speed
         let mut speed_counter = self.speed_counter.borrow_mut();
@@ -51,12 +76,14 @@ impl UIView for HeaderSlicesView {
 
         // overall progress
         let progress_desc = std::format!(
-            "{} headers {} - {} of {} at {} blk/sec ...",
+            "{} headers {} - {} of {} at {} blk/sec, {} requests in flight, {} in memory ...",
             phase_name,
             min_block_num.0,
             max_block_num.0,
             final_block_num.0,
             speed,
+            in_flight_count,
+            memory_size,
         );
         stdout.queue(style::Print(progress_desc))?;
         stdout.queue(terminal::Clear(terminal::ClearType::UntilNewLine))?;
@@ -1,18 +1,27 @@
 use super::{
+    checkpoints::Checkpoints,
     header_slice_status_watch::HeaderSliceStatusWatch,
     header_slice_verifier,
     header_slices::{HeaderSlice, HeaderSliceStatus, HeaderSlices},
 };
-use crate::{downloader::headers::parallel::map_parallel, sentry::chain_config::ChainConfig};
+use crate::{
+    consensus::{engine_factory, Consensus},
+    downloader::headers::parallel::map_parallel,
+    sentry::chain_config::ChainConfig,
+};
 use parking_lot::RwLock;
 use std::{ops::DerefMut, sync::Arc, time::SystemTime};
 use tracing::*;
 
 /// Verifies the block structure and sequence rules in each slice and sets VerifiedInternally status.
+/// Optionally verifies the seals of the headers with the consensus engine.
+/// The headers at the checkpoint heights must have the checkpoint hashes.
 pub struct VerifyStageLinear {
     header_slices: Arc<HeaderSlices>,
     slice_size: usize,
     chain_config: ChainConfig,
+    seal_engine: Option<Arc<dyn Consensus>>,
+    checkpoints: Arc<Checkpoints>,
     pending_watch: HeaderSliceStatusWatch,
 }
 
@@ -21,17 +30,30 @@ impl VerifyStageLinear {
         header_slices: Arc<HeaderSlices>,
         slice_size: usize,
         chain_config: ChainConfig,
-    ) -> Self {
-        Self {
+        verify_seal: bool,
+        checkpoints: Arc<Checkpoints>,
+    ) -> anyhow::Result<Self> {
+        let seal_engine = if verify_seal {
+            Some(Arc::from(engine_factory(
+                chain_config.chain_spec().clone(),
+            )?))
+        } else {
+            None
+        };
+
+        let instance = Self {
             header_slices: header_slices.clone(),
             slice_size,
             chain_config,
+            seal_engine,
+            checkpoints,
             pending_watch: HeaderSliceStatusWatch::new(
                 HeaderSliceStatus::Downloaded,
                 header_slices,
                 "VerifyStageLinear",
             ),
-        }
+        };
+        Ok(instance)
     }
 
     pub async fn execute(&mut self) -> anyhow::Result<()> {
@@ -57,6 +79,9 @@ impl VerifyStageLinear {
             }
 
             let slices_verified = self.verify_slices_parallel(&slices_batch).await;
+            let slices_verified = self
+                .verify_slices_seal_parallel(&slices_batch, slices_verified)
+                .await;
 
             for (i, slice_lock) in slices_batch.iter().enumerate() {
                 let mut slice = slice_lock.write();
@@ -77,11 +102,60 @@ impl VerifyStageLinear {
         map_parallel(Vec::from(slices), |slice_lock| -> bool {
             let mut slice = slice_lock.write();
             Self::prepare_slice_hashes(&mut slice);
+            let internal_result = match &slice.headers {
+                Some(headers) => header_slice_verifier::verify_slice_internal(headers),
+                None => Ok(()),
+            }
+            .and_then(|_| self.checkpoints.verify_slice(&slice));
+            if let Err(reason) = internal_result {
+                slice.invalid_reason = Some(reason);
+                return false;
+            }
             self.verify_slice(&slice)
         })
         .await
     }
 
+    /// Verifies the seals of the slices which have passed the other checks, a task per slice.
+    async fn verify_slices_seal_parallel(
+        &self,
+        slices: &[Arc<RwLock<HeaderSlice>>],
+        slices_verified: Vec<bool>,
+    ) -> Vec<bool> {
+        let engine = match &self.seal_engine {
+            Some(engine) => engine,
+            None => return slices_verified,
+        };
+
+        let handles = slices
+            .iter()
+            .zip(slices_verified)
+            .map(|(slice_lock, is_verified)| {
+                let headers = if is_verified {
+                    slice_lock.read().headers.clone()
+                } else {
+                    None
+                };
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    match headers {
+                        Some(headers) => {
+                            header_slice_verifier::verify_slice_seals(&headers, engine.as_ref())
+                                .await
+                        }
+                        None => false,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut results = Vec::<bool>::with_capacity(handles.len());
+        for handle in handles {
+            results.push(handle.await.unwrap_or(false));
+        }
+        results
+    }
+
     fn prepare_slice_hashes(slice: &mut HeaderSlice) {
         if let Some(headers) = slice.headers.as_mut() {
             for header in headers {
@@ -106,13 +180,16 @@ impl VerifyStageLinear {
             return false;
         }
 
-        header_slice_verifier::verify_slice_is_linked_by_parent_hash(headers)
-            && header_slice_verifier::verify_slice_block_nums(headers, slice.start_block_num)
+        header_slice_verifier::verify_slice_block_nums(headers, slice.start_block_num)
             && header_slice_verifier::verify_slice_timestamps(headers, Self::now_timestamp())
             && header_slice_verifier::verify_slice_difficulties(
                 headers,
                 self.chain_config.chain_spec(),
             )
+            && header_slice_verifier::verify_slice_base_fees(
+                headers,
+                self.chain_config.chain_spec(),
+            )
             && header_slice_verifier::verify_slice_pow(headers)
     }
 }
@@ -123,3 +200,161 @@ impl super::stage::Stage for VerifyStageLinear {
         VerifyStageLinear::execute(self).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::{
+            difficulty::{canonical_difficulty, BlockDifficultyBombData},
+            expected_base_fee_per_gas,
+        },
+        downloader::headers::{
+            header::BlockHeader,
+            header_slices::{InvalidReason, HEADER_SLICE_SIZE},
+        },
+        models::{self, BlockNumber, PartialHeader, EMPTY_LIST_HASH, EMPTY_ROOT},
+        res::chainspec::MAINNET,
+    };
+    use ethereum_types::H256;
+
+    fn make_chain(
+        eip1559_block: BlockNumber,
+        invalid_base_fee_index: Option<usize>,
+    ) -> Vec<BlockHeader> {
+        let mut headers = Vec::<BlockHeader>::with_capacity(HEADER_SLICE_SIZE);
+        for index in 0..HEADER_SLICE_SIZE {
+            let mut header = PartialHeader::empty();
+            header.number = BlockNumber(index as u64);
+            header.timestamp = (index as u64) * 15;
+            header.gas_limit = 30_000_000;
+            header.gas_used = (index as u64) * 1_000_000 % header.gas_limit;
+            header.difficulty = 0x400000000_u64.into();
+            if let Some(parent) = headers.last() {
+                header.parent_hash = parent.hash();
+                header.difficulty = canonical_difficulty(
+                    header.number,
+                    header.timestamp,
+                    parent.difficulty(),
+                    parent.timestamp(),
+                    false,
+                    false,
+                    false,
+                    Some(BlockDifficultyBombData {
+                        delay_to: BlockNumber(0),
+                    }),
+                );
+            }
+
+            let mut header = models::BlockHeader::new(header, EMPTY_LIST_HASH, EMPTY_ROOT);
+            if let Some(parent) = headers.last() {
+                header.base_fee_per_gas =
+                    expected_base_fee_per_gas(&header, &parent.header, Some(eip1559_block));
+            }
+            if Some(index) == invalid_base_fee_index {
+                header.base_fee_per_gas = header.base_fee_per_gas.map(|base_fee| base_fee + 1);
+            }
+            headers.push(BlockHeader::from(header));
+        }
+        headers
+    }
+
+    async fn verify_with_checkpoints(
+        headers: Vec<BlockHeader>,
+        chain_config: ChainConfig,
+        checkpoints: Checkpoints,
+    ) -> (HeaderSliceStatus, Option<InvalidReason>) {
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE,
+                BlockNumber(0),
+                BlockNumber(HEADER_SLICE_SIZE as u64),
+            )
+            .unwrap(),
+        );
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        {
+            let mut slice = slice_lock.write();
+            slice.headers = Some(headers);
+            header_slices.set_slice_status(slice.deref_mut(), HeaderSliceStatus::Downloaded);
+        }
+
+        let mut stage = VerifyStageLinear::new(
+            header_slices.clone(),
+            HEADER_SLICE_SIZE,
+            chain_config,
+            false,
+            Arc::new(checkpoints),
+        )
+        .unwrap();
+        stage.execute().await.unwrap();
+
+        let slice = slice_lock.read();
+        (slice.status, slice.invalid_reason.clone())
+    }
+
+    async fn verify(headers: Vec<BlockHeader>, chain_config: ChainConfig) -> HeaderSliceStatus {
+        verify_with_checkpoints(headers, chain_config, Checkpoints::default())
+            .await
+            .0
+    }
+
+    #[tokio::test]
+    async fn verify_base_fees() {
+        let eip1559_block = BlockNumber(100);
+        let mut chain_spec = MAINNET.clone();
+        chain_spec.consensus.eip1559_block = Some(eip1559_block);
+        let chain_config = ChainConfig::with_genesis_block_hash(chain_spec, H256::zero());
+
+        assert_eq!(
+            verify(make_chain(eip1559_block, None), chain_config.clone()).await,
+            HeaderSliceStatus::VerifiedInternally
+        );
+        // the base fee of the fork block itself is INITIAL_BASE_FEE
+        assert_eq!(
+            verify(make_chain(eip1559_block, Some(100)), chain_config.clone()).await,
+            HeaderSliceStatus::Invalid
+        );
+        assert_eq!(
+            verify(make_chain(eip1559_block, Some(150)), chain_config).await,
+            HeaderSliceStatus::Invalid
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_checkpoints() {
+        let eip1559_block = BlockNumber(100);
+        let mut chain_spec = MAINNET.clone();
+        chain_spec.consensus.eip1559_block = Some(eip1559_block);
+        let chain_config = ChainConfig::with_genesis_block_hash(chain_spec, H256::zero());
+        let headers = make_chain(eip1559_block, None);
+        let block_num = BlockNumber(100);
+        let hash = headers[100].hash();
+
+        assert_eq!(
+            verify_with_checkpoints(
+                headers.clone(),
+                chain_config.clone(),
+                Checkpoints::new(vec![(block_num, hash), (BlockNumber(1_000), H256::zero())]),
+            )
+            .await,
+            (HeaderSliceStatus::VerifiedInternally, None)
+        );
+        assert_eq!(
+            verify_with_checkpoints(
+                headers,
+                chain_config,
+                Checkpoints::new(vec![(block_num, H256::zero())]),
+            )
+            .await,
+            (
+                HeaderSliceStatus::Invalid,
+                Some(InvalidReason::CheckpointMismatch {
+                    block_num,
+                    expected: H256::zero(),
+                    got: hash,
+                })
+            )
+        );
+    }
+}
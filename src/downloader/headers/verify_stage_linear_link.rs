@@ -14,8 +14,13 @@ use std::{
 use tracing::*;
 
 /// Verifies the sequence rules to link the slices with the last known verified header and sets Verified status.
+///
+/// The slices can be split into several adjacent ranges downloaded in parallel.
+/// The ranges are linked one after another: the first slice of a range must link to the last verified header
+/// of the previous range, so no slice of a range is Verified before all the previous ranges are.
 pub struct VerifyStageLinearLink {
-    header_slices: Arc<HeaderSlices>,
+    ranges: Vec<Arc<HeaderSlices>>,
+    current_range: usize,
     chain_config: ChainConfig,
     start_block_num: BlockNumber,
     start_block_hash: ethereum_types::H256,
@@ -26,13 +31,15 @@ pub struct VerifyStageLinearLink {
 
 impl VerifyStageLinearLink {
     pub fn new(
-        header_slices: Arc<HeaderSlices>,
+        ranges: Vec<Arc<HeaderSlices>>,
         chain_config: ChainConfig,
         start_block_num: BlockNumber,
         start_block_hash: ethereum_types::H256,
     ) -> Self {
+        let header_slices = ranges[0].clone();
         Self {
-            header_slices: header_slices.clone(),
+            ranges,
+            current_range: 0,
             chain_config,
             start_block_num,
             start_block_hash,
@@ -63,26 +70,54 @@ impl VerifyStageLinearLink {
 
         self.remaining_count = pending_count - updated_count;
 
+        if self.is_current_range_linked() {
+            self.current_range += 1;
+            debug!(
+                "VerifyStageLinearLink: linking the range {}",
+                self.current_range
+            );
+            self.pending_watch = HeaderSliceStatusWatch::new(
+                HeaderSliceStatus::VerifiedInternally,
+                self.header_slices().clone(),
+                "VerifyStageLinearLink",
+            );
+            self.remaining_count = 0;
+        }
+
         debug!("VerifyStageLinearLink: done");
         Ok(())
     }
 
+    fn header_slices(&self) -> &Arc<HeaderSlices> {
+        &self.ranges[self.current_range]
+    }
+
+    /// The last slice of the current range is verified, and there's a next range to link to it.
+    fn is_current_range_linked(&self) -> bool {
+        (self.current_range + 1 < self.ranges.len())
+            && self.last_verified_header.as_ref().map_or(false, |header| {
+                header.number().0 + 1 == self.header_slices().final_block_num().0
+            })
+    }
+
     fn verify_pending_monotonic(&mut self, pending_count: usize) -> anyhow::Result<usize> {
         let mut updated_count: usize = 0;
         for _ in 0..pending_count {
             let initial_value = Option::<Arc<RwLock<HeaderSlice>>>::None;
-            let next_slice_lock = self.header_slices.try_fold(initial_value, |_, slice_lock| {
-                let slice = slice_lock.read();
-                match slice.status {
-                    HeaderSliceStatus::Verified | HeaderSliceStatus::Saved => {
-                        ControlFlow::Continue(None)
-                    }
-                    HeaderSliceStatus::VerifiedInternally => {
-                        ControlFlow::Break(Some(slice_lock.clone()))
+            let next_slice_lock = self
+                .header_slices()
+                .try_fold(initial_value, |_, slice_lock| {
+                    let slice = slice_lock.read();
+                    match slice.status {
+                        HeaderSliceStatus::Verified | HeaderSliceStatus::Saved => {
+                            ControlFlow::Continue(None)
+                        }
+                        HeaderSliceStatus::VerifiedInternally => {
+                            ControlFlow::Break(Some(slice_lock.clone()))
+                        }
+                        _ => ControlFlow::Break(None),
                     }
-                    _ => ControlFlow::Break(None),
-                }
-            });
+                });
 
             if let ControlFlow::Break(Some(slice_lock)) = next_slice_lock {
                 let is_verified = self.verify_pending_slice(slice_lock);
@@ -104,13 +139,13 @@ impl VerifyStageLinearLink {
 
         let mut slice = RwLockUpgradableReadGuard::upgrade(slice);
         if is_verified {
-            self.header_slices
+            self.header_slices()
                 .set_slice_status(slice.deref_mut(), HeaderSliceStatus::Verified);
             if let Some(last_verified_header) = slice.headers.as_ref().unwrap().iter().last() {
                 self.last_verified_header = Some(last_verified_header.clone());
             }
         } else {
-            self.header_slices
+            self.header_slices()
                 .set_slice_status(slice.deref_mut(), HeaderSliceStatus::Invalid);
         }
 
@@ -138,7 +173,8 @@ impl VerifyStageLinearLink {
         if child.number() == self.start_block_num {
             return child.hash() == self.start_block_hash;
         }
-        // otherwise we expect that we have a verified parent
+        // otherwise we expect that we have a verified parent,
+        // at the start of a range it is the last header of the previous range
         if parent.is_none() {
             return false;
         }
@@ -152,6 +188,11 @@ impl VerifyStageLinearLink {
                 parent,
                 self.chain_config.chain_spec(),
             )
+            && header_slice_verifier::verify_link_base_fee(
+                child,
+                parent,
+                self.chain_config.chain_spec(),
+            )
             && header_slice_verifier::verify_link_pow(child, parent)
     }
 }
@@ -162,3 +203,121 @@ impl super::stage::Stage for VerifyStageLinearLink {
         VerifyStageLinearLink::execute(self).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::difficulty::{canonical_difficulty, BlockDifficultyBombData},
+        downloader::headers::header_slices::HEADER_SLICE_SIZE,
+        models::{self, PartialHeader, EMPTY_LIST_HASH, EMPTY_ROOT},
+        sentry::chain_config::ChainsConfig,
+    };
+
+    fn make_chain(len: usize) -> Vec<BlockHeader> {
+        let mut headers = Vec::<BlockHeader>::with_capacity(len);
+        for number in 0..len as u64 {
+            let mut header = PartialHeader::empty();
+            header.number = BlockNumber(number);
+            header.timestamp = number * 15;
+            header.difficulty = match headers.last() {
+                Some(parent) => {
+                    header.parent_hash = parent.hash();
+                    canonical_difficulty(
+                        header.number,
+                        header.timestamp,
+                        parent.difficulty(),
+                        parent.timestamp(),
+                        false,
+                        false,
+                        false,
+                        Some(BlockDifficultyBombData {
+                            delay_to: BlockNumber(0),
+                        }),
+                    )
+                }
+                None => 0x400000000_u64.into(),
+            };
+            headers.push(BlockHeader::from(models::BlockHeader::new(
+                header,
+                EMPTY_LIST_HASH,
+                EMPTY_ROOT,
+            )));
+        }
+        headers
+    }
+
+    fn make_range(headers: &[BlockHeader], start: usize) -> Arc<HeaderSlices> {
+        let header_slices = Arc::new(
+            HeaderSlices::new(
+                std::mem::size_of::<BlockHeader>() * HEADER_SLICE_SIZE,
+                BlockNumber(start as u64),
+                BlockNumber((start + HEADER_SLICE_SIZE) as u64),
+            )
+            .unwrap(),
+        );
+        let slice_lock = header_slices.first_empty_slice().unwrap();
+        let mut slice = slice_lock.write();
+        slice.headers = Some(headers[start..start + HEADER_SLICE_SIZE].to_vec());
+        header_slices.set_slice_status(slice.deref_mut(), HeaderSliceStatus::VerifiedInternally);
+        header_slices
+    }
+
+    fn statuses(ranges: &[Arc<HeaderSlices>]) -> Vec<HeaderSliceStatus> {
+        ranges
+            .iter()
+            .flat_map(|header_slices| header_slices.clone_statuses())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn link_ranges() {
+        let chain_config = ChainsConfig::new().unwrap().get("mainnet").unwrap();
+        let headers = make_chain(HEADER_SLICE_SIZE * 2);
+
+        for is_seam_valid in [true, false] {
+            let mut second_range_headers = headers.clone();
+            if !is_seam_valid {
+                // a header of another chain at the start of the second range
+                let mut header =
+                    PartialHeader::from(second_range_headers[HEADER_SLICE_SIZE].header.clone());
+                header.parent_hash = ethereum_types::H256::repeat_byte(0xaa);
+                second_range_headers[HEADER_SLICE_SIZE] = BlockHeader::from(
+                    models::BlockHeader::new(header, EMPTY_LIST_HASH, EMPTY_ROOT),
+                );
+            }
+
+            let ranges = vec![
+                make_range(&headers, 0),
+                make_range(&second_range_headers, HEADER_SLICE_SIZE),
+            ];
+            let mut stage = VerifyStageLinearLink::new(
+                ranges.clone(),
+                chain_config.clone(),
+                BlockNumber(0),
+                headers[0].hash(),
+            );
+
+            // the second range waits for the first one
+            stage.execute().await.unwrap();
+            assert_eq!(
+                statuses(&ranges),
+                vec![
+                    HeaderSliceStatus::Verified,
+                    HeaderSliceStatus::VerifiedInternally
+                ]
+            );
+
+            stage.execute().await.unwrap();
+            let expected_status = if is_seam_valid {
+                HeaderSliceStatus::Verified
+            } else {
+                HeaderSliceStatus::Invalid
+            };
+            assert_eq!(
+                statuses(&ranges),
+                vec![HeaderSliceStatus::Verified, expected_status]
+            );
+        }
+    }
+}
@@ -1,10 +1,17 @@
 mod downloader_impl;
+pub mod fork_choice;
 mod headers;
 pub mod opts;
 pub mod sentry_status_provider;
 
-pub use headers::downloader::{
-    DownloaderReport as HeaderDownloaderReport, DownloaderRunState as HeaderDownloaderRunState,
+pub use headers::{
+    checkpoints::Checkpoints,
+    downloader::{
+        DownloaderCancelSignal as HeaderDownloaderCancelSignal,
+        DownloaderOptions as HeaderDownloaderOptions, DownloaderReport as HeaderDownloaderReport,
+        DownloaderRunState as HeaderDownloaderRunState,
+    },
+    health::DownloaderHealth,
 };
 
 #[cfg(test)]
@@ -1,3 +1,6 @@
+use super::HeaderDownloaderOptions;
+use crate::sentry::sentry_client::PeerId;
+use std::{collections::HashSet, time::Duration};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -14,12 +17,96 @@ pub struct Opts {
         default_value = "100000"
     )]
     pub headers_batch_size: usize,
+    #[structopt(
+        long = "downloader.headers-window",
+        help = "Only download the headers of this many last preverified blocks, and the ones after them (all if not set)."
+    )]
+    pub headers_window: Option<usize>,
+    #[structopt(
+        long = "downloader.headers-max-in-flight-requests",
+        help = "How many header slices can be requested from the peers simultaneously (unlimited if not set)."
+    )]
+    pub headers_max_in_flight_requests: Option<usize>,
+    #[structopt(
+        long = "downloader.headers-hard-mem-limit",
+        help = "Stop requesting header slices while the downloaded headers take more than this memory in Mb (unlimited if not set)."
+    )]
+    pub headers_hard_mem_limit_mb: Option<u32>,
+    #[structopt(
+        long = "downloader.headers-max-total-retries",
+        help = "Abort the headers download after this many slice request retries in total (unlimited if not set)."
+    )]
+    pub headers_max_total_retries: Option<u64>,
+    #[structopt(
+        long = "downloader.headers-linear-ranges",
+        help = "Split the headers download after the preverified ones into this many ranges downloaded in parallel.",
+        default_value = "1"
+    )]
+    pub headers_linear_ranges: usize,
+    #[structopt(
+        long = "downloader.headers-flush-threshold",
+        help = "Save the verified header slices into the database in batches of this many slices.",
+        default_value = "1"
+    )]
+    pub headers_flush_threshold: usize,
+    #[structopt(
+        long = "downloader.headers-verify-seal",
+        help = "Verify the seals of the headers after the preverified ones with the consensus engine (CPU-heavy)."
+    )]
+    pub headers_verify_seal: bool,
+    #[structopt(
+        long = "downloader.headers-notify-interval",
+        help = "Notify the download stages of the slice status changes at most once per this many milliseconds.",
+        default_value = "100"
+    )]
+    pub headers_notify_interval_ms: u64,
+    #[structopt(
+        long = "downloader.headers-peer-allowlist",
+        help = "Comma-separated IDs of the only peers to download the headers from (any if not set).",
+        use_delimiter = true
+    )]
+    pub headers_peer_allowlist: Vec<PeerId>,
 }
 
 impl Opts {
+    pub fn headers_peer_allowlist(&self) -> Option<HashSet<PeerId>> {
+        if self.headers_peer_allowlist.is_empty() {
+            None
+        } else {
+            Some(self.headers_peer_allowlist.iter().copied().collect())
+        }
+    }
+
+    pub fn headers_notify_interval(&self) -> Duration {
+        Duration::from_millis(self.headers_notify_interval_ms)
+    }
+
     pub fn headers_mem_limit(&self) -> usize {
         byte_unit::n_mib_bytes!(self.headers_mem_limit_mb as u128)
             .try_into()
             .unwrap_or(usize::MAX)
     }
+
+    pub fn headers_downloader_options(&self) -> HeaderDownloaderOptions {
+        HeaderDownloaderOptions {
+            mem_limit: self.headers_mem_limit(),
+            max_in_flight_requests: self.headers_max_in_flight_requests,
+            hard_mem_limit: self.headers_hard_mem_limit(),
+            max_total_retries: self.headers_max_total_retries,
+            linear_ranges_count: self.headers_linear_ranges,
+            flush_threshold: self.headers_flush_threshold,
+            verify_seal: self.headers_verify_seal,
+            notify_interval: self.headers_notify_interval(),
+            window: self.headers_window,
+            max_reorg_depth: None,
+        }
+    }
+
+    pub fn headers_hard_mem_limit(&self) -> Option<usize> {
+        self.headers_hard_mem_limit_mb.map(|limit_mb| {
+            byte_unit::n_mib_bytes!(limit_mb as u128)
+                .try_into()
+                .unwrap_or(usize::MAX)
+        })
+    }
 }
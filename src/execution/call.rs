@@ -0,0 +1,670 @@
+use super::{
+    analysis_cache::AnalysisCache, evm, precompiled::PrecompileRegistry,
+    processor::gas_left_with_refund,
+};
+use crate::{
+    chain::intrinsic_gas::intrinsic_gas, consensus::ValidationError, h256_to_u256, models::*,
+    state::IntraBlockState, State,
+};
+use anyhow::bail;
+use bytes::Bytes;
+use ethereum_types::*;
+use evmodin::{Revision, StatusCode};
+use std::{
+    cmp::{max, min},
+    collections::HashMap,
+    fmt::Display,
+};
+
+/// Replaces the fields of an account before the call. The fields which are not set are left as is.
+#[derive(Clone, Debug, Default)]
+pub struct AccountOverride {
+    pub balance: Option<U256>,
+    pub nonce: Option<u64>,
+    pub code: Option<Bytes>,
+    /// Replaces the whole storage of the account.
+    pub state: Option<HashMap<U256, U256>>,
+    /// Replaces only the given storage slots.
+    pub state_diff: Option<HashMap<U256, U256>>,
+}
+
+pub type StateOverrides = HashMap<Address, AccountOverride>;
+
+/// A message call which isn't signed, like in `eth_call`.
+#[derive(Clone, Debug, Default)]
+pub struct CallMessage {
+    pub from: Address,
+    /// None creates a contract.
+    pub to: Option<Address>,
+    /// The block gas limit if not set.
+    pub gas: Option<u64>,
+    /// If zero, the call is executed as if the base fee was zero too.
+    pub gas_price: U256,
+    pub value: U256,
+    pub input: Bytes,
+    /// The accounts and the storage slots to warm up before the call.
+    pub access_list: AccessList,
+}
+
+impl CallMessage {
+    fn to_message(&self, chain_id: ChainId, nonce: u64, gas_limit: u64) -> Message {
+        let action = self
+            .to
+            .map_or(TransactionAction::Create, TransactionAction::Call);
+        if self.access_list.is_empty() {
+            Message::Legacy {
+                chain_id: Some(chain_id),
+                nonce,
+                gas_price: self.gas_price,
+                gas_limit,
+                action,
+                value: self.value,
+                input: self.input.clone(),
+            }
+        } else {
+            Message::EIP2930 {
+                chain_id,
+                nonce,
+                gas_price: self.gas_price,
+                gas_limit,
+                action,
+                value: self.value,
+                input: self.input.clone(),
+                access_list: self.access_list.clone(),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallOutput {
+    pub status_code: StatusCode,
+    pub output_data: Bytes,
+    pub gas_used: u64,
+}
+
+async fn apply_overrides<S: State>(
+    state: &mut IntraBlockState<'_, S>,
+    overrides: &StateOverrides,
+) -> anyhow::Result<()> {
+    for (&address, account_override) in overrides {
+        if let Some(storage) = &account_override.state {
+            if account_override.state_diff.is_some() {
+                bail!(
+                    "account {:?} has both the state and the state diff overridden",
+                    address
+                );
+            }
+
+            // a new incarnation starts with an empty storage, the rest of the account is kept
+            let nonce = state.get_nonce(address).await?;
+            let code = state.get_code(address).await?;
+            state.create_contract(address).await?;
+            state.set_nonce(address, nonce).await?;
+            if let Some(code) = code {
+                state.set_code(address, code).await?;
+            }
+
+            for (&location, &value) in storage {
+                state.set_storage(address, location, value).await?;
+            }
+        }
+
+        if let Some(balance) = account_override.balance {
+            state.set_balance(address, balance).await?;
+        }
+        if let Some(nonce) = account_override.nonce {
+            state.set_nonce(address, nonce).await?;
+        }
+        // the code is looked up and analyzed by its hash, so the previous code of the account is not used
+        if let Some(code) = &account_override.code {
+            state.set_code(address, code.clone()).await?;
+        }
+        if let Some(storage_diff) = &account_override.state_diff {
+            for (&location, &value) in storage_diff {
+                state.set_storage(address, location, value).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Executes the message on top of the state in the context of the block,
+/// with the overrides applied to the state first.
+/// Nothing is written into the state, and no fees are charged.
+pub async fn call<S: State>(
+    state: &mut S,
+    chain_spec: &ChainSpec,
+    header: &PartialHeader,
+    message: &CallMessage,
+    overrides: &StateOverrides,
+) -> anyhow::Result<CallOutput> {
+    let block_spec = chain_spec.collect_block_spec(header.number);
+    let rev = block_spec.revision;
+
+    let header = if message.gas_price.is_zero() {
+        PartialHeader {
+            base_fee_per_gas: header.base_fee_per_gas.map(|_| U256::zero()),
+            ..header.clone()
+        }
+    } else {
+        if let Some(base_fee_per_gas) = header.base_fee_per_gas {
+            if message.gas_price < base_fee_per_gas {
+                return Err(ValidationError::MaxFeeLessThanBase.into());
+            }
+        }
+        header.clone()
+    };
+
+    let mut state = IntraBlockState::new(state);
+    apply_overrides(&mut state, overrides).await?;
+    state.clear_journal_and_substate();
+
+    let nonce = state.get_nonce(message.from).await?;
+    let txn = MessageWithSender {
+        message: message.to_message(
+            block_spec.params.chain_id,
+            nonce,
+            message.gas.unwrap_or(header.gas_limit),
+        ),
+        sender: message.from,
+    };
+
+    state.access_account(txn.sender);
+    if let TransactionAction::Call(to) = txn.action() {
+        state.access_account(to);
+        // EVM itself increments the nonce for contract creation
+        state.set_nonce(txn.sender, nonce + 1).await?;
+    }
+
+    for entry in &*txn.access_list() {
+        state.access_account(entry.address);
+        for &key in &entry.slots {
+            state.access_storage(entry.address, h256_to_u256(key));
+        }
+    }
+
+    let g0 = intrinsic_gas(&txn, rev >= Revision::Homestead, rev >= Revision::Istanbul);
+    let gas = u128::from(txn.gas_limit())
+        .checked_sub(g0)
+        .ok_or(ValidationError::IntrinsicGas)? as u64;
+
+    let vm_res = evm::execute(
+        &mut state,
+        &mut AnalysisCache::default(),
+        &header,
+        &block_spec,
+        &PrecompileRegistry::new(rev),
+        &txn,
+        gas,
+    )
+    .await?;
+
+    let gas_left =
+        gas_left_with_refund(&state, &block_spec, txn.gas_limit(), vm_res.gas_left as u64);
+
+    Ok(CallOutput {
+        status_code: vm_res.status_code,
+        output_data: vm_res.output_data,
+        gas_used: txn.gas_limit() - gas_left,
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EstimateGasConfig {
+    /// Maximum number of the calls in the binary search.
+    pub max_iterations: usize,
+    /// The search stops once the estimate is within this much gas above the minimum.
+    pub tolerance: u64,
+}
+
+impl Default for EstimateGasConfig {
+    fn default() -> Self {
+        Self {
+            max_iterations: 64,
+            tolerance: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EstimateGasError {
+    /// The call reverts even with the maximum gas, with this output.
+    Reverted(Bytes),
+    /// The call fails even with the maximum gas.
+    Failed(StatusCode),
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for EstimateGasError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Other(e)
+    }
+}
+
+impl Display for EstimateGasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for EstimateGasError {}
+
+/// Finds the minimal gas limit the call succeeds with, by a binary search
+/// between the intrinsic gas and the gas of the message, or the block gas limit if not set.
+/// Every attempt executes the call against the same state, since nothing is written into it.
+pub async fn estimate_gas<S: State>(
+    state: &mut S,
+    chain_spec: &ChainSpec,
+    header: &PartialHeader,
+    message: &CallMessage,
+    overrides: &StateOverrides,
+    config: EstimateGasConfig,
+) -> Result<u64, EstimateGasError> {
+    let rev = chain_spec.collect_block_spec(header.number).revision;
+
+    let with_gas = |gas: u64| CallMessage {
+        gas: Some(gas),
+        ..message.clone()
+    };
+
+    let mut hi = min(message.gas.unwrap_or(header.gas_limit), header.gas_limit);
+
+    // the attempt with the most gas tells whether the call can succeed at all
+    let output = call(state, chain_spec, header, &with_gas(hi), overrides).await?;
+    match output.status_code {
+        StatusCode::Success => {}
+        StatusCode::Revert => return Err(EstimateGasError::Reverted(output.output_data)),
+        status_code => return Err(EstimateGasError::Failed(status_code)),
+    }
+
+    let intrinsic_gas = intrinsic_gas(
+        &message.to_message(chain_spec.params.chain_id, 0, 0),
+        rev >= Revision::Homestead,
+        rev >= Revision::Istanbul,
+    );
+    // Due to the refunds and the EIP-150 rule of all but one 64th,
+    // the call may need more gas than it ends up using, but never less.
+    let mut lo = max(
+        intrinsic_gas.saturating_sub(1) as u64,
+        output.gas_used.saturating_sub(1),
+    );
+
+    // the call succeeds with hi and fails with lo
+    let mut iterations = 0;
+    while hi - lo > max(1, config.tolerance) && iterations < config.max_iterations {
+        let mid = lo + (hi - lo) / 2;
+        let output = call(state, chain_spec, header, &with_gas(mid), overrides).await?;
+        if output.status_code == StatusCode::Success {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+        iterations += 1;
+    }
+
+    Ok(hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::keccak256, res::chainspec::MAINNET, util::test_util::run_test, InMemoryState,
+    };
+    use hex_literal::hex;
+
+    const CONTRACT: Address = H160(hex!("00000000000000000000000000000000000000c0"));
+    const CALLER: Address = H160(hex!("00000000000000000000000000000000000000ca"));
+
+    fn header() -> PartialHeader {
+        PartialHeader {
+            number: 13_000_000.into(),
+            timestamp: 1_628_000_000,
+            gas_limit: 30_000_000,
+            base_fee_per_gas: Some(U256::from(10_000_000_000_u64)),
+            ..PartialHeader::empty()
+        }
+    }
+
+    fn call_message() -> CallMessage {
+        CallMessage {
+            from: CALLER,
+            to: Some(CONTRACT),
+            ..Default::default()
+        }
+    }
+
+    fn output_word(output: &CallOutput, index: usize) -> U256 {
+        U256::from_big_endian(&output.output_data[index * 32..(index + 1) * 32])
+    }
+
+    async fn deploy(db: &mut InMemoryState, code: Bytes, storage: &[(u64, u64)]) {
+        db.begin_block(BlockNumber(0));
+        let code_hash = keccak256(&code);
+        db.update_account(
+            CONTRACT,
+            None,
+            Some(Account {
+                code_hash,
+                ..Default::default()
+            }),
+        );
+        db.update_code(code_hash, code).await.unwrap();
+        for &(location, value) in storage {
+            db.update_storage(CONTRACT, location.into(), U256::zero(), value.into())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn code_override() {
+        run_test(async {
+            let mut db = InMemoryState::default();
+            // returns 1
+            let code = Bytes::from(hex!("600160005260206000f3").to_vec());
+            deploy(&mut db, code.clone(), &[]).await;
+
+            let output = call(
+                &mut db,
+                &MAINNET,
+                &header(),
+                &call_message(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(output.status_code, StatusCode::Success);
+            assert_eq!(output_word(&output, 0), 1.into());
+            assert!(output.gas_used > 21_000);
+
+            // returns 2
+            let overrides = [(
+                CONTRACT,
+                AccountOverride {
+                    code: Some(hex!("600260005260206000f3").to_vec().into()),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect();
+            let output = call(&mut db, &MAINNET, &header(), &call_message(), &overrides)
+                .await
+                .unwrap();
+            assert_eq!(output_word(&output, 0), 2.into());
+
+            // nothing is persisted
+            assert_eq!(
+                db.read_account(CONTRACT).await.unwrap().unwrap().code_hash,
+                keccak256(&code)
+            );
+            assert_eq!(db.read_account(CALLER).await.unwrap(), None);
+        })
+    }
+
+    async fn call_with_override(
+        db: &mut InMemoryState,
+        account_override: AccountOverride,
+    ) -> anyhow::Result<CallOutput> {
+        let overrides = [(CONTRACT, account_override)].into_iter().collect();
+        call(db, &MAINNET, &header(), &call_message(), &overrides).await
+    }
+
+    #[test]
+    fn storage_override() {
+        run_test(async {
+            let mut db = InMemoryState::default();
+            // returns storage[0] and storage[1]
+            let code = hex!("60005460005260015460205260406000f3").to_vec().into();
+            deploy(&mut db, code, &[(0, 5), (1, 6)]).await;
+
+            let output = call_with_override(
+                &mut db,
+                AccountOverride {
+                    state_diff: Some([(0.into(), 7.into())].into_iter().collect()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(output_word(&output, 0), 7.into());
+            assert_eq!(output_word(&output, 1), 6.into());
+
+            // the code survives the storage replacement
+            let output = call_with_override(
+                &mut db,
+                AccountOverride {
+                    state: Some([(1.into(), 3.into())].into_iter().collect()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(output_word(&output, 0), 0.into());
+            assert_eq!(output_word(&output, 1), 3.into());
+
+            assert!(call_with_override(
+                &mut db,
+                AccountOverride {
+                    state: Some(Default::default()),
+                    state_diff: Some(Default::default()),
+                    ..Default::default()
+                }
+            )
+            .await
+            .is_err());
+        })
+    }
+
+    #[test]
+    fn block_context() {
+        run_test(async {
+            let mut db = InMemoryState::default();
+            // returns NUMBER, TIMESTAMP and BASEFEE
+            let code = hex!("43600052426020524860405260606000f3").to_vec().into();
+            deploy(&mut db, code, &[]).await;
+
+            let header = PartialHeader {
+                number: 13_500_000.into(),
+                timestamp: 1_640_000_000,
+                base_fee_per_gas: Some(U256::from(50_000_000_000_u64)),
+                ..header()
+            };
+
+            // the caller can't pay the value without the balance override
+            let message = CallMessage {
+                gas_price: U256::from(60_000_000_000_u64),
+                value: 1000.into(),
+                ..call_message()
+            };
+            let output = call(&mut db, &MAINNET, &header, &message, &Default::default())
+                .await
+                .unwrap();
+            assert_eq!(output.status_code, StatusCode::InsufficientBalance);
+
+            let overrides = [(
+                CALLER,
+                AccountOverride {
+                    balance: Some(1000.into()),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect();
+            let output = call(&mut db, &MAINNET, &header, &message, &overrides)
+                .await
+                .unwrap();
+            assert_eq!(output.status_code, StatusCode::Success);
+            assert_eq!(output_word(&output, 0), 13_500_000.into());
+            assert_eq!(output_word(&output, 1), 1_640_000_000.into());
+            assert_eq!(output_word(&output, 2), 50_000_000_000_u64.into());
+
+            // the base fee is zero for the calls without the gas price
+            let output = call(
+                &mut db,
+                &MAINNET,
+                &header,
+                &call_message(),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(output_word(&output, 2), 0.into());
+
+            assert!(call(
+                &mut db,
+                &MAINNET,
+                &header,
+                &CallMessage {
+                    gas_price: 1.into(),
+                    ..call_message()
+                },
+                &Default::default()
+            )
+            .await
+            .is_err());
+        })
+    }
+
+    async fn estimate(
+        db: &mut InMemoryState,
+        message: &CallMessage,
+        config: EstimateGasConfig,
+    ) -> Result<u64, EstimateGasError> {
+        estimate_gas(
+            db,
+            &MAINNET,
+            &header(),
+            message,
+            &Default::default(),
+            config,
+        )
+        .await
+    }
+
+    #[test]
+    fn estimate_transfer() {
+        run_test(async {
+            let mut db = InMemoryState::default();
+            let message = CallMessage {
+                to: Some(H160(hex!("00000000000000000000000000000000000000ee"))),
+                ..call_message()
+            };
+            assert_eq!(
+                estimate(&mut db, &message, Default::default())
+                    .await
+                    .unwrap(),
+                21_000
+            );
+        })
+    }
+
+    #[test]
+    fn estimate_with_refund() {
+        run_test(async {
+            let mut db = InMemoryState::default();
+            // clears storage[0], which needs more gas than it uses after the refund
+            let code = hex!("600060005500").to_vec().into();
+            deploy(&mut db, code, &[(0, 1)]).await;
+
+            let gas_used = call(
+                &mut db,
+                &MAINNET,
+                &header(),
+                &call_message(),
+                &Default::default(),
+            )
+            .await
+            .unwrap()
+            .gas_used;
+
+            let estimate = estimate(&mut db, &call_message(), Default::default())
+                .await
+                .unwrap();
+            assert!(estimate > gas_used);
+
+            for (gas, success) in [(estimate, true), (estimate - 1, false)] {
+                let message = CallMessage {
+                    gas: Some(gas),
+                    ..call_message()
+                };
+                let output = call(&mut db, &MAINNET, &header(), &message, &Default::default())
+                    .await
+                    .unwrap();
+                assert_eq!(output.status_code == StatusCode::Success, success);
+            }
+
+            // a rough estimate takes fewer attempts
+            let rough = estimate(
+                &mut db,
+                &call_message(),
+                EstimateGasConfig {
+                    tolerance: 5000,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert!(rough >= estimate && rough - estimate <= 5000);
+
+            let unsearched = estimate(
+                &mut db,
+                &call_message(),
+                EstimateGasConfig {
+                    max_iterations: 0,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(unsearched, header().gas_limit);
+        })
+    }
+
+    #[test]
+    fn estimate_always_reverts() {
+        run_test(async {
+            let mut db = InMemoryState::default();
+            // reverts with 42
+            let code = hex!("602a60005260206000fd").to_vec().into();
+            deploy(&mut db, code, &[]).await;
+
+            match estimate(&mut db, &call_message(), Default::default()).await {
+                Err(EstimateGasError::Reverted(output_data)) => {
+                    assert_eq!(U256::from_big_endian(&output_data), 42.into())
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn estimate_access_list() {
+        run_test(async {
+            let mut db = InMemoryState::default();
+            // returns storage[0] and storage[1]
+            let code = hex!("60005460005260015460205260406000f3").to_vec().into();
+            deploy(&mut db, code, &[(0, 5), (1, 6)]).await;
+
+            let without_list = estimate(&mut db, &call_message(), Default::default())
+                .await
+                .unwrap();
+
+            let message = CallMessage {
+                access_list: vec![AccessListItem {
+                    address: CONTRACT,
+                    slots: vec![H256::zero()],
+                }],
+                ..call_message()
+            };
+            let with_list = estimate(&mut db, &message, Default::default())
+                .await
+                .unwrap();
+
+            // the address and the slot cost 2400 + 1900 upfront, and save 2000 on a cold SLOAD
+            assert_eq!(with_list, without_list + 2300);
+        })
+    }
+}
@@ -1,4 +1,9 @@
-use super::{address::*, analysis_cache::AnalysisCache, precompiled};
+use super::{
+    address::*,
+    analysis_cache::AnalysisCache,
+    precompiled::PrecompileRegistry,
+    tracer::{CallType, NoopTracer, Tracer},
+};
 use crate::{
     chain::protocol_param::{fee, param},
     h256_to_u256,
@@ -26,14 +31,16 @@ pub struct CallResult {
     pub output_data: Bytes,
 }
 
-struct Evm<'r, 'state, 'analysis, 'h, 'c, 't, B>
+struct Evm<'r, 'state, 'tracer, 'analysis, 'h, 'c, 'p, 't, B>
 where
     B: State,
 {
     state: &'state mut IntraBlockState<'r, B>,
+    tracer: &'tracer mut dyn Tracer,
     analysis_cache: &'analysis mut AnalysisCache,
     header: &'h PartialHeader,
     block_spec: &'c BlockExecutionSpec,
+    precompiles: &'p PrecompileRegistry,
     txn: &'t MessageWithSender,
     beneficiary: Address,
 }
@@ -43,14 +50,42 @@ pub async fn execute<B: State>(
     analysis_cache: &mut AnalysisCache,
     header: &PartialHeader,
     block_spec: &BlockExecutionSpec,
+    precompiles: &PrecompileRegistry,
+    txn: &MessageWithSender,
+    gas: u64,
+) -> anyhow::Result<CallResult> {
+    execute_with_tracer(
+        state,
+        analysis_cache,
+        header,
+        block_spec,
+        precompiles,
+        txn,
+        gas,
+        &mut NoopTracer,
+    )
+    .await
+}
+
+/// Same as [`execute`], reporting every call and contract creation to the tracer.
+#[allow(clippy::too_many_arguments)]
+pub async fn execute_with_tracer<B: State>(
+    state: &mut IntraBlockState<'_, B>,
+    analysis_cache: &mut AnalysisCache,
+    header: &PartialHeader,
+    block_spec: &BlockExecutionSpec,
+    precompiles: &PrecompileRegistry,
     txn: &MessageWithSender,
     gas: u64,
+    tracer: &mut dyn Tracer,
 ) -> anyhow::Result<CallResult> {
     let mut evm = Evm {
         header,
         analysis_cache,
         state,
+        tracer,
         block_spec,
+        precompiles,
         txn,
         beneficiary: header.beneficiary,
     };
@@ -87,12 +122,48 @@ pub async fn execute<B: State>(
     })
 }
 
-impl<'r, 'state, 'analysis, 'h, 'c, 't, B> Evm<'r, 'state, 'analysis, 'h, 'c, 't, B>
+impl<'r, 'state, 'tracer, 'analysis, 'h, 'c, 'p, 't, B>
+    Evm<'r, 'state, 'tracer, 'analysis, 'h, 'c, 'p, 't, B>
 where
     B: State,
 {
+    fn contract_address(message: &CreateMessage, nonce: u64) -> Address {
+        if let Some(salt) = message.salt {
+            create2_address(
+                message.sender,
+                salt,
+                H256::from_slice(&Keccak256::digest(&message.initcode[..])[..]),
+            )
+        } else {
+            create_address(message.sender, nonce)
+        }
+    }
+
     #[async_recursion]
     async fn create(&mut self, message: CreateMessage) -> anyhow::Result<Output> {
+        let nonce = self.state.get_nonce(message.sender).await?;
+        self.tracer.capture_start(
+            if message.salt.is_some() {
+                CallType::Create2
+            } else {
+                CallType::Create
+            },
+            message.sender,
+            Self::contract_address(&message, nonce),
+            message.endowment,
+            &message.initcode,
+            message.gas as u64,
+        );
+
+        let res = self.create_inner(message).await?;
+
+        self.tracer
+            .capture_end(res.status_code, res.gas_left, &res.output_data);
+
+        Ok(res)
+    }
+
+    async fn create_inner(&mut self, message: CreateMessage) -> anyhow::Result<Output> {
         let mut res = Output {
             status_code: StatusCode::Success,
             gas_left: message.gas,
@@ -109,17 +180,7 @@ where
         let nonce = self.state.get_nonce(message.sender).await?;
         self.state.set_nonce(message.sender, nonce + 1).await?;
 
-        let contract_addr = {
-            if let Some(salt) = message.salt {
-                create2_address(
-                    message.sender,
-                    salt,
-                    H256::from_slice(&Keccak256::digest(&message.initcode[..])[..]),
-                )
-            } else {
-                create_address(message.sender, nonce)
-            }
-        };
+        let contract_addr = Self::contract_address(&message, nonce);
 
         self.state.access_account(contract_addr);
 
@@ -200,6 +261,30 @@ where
 
     #[async_recursion]
     async fn call(&mut self, message: EvmMessage) -> anyhow::Result<Output> {
+        let (call_type, to) = match message.kind {
+            CallKind::Call if message.is_static => (CallType::StaticCall, message.recipient),
+            CallKind::Call => (CallType::Call, message.recipient),
+            CallKind::DelegateCall => (CallType::DelegateCall, message.code_address),
+            _ => (CallType::CallCode, message.code_address),
+        };
+        self.tracer.capture_start(
+            call_type,
+            message.sender,
+            to,
+            message.value,
+            &message.input_data,
+            message.gas as u64,
+        );
+
+        let res = self.call_inner(message).await?;
+
+        self.tracer
+            .capture_end(res.status_code, res.gas_left, &res.output_data);
+
+        Ok(res)
+    }
+
+    async fn call_inner(&mut self, message: EvmMessage) -> anyhow::Result<Output> {
         let mut res = Output {
             status_code: StatusCode::Success,
             gas_left: message.gas,
@@ -215,12 +300,12 @@ where
             return Ok(res);
         }
 
-        let precompiled = self.is_precompiled(message.code_address);
+        let precompiled = self.precompiles.get(message.code_address).copied();
 
         // https://eips.ethereum.org/EIPS/eip-161
         if value.is_zero()
             && self.block_spec.revision >= Revision::Spurious
-            && !precompiled
+            && precompiled.is_none()
             && !self.state.exists(message.code_address).await?
         {
             return Ok(res);
@@ -241,9 +326,7 @@ where
             }
         }
 
-        if precompiled {
-            let num = message.code_address.0[ADDRESS_LENGTH - 1] as usize;
-            let contract = &precompiled::CONTRACTS[num - 1];
+        if let Some(contract) = precompiled {
             let input = message.input_data;
             if let Some(gas) = (contract.gas)(input.clone(), self.block_spec.revision)
                 .and_then(|g| i64::try_from(g).ok())
@@ -308,12 +391,21 @@ where
         };
 
         let mut interrupt = analysis
-            .execute_resumable(false, msg, self.block_spec.revision)
+            .execute_resumable(
+                self.tracer.traces_instructions(),
+                msg,
+                self.block_spec.revision,
+            )
             .resume(());
 
         let output = loop {
             interrupt = match interrupt {
-                InterruptVariant::InstructionStart(_) => unreachable!("tracing is disabled"),
+                InterruptVariant::InstructionStart(i) => {
+                    let data = i.data();
+                    self.tracer
+                        .capture_instruction(data.pc, data.opcode, data.state.gas_left());
+                    i.resume(())
+                }
                 InterruptVariant::AccountExists(i) => {
                     let address = i.data().address;
                     let exists = if self.block_spec.revision >= Revision::Spurious {
@@ -558,7 +650,7 @@ where
                 InterruptVariant::AccessAccount(i) => {
                     let address = i.data().address;
 
-                    let status = if self.is_precompiled(address) {
+                    let status = if self.precompiles.contains(address) {
                         AccessStatus::Warm
                     } else {
                         self.state.access_account(address)
@@ -587,30 +679,6 @@ where
 
         Ok(output)
     }
-
-    fn number_of_precompiles(&self) -> u8 {
-        match self.block_spec.revision {
-            Revision::Frontier | Revision::Homestead | Revision::Tangerine | Revision::Spurious => {
-                precompiled::NUM_OF_FRONTIER_CONTRACTS as u8
-            }
-            Revision::Byzantium | Revision::Constantinople | Revision::Petersburg => {
-                precompiled::NUM_OF_BYZANTIUM_CONTRACTS as u8
-            }
-            Revision::Istanbul | Revision::Berlin | Revision::London | Revision::Shanghai => {
-                precompiled::NUM_OF_ISTANBUL_CONTRACTS as u8
-            }
-        }
-    }
-
-    fn is_precompiled(&self, contract: Address) -> bool {
-        if contract.is_zero() {
-            false
-        } else {
-            let mut max_precompiled = Address::zero();
-            max_precompiled.0[ADDRESS_LENGTH - 1] = self.number_of_precompiles() as u8;
-            contract <= max_precompiled
-        }
-    }
 }
 
 #[cfg(test)]
@@ -626,11 +694,13 @@ mod tests {
         txn: &MessageWithSender,
         gas: u64,
     ) -> CallResult {
+        let block_spec = MAINNET.collect_block_spec(header.number);
         super::execute(
             state,
             &mut AnalysisCache::default(),
             header,
-            &MAINNET.collect_block_spec(header.number),
+            &block_spec,
+            &PrecompileRegistry::new(block_spec.revision),
             txn,
             gas,
         )
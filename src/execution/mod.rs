@@ -3,9 +3,11 @@ use crate::{consensus, crypto::*, models::*, State};
 
 pub mod address;
 pub mod analysis_cache;
+pub mod call;
 pub mod evm;
 pub mod precompiled;
 pub mod processor;
+pub mod tracer;
 
 pub async fn execute_block<S: State>(
     state: &mut S,
@@ -1,4 +1,5 @@
 use crate::{chain::protocol_param::param, crypto::*, util::*};
+use anyhow::bail;
 use arrayref::array_ref;
 use bytes::{Buf, Bytes};
 use ethereum_types::*;
@@ -14,6 +15,7 @@ use sha2::*;
 use sha3::*;
 use std::{
     cmp::min,
+    collections::HashMap,
     convert::TryFrom,
     io::{repeat, Read},
     mem::size_of,
@@ -23,6 +25,7 @@ use substrate_bn::*;
 pub type GasFunction = fn(Bytes, Revision) -> Option<u64>;
 pub type RunFunction = fn(Bytes) -> Option<Bytes>;
 
+#[derive(Clone, Copy)]
 pub struct Contract {
     pub gas: GasFunction,
     pub run: RunFunction,
@@ -71,6 +74,72 @@ pub const NUM_OF_FRONTIER_CONTRACTS: usize = 4;
 pub const NUM_OF_BYZANTIUM_CONTRACTS: usize = 8;
 pub const NUM_OF_ISTANBUL_CONTRACTS: usize = 9;
 
+/// Number of standard precompiles active at the given revision.
+pub fn num_of_contracts(revision: Revision) -> usize {
+    match revision {
+        Revision::Frontier | Revision::Homestead | Revision::Tangerine | Revision::Spurious => {
+            NUM_OF_FRONTIER_CONTRACTS
+        }
+        Revision::Byzantium | Revision::Constantinople | Revision::Petersburg => {
+            NUM_OF_BYZANTIUM_CONTRACTS
+        }
+        Revision::Istanbul | Revision::Berlin | Revision::London | Revision::Shanghai => {
+            NUM_OF_ISTANBUL_CONTRACTS
+        }
+    }
+}
+
+/// Whether the address belongs to one of the standard precompiles, active or not.
+pub fn is_standard(address: Address) -> bool {
+    !address.is_zero() && address <= Address::from_low_u64_be(NUM_OF_ISTANBUL_CONTRACTS as u64)
+}
+
+/// Precompiled contracts available to the EVM, keyed by address.
+#[derive(Clone)]
+pub struct PrecompileRegistry {
+    contracts: HashMap<Address, Contract>,
+}
+
+impl PrecompileRegistry {
+    /// Registry seeded with the standard precompiles active at the given revision.
+    pub fn new(revision: Revision) -> Self {
+        Self {
+            contracts: CONTRACTS
+                .iter()
+                .take(num_of_contracts(revision))
+                .enumerate()
+                .map(|(i, contract)| (Address::from_low_u64_be(i as u64 + 1), *contract))
+                .collect(),
+        }
+    }
+
+    /// Register a custom precompile.
+    /// Addresses of the standard precompiles are reserved, see `register_shadowing`.
+    pub fn register(&mut self, address: Address, contract: Contract) -> anyhow::Result<()> {
+        if is_standard(address) {
+            bail!(
+                "{:?} is reserved for a standard precompile, use register_shadowing to replace it",
+                address
+            );
+        }
+        self.contracts.insert(address, contract);
+        Ok(())
+    }
+
+    /// Register a precompile, replacing the standard one at the same address if any.
+    pub fn register_shadowing(&mut self, address: Address, contract: Contract) {
+        self.contracts.insert(address, contract);
+    }
+
+    pub fn get(&self, address: Address) -> Option<&Contract> {
+        self.contracts.get(&address)
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        self.contracts.contains_key(&address)
+    }
+}
+
 fn ecrecover_gas(_: Bytes, _: Revision) -> Option<u64> {
     Some(3_000)
 }
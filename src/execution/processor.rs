@@ -5,7 +5,11 @@ use crate::{
         protocol_param::{fee, param},
     },
     consensus::*,
-    execution::evm,
+    execution::{
+        evm,
+        precompiled::PrecompileRegistry,
+        tracer::{NoopTracer, Tracer},
+    },
     h256_to_u256,
     models::*,
     state::IntraBlockState,
@@ -27,6 +31,7 @@ where
     header: &'h PartialHeader,
     block: &'b BlockBodyWithSenders,
     block_spec: &'c BlockExecutionSpec,
+    precompiles: PrecompileRegistry,
     cumulative_gas_used: u64,
 }
 
@@ -49,10 +54,17 @@ where
             header,
             block,
             block_spec,
+            precompiles: PrecompileRegistry::new(block_spec.revision),
             cumulative_gas_used: 0,
         }
     }
 
+    /// Precompiles available to the executed transactions,
+    /// seeded with the standard ones active in the block.
+    pub fn precompiles_mut(&mut self) -> &mut PrecompileRegistry {
+        &mut self.precompiles
+    }
+
     fn available_gas(&self) -> u64 {
         self.header.gas_limit - self.cumulative_gas_used
     }
@@ -69,6 +81,7 @@ where
         pre_validate_transaction(
             tx,
             self.block_spec.params.chain_id,
+            self.block_spec.revision >= Revision::Spurious,
             self.header.base_fee_per_gas,
         )
         .expect("Tx must have been prevalidated");
@@ -117,6 +130,14 @@ where
     }
 
     async fn execute_transaction(&mut self, txn: &MessageWithSender) -> anyhow::Result<Receipt> {
+        self.execute_transaction_traced(txn, &mut NoopTracer).await
+    }
+
+    async fn execute_transaction_traced(
+        &mut self,
+        txn: &MessageWithSender,
+        tracer: &mut dyn Tracer,
+    ) -> anyhow::Result<Receipt> {
         let rev = self.block_spec.revision;
 
         self.state.clear_journal_and_substate();
@@ -150,17 +171,27 @@ where
             .checked_sub(g0)
             .ok_or(ValidationError::IntrinsicGas)? as u64;
 
-        let vm_res = evm::execute(
+        let vm_res = evm::execute_with_tracer(
             &mut self.state,
             self.analysis_cache,
             self.header,
             self.block_spec,
+            &self.precompiles,
             txn,
             gas,
+            tracer,
         )
         .await?;
 
-        let gas_used = txn.gas_limit() - self.refund_gas(txn, vm_res.gas_left as u64).await?;
+        let gas_used = txn.gas_limit()
+            - refund_gas(
+                &mut self.state,
+                self.header,
+                self.block_spec,
+                txn,
+                vm_res.gas_left as u64,
+            )
+            .await?;
 
         // award the miner
         let priority_fee_per_gas = txn.priority_fee_per_gas(base_fee_per_gas);
@@ -190,19 +221,40 @@ where
     }
 
     pub async fn execute_block_no_post_validation(&mut self) -> anyhow::Result<Vec<Receipt>> {
+        self.execute_block_traced(&mut NoopTracer).await
+    }
+
+    /// Same as `execute_block_no_post_validation`, reporting the calls of all the transactions
+    /// to the tracer, one transaction after another.
+    pub async fn execute_block_traced(
+        &mut self,
+        tracer: &mut dyn Tracer,
+    ) -> anyhow::Result<Vec<Receipt>> {
         let mut receipts = Vec::with_capacity(self.block.transactions.len());
 
-        for (&address, &balance) in &self.block_spec.balance_changes {
-            self.state.set_balance(address, balance).await?;
-        }
+        self.apply_balance_changes().await?;
 
         for (i, txn) in self.block.transactions.iter().enumerate() {
             self.validate_transaction(txn)
                 .await
                 .with_context(|| format!("Failed to validate tx #{}", i))?;
-            receipts.push(self.execute_transaction(txn).await?);
+            receipts.push(self.execute_transaction_traced(txn, tracer).await?);
         }
 
+        self.finalize_block().await?;
+
+        Ok(receipts)
+    }
+
+    async fn apply_balance_changes(&mut self) -> anyhow::Result<()> {
+        for (&address, &balance) in &self.block_spec.balance_changes {
+            self.state.set_balance(address, balance).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finalize_block(&mut self) -> anyhow::Result<()> {
         for change in self
             .engine
             .finalize(self.header, &self.block.ommers, self.block_spec.revision)
@@ -215,10 +267,52 @@ where
             }
         }
 
-        Ok(receipts)
+        Ok(())
+    }
+
+    /// Fast path for a block without transactions: only the balance changes and the rewards
+    /// are applied, leaving the same state as the general path.
+    async fn execute_and_write_empty_block(mut self) -> anyhow::Result<Vec<Receipt>> {
+        self.apply_balance_changes().await?;
+        self.finalize_block().await?;
+
+        if self.header.gas_used != 0 {
+            return Err(ValidationError::WrongBlockGas {
+                expected: self.header.gas_used,
+                got: 0,
+                transactions: vec![],
+            }
+            .into());
+        }
+
+        if self.block_spec.revision >= Revision::Byzantium
+            && self.header.receipts_root != EMPTY_ROOT
+        {
+            return Err(ValidationError::WrongReceiptsRoot {
+                expected: EMPTY_ROOT,
+                got: self.header.receipts_root,
+            }
+            .into());
+        }
+
+        if self.header.logs_bloom != Bloom::zero() {
+            return Err(ValidationError::WrongLogsBloom {
+                expected: Bloom::zero(),
+                got: self.header.logs_bloom,
+            }
+            .into());
+        }
+
+        self.state.write_to_db(self.header.number).await?;
+
+        Ok(vec![])
     }
 
     pub async fn execute_and_write_block(mut self) -> anyhow::Result<Vec<Receipt>> {
+        if self.block.transactions.is_empty() {
+            return self.execute_and_write_empty_block().await;
+        }
+
         let receipts = self.execute_block_no_post_validation().await?;
 
         let gas_used = receipts.last().map(|r| r.cumulative_gas_used).unwrap_or(0);
@@ -273,46 +367,118 @@ where
 
         Ok(receipts)
     }
+}
 
-    async fn refund_gas(
-        &mut self,
-        txn: &MessageWithSender,
-        mut gas_left: u64,
-    ) -> anyhow::Result<u64> {
-        let mut refund = self.state.get_refund();
-        if self.block_spec.revision < Revision::London {
-            refund += fee::R_SELF_DESTRUCT * self.state.number_of_self_destructs() as u64;
-        }
-        let max_refund_quotient = if self.block_spec.revision >= Revision::London {
-            param::MAX_REFUND_QUOTIENT_LONDON
-        } else {
-            param::MAX_REFUND_QUOTIENT_FRONTIER
-        };
-        let max_refund = (txn.gas_limit() - gas_left) / max_refund_quotient;
-        refund = min(refund, max_refund);
-        gas_left += refund;
+/// The gas left after the execution with the refund added.
+pub(crate) fn gas_left_with_refund<S: State>(
+    state: &IntraBlockState<'_, S>,
+    block_spec: &BlockExecutionSpec,
+    gas_limit: u64,
+    gas_left: u64,
+) -> u64 {
+    let mut refund = state.get_refund();
+    // https://eips.ethereum.org/EIPS/eip-3529 removes the SELFDESTRUCT refund
+    if block_spec.revision < Revision::London {
+        refund += fee::R_SELF_DESTRUCT * state.number_of_self_destructs() as u64;
+    }
+    // and caps the refund at gas_used / 5 instead of gas_used / 2
+    let max_refund_quotient = if block_spec.revision >= Revision::London {
+        param::MAX_REFUND_QUOTIENT_LONDON
+    } else {
+        param::MAX_REFUND_QUOTIENT_FRONTIER
+    };
+    let max_refund = (gas_limit - gas_left) / max_refund_quotient;
+    gas_left + min(refund, max_refund)
+}
 
-        let base_fee_per_gas = self.header.base_fee_per_gas.unwrap_or_else(U256::zero);
-        let effective_gas_price = txn.effective_gas_price(base_fee_per_gas);
-        self.state
-            .add_to_balance(txn.sender, U256::from(gas_left) * effective_gas_price)
-            .await?;
+pub(crate) async fn refund_gas<S: State>(
+    state: &mut IntraBlockState<'_, S>,
+    header: &PartialHeader,
+    block_spec: &BlockExecutionSpec,
+    txn: &MessageWithSender,
+    gas_left: u64,
+) -> anyhow::Result<u64> {
+    let gas_left = gas_left_with_refund(state, block_spec, txn.gas_limit(), gas_left);
+
+    let base_fee_per_gas = header.base_fee_per_gas.unwrap_or_else(U256::zero);
+    let effective_gas_price = txn.effective_gas_price(base_fee_per_gas);
+    state
+        .add_to_balance(txn.sender, U256::from(gas_left) * effective_gas_price)
+        .await?;
 
-        Ok(gas_left)
-    }
+    Ok(gas_left)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        execution::address::create_address, res::chainspec::MAINNET, util::test_util::run_test,
+        execution::{address::create_address, precompiled::Contract, tracer::OpcodeGasTracer},
+        res::chainspec::MAINNET,
+        util::test_util::run_test,
         InMemoryState,
     };
     use bytes::Bytes;
     use bytes_literal::bytes;
+    use evmodin::opcode::OpCode;
     use hex_literal::hex;
 
+    #[test]
+    fn empty_block_fast_path() {
+        run_test(async {
+            let header = PartialHeader {
+                number: 1_000_000.into(),
+                beneficiary: hex!("4bb96091ee9d802ed039c4d1a5f6216f90f81b01").into(),
+                ..PartialHeader::empty()
+            };
+            let block = BlockBodyWithSenders {
+                transactions: vec![],
+                ommers: vec![BlockHeader {
+                    number: 999_999.into(),
+                    beneficiary: hex!("004512399a230565b99be5c3b0030a56f3ace68c").into(),
+                    ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+                }],
+            };
+            let block_spec = MAINNET.collect_block_spec(header.number);
+            let mut analysis_cache = AnalysisCache::default();
+            let mut engine = engine_factory(MAINNET.clone()).unwrap();
+
+            let mut fast = InMemoryState::default();
+            let receipts = ExecutionProcessor::new(
+                &mut fast,
+                &mut analysis_cache,
+                &mut *engine,
+                &header,
+                &block,
+                &block_spec,
+            )
+            .execute_and_write_block()
+            .await
+            .unwrap();
+            assert!(receipts.is_empty());
+
+            let mut general = InMemoryState::default();
+            let mut processor = ExecutionProcessor::new(
+                &mut general,
+                &mut analysis_cache,
+                &mut *engine,
+                &header,
+                &block,
+                &block_spec,
+            );
+            processor.execute_block_no_post_validation().await.unwrap();
+            processor
+                .into_state()
+                .write_to_db(header.number)
+                .await
+                .unwrap();
+
+            // the miner and the ommer miner are rewarded
+            assert_eq!(fast.number_of_accounts(), 2);
+            assert_eq!(fast.state_root_hash(), general.state_root_hash());
+        })
+    }
+
     #[test]
     fn zero_gas_price() {
         run_test(async {
@@ -358,6 +524,186 @@ mod tests {
         })
     }
 
+    #[test]
+    fn custom_precompile() {
+        run_test(async {
+            let header = PartialHeader {
+                number: 2_687_232.into(),
+                gas_limit: 3_303_221,
+                beneficiary: hex!("4bb96091ee9d802ed039c4d1a5f6216f90f81b01").into(),
+                ..PartialHeader::empty()
+            };
+            let block = Default::default();
+
+            let sender = hex!("004512399a230565b99be5c3b0030a56f3ace68c").into();
+            let custom = hex!("00000000000000000000000000000000000c0de1").into();
+            let failing = hex!("00000000000000000000000000000000000c0de2").into();
+
+            let txn = |nonce, to| MessageWithSender {
+                message: Message::Legacy {
+                    chain_id: None,
+                    nonce,
+                    gas_price: U256::zero(),
+                    gas_limit: 100_000,
+                    action: TransactionAction::Call(to),
+                    value: U256::zero(),
+                    input: Bytes::new(),
+                },
+                sender,
+            };
+
+            let mut state = InMemoryState::default();
+            let mut analysis_cache = AnalysisCache::default();
+            let mut engine = engine_factory(MAINNET.clone()).unwrap();
+            let block_spec = MAINNET.collect_block_spec(header.number);
+            let mut processor = ExecutionProcessor::new(
+                &mut state,
+                &mut analysis_cache,
+                &mut *engine,
+                &header,
+                &block,
+                &block_spec,
+            );
+
+            let contract = Contract {
+                gas: |_, _| Some(1_000),
+                run: |input| Some(input),
+            };
+            let sha256 = Address::from_low_u64_be(2);
+            assert!(processor
+                .precompiles_mut()
+                .register(sha256, contract)
+                .is_err());
+            processor
+                .precompiles_mut()
+                .register(custom, contract)
+                .unwrap();
+            processor
+                .precompiles_mut()
+                .register(
+                    failing,
+                    Contract {
+                        gas: |_, _| Some(1_000),
+                        run: |_| None,
+                    },
+                )
+                .unwrap();
+
+            let receipt = processor
+                .execute_transaction(&txn(0, custom))
+                .await
+                .unwrap();
+            assert!(receipt.success);
+            assert_eq!(receipt.cumulative_gas_used, fee::G_TRANSACTION + 1_000);
+
+            let receipt = processor
+                .execute_transaction(&txn(1, failing))
+                .await
+                .unwrap();
+            assert!(!receipt.success);
+
+            processor
+                .precompiles_mut()
+                .register_shadowing(sha256, contract);
+            let receipt = processor
+                .execute_transaction(&txn(2, sha256))
+                .await
+                .unwrap();
+            assert!(receipt.success);
+            assert_eq!(
+                receipt.cumulative_gas_used,
+                fee::G_TRANSACTION + 1_000 + 100_000 + fee::G_TRANSACTION + 1_000
+            );
+        })
+    }
+
+    #[test]
+    fn eip3529_refund_cap() {
+        async fn gas_used(block_number: u64, code: Bytes) -> u64 {
+            let header = PartialHeader {
+                number: block_number.into(),
+                gas_limit: 1_000_000,
+                ..PartialHeader::empty()
+            };
+            let block = Default::default();
+
+            let sender = hex!("71562b71999873db5b286df957af199ec94617f7").into();
+            let contract = hex!("b0b0face00000000000000000000000000000001").into();
+
+            let txn = MessageWithSender {
+                message: Message::Legacy {
+                    chain_id: None,
+                    nonce: 0,
+                    gas_price: U256::zero(),
+                    gas_limit: 100_000,
+                    action: TransactionAction::Call(contract),
+                    value: U256::zero(),
+                    input: Bytes::new(),
+                },
+                sender,
+            };
+
+            let mut state = InMemoryState::default();
+            let mut analysis_cache = AnalysisCache::default();
+            let mut engine = engine_factory(MAINNET.clone()).unwrap();
+            let block_spec = MAINNET.collect_block_spec(header.number);
+            let mut processor = ExecutionProcessor::new(
+                &mut state,
+                &mut analysis_cache,
+                &mut *engine,
+                &header,
+                &block,
+                &block_spec,
+            );
+
+            processor.state.set_code(contract, code).await.unwrap();
+            for key in 0..2 {
+                processor
+                    .state
+                    .set_storage(contract, key.into(), 1.into())
+                    .await
+                    .unwrap();
+            }
+            processor.state.finalize_transaction();
+
+            let receipt = processor.execute_transaction(&txn).await.unwrap();
+            assert!(receipt.success);
+            receipt.cumulative_gas_used
+        }
+
+        run_test(async {
+            let berlin = 12_964_999;
+            let london = 12_965_000;
+
+            // PUSH1 0 PUSH1 0 SSTORE STOP
+            let clear_one = bytes!("600060005500");
+            // 21_000 + 2 * 3 + 2_900 (SSTORE_RESET) + 2_100 (cold slot)
+            let total = 26_006;
+            // R_SCLEAR is capped at gas_used / 2
+            assert_eq!(gas_used(berlin, clear_one.clone()).await, total - total / 2);
+            // SSTORE_RESET + ACCESS_LIST_STORAGE_KEY_COST fits under gas_used / 5
+            assert_eq!(gas_used(london, clear_one).await, total - 4_800);
+
+            // PUSH1 0 PUSH1 0 SSTORE PUSH1 0 PUSH1 1 SSTORE STOP
+            let clear_two = bytes!("6000600055600060015500");
+            let total = 21_000 + 4 * 3 + 2 * 5_000;
+            assert_eq!(gas_used(berlin, clear_two.clone()).await, total - total / 2);
+            // 2 * 4_800 exceeds gas_used / 5
+            assert_eq!(gas_used(london, clear_two).await, total - total / 5);
+
+            // CALLER SELFDESTRUCT
+            let selfdestruct = bytes!("33ff");
+            // 21_000 + 2 + 5_000, the beneficiary is warm
+            let total = 26_002;
+            assert_eq!(
+                gas_used(berlin, selfdestruct.clone()).await,
+                total - total / 2
+            );
+            // no refund for SELFDESTRUCT since London
+            assert_eq!(gas_used(london, selfdestruct).await, total);
+        })
+    }
+
     #[test]
     fn eip3607_reject_transactions_from_senders_with_deployed_code() {
         run_test(async {
@@ -513,6 +859,98 @@ mod tests {
         })
     }
 
+    #[test]
+    fn opcode_gas() {
+        run_test(async {
+            let header = PartialHeader {
+                number: 13_000_000.into(),
+                gas_limit: 1_000_000,
+                beneficiary: hex!("4bb96091ee9d802ed039c4d1a5f6216f90f81b01").into(),
+                ..PartialHeader::empty()
+            };
+            let sender = hex!("004512399a230565b99be5c3b0030a56f3ace68c").into();
+            let caller = hex!("0000000000000000000000000000000000ca11e4").into();
+            let callee = hex!("00000000000000000000000000000000000ca11e").into();
+
+            // 0      PUSH1  => 00 (x5)
+            // 10     PUSH20 => 00000000000000000000000000000000000ca11e
+            // 31     GAS
+            // 32     CALL
+            // 33     STOP
+            let caller_code = hex!(
+                "6000600060006000600073"
+                "00000000000000000000000000000000000ca11e"
+                "5af100"
+            );
+            // 0      PUSH1  => 2a
+            // 2      PUSH1  => 00
+            // 4      SSTORE
+            // 5      PUSH1  => 00
+            // 7      SLOAD
+            // 8      POP
+            // 9      STOP
+            let callee_code = hex!("602a6000556000545000");
+
+            let block = BlockBodyWithSenders {
+                transactions: vec![MessageWithSender {
+                    message: Message::Legacy {
+                        chain_id: None,
+                        nonce: 0,
+                        gas_price: U256::zero(),
+                        gas_limit: 100_000,
+                        action: TransactionAction::Call(caller),
+                        value: U256::zero(),
+                        input: Bytes::new(),
+                    },
+                    sender,
+                }],
+                ommers: vec![],
+            };
+
+            let mut state = InMemoryState::default();
+            let mut analysis_cache = AnalysisCache::default();
+            let mut engine = engine_factory(MAINNET.clone()).unwrap();
+            let block_spec = MAINNET.collect_block_spec(header.number);
+            let mut processor = ExecutionProcessor::new(
+                &mut state,
+                &mut analysis_cache,
+                &mut *engine,
+                &header,
+                &block,
+                &block_spec,
+            );
+            processor
+                .state()
+                .set_code(caller, caller_code.to_vec().into())
+                .await
+                .unwrap();
+            processor
+                .state()
+                .set_code(callee, callee_code.to_vec().into())
+                .await
+                .unwrap();
+
+            let mut tracer = OpcodeGasTracer::new();
+            let receipts = processor.execute_block_traced(&mut tracer).await.unwrap();
+            assert!(receipts[0].success);
+
+            let gas_used = tracer.gas_used();
+            // a cold slot set from zero
+            assert_eq!(gas_used[&OpCode::SSTORE], 22_100);
+            assert_eq!(gas_used[&OpCode::SLOAD], 100);
+            // the cold callee, without the gas used by it
+            assert_eq!(gas_used[&OpCode::CALL], 2_600);
+            assert_eq!(gas_used[&OpCode::PUSH1], 8 * 3);
+            assert_eq!(tracer.hottest()[0], (OpCode::SSTORE, 22_100));
+
+            // all the gas above the intrinsic one is accounted exactly once
+            assert_eq!(
+                gas_used.values().sum::<u64>(),
+                receipts[0].cumulative_gas_used - fee::G_TRANSACTION
+            );
+        })
+    }
+
     #[test]
     fn selfdestruct() {
         run_test(async {
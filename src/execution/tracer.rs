@@ -0,0 +1,519 @@
+use crate::util::*;
+use bytes::Bytes;
+use ethereum_types::{Address, U256, U64};
+use evmodin::{opcode::OpCode, StatusCode};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CallType {
+    Call,
+    StaticCall,
+    DelegateCall,
+    CallCode,
+    Create,
+    Create2,
+}
+
+impl CallType {
+    pub fn is_create(self) -> bool {
+        matches!(self, Self::Create | Self::Create2)
+    }
+}
+
+/// Hooks into the calls and the contract creations made by the EVM.
+pub trait Tracer: Send {
+    /// A call or a contract creation starts,
+    /// nested into the innermost one which hasn't ended yet.
+    fn capture_start(
+        &mut self,
+        call_type: CallType,
+        from: Address,
+        to: Address,
+        value: U256,
+        input: &[u8],
+        gas: u64,
+    );
+
+    /// The innermost call or contract creation which hasn't ended yet ends.
+    fn capture_end(&mut self, status_code: StatusCode, gas_left: i64, output: &[u8]);
+
+    /// Whether `capture_instruction` should be called, which slows the interpreter down.
+    fn traces_instructions(&self) -> bool {
+        false
+    }
+
+    /// An instruction of the innermost call or contract creation starts,
+    /// with the gas left before it's charged.
+    fn capture_instruction(&mut self, pc: usize, opcode: OpCode, gas_left: i64) {
+        let _ = (pc, opcode, gas_left);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NoopTracer;
+
+impl Tracer for NoopTracer {
+    fn capture_start(&mut self, _: CallType, _: Address, _: Address, _: U256, _: &[u8], _: u64) {}
+
+    fn capture_end(&mut self, _: StatusCode, _: i64, _: &[u8]) {}
+}
+
+fn error_message(status_code: StatusCode) -> Option<String> {
+    match status_code {
+        StatusCode::Success => None,
+        StatusCode::Revert => Some("execution reverted".to_string()),
+        StatusCode::OutOfGas => Some("out of gas".to_string()),
+        StatusCode::InsufficientBalance => Some("insufficient balance for transfer".to_string()),
+        other => Some(format!("{:?}", other)),
+    }
+}
+
+/// A call or a contract creation in the `callTracer` format.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallFrame {
+    #[serde(rename = "type")]
+    pub call_type: CallType,
+    pub from: Address,
+    /// Callee, the executed code for `DELEGATECALL` and `CALLCODE`, the new contract for creations.
+    pub to: Address,
+    pub value: U256,
+    pub gas: U64,
+    pub gas_used: U64,
+    #[serde(with = "hexbytes")]
+    pub input: Bytes,
+    #[serde(with = "hexbytes")]
+    pub output: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub calls: Vec<CallFrame>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum TraceAction {
+    #[serde(rename_all = "camelCase")]
+    Call {
+        call_type: String,
+        from: Address,
+        to: Address,
+        gas: U64,
+        #[serde(with = "hexbytes")]
+        input: Bytes,
+        value: U256,
+    },
+    #[serde(rename_all = "camelCase")]
+    Create {
+        from: Address,
+        gas: U64,
+        #[serde(with = "hexbytes")]
+        init: Bytes,
+        value: U256,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum TraceResult {
+    #[serde(rename_all = "camelCase")]
+    Call {
+        gas_used: U64,
+        #[serde(with = "hexbytes")]
+        output: Bytes,
+    },
+    #[serde(rename_all = "camelCase")]
+    Create {
+        address: Address,
+        #[serde(with = "hexbytes")]
+        code: Bytes,
+        gas_used: U64,
+    },
+}
+
+/// A call or a contract creation in the flat `trace_` format.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlatTrace {
+    pub action: TraceAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<TraceResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub subtraces: usize,
+    /// Indices of the call among its siblings, from the top-level call down.
+    pub trace_address: Vec<usize>,
+    #[serde(rename = "type")]
+    pub trace_type: String,
+}
+
+impl FlatTrace {
+    fn new(frame: &CallFrame, trace_address: Vec<usize>) -> Self {
+        let (action, result, trace_type) = if frame.call_type.is_create() {
+            (
+                TraceAction::Create {
+                    from: frame.from,
+                    gas: frame.gas,
+                    init: frame.input.clone(),
+                    value: frame.value,
+                },
+                TraceResult::Create {
+                    address: frame.to,
+                    code: frame.output.clone(),
+                    gas_used: frame.gas_used,
+                },
+                "create",
+            )
+        } else {
+            (
+                TraceAction::Call {
+                    call_type: format!("{:?}", frame.call_type).to_lowercase(),
+                    from: frame.from,
+                    to: frame.to,
+                    gas: frame.gas,
+                    input: frame.input.clone(),
+                    value: frame.value,
+                },
+                TraceResult::Call {
+                    gas_used: frame.gas_used,
+                    output: frame.output.clone(),
+                },
+                "call",
+            )
+        };
+
+        Self {
+            action,
+            result: if frame.error.is_none() {
+                Some(result)
+            } else {
+                None
+            },
+            error: frame.error.clone(),
+            subtraces: frame.calls.len(),
+            trace_address,
+            trace_type: trace_type.to_string(),
+        }
+    }
+}
+
+fn flatten(frame: &CallFrame, trace_address: Vec<usize>, out: &mut Vec<FlatTrace>) {
+    out.push(FlatTrace::new(frame, trace_address.clone()));
+    for (i, call) in frame.calls.iter().enumerate() {
+        let mut trace_address = trace_address.clone();
+        trace_address.push(i);
+        flatten(call, trace_address, out);
+    }
+}
+
+/// Records the tree of calls made by a transaction.
+///
+/// A failed call is recorded with its error even if the caller goes on successfully,
+/// and the calls it made are kept as they were before being reverted.
+#[derive(Debug, Default)]
+pub struct CallTracer {
+    stack: Vec<CallFrame>,
+    root: Option<CallFrame>,
+}
+
+impl CallTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The top-level call, once it has ended.
+    pub fn call_frame(&self) -> Option<&CallFrame> {
+        self.root.as_ref()
+    }
+
+    pub fn into_call_frame(self) -> Option<CallFrame> {
+        self.root
+    }
+
+    /// All calls in the order they started, with their positions in the tree.
+    pub fn flat_traces(&self) -> Vec<FlatTrace> {
+        let mut out = Vec::new();
+        if let Some(root) = &self.root {
+            flatten(root, vec![], &mut out);
+        }
+        out
+    }
+}
+
+impl Tracer for CallTracer {
+    fn capture_start(
+        &mut self,
+        call_type: CallType,
+        from: Address,
+        to: Address,
+        value: U256,
+        input: &[u8],
+        gas: u64,
+    ) {
+        self.stack.push(CallFrame {
+            call_type,
+            from,
+            to,
+            value,
+            gas: gas.into(),
+            gas_used: U64::zero(),
+            input: input.to_vec().into(),
+            output: Bytes::new(),
+            error: None,
+            calls: vec![],
+        });
+    }
+
+    fn capture_end(&mut self, status_code: StatusCode, gas_left: i64, output: &[u8]) {
+        let mut frame = self.stack.pop().expect("call ended without having started");
+
+        frame.gas_used = frame
+            .gas
+            .as_u64()
+            .saturating_sub(gas_left.max(0) as u64)
+            .into();
+        frame.output = output.to_vec().into();
+        frame.error = error_message(status_code);
+
+        if let Some(parent) = self.stack.last_mut() {
+            parent.calls.push(frame);
+        } else {
+            self.root = Some(frame);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OpcodeGasFrame {
+    gas: u64,
+    /// The instruction being executed, and the gas left before it.
+    current: Option<(OpCode, i64)>,
+    /// Gas used by the calls made by the current instruction.
+    nested_gas_used: u64,
+    /// Unset for the calls without code.
+    has_instructions: bool,
+}
+
+/// Sums up the gas used by every opcode, e.g. over a block, to find the hot ones.
+///
+/// The gas used by a call is accounted to the instructions of the callee,
+/// the calling instruction is only charged with its own cost. The calls without code,
+/// like the precompiles, are accounted to the calling instruction.
+/// The refunds aren't subtracted.
+#[derive(Debug, Default)]
+pub struct OpcodeGasTracer {
+    gas_used: HashMap<OpCode, u64>,
+    stack: Vec<OpcodeGasFrame>,
+}
+
+impl OpcodeGasTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gas_used(&self) -> &HashMap<OpCode, u64> {
+        &self.gas_used
+    }
+
+    pub fn into_gas_used(self) -> HashMap<OpCode, u64> {
+        self.gas_used
+    }
+
+    /// The opcodes by the gas used, the hottest first.
+    pub fn hottest(&self) -> Vec<(OpCode, u64)> {
+        let mut hottest = self
+            .gas_used
+            .iter()
+            .map(|(opcode, gas_used)| (*opcode, *gas_used))
+            .collect::<Vec<_>>();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.to_u8().cmp(&b.0.to_u8())));
+        hottest
+    }
+
+    /// Charges the current instruction of the frame with the gas spent until `gas_left`.
+    fn finish_instruction(
+        gas_used: &mut HashMap<OpCode, u64>,
+        frame: &mut OpcodeGasFrame,
+        gas_left: i64,
+    ) {
+        if let Some((opcode, gas_left_before)) = frame.current.take() {
+            let spent = (gas_left_before - gas_left.max(0)).max(0) as u64;
+            *gas_used.entry(opcode).or_default() += spent.saturating_sub(frame.nested_gas_used);
+        }
+        frame.nested_gas_used = 0;
+    }
+}
+
+impl Tracer for OpcodeGasTracer {
+    fn capture_start(&mut self, _: CallType, _: Address, _: Address, _: U256, _: &[u8], gas: u64) {
+        self.stack.push(OpcodeGasFrame {
+            gas,
+            current: None,
+            nested_gas_used: 0,
+            has_instructions: false,
+        });
+    }
+
+    fn capture_end(&mut self, _: StatusCode, gas_left: i64, _: &[u8]) {
+        let mut frame = self.stack.pop().expect("call ended without having started");
+        Self::finish_instruction(&mut self.gas_used, &mut frame, gas_left);
+
+        if frame.has_instructions {
+            if let Some(parent) = self.stack.last_mut() {
+                parent.nested_gas_used += frame.gas.saturating_sub(gas_left.max(0) as u64);
+            }
+        }
+    }
+
+    fn traces_instructions(&self) -> bool {
+        true
+    }
+
+    fn capture_instruction(&mut self, _: usize, opcode: OpCode, gas_left: i64) {
+        let frame = self
+            .stack
+            .last_mut()
+            .expect("instruction outside of a call");
+        Self::finish_instruction(&mut self.gas_used, frame, gas_left);
+        frame.current = Some((opcode, gas_left));
+        frame.has_instructions = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        execution::{
+            address::create2_address, analysis_cache::AnalysisCache, evm::execute_with_tracer,
+            precompiled::PrecompileRegistry,
+        },
+        models::*,
+        res::chainspec::MAINNET,
+        util::test_util::run_test,
+        InMemoryState, IntraBlockState,
+    };
+    use ethereum_types::H256;
+    use hex_literal::hex;
+    use sha3::{Digest, Keccak256};
+
+    #[test]
+    fn nested_calls() {
+        run_test(async {
+            let header = PartialHeader {
+                number: 13_000_000.into(),
+                ..PartialHeader::empty()
+            };
+            let block_spec = MAINNET.collect_block_spec(header.number);
+
+            let caller = hex!("0a6bb546b9208cfab9e8fa2b9b2c042b18df7030").into();
+            let parent = Address::from(hex!("b1f8e55c7f64d203c1400b9d8555d050f94adf39"));
+            let reverter = Address::from(hex!("8b299e2b7d7f43c0ce3068263545309ff4ffb521"));
+
+            // CALL reverter, ignore the result, then CREATE2 with salt 0 and initcode 0x00.
+            let mut parent_code = hex!("60006000600060006000").to_vec();
+            parent_code.push(0x73);
+            parent_code.extend_from_slice(reverter.as_bytes());
+            parent_code.extend_from_slice(&hex!("5af1506000600160006000f55000"));
+
+            // REVERT with 0x2a.
+            let reverter_code = hex!("602a60005260206000fd");
+
+            let mut db = InMemoryState::default();
+            let mut state = IntraBlockState::new(&mut db);
+            state.set_code(parent, parent_code.into()).await.unwrap();
+            state
+                .set_code(reverter, reverter_code.to_vec().into())
+                .await
+                .unwrap();
+
+            let txn = MessageWithSender {
+                message: Message::Legacy {
+                    action: TransactionAction::Call(parent),
+                    chain_id: Default::default(),
+                    nonce: Default::default(),
+                    gas_price: Default::default(),
+                    gas_limit: Default::default(),
+                    value: Default::default(),
+                    input: Default::default(),
+                },
+                sender: caller,
+            };
+
+            let mut tracer = CallTracer::new();
+            let res = execute_with_tracer(
+                &mut state,
+                &mut AnalysisCache::default(),
+                &header,
+                &block_spec,
+                &PrecompileRegistry::new(block_spec.revision),
+                &txn,
+                100_000,
+                &mut tracer,
+            )
+            .await
+            .unwrap();
+            assert_eq!(res.status_code, StatusCode::Success);
+
+            let root = tracer.call_frame().unwrap();
+            assert_eq!(root.call_type, CallType::Call);
+            assert_eq!(root.from, caller);
+            assert_eq!(root.to, parent);
+            assert_eq!(root.gas, 100_000_u64.into());
+            assert_eq!(root.gas_used, (100_000 - res.gas_left as u64).into());
+            assert_eq!(root.error, None);
+            assert_eq!(root.calls.len(), 2);
+
+            // the reverted subcall doesn't make the parent fail
+            let reverted = &root.calls[0];
+            assert_eq!(reverted.call_type, CallType::Call);
+            assert_eq!(reverted.from, parent);
+            assert_eq!(reverted.to, reverter);
+            assert_eq!(reverted.error.as_deref(), Some("execution reverted"));
+            assert_eq!(reverted.output, u256_to_h256(0x2a.into()).as_bytes());
+
+            let created = &root.calls[1];
+            let created_address = create2_address(
+                parent,
+                U256::zero(),
+                H256::from_slice(&Keccak256::digest(&[0x00_u8][..])[..]),
+            );
+            assert_eq!(created.call_type, CallType::Create2);
+            assert_eq!(created.from, parent);
+            assert_eq!(created.to, created_address);
+            assert_eq!(created.input, vec![0x00]);
+            assert_eq!(created.error, None);
+            assert!(state.exists(created_address).await.unwrap());
+
+            let flat = tracer.flat_traces();
+            assert_eq!(
+                flat.iter()
+                    .map(|trace| (trace.trace_address.clone(), trace.subtraces))
+                    .collect::<Vec<_>>(),
+                vec![(vec![], 2), (vec![0], 0), (vec![1], 0)]
+            );
+            assert_eq!(flat[1].result, None);
+            assert_eq!(flat[1].error.as_deref(), Some("execution reverted"));
+            assert_eq!(flat[2].trace_type, "create");
+            assert_eq!(
+                flat[2].result,
+                Some(TraceResult::Create {
+                    address: created_address,
+                    code: Bytes::new(),
+                    gas_used: created.gas_used,
+                })
+            );
+
+            let json = serde_json::to_value(root).unwrap();
+            assert_eq!(json["type"], "CALL");
+            assert_eq!(json["calls"][1]["type"], "CREATE2");
+            assert_eq!(json["calls"][0]["error"], "execution reverted");
+            assert!(json["calls"][1].get("error").is_none());
+            let json = serde_json::to_value(&flat[0]).unwrap();
+            assert_eq!(json["action"]["callType"], "call");
+            assert_eq!(json["traceAddress"], serde_json::json!([]));
+        })
+    }
+}
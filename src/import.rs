@@ -0,0 +1,376 @@
+use crate::{
+    accessors::chain,
+    consensus::ValidationError,
+    kv::{tables, traits::*},
+    models::*,
+    stagedsync::stages::{BODIES, HEADERS},
+};
+use anyhow::{bail, format_err};
+use std::io::Read;
+use tracing::*;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportedBlocks {
+    /// Blocks written to the database.
+    pub imported: u64,
+    /// Blocks skipped because they are canonical already, e.g. the genesis.
+    pub duplicates: u64,
+    /// The last block of the input.
+    pub last_block: Option<BlockNumber>,
+}
+
+/// Imports RLP-encoded blocks in sequence, the format of `geth export`, instead of
+/// downloading them from the sentry. Writes the canonical headers and bodies on top of
+/// the canonical chain for the stages from `BlockHashes` on, the blocks are not executed.
+///
+/// Commits every `commit_every` imported blocks, along with the progress of the `Headers`
+/// and `Bodies` stages, so that an interrupted import resumes from the last commit.
+///
+/// The blocks which are canonical already are skipped. Any other block has to link to
+/// the canonical one below it, so a gap in the sequence or a fork is an error, and so is
+/// a body which doesn't match its header. The blocks imported since the last commit
+/// are discarded then.
+pub async fn import_rlp<'db, DB, R>(
+    db: &'db DB,
+    reader: &mut R,
+    commit_every: u64,
+) -> anyhow::Result<ImportedBlocks>
+where
+    DB: MutableKV,
+    R: Read,
+{
+    let mut tx = db.begin_mutable().await?;
+    let mut last_imported = None;
+    let mut imported_since_commit = 0;
+
+    let mut imported_blocks = ImportedBlocks::default();
+    let mut buffer = Vec::<u8>::new();
+    let mut chunk = vec![0u8; 1 << 16];
+    loop {
+        let read_count = reader.read(&mut chunk)?;
+        buffer.extend_from_slice(&chunk[..read_count]);
+
+        let mut offset = 0;
+        while offset < buffer.len() {
+            let block_len = match rlp::Rlp::new(&buffer[offset..]).payload_info() {
+                Ok(info) => info.header_len + info.value_len,
+                // the rest of the block is in the next chunk
+                Err(rlp::DecoderError::RlpIsTooShort) if read_count > 0 => break,
+                Err(error) => return Err(error.into()),
+            };
+            if offset + block_len > buffer.len() {
+                if read_count == 0 {
+                    bail!("the last block is truncated");
+                }
+                break;
+            }
+            let block = rlp::decode::<Block>(&buffer[offset..offset + block_len])?;
+            offset += block_len;
+
+            let number = block.header.number;
+            imported_blocks.last_block = Some(number);
+            if import_block(&tx, block).await? {
+                imported_blocks.imported += 1;
+                if imported_blocks.imported % 100_000 == 0 {
+                    info!("Imported {} blocks", imported_blocks.imported);
+                }
+
+                last_imported = Some(number);
+                imported_since_commit += 1;
+                if imported_since_commit >= commit_every {
+                    commit(tx, number).await?;
+                    tx = db.begin_mutable().await?;
+                    imported_since_commit = 0;
+                }
+            } else {
+                imported_blocks.duplicates += 1;
+            }
+        }
+        buffer.drain(..offset);
+
+        if read_count == 0 {
+            break;
+        }
+    }
+
+    if let Some(last_imported) = last_imported {
+        if imported_since_commit > 0 {
+            commit(tx, last_imported).await?;
+        }
+    }
+
+    Ok(imported_blocks)
+}
+
+/// Commits the imported blocks up to `last_imported` as the progress of the stages the import replaces.
+async fn commit<'db, RwTx: MutableTransaction<'db>>(
+    tx: RwTx,
+    last_imported: BlockNumber,
+) -> anyhow::Result<()> {
+    HEADERS.save_progress(&tx, last_imported).await?;
+    BODIES.save_progress(&tx, last_imported).await?;
+    debug!("Committing the blocks up to {}", last_imported);
+    tx.commit().await
+}
+
+/// Returns false if the block is canonical already.
+async fn import_block<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+    block: Block,
+) -> anyhow::Result<bool> {
+    let Block {
+        header,
+        transactions,
+        ommers,
+    } = block;
+    let number = header.number;
+    let hash = header.hash();
+
+    if let Some(canonical_hash) = chain::canonical_hash::read(tx, number).await? {
+        if canonical_hash == hash {
+            return Ok(false);
+        }
+        bail!(
+            "Block {}/{:?} conflicts with the canonical block {:?}",
+            number,
+            hash,
+            canonical_hash
+        );
+    }
+
+    let parent_number = BlockNumber(
+        number
+            .0
+            .checked_sub(1)
+            .ok_or_else(|| format_err!("Genesis is not initialized"))?,
+    );
+    let parent_hash = chain::canonical_hash::read(tx, parent_number)
+        .await?
+        .ok_or_else(|| {
+            format_err!(
+                "Gap before block {}: no canonical block {}",
+                number,
+                parent_number
+            )
+        })?;
+    if header.parent_hash != parent_hash {
+        return Err(
+            anyhow::Error::from(ValidationError::UnknownParent).context(format!(
+                "Block {}/{:?} does not link to the canonical block {:?}",
+                number, hash, parent_hash
+            )),
+        );
+    }
+
+    let expected_ommers_hash = Block::ommers_hash(&ommers);
+    if header.ommers_hash != expected_ommers_hash {
+        return Err(ValidationError::WrongOmmersHash {
+            expected: expected_ommers_hash,
+            got: header.ommers_hash,
+        }
+        .into());
+    }
+
+    let parent_body = chain::storage_body::read(tx, parent_hash, parent_number)
+        .await?
+        .ok_or_else(|| format_err!("Missing body of the parent block {}", parent_number))?;
+    chain::block_body::write_stream(
+        tx,
+        &header,
+        parent_body.base_tx_id + parent_body.tx_amount as u64,
+        ommers,
+        tokio_stream::iter(transactions.into_iter().map(Ok)),
+    )
+    .await?;
+
+    let total_difficulty = chain::td::read_parent_and_accumulate(tx, &header).await?;
+    chain::td::write(tx, hash, number, total_difficulty).await?;
+    tx.set(tables::Header, (number, hash), header).await?;
+    tx.set(tables::HeaderNumber, hash, number).await?;
+    chain::canonical_hash::write(tx, number, hash).await?;
+    tx.set(tables::LastHeader, Default::default(), hash).await?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{genesis::initialize_genesis, kv::new_mem_database, res::chainspec::MAINNET};
+    use bytes::Bytes;
+    use ethereum_types::{H256, U256};
+
+    fn child_block(parent: &BlockHeader, nonce: u64) -> Block {
+        let transaction = MessageWithSignature {
+            message: Message::Legacy {
+                chain_id: None,
+                nonce,
+                gas_price: 20_000.into(),
+                gas_limit: 21_000,
+                action: TransactionAction::Create,
+                value: 0.into(),
+                input: Bytes::new(),
+            },
+            signature: MessageSignature::new(false, H256::repeat_byte(2), H256::repeat_byte(3))
+                .unwrap(),
+        };
+        Block::new(
+            PartialHeader {
+                parent_hash: parent.hash(),
+                number: parent.number + 1,
+                difficulty: 0x100.into(),
+                ..PartialHeader::empty()
+            },
+            vec![transaction],
+            vec![],
+        )
+    }
+
+    fn encode(blocks: &[Block]) -> Vec<u8> {
+        blocks.iter().flat_map(|block| rlp::encode(block)).collect()
+    }
+
+    #[tokio::test]
+    async fn import() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        initialize_genesis(&tx, MAINNET.clone()).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let tx = db.begin().await.unwrap();
+        let genesis_hash = chain::canonical_hash::read(&tx, 0).await.unwrap().unwrap();
+        let genesis_header = chain::header::read(&tx, genesis_hash, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let genesis_body = chain::block_body::read_without_senders(&tx, genesis_hash, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut blocks = vec![Block {
+            header: genesis_header,
+            transactions: genesis_body.transactions,
+            ommers: genesis_body.ommers,
+        }];
+        for nonce in 0..3 {
+            let block = child_block(&blocks.last().unwrap().header, nonce);
+            blocks.push(block);
+        }
+
+        drop(tx);
+
+        // the genesis is there already
+        assert_eq!(
+            import_rlp(&db, &mut encode(&blocks).as_slice(), 2)
+                .await
+                .unwrap(),
+            ImportedBlocks {
+                imported: 3,
+                duplicates: 1,
+                last_block: Some(BlockNumber(3)),
+            }
+        );
+        let tx = db.begin().await.unwrap();
+        for block in &blocks {
+            let hash = block.header.hash();
+            let number = block.header.number;
+            assert_eq!(
+                chain::canonical_hash::read(&tx, number).await.unwrap(),
+                Some(hash)
+            );
+            assert_eq!(
+                chain::block_body::read_without_senders(&tx, hash, number)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .transactions,
+                block.transactions
+            );
+        }
+        let last_hash = blocks[3].header.hash();
+        assert_eq!(
+            chain::td::read(&tx, last_hash, 3).await.unwrap(),
+            Some(blocks[0].header.difficulty + U256::from(0x300))
+        );
+        assert_eq!(
+            tx.get(tables::LastHeader, Default::default())
+                .await
+                .unwrap(),
+            Some(last_hash)
+        );
+        for stage in [HEADERS, BODIES] {
+            assert_eq!(stage.get_progress(&tx).await.unwrap(), Some(BlockNumber(3)));
+        }
+        drop(tx);
+
+        // a repeated import is a no-op
+        assert_eq!(
+            import_rlp(&db, &mut encode(&blocks[2..]).as_slice(), 2)
+                .await
+                .unwrap()
+                .imported,
+            0
+        );
+
+        // a gap in the sequence
+        let block4 = child_block(&blocks[3].header, 3);
+        let block5 = child_block(&block4.header, 4);
+        assert!(import_rlp(&db, &mut encode(&[block5]).as_slice(), 2)
+            .await
+            .is_err());
+
+        // a fork of the canonical block
+        let fork = child_block(&blocks[2].header, 10);
+        assert!(import_rlp(&db, &mut encode(&[fork]).as_slice(), 2)
+            .await
+            .is_err());
+
+        // truncated
+        let mut encoded = encode(&[block4]);
+        encoded.pop();
+        assert!(import_rlp(&db, &mut encoded.as_slice(), 2).await.is_err());
+        let tx = db.begin().await.unwrap();
+        assert_eq!(chain::canonical_hash::read(&tx, 4).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn commit_every() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        initialize_genesis(&tx, MAINNET.clone()).await.unwrap();
+        let genesis_hash = chain::canonical_hash::read(&tx, 0).await.unwrap().unwrap();
+        let mut headers = vec![chain::header::read(&tx, genesis_hash, 0)
+            .await
+            .unwrap()
+            .unwrap()];
+        tx.commit().await.unwrap();
+
+        let mut blocks = vec![];
+        for nonce in 0..4 {
+            let block = child_block(headers.last().unwrap(), nonce);
+            headers.push(block.header.clone());
+            blocks.push(block);
+        }
+        // the body of the block 4 doesn't match its header
+        blocks[3].header.transactions_root = H256::repeat_byte(0xaa);
+
+        let error = import_rlp(&db, &mut encode(&blocks).as_slice(), 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ValidationError>(),
+            Some(ValidationError::WrongTransactionsRoot { .. })
+        ));
+
+        // the blocks 1 and 2 are committed, the block 3 is discarded with the block 4
+        let tx = db.begin().await.unwrap();
+        assert_eq!(
+            chain::canonical_hash::read(&tx, 2).await.unwrap(),
+            Some(headers[2].hash())
+        );
+        assert_eq!(chain::canonical_hash::read(&tx, 3).await.unwrap(), None);
+        for stage in [HEADERS, BODIES] {
+            assert_eq!(stage.get_progress(&tx).await.unwrap(), Some(BlockNumber(2)));
+        }
+    }
+}
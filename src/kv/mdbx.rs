@@ -2,7 +2,7 @@ use crate::kv::{traits::*, *};
 use ::mdbx::{DatabaseFlags, EnvironmentKind, TransactionKind, WriteFlags, RO, RW};
 use anyhow::Context;
 use async_trait::async_trait;
-use std::{collections::HashMap, ops::Deref, path::Path};
+use std::{borrow::Cow, collections::HashMap, ops::Deref, path::Path};
 use tables::*;
 
 #[derive(Clone, Debug)]
@@ -32,18 +32,14 @@ impl<E: EnvironmentKind> Environment<E> {
         mut b: ::mdbx::EnvironmentBuilder<E>,
         path: &Path,
         chart: DatabaseChart,
-        ro: bool,
+        mode: ::mdbx::Mode,
+        exclusive: bool,
     ) -> anyhow::Result<Self> {
         b.set_max_dbs(std::cmp::max(chart.len(), 1));
 
         b.set_flags(::mdbx::EnvironmentFlags {
-            mode: if ro {
-                ::mdbx::Mode::ReadOnly
-            } else {
-                ::mdbx::Mode::ReadWrite {
-                    sync_mode: ::mdbx::SyncMode::Durable,
-                }
-            },
+            mode,
+            exclusive,
             no_rdahead: true,
             coalesce: true,
             ..Default::default()
@@ -61,7 +57,16 @@ impl<E: EnvironmentKind> Environment<E> {
         path: &Path,
         chart: DatabaseChart,
     ) -> anyhow::Result<Self> {
-        Self::open(b, path, chart, true)
+        Self::open(b, path, chart, ::mdbx::Mode::ReadOnly, false)
+    }
+
+    /// Opens read-only, failing if any other process has the database open.
+    pub fn open_ro_exclusive(
+        b: ::mdbx::EnvironmentBuilder<E>,
+        path: &Path,
+        chart: DatabaseChart,
+    ) -> anyhow::Result<Self> {
+        Self::open(b, path, chart, ::mdbx::Mode::ReadOnly, true)
     }
 
     pub fn open_rw(
@@ -69,7 +74,22 @@ impl<E: EnvironmentKind> Environment<E> {
         path: &Path,
         chart: DatabaseChart,
     ) -> anyhow::Result<Self> {
-        let s = Self::open(b, path, chart.clone(), false)?;
+        Self::open_rw_with_sync_mode(b, path, chart, ::mdbx::SyncMode::Durable)
+    }
+
+    pub fn open_rw_with_sync_mode(
+        b: ::mdbx::EnvironmentBuilder<E>,
+        path: &Path,
+        chart: DatabaseChart,
+        sync_mode: ::mdbx::SyncMode,
+    ) -> anyhow::Result<Self> {
+        let s = Self::open(
+            b,
+            path,
+            chart.clone(),
+            ::mdbx::Mode::ReadWrite { sync_mode },
+            false,
+        )?;
 
         let tx = s.inner.begin_rw_txn()?;
         for (table, info) in &*chart {
@@ -86,6 +106,41 @@ impl<E: EnvironmentKind> Environment<E> {
 
         Ok(s)
     }
+
+    /// Rewrites the tables from the chart into `dst` in key order,
+    /// so that the copy has no free pages and densely filled leaves.
+    pub fn copy_compacted<E2: EnvironmentKind>(
+        &self,
+        dst: &Environment<E2>,
+        chart: &DatabaseChart,
+    ) -> anyhow::Result<()> {
+        let src_tx = self.inner.begin_ro_txn()?;
+        for (table, info) in &**chart {
+            let src_db = src_tx
+                .open_db(Some(table))
+                .with_context(|| format!("failed to open table: {}", table))?;
+            let mut src_cursor = src_tx.cursor(&src_db)?;
+
+            // commit table by table to keep the write transactions reasonably small
+            let dst_tx = dst.inner.begin_rw_txn()?;
+            {
+                let dst_db = dst_tx.open_db(Some(table))?;
+                let mut dst_cursor = dst_tx.cursor(&dst_db)?;
+                let flags = if info.dup_sort {
+                    WriteFlags::APPEND_DUP
+                } else {
+                    WriteFlags::APPEND
+                };
+                for item in src_cursor.iter::<Cow<[u8]>, Cow<[u8]>>() {
+                    let (k, v) = item?;
+                    dst_cursor.put(&k, &v, flags)?;
+                }
+            }
+            dst_tx.commit()?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<E: EnvironmentKind> Deref for Environment<E> {
@@ -118,6 +173,17 @@ impl<E: EnvironmentKind> MutableKV for Environment<E> {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableStat {
+    pub entries: u64,
+    pub depth: u32,
+    pub branch_pages: u64,
+    pub leaf_pages: u64,
+    pub overflow_pages: u64,
+    /// Size of all the pages.
+    pub total_bytes: u64,
+}
+
 #[derive(Debug)]
 pub struct MdbxTransaction<'env, K, E>
 where
@@ -131,8 +197,8 @@ impl<'env, E> MdbxTransaction<'env, RO, E>
 where
     E: EnvironmentKind,
 {
-    pub fn table_sizes(&self) -> anyhow::Result<HashMap<String, u64>> {
-        let mut out = HashMap::new();
+    pub fn table_stats(&self) -> anyhow::Result<Vec<(String, TableStat)>> {
+        let mut out = Vec::new();
         let main_db = self.inner.open_db(None)?;
         let mut cursor = self.inner.cursor(&main_db)?;
         while let Some((table, _)) = cursor.next_nodup::<Vec<u8>, ()>()? {
@@ -146,11 +212,18 @@ where
                 .db_stat(&db)
                 .with_context(|| format!("failed to get stats for table: {}", table))?;
 
-            out.insert(
+            out.push((
                 table,
-                ((st.leaf_pages() + st.branch_pages() + st.overflow_pages())
-                    * st.page_size() as usize) as u64,
-            );
+                TableStat {
+                    entries: st.entries() as u64,
+                    depth: st.depth() as u32,
+                    branch_pages: st.branch_pages() as u64,
+                    leaf_pages: st.leaf_pages() as u64,
+                    overflow_pages: st.overflow_pages() as u64,
+                    total_bytes: ((st.leaf_pages() + st.branch_pages() + st.overflow_pages())
+                        * st.page_size() as usize) as u64,
+                },
+            ));
 
             unsafe {
                 self.inner.close_db(db)?;
@@ -159,6 +232,14 @@ where
 
         Ok(out)
     }
+
+    pub fn table_sizes(&self) -> anyhow::Result<HashMap<String, u64>> {
+        Ok(self
+            .table_stats()?
+            .into_iter()
+            .map(|(table, stat)| (table, stat.total_bytes))
+            .collect())
+    }
 }
 
 #[async_trait]
@@ -7,10 +7,11 @@ pub mod traits;
 use self::traits::*;
 use crate::kv::tables::CHAINDATA_TABLES;
 use ::mdbx::{Geometry, WriteMap};
+use anyhow::Context;
 use async_trait::async_trait;
 use byte_unit::*;
 use bytes::Bytes as StaticBytes;
-use std::{fmt::Debug, ops::Deref};
+use std::{fmt::Debug, ops::Deref, str::FromStr};
 
 #[derive(Debug)]
 pub struct CustomTable(pub string::String<StaticBytes>);
@@ -59,34 +60,255 @@ impl traits::MutableKV for MdbxWithDirHandle {
     }
 }
 
+/// When and how the committed data is flushed to the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Every commit is synced to the disk. The slowest, but the database survives
+    /// both process crashes and power failures with all the committed data.
+    Durable,
+    /// The data is synced on every commit, but the meta page is not.
+    /// A system crash can roll back the last committed transaction, but not corrupt the database.
+    NoMetaSync,
+    /// Commits are not synced (`MDBX_SAFE_NOSYNC`), the OS flushes the data at its own pace.
+    /// A system crash can lose the recent commits, but leaves a consistent database.
+    /// Good for the bulk import, which can be redone.
+    SafeNoSync,
+    /// Commits are not synced and the meta pages are updated in place.
+    /// The fastest, but a system crash can corrupt the database beyond repair.
+    UtterlyNoSync,
+}
+
+impl From<Durability> for ::mdbx::SyncMode {
+    fn from(durability: Durability) -> Self {
+        match durability {
+            Durability::Durable => Self::Durable,
+            Durability::NoMetaSync => Self::NoMetaSync,
+            Durability::SafeNoSync => Self::SafeNoSync,
+            Durability::UtterlyNoSync => Self::UtterlyNoSync,
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "durable" => Self::Durable,
+            "no-meta-sync" => Self::NoMetaSync,
+            "safe-no-sync" => Self::SafeNoSync,
+            "utterly-no-sync" => Self::UtterlyNoSync,
+            other => anyhow::bail!("unknown durability mode: {}", other),
+        })
+    }
+}
+
+/// The MDBX environment settings.
+#[derive(Clone, Copy, Debug)]
+pub struct DatabaseConfig {
+    /// The upper limit of the database file size in bytes.
+    /// The address space for it is reserved up front, so a big value avoids remapping as the database grows.
+    pub max_size: usize,
+    /// How much the database file grows by at once, MDBX default if not set.
+    pub growth_step: Option<usize>,
+    /// Maximum number of simultaneous read transactions, MDBX default if not set.
+    pub max_readers: Option<u64>,
+    pub durability: Durability,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            max_size: n_tib_bytes!(4).try_into().unwrap_or(usize::MAX),
+            growth_step: Some(n_gib_bytes!(4) as usize),
+            max_readers: None,
+            durability: Durability::Durable,
+        }
+    }
+}
+
 pub fn new_mem_database() -> anyhow::Result<impl traits::MutableKV> {
     let tmpdir = tempfile::tempdir()?;
+    let config = DatabaseConfig {
+        max_size: n_mib_bytes!(64) as usize,
+        growth_step: None,
+        ..Default::default()
+    };
     Ok(MdbxWithDirHandle {
-        inner: new_environment(tmpdir.path(), n_mib_bytes!(64), None)?,
+        inner: new_environment(tmpdir.path(), &config)?,
         _tmpdir: Some(tmpdir),
     })
 }
 
-pub fn new_database(path: &std::path::Path) -> anyhow::Result<impl traits::MutableKV> {
+pub fn new_database(
+    path: &std::path::Path,
+    config: &DatabaseConfig,
+) -> anyhow::Result<impl traits::MutableKV> {
     Ok(MdbxWithDirHandle {
-        inner: new_environment(path, n_tib_bytes!(4), Some(n_gib_bytes!(4) as usize))?,
+        inner: new_environment(path, config)?,
         _tmpdir: None,
     })
 }
 
+const MDBX_DATA_FILE: &str = "mdbx.dat";
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionReport {
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// Copies the database at `path` into a new database at `dst_path` with compaction,
+/// like `mdbx_copy -c` does. The free pages left after unwinds and deletions are not copied.
+/// Refuses to run if the database is open by anyone else, e.g. a running node.
+pub fn compact_database(
+    path: &std::path::Path,
+    dst_path: &std::path::Path,
+) -> anyhow::Result<CompactionReport> {
+    anyhow::ensure!(
+        !dst_path.join(MDBX_DATA_FILE).exists(),
+        "{} already contains a database",
+        dst_path.display()
+    );
+
+    let size_before = std::fs::metadata(path.join(MDBX_DATA_FILE))?.len();
+    {
+        let mut builder = ::mdbx::Environment::<WriteMap>::new();
+        builder.set_max_dbs(CHAINDATA_TABLES.len());
+        let src =
+            mdbx::Environment::open_ro_exclusive(builder, path, CHAINDATA_TABLES.deref().clone())
+                .context("failed to open the database exclusively, is the node still running?")?;
+
+        std::fs::create_dir_all(dst_path)?;
+        let dst = new_environment(dst_path, &DatabaseConfig::default())?;
+
+        src.copy_compacted(&dst, &CHAINDATA_TABLES)?;
+    }
+    let size_after = std::fs::metadata(dst_path.join(MDBX_DATA_FILE))?.len();
+
+    Ok(CompactionReport {
+        size_before,
+        size_after,
+    })
+}
+
 fn new_environment(
     path: &std::path::Path,
-    size_upper_limit: u128,
-    growth_step: Option<usize>,
+    config: &DatabaseConfig,
 ) -> anyhow::Result<mdbx::Environment<WriteMap>> {
     let mut builder = ::mdbx::Environment::<WriteMap>::new();
     builder.set_max_dbs(CHAINDATA_TABLES.len());
     builder.set_geometry(Geometry {
-        size: Some(0..size_upper_limit.try_into().unwrap_or(usize::MAX)),
-        growth_step: growth_step.map(|s| s.try_into().unwrap_or(isize::MAX)),
+        size: Some(0..config.max_size),
+        growth_step: config
+            .growth_step
+            .map(|s| s.try_into().unwrap_or(isize::MAX)),
         shrink_threshold: None,
         page_size: None,
     });
+    if let Some(max_readers) = config.max_readers {
+        builder.set_max_readers(max_readers);
+    }
     builder.set_rp_augment_limit(16 * 256 * 1024);
-    mdbx::Environment::open_rw(builder, path, CHAINDATA_TABLES.deref().clone())
+    mdbx::Environment::open_rw_with_sync_mode(
+        builder,
+        path,
+        CHAINDATA_TABLES.deref().clone(),
+        config.durability.into(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Account, Address, BlockNumber};
+    use ethereum_types::H256;
+
+    #[tokio::test]
+    async fn compaction() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = new_database(src_dir.path(), &DatabaseConfig::default()).unwrap();
+            let tx = db.begin_mutable().await.unwrap();
+            for i in 0..10_000 {
+                tx.set(
+                    tables::CanonicalHeader,
+                    BlockNumber(i),
+                    H256::from_low_u64_be(i),
+                )
+                .await
+                .unwrap();
+            }
+            for i in 0..9_000 {
+                tx.del(tables::CanonicalHeader, BlockNumber(i), None)
+                    .await
+                    .unwrap();
+            }
+            tx.commit().await.unwrap();
+
+            // still open
+            assert!(compact_database(src_dir.path(), dst_dir.path()).is_err());
+        }
+
+        let report = compact_database(src_dir.path(), dst_dir.path()).unwrap();
+        assert!(report.size_after <= report.size_before);
+
+        let db = new_database(dst_dir.path(), &DatabaseConfig::default()).unwrap();
+        let tx = db.begin().await.unwrap();
+        let mut cursor = tx.cursor(tables::CanonicalHeader).await.unwrap();
+        let mut entry = cursor.first().await.unwrap();
+        let mut expected = 9_000;
+        while let Some((block_number, hash)) = entry {
+            assert_eq!(block_number, BlockNumber(expected));
+            assert_eq!(hash, H256::from_low_u64_be(expected));
+            expected += 1;
+            entry = cursor.next().await.unwrap();
+        }
+        assert_eq!(expected, 10_000);
+
+        // the destination is taken
+        assert!(compact_database(src_dir.path(), dst_dir.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn seek_exact_between_keys() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let account = Account::default();
+        for address in [1, 3] {
+            tx.set(tables::Account, Address::from_low_u64_be(address), account)
+                .await
+                .unwrap();
+        }
+
+        let mut cursor = tx.mutable_cursor(tables::Account).await.unwrap();
+        let missing = Address::from_low_u64_be(2);
+        // seek lands on the next key, seek_exact does not
+        assert_eq!(
+            cursor
+                .seek(missing)
+                .await
+                .unwrap()
+                .map(|(address, _)| address),
+            Some(Address::from_low_u64_be(3))
+        );
+        assert_eq!(cursor.seek_exact(missing).await.unwrap(), None);
+
+        let present = Address::from_low_u64_be(3);
+        assert_eq!(
+            cursor.seek_exact(present).await.unwrap(),
+            Some((present, account))
+        );
+        cursor.delete_current().await.unwrap();
+
+        let mut cursor = tx.cursor(tables::Account).await.unwrap();
+        assert_eq!(
+            cursor.first().await.unwrap(),
+            Some((Address::from_low_u64_be(1), account))
+        );
+        assert_eq!(cursor.next().await.unwrap(), None);
+    }
 }
@@ -820,7 +820,7 @@ decl_table!(LogAddressIndex => Vec<u8> => RoaringTreemap);
 decl_table!(CallTraceSet => BlockNumber => CallTraceSetEntry);
 decl_table!(CallFromIndex => Vec<u8> => RoaringTreemap);
 decl_table!(CallToIndex => Vec<u8> => RoaringTreemap);
-decl_table!(BlockTransactionLookup => H256 => TruncateStart<BlockNumber>);
+decl_table!(BlockTransactionLookup => H256 => (BlockNumber, u64));
 decl_table!(Config => H256 => ChainSpec);
 decl_table!(SyncStage => StageId => BlockNumber);
 decl_table!(TxSender => HeaderKey => Vec<Address>);
@@ -28,6 +28,7 @@ pub mod crypto;
 pub mod downloader;
 pub mod etl;
 pub mod execution;
+pub mod import;
 pub mod kv;
 pub mod models;
 pub mod res;
@@ -35,6 +36,7 @@ pub mod sentry;
 pub mod stagedsync;
 pub mod stages;
 mod state;
+pub mod txpool;
 pub(crate) mod util;
 
 pub use stagedsync::stages::StageId;
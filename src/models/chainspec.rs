@@ -115,6 +115,72 @@ impl ChainSpec {
     }
 }
 
+/// Reuses the last `BlockExecutionSpec` while the blocks stay between the same
+/// spec-changing blocks (upgrades, system contract and balance changes).
+/// The blocks at the boundaries get their own specs, since they carry the transitions.
+#[derive(Debug)]
+pub struct BlockSpecCache<'a> {
+    chain_spec: &'a ChainSpec,
+    boundaries: Vec<BlockNumber>,
+    cached: Option<((usize, bool), BlockExecutionSpec)>,
+}
+
+impl<'a> BlockSpecCache<'a> {
+    pub fn new(chain_spec: &'a ChainSpec) -> Self {
+        let upgrades = &chain_spec.upgrades;
+        let boundaries = [
+            upgrades.homestead,
+            upgrades.tangerine,
+            upgrades.spurious,
+            upgrades.byzantium,
+            upgrades.constantinople,
+            upgrades.petersburg,
+            upgrades.istanbul,
+            upgrades.berlin,
+            upgrades.london,
+        ]
+        .iter()
+        .copied()
+        .flatten()
+        .chain(chain_spec.contracts.keys().copied())
+        .chain(chain_spec.balances.keys().copied())
+        .collect::<BTreeSet<BlockNumber>>()
+        .into_iter()
+        .collect();
+
+        Self {
+            chain_spec,
+            boundaries,
+            cached: None,
+        }
+    }
+
+    /// The number of boundaries passed, and whether the block is at the last of them.
+    fn fork_id(&self, block_number: BlockNumber) -> (usize, bool) {
+        let passed = self
+            .boundaries
+            .partition_point(|&boundary| boundary <= block_number);
+        let at_boundary = passed > 0 && self.boundaries[passed - 1] == block_number;
+        (passed, at_boundary)
+    }
+
+    pub fn get(&mut self, block_number: impl Into<BlockNumber>) -> &BlockExecutionSpec {
+        let block_number = block_number.into();
+        let fork_id = self.fork_id(block_number);
+
+        let chain_spec = self.chain_spec;
+        let cached = match self.cached.take() {
+            Some((cached_fork_id, spec)) if cached_fork_id == fork_id => (fork_id, spec),
+            _ => (fork_id, chain_spec.collect_block_spec(block_number)),
+        };
+        let spec = &self.cached.insert(cached).1;
+
+        debug_assert_eq!(*spec, chain_spec.collect_block_spec(block_number));
+
+        spec
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct DifficultyBomb {
     pub delays: BTreeMap<BlockNumber, BlockNumber>,
@@ -499,4 +565,29 @@ mod tests {
             .collect()
         );
     }
+
+    #[test]
+    fn block_spec_cache() {
+        for chain_spec in [&*MAINNET, &*RINKEBY] {
+            let mut cache = BlockSpecCache::new(chain_spec);
+
+            let mut blocks = vec![BlockNumber(0), BlockNumber(1), BlockNumber(2)];
+            for fork in chain_spec.gather_forks() {
+                for block in fork.0.saturating_sub(2)..=fork.0 + 2 {
+                    blocks.push(BlockNumber(block));
+                }
+            }
+            // going back must not reuse a later spec
+            blocks.push(BlockNumber(1));
+
+            for block in blocks {
+                assert_eq!(
+                    *cache.get(block),
+                    chain_spec.collect_block_spec(block),
+                    "block {}",
+                    block
+                );
+            }
+        }
+    }
 }
@@ -4,6 +4,7 @@ use bytes::Bytes;
 use ethereum_types::*;
 use rlp::*;
 use serde::*;
+use std::fmt::{self, Display};
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 /// Ethereum block header definition.
@@ -101,6 +102,265 @@ impl Decodable for BlockHeader {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderDecodeErrorReason {
+    UnexpectedEnd,
+    ExpectedList,
+    ExpectedString,
+    /// Length prefix is not the shortest possible one.
+    NonCanonicalSize,
+    /// Integer with leading zero bytes.
+    NonCanonicalInteger,
+    IntegerOverflow,
+    InvalidLength {
+        expected: usize,
+        got: usize,
+    },
+    TooFewFields,
+    TooManyFields,
+    TrailingBytes,
+}
+
+/// Error of [`BlockHeader::decode_checked`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderDecodeError {
+    /// Offset of the offending item in the input.
+    pub offset: usize,
+    pub reason: HeaderDecodeErrorReason,
+}
+
+impl Display for HeaderDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed header RLP at offset {}: {:?}",
+            self.offset, self.reason
+        )
+    }
+}
+
+impl std::error::Error for HeaderDecodeError {}
+
+struct RlpItem<'a> {
+    offset: usize,
+    is_list: bool,
+    payload: &'a [u8],
+    payload_offset: usize,
+    end: usize,
+}
+
+fn read_rlp_length(
+    bytes: &[u8],
+    offset: usize,
+    len_of_len: usize,
+) -> Result<usize, HeaderDecodeError> {
+    let err = |reason| HeaderDecodeError { offset, reason };
+
+    let len_bytes = bytes
+        .get(offset + 1..offset + 1 + len_of_len)
+        .ok_or_else(|| err(HeaderDecodeErrorReason::UnexpectedEnd))?;
+    if len_bytes[0] == 0 {
+        return Err(err(HeaderDecodeErrorReason::NonCanonicalSize));
+    }
+    if len_of_len > std::mem::size_of::<usize>() {
+        return Err(err(HeaderDecodeErrorReason::IntegerOverflow));
+    }
+
+    let len = len_bytes
+        .iter()
+        .fold(0_usize, |acc, &b| (acc << 8) | b as usize);
+    if len < 56 {
+        return Err(err(HeaderDecodeErrorReason::NonCanonicalSize));
+    }
+
+    Ok(len)
+}
+
+fn read_rlp_item(bytes: &[u8], offset: usize) -> Result<RlpItem<'_>, HeaderDecodeError> {
+    let err = |reason| HeaderDecodeError { offset, reason };
+
+    let prefix = *bytes
+        .get(offset)
+        .ok_or_else(|| err(HeaderDecodeErrorReason::UnexpectedEnd))?;
+    let (is_list, header_len, payload_len) = match prefix {
+        0x00..=0x7f => (false, 0, 1),
+        0x80..=0xb7 => (false, 1, (prefix - 0x80) as usize),
+        0xb8..=0xbf => {
+            let len_of_len = (prefix - 0xb7) as usize;
+            (
+                false,
+                1 + len_of_len,
+                read_rlp_length(bytes, offset, len_of_len)?,
+            )
+        }
+        0xc0..=0xf7 => (true, 1, (prefix - 0xc0) as usize),
+        0xf8..=0xff => {
+            let len_of_len = (prefix - 0xf7) as usize;
+            (
+                true,
+                1 + len_of_len,
+                read_rlp_length(bytes, offset, len_of_len)?,
+            )
+        }
+    };
+
+    let payload_offset = offset + header_len;
+    let end = payload_offset
+        .checked_add(payload_len)
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| err(HeaderDecodeErrorReason::UnexpectedEnd))?;
+    let payload = &bytes[payload_offset..end];
+
+    // a single byte below 0x80 is its own encoding
+    if !is_list && header_len == 1 && payload_len == 1 && payload[0] < 0x80 {
+        return Err(err(HeaderDecodeErrorReason::NonCanonicalSize));
+    }
+
+    Ok(RlpItem {
+        offset,
+        is_list,
+        payload,
+        payload_offset,
+        end,
+    })
+}
+
+/// Sequential reader of the string items inside of an RLP list.
+struct RlpFields<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RlpFields<'a> {
+    fn has_next(&self) -> bool {
+        self.pos < self.bytes.len()
+    }
+
+    fn next_string(&mut self) -> Result<&'a [u8], HeaderDecodeError> {
+        if !self.has_next() {
+            return Err(HeaderDecodeError {
+                offset: self.pos,
+                reason: HeaderDecodeErrorReason::TooFewFields,
+            });
+        }
+
+        let item = read_rlp_item(self.bytes, self.pos)?;
+        if item.is_list {
+            return Err(HeaderDecodeError {
+                offset: item.offset,
+                reason: HeaderDecodeErrorReason::ExpectedString,
+            });
+        }
+        self.pos = item.end;
+
+        Ok(item.payload)
+    }
+
+    fn next_fixed(&mut self, len: usize) -> Result<&'a [u8], HeaderDecodeError> {
+        let offset = self.pos;
+        let payload = self.next_string()?;
+        if payload.len() != len {
+            return Err(HeaderDecodeError {
+                offset,
+                reason: HeaderDecodeErrorReason::InvalidLength {
+                    expected: len,
+                    got: payload.len(),
+                },
+            });
+        }
+
+        Ok(payload)
+    }
+
+    fn next_uint(&mut self, max_len: usize) -> Result<&'a [u8], HeaderDecodeError> {
+        let offset = self.pos;
+        let payload = self.next_string()?;
+        if payload.len() > max_len {
+            return Err(HeaderDecodeError {
+                offset,
+                reason: HeaderDecodeErrorReason::IntegerOverflow,
+            });
+        }
+        if payload.first() == Some(&0) {
+            return Err(HeaderDecodeError {
+                offset,
+                reason: HeaderDecodeErrorReason::NonCanonicalInteger,
+            });
+        }
+
+        Ok(payload)
+    }
+
+    fn next_u64(&mut self) -> Result<u64, HeaderDecodeError> {
+        Ok(self
+            .next_uint(8)?
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    fn next_u256(&mut self) -> Result<U256, HeaderDecodeError> {
+        Ok(U256::from_big_endian(self.next_uint(32)?))
+    }
+}
+
+impl BlockHeader {
+    /// Strict decoding of an untrusted header.
+    /// Unlike the `Decodable` implementation, this rejects trailing bytes,
+    /// extra fields and non-canonical encodings, and reports where the input is malformed.
+    pub fn decode_checked(bytes: &[u8]) -> Result<Self, HeaderDecodeError> {
+        let list = read_rlp_item(bytes, 0)?;
+        if !list.is_list {
+            return Err(HeaderDecodeError {
+                offset: 0,
+                reason: HeaderDecodeErrorReason::ExpectedList,
+            });
+        }
+        if list.end != bytes.len() {
+            return Err(HeaderDecodeError {
+                offset: list.end,
+                reason: HeaderDecodeErrorReason::TrailingBytes,
+            });
+        }
+
+        let mut fields = RlpFields {
+            bytes: &bytes[..list.end],
+            pos: list.payload_offset,
+        };
+
+        let header = Self {
+            parent_hash: H256::from_slice(fields.next_fixed(H256::len_bytes())?),
+            ommers_hash: H256::from_slice(fields.next_fixed(H256::len_bytes())?),
+            beneficiary: H160::from_slice(fields.next_fixed(H160::len_bytes())?),
+            state_root: H256::from_slice(fields.next_fixed(H256::len_bytes())?),
+            transactions_root: H256::from_slice(fields.next_fixed(H256::len_bytes())?),
+            receipts_root: H256::from_slice(fields.next_fixed(H256::len_bytes())?),
+            logs_bloom: Bloom::from_slice(fields.next_fixed(Bloom::len_bytes())?),
+            difficulty: fields.next_u256()?,
+            number: BlockNumber(fields.next_u64()?),
+            gas_limit: fields.next_u64()?,
+            gas_used: fields.next_u64()?,
+            timestamp: fields.next_u64()?,
+            extra_data: Bytes::copy_from_slice(fields.next_string()?),
+            mix_hash: H256::from_slice(fields.next_fixed(H256::len_bytes())?),
+            nonce: H64::from_slice(fields.next_fixed(H64::len_bytes())?),
+            base_fee_per_gas: if fields.has_next() {
+                Some(fields.next_u256()?)
+            } else {
+                None
+            },
+        };
+
+        if fields.has_next() {
+            return Err(HeaderDecodeError {
+                offset: fields.pos,
+                reason: HeaderDecodeErrorReason::TooManyFields,
+            });
+        }
+
+        Ok(header)
+    }
+}
+
 impl BlockHeader {
     #[must_use]
     pub fn new(partial_header: PartialHeader, ommers_hash: H256, transactions_root: H256) -> Self {
@@ -280,3 +540,112 @@ impl PartialHeader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    fn sample_header() -> BlockHeader {
+        BlockHeader {
+            parent_hash: H256::repeat_byte(0x11),
+            ommers_hash: H256::repeat_byte(0x22),
+            beneficiary: H160::repeat_byte(0x33),
+            difficulty: 0x020000.into(),
+            number: BlockNumber(1),
+            gas_limit: 5000,
+            gas_used: 0,
+            timestamp: 0x55ba4224,
+            extra_data: Bytes::from_static(b"Geth/v1.0.0/linux/go1.4.2"),
+            nonce: H64::repeat_byte(0x44),
+            base_fee_per_gas: Some(7.into()),
+            ..BlockHeader::empty()
+        }
+    }
+
+    #[test]
+    fn decode_checked_roundtrip() {
+        for header in [
+            sample_header(),
+            BlockHeader {
+                base_fee_per_gas: None,
+                ..sample_header()
+            },
+        ] {
+            let encoded = rlp::encode(&header);
+            assert_eq!(BlockHeader::decode_checked(&encoded).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn decode_checked_rejects_trailing_bytes() {
+        let mut encoded = rlp::encode(&sample_header()).to_vec();
+        let len = encoded.len();
+        encoded.push(0x80);
+        assert_eq!(
+            BlockHeader::decode_checked(&encoded),
+            Err(HeaderDecodeError {
+                offset: len,
+                reason: HeaderDecodeErrorReason::TrailingBytes,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_checked_rejects_non_canonical_integer() {
+        let header = sample_header();
+        let mut s = RlpStream::new_list(15);
+        s.append(&header.parent_hash);
+        s.append(&header.ommers_hash);
+        s.append(&header.beneficiary);
+        s.append(&header.state_root);
+        s.append(&header.transactions_root);
+        s.append(&header.receipts_root);
+        s.append(&header.logs_bloom);
+        s.append(&header.difficulty);
+        // number with a leading zero
+        s.append(&&[0_u8, 1][..]);
+        s.append(&header.gas_limit);
+        s.append(&header.gas_used);
+        s.append(&header.timestamp);
+        s.append(&header.extra_data.as_ref());
+        s.append(&header.mix_hash);
+        s.append(&header.nonce);
+        let encoded = s.out();
+
+        assert_eq!(
+            BlockHeader::decode_checked(&encoded).unwrap_err().reason,
+            HeaderDecodeErrorReason::NonCanonicalInteger
+        );
+    }
+
+    #[test]
+    fn decode_checked_rejects_garbage() {
+        let encoded = rlp::encode(&sample_header());
+        for len in 0..encoded.len() {
+            assert!(BlockHeader::decode_checked(&encoded[..len]).is_err());
+        }
+
+        assert_eq!(
+            BlockHeader::decode_checked(&hex!("8180"))
+                .unwrap_err()
+                .reason,
+            HeaderDecodeErrorReason::ExpectedList
+        );
+        assert_eq!(
+            BlockHeader::decode_checked(&hex!("c0")).unwrap_err(),
+            HeaderDecodeError {
+                offset: 1,
+                reason: HeaderDecodeErrorReason::TooFewFields,
+            }
+        );
+        // single byte below 0x80 must not have a length prefix
+        assert_eq!(
+            BlockHeader::decode_checked(&hex!("c28101")).unwrap_err(),
+            HeaderDecodeError {
+                offset: 1,
+                reason: HeaderDecodeErrorReason::NonCanonicalSize,
+            }
+        );
+    }
+}
@@ -24,6 +24,17 @@ impl ChainConfig {
         }
     }
 
+    /// Config of a chain which genesis is built elsewhere, e.g. a private chain.
+    pub fn with_genesis_block_hash(
+        chain_spec: ChainSpec,
+        genesis_block_hash: ethereum_types::H256,
+    ) -> Self {
+        Self {
+            chain_spec,
+            genesis_block_hash,
+        }
+    }
+
     pub fn network_id(&self) -> NetworkId {
         self.chain_spec.params.network_id
     }
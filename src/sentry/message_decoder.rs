@@ -1,4 +1,60 @@
-use super::messages::*;
+use super::{messages::*, sentry_client::PeerId};
+use std::fmt;
+
+/// Decompressed messages above this are rejected, like the devp2p limit of an uncompressed message.
+pub const MAX_DECOMPRESSED_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum PayloadDecompressError {
+    /// The size declared in the snappy header is above the limit.
+    Oversized {
+        declared: usize,
+        max: usize,
+    },
+    Snappy(snap::Error),
+}
+
+impl fmt::Display for PayloadDecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for PayloadDecompressError {}
+
+/// A message payload of the peer was rejected, so the peer should be penalized.
+#[derive(Debug)]
+pub struct RejectedPayloadError {
+    pub from_peer_id: Option<PeerId>,
+    pub error: PayloadDecompressError,
+}
+
+impl fmt::Display for RejectedPayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for RejectedPayloadError {}
+
+/// Decompresses a snappy-compressed payload of the eth protocol version 64+.
+/// The declared size is checked before allocating, so that a peer can't make us allocate
+/// gigabytes with a few bytes of a decompression bomb.
+pub fn decompress_snappy_payload(
+    payload: &[u8],
+    max_decompressed_size: usize,
+) -> Result<Vec<u8>, PayloadDecompressError> {
+    let declared = snap::raw::decompress_len(payload).map_err(PayloadDecompressError::Snappy)?;
+    if declared > max_decompressed_size {
+        return Err(PayloadDecompressError::Oversized {
+            declared,
+            max: max_decompressed_size,
+        });
+    }
+    snap::raw::Decoder::new()
+        .decompress_vec(payload)
+        .map_err(PayloadDecompressError::Snappy)
+}
 
 pub fn decode_rlp_message(id: EthMessageId, message_bytes: &[u8]) -> anyhow::Result<Message> {
     let message: Message = match id {
@@ -36,16 +92,48 @@ impl rlp::Encodable for Message {
 mod tests {
     use super::super::{
         block_id::BlockId,
-        message_decoder::decode_rlp_message,
+        message_decoder::{
+            decode_rlp_message, decompress_snappy_payload, PayloadDecompressError,
+            MAX_DECOMPRESSED_PAYLOAD_SIZE,
+        },
         messages::{
-            BlockHashAndNumber, EthMessageId, GetBlockHeadersMessage, GetBlockHeadersMessageParams,
-            Message, NewBlockHashesMessage,
+            BlockHashAndNumber, BlockHeadersMessage, EthMessageId, GetBlockHeadersMessage,
+            GetBlockHeadersMessageParams, Message, NewBlockHashesMessage,
         },
     };
-    use crate::models::BlockNumber;
+    use crate::models::{BlockHeader, BlockNumber, HeaderDecodeError, HeaderDecodeErrorReason};
     use ethereum_types::H256;
     use hex_literal::hex;
 
+    #[test]
+    fn decompress_snappy() {
+        let message = hex!("ca820457c682270f050580");
+        let payload = snap::raw::Encoder::new().compress_vec(&message).unwrap();
+        assert_eq!(
+            decompress_snappy_payload(&payload, MAX_DECOMPRESSED_PAYLOAD_SIZE).unwrap(),
+            message
+        );
+        assert!(matches!(
+            decompress_snappy_payload(&payload, message.len() - 1),
+            Err(PayloadDecompressError::Oversized { declared, .. }) if declared == message.len()
+        ));
+
+        // a few bytes declaring 1 GiB, as the varint header
+        let bomb = [0x80, 0x80, 0x80, 0x80, 0x04, 0x00];
+        assert!(matches!(
+            decompress_snappy_payload(&bomb, MAX_DECOMPRESSED_PAYLOAD_SIZE),
+            Err(PayloadDecompressError::Oversized {
+                declared: 0x4000_0000,
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            decompress_snappy_payload(&[0xff], MAX_DECOMPRESSED_PAYLOAD_SIZE),
+            Err(PayloadDecompressError::Snappy(_))
+        ));
+    }
+
     #[test]
     fn decode_new_block_hashes() {
         let expected_bytes =
@@ -118,4 +206,33 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn decode_block_headers_malformed() {
+        let header = BlockHeader {
+            number: BlockNumber(1),
+            gas_limit: 5000,
+            ..BlockHeader::empty()
+        };
+
+        let mut stream = rlp::RlpStream::new_list(2);
+        stream.append(&7_u64);
+        stream.begin_list(2);
+        stream.append(&header);
+        stream.append_raw(&hex!("c0"), 1);
+        let bytes = stream.out();
+
+        let message = decode_rlp_message(EthMessageId::BlockHeaders, &bytes).unwrap();
+        assert_eq!(
+            message,
+            Message::BlockHeaders(BlockHeadersMessage {
+                request_id: 7,
+                headers: vec![header],
+                malformed: Some(HeaderDecodeError {
+                    offset: 1,
+                    reason: HeaderDecodeErrorReason::TooFewFields,
+                }),
+            })
+        );
+    }
 }
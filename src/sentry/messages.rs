@@ -1,6 +1,9 @@
 use super::block_id::BlockId;
-use crate::models::{Block as BlockType, BlockHeader as HeaderType, BlockNumber};
+use crate::models::{
+    Block as BlockType, BlockHeader as HeaderType, BlockNumber, HeaderDecodeError,
+};
 use ethereum_types::H256;
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
 use rlp_derive::*;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, strum::EnumIter)]
@@ -47,10 +50,53 @@ pub struct GetBlockHeadersMessageParams {
     pub reverse: u8,
 }
 
-#[derive(RlpEncodable, RlpDecodable, Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct BlockHeadersMessage {
     pub request_id: u64,
     pub headers: Vec<HeaderType>,
+    /// The headers are decoded with `BlockHeader::decode_checked`.
+    /// If one of them is malformed, `headers` contains the ones before it.
+    pub malformed: Option<HeaderDecodeError>,
+}
+
+impl Encodable for BlockHeadersMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2);
+        s.append(&self.request_id);
+        s.append_list(&self.headers);
+    }
+}
+
+impl Decodable for BlockHeadersMessage {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 2 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let request_id = rlp.val_at(0)?;
+
+        let headers_rlp = rlp.at(1)?;
+        if !headers_rlp.is_list() {
+            return Err(DecoderError::RlpExpectedToBeList);
+        }
+
+        let mut headers = Vec::new();
+        let mut malformed = None;
+        for header_rlp in headers_rlp.iter() {
+            match HeaderType::decode_checked(header_rlp.as_raw()) {
+                Ok(header) => headers.push(header),
+                Err(error) => {
+                    malformed = Some(error);
+                    break;
+                }
+            }
+        }
+
+        Ok(Self {
+            request_id,
+            headers,
+            malformed,
+        })
+    }
 }
 
 #[derive(RlpEncodable, RlpDecodable, Clone, PartialEq, Debug)]
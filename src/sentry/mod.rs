@@ -1,6 +1,6 @@
 pub mod block_id;
 pub mod chain_config;
-mod message_decoder;
+pub mod message_decoder;
 pub mod messages;
 pub mod sentry_address;
 pub mod sentry_client;
@@ -5,7 +5,7 @@ use super::{
 use crate::models::BlockNumber;
 use async_trait::async_trait;
 use futures_core::Stream;
-use std::{fmt::Debug, pin::Pin};
+use std::{fmt::Debug, pin::Pin, time::Duration};
 
 #[derive(Clone, Debug)]
 pub struct Status {
@@ -17,7 +17,7 @@ pub struct Status {
 
 pub type PeerId = ethereum_types::H512;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PeerFilter {
     MinBlock(u64),
     PeerId(PeerId),
@@ -25,6 +25,41 @@ pub enum PeerFilter {
     All,
 }
 
+/// How long to wait for a response before requesting again, per request message type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestTimeouts {
+    /// GetBlockHeaders responses are small, and come quickly (5 sec by default).
+    pub get_block_headers: Duration,
+    /// GetBlockBodies responses are much larger, and take longer to transfer (15 sec by default).
+    pub get_block_bodies: Duration,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            get_block_headers: Duration::from_secs(5),
+            get_block_bodies: Duration::from_secs(15),
+        }
+    }
+}
+
+impl RequestTimeouts {
+    /// The timeout for a request retry attempt. It grows with the attempts up to 6 times the base timeout.
+    /// Requests other than GetBlockBodies use the GetBlockHeaders timeout.
+    pub fn timeout(&self, request_id: EthMessageId, attempt: u16) -> Duration {
+        let base = match request_id {
+            EthMessageId::GetBlockBodies => self.get_block_bodies,
+            _ => self.get_block_headers,
+        };
+        match attempt {
+            0 => base,
+            1 => base * 2,
+            2 => base * 3,
+            _ => base * 6,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MessageFromPeer {
     pub message: Message,
@@ -51,3 +86,29 @@ pub trait SentryClient: Send + Debug {
         filter_ids: &[EthMessageId],
     ) -> anyhow::Result<MessageFromPeerStream>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_timeouts() {
+        let timeouts = RequestTimeouts::default();
+        assert_eq!(
+            timeouts.timeout(EthMessageId::GetBlockHeaders, 0),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            timeouts.timeout(EthMessageId::GetBlockHeaders, 3),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            timeouts.timeout(EthMessageId::GetBlockBodies, 1),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            timeouts.timeout(EthMessageId::GetNodeData, 2),
+            Duration::from_secs(15)
+        );
+    }
+}
@@ -17,11 +17,20 @@ pub trait SentryClientConnector: Send {
 
 pub struct SentryClientConnectorImpl {
     sentry_api_addr: SentryAddress,
+    max_decompressed_payload_size: Option<usize>,
 }
 
 impl SentryClientConnectorImpl {
     pub fn new(sentry_api_addr: SentryAddress) -> Self {
-        Self { sentry_api_addr }
+        Self {
+            sentry_api_addr,
+            max_decompressed_payload_size: None,
+        }
+    }
+
+    /// See `SentryClientImpl::set_snappy_payloads`.
+    pub fn set_snappy_payloads(&mut self, max_decompressed_payload_size: Option<usize>) {
+        self.max_decompressed_payload_size = max_decompressed_payload_size;
     }
 }
 
@@ -35,6 +44,7 @@ impl SentryClientConnector for SentryClientConnectorImpl {
             let result = SentryClientImpl::new(sentry_api_addr).await;
             match result {
                 Ok(mut client) => {
+                    client.set_snappy_payloads(self.max_decompressed_payload_size);
                     let status_result = client.set_status(status.clone()).await;
                     match status_result {
                         Ok(_) => return Ok(Box::new(client)),
@@ -9,13 +9,23 @@ use tracing::*;
 #[derive(Debug)]
 pub struct SentryClientImpl {
     client: grpc_sentry::sentry_client::SentryClient<tonic::transport::channel::Channel>,
+    max_decompressed_payload_size: Option<usize>,
 }
 
 impl SentryClientImpl {
     pub async fn new(addr: SentryAddress) -> anyhow::Result<Self> {
         info!("SentryClient connecting to {}...", addr.addr);
         let client = grpc_sentry::sentry_client::SentryClient::connect(addr.addr).await?;
-        Ok(SentryClientImpl { client })
+        Ok(SentryClientImpl {
+            client,
+            max_decompressed_payload_size: None,
+        })
+    }
+
+    /// Expect the payloads of the received messages snappy-compressed,
+    /// unless the sentry decompresses them. Larger decompressed payloads are rejected.
+    pub fn set_snappy_payloads(&mut self, max_decompressed_payload_size: Option<usize>) {
+        self.max_decompressed_payload_size = max_decompressed_payload_size;
     }
 }
 
@@ -130,7 +140,8 @@ impl SentryClient for SentryClientImpl {
         let tonic_stream = tonic_stream_fuse_on_error(tonic_stream);
         debug!("SentryClient receive_messages subscribed to incoming messages");
 
-        let stream = tonic_stream.map(|result: Result<grpc_sentry::InboundMessage, tonic::Status>| -> anyhow::Result<MessageFromPeer> {
+        let max_decompressed_payload_size = self.max_decompressed_payload_size;
+        let stream = tonic_stream.map(move |result: Result<grpc_sentry::InboundMessage, tonic::Status>| -> anyhow::Result<MessageFromPeer> {
             match result {
                 Ok(inbound_message) => {
                     let grpc_message_id = grpc_sentry::MessageId::from_i32(inbound_message.id)
@@ -138,7 +149,15 @@ impl SentryClient for SentryClientImpl {
                     let message_id = EthMessageId::try_from(grpc_message_id)?;
                     let grpc_peer_id: Option<grpc_types::H512> = inbound_message.peer_id;
                     let peer_id: Option<PeerId> = grpc_peer_id.map(ethereum_types::H512::from);
-                    let message_bytes: bytes::Bytes = inbound_message.data;
+                    let mut message_bytes: bytes::Bytes = inbound_message.data;
+                    if let Some(max_decompressed_payload_size) = max_decompressed_payload_size {
+                        message_bytes = message_decoder::decompress_snappy_payload(message_bytes.as_ref(), max_decompressed_payload_size)
+                            .map_err(|error| message_decoder::RejectedPayloadError {
+                                from_peer_id: peer_id,
+                                error,
+                            })?
+                            .into();
+                    }
                     let message = message_decoder::decode_rlp_message(message_id, message_bytes.as_ref())?;
                     let message_from_peer = MessageFromPeer {
                         message,
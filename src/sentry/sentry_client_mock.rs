@@ -11,6 +11,7 @@ use tokio_stream::{wrappers, StreamExt};
 pub struct SentryClientMock {
     message_sender: Option<broadcast::Sender<MessageFromPeer>>,
     message_receiver: Option<broadcast::Receiver<MessageFromPeer>>,
+    is_unresponsive: bool,
 }
 
 impl SentryClientMock {
@@ -19,6 +20,15 @@ impl SentryClientMock {
         SentryClientMock {
             message_sender: Some(message_sender),
             message_receiver: Some(message_receiver),
+            is_unresponsive: false,
+        }
+    }
+
+    /// Accepts the requests, but never replies, and never ends the message stream.
+    pub fn new_unresponsive() -> Self {
+        Self {
+            is_unresponsive: true,
+            ..Self::new()
         }
     }
 
@@ -42,7 +52,9 @@ impl SentryClient for SentryClientMock {
         _message: Message,
         _peer_filter: PeerFilter,
     ) -> anyhow::Result<u32> {
-        self.stop_receiving_messages();
+        if !self.is_unresponsive {
+            self.stop_receiving_messages();
+        }
         Ok(1)
     }
 
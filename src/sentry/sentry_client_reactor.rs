@@ -1,4 +1,5 @@
 use super::{
+    message_decoder::RejectedPayloadError,
     messages::{EthMessageId, Message},
     sentry_client::*,
     sentry_client_connector,
@@ -7,7 +8,7 @@ use futures_core::{Future, Stream};
 use futures_util::{FutureExt, TryStreamExt};
 use parking_lot::RwLock;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt,
     fmt::{Debug, Formatter},
     pin::Pin,
@@ -35,10 +36,15 @@ pub struct SentryClientReactor {
     event_loop: Mutex<Option<SentryClientReactorEventLoop>>,
     event_loop_handle: Option<JoinHandle<()>>,
     stop_signal_sender: mpsc::Sender<()>,
+    request_timeouts: RequestTimeouts,
+    preferred_peers: Vec<PeerId>,
+    penalized_peers: RwLock<HashSet<PeerId>>,
 }
 
 struct SentryClientReactorEventLoop {
     sentry_connector: sentry_client_connector::SentryClientConnectorStream,
+    /// To penalize the peers of the rejected payloads.
+    send_message_sender: mpsc::Sender<SentryCommand>,
     send_message_receiver: mpsc::Receiver<SentryCommand>,
     receive_messages_senders: ReceiveMessagesSenders,
     stop_signal_receiver: mpsc::Receiver<()>,
@@ -54,6 +60,8 @@ enum SentryCommand {
 struct SendMessageParams {
     message: Message,
     peer_filter: PeerFilter,
+    /// Tried one by one before the peer_filter until one of them is connected.
+    preferred_peers: Vec<PeerId>,
 }
 
 #[derive(Debug)]
@@ -92,6 +100,7 @@ impl SentryClientReactor {
 
         let event_loop = SentryClientReactorEventLoop {
             sentry_connector: sentry_connector_stream,
+            send_message_sender: send_message_sender.clone(),
             send_message_receiver,
             receive_messages_senders: Arc::clone(&receive_messages_senders),
             stop_signal_receiver,
@@ -103,9 +112,35 @@ impl SentryClientReactor {
             event_loop: Mutex::new(Some(event_loop)),
             event_loop_handle: None,
             stop_signal_sender,
+            request_timeouts: RequestTimeouts::default(),
+            preferred_peers: Vec::new(),
+            penalized_peers: RwLock::new(HashSet::new()),
         }
     }
 
+    pub fn set_request_timeouts(&mut self, request_timeouts: RequestTimeouts) {
+        self.request_timeouts = request_timeouts;
+    }
+
+    pub fn request_timeouts(&self) -> &RequestTimeouts {
+        &self.request_timeouts
+    }
+
+    /// Peers to send the requests to first, e.g. own well-connected nodes.
+    pub fn set_preferred_peers(&mut self, preferred_peers: Vec<PeerId>) {
+        self.preferred_peers = preferred_peers;
+    }
+
+    /// The preferred peers which were not penalized.
+    pub fn preferred_peers(&self) -> Vec<PeerId> {
+        let penalized_peers = self.penalized_peers.read();
+        self.preferred_peers
+            .iter()
+            .filter(|peer_id| !penalized_peers.contains(peer_id))
+            .copied()
+            .collect()
+    }
+
     pub fn into_shared(self) -> SentryClientReactorShared {
         Arc::new(tokio::sync::RwLock::new(self))
     }
@@ -148,6 +183,7 @@ impl SentryClientReactor {
     }
 
     pub async fn penalize_peer(&self, peer_id: PeerId) -> anyhow::Result<()> {
+        self.penalized_peers.write().insert(peer_id);
         let command = SentryCommand::PenalizePeer(peer_id);
         let result = self.send_message_sender.send(command).await;
         result.map_err(|_| anyhow::Error::new(SendMessageError::ReactorStopped))
@@ -161,6 +197,7 @@ impl SentryClientReactor {
         let params = SendMessageParams {
             message,
             peer_filter,
+            preferred_peers: Vec::new(),
         };
         let command = SentryCommand::SendMessage(params);
         let result = self.send_message_sender.send(command).await;
@@ -172,10 +209,28 @@ impl SentryClientReactor {
         message: Message,
         peer_filter: PeerFilter,
     ) -> anyhow::Result<()> {
-        let params = SendMessageParams {
+        self.try_send_command(SendMessageParams {
             message,
             peer_filter,
-        };
+            preferred_peers: Vec::new(),
+        })
+    }
+
+    /// Sends to the first connected of the preferred peers,
+    /// or according to the peer_filter if none of them is available.
+    pub fn try_send_message_to_preferred(
+        &self,
+        message: Message,
+        peer_filter: PeerFilter,
+    ) -> anyhow::Result<()> {
+        self.try_send_command(SendMessageParams {
+            message,
+            peer_filter,
+            preferred_peers: self.preferred_peers(),
+        })
+    }
+
+    fn try_send_command(&self, params: SendMessageParams) -> anyhow::Result<()> {
         let command = SentryCommand::SendMessage(params);
         let result = self.send_message_sender.try_send(command);
         match result {
@@ -324,12 +379,20 @@ mod stream_factory {
         Box::pin(receive_stream.map_ok(EventLoopStreamResult::Receive))
     }
 
-    async fn send_sentry_command(
+    pub(super) async fn send_sentry_command(
         command: SentryCommand,
         sentry: &mut Box<dyn SentryClient>,
     ) -> anyhow::Result<u32> {
         match command {
             SentryCommand::SendMessage(params) => {
+                for peer_id in params.preferred_peers {
+                    let sent_peers_count = sentry
+                        .send_message(params.message.clone(), PeerFilter::PeerId(peer_id))
+                        .await?;
+                    if sent_peers_count > 0 {
+                        return Ok(sent_peers_count);
+                    }
+                }
                 sentry
                     .send_message(params.message, params.peer_filter)
                     .await
@@ -483,6 +546,16 @@ impl SentryClientReactorEventLoop {
                                 "SentryClientReactor.EventLoop receive message error: {}",
                                 error
                             );
+                            if let Some(RejectedPayloadError {
+                                from_peer_id: Some(peer_id),
+                                ..
+                            }) = error.downcast_ref::<RejectedPayloadError>()
+                            {
+                                let command = SentryCommand::PenalizePeer(*peer_id);
+                                if self.send_message_sender.try_send(command).is_err() {
+                                    warn!("SentryClientReactor.EventLoop failed to penalize {:?}: the send queue is full", peer_id);
+                                }
+                            }
                             if sentry_client_connector::is_disconnect_error(&error) {
                                 info!("SentryClientReactor.EventLoop reconnecting sentry streams");
                                 stream.remove(&EventLoopStreamId::Send);
@@ -520,3 +593,131 @@ mod tests {
         let _ = SyncTester(sentry);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{super::sentry_client_connector::SentryClientConnectorTest, *};
+    use crate::sentry::{
+        messages::{GetBlockHeadersMessage, GetBlockHeadersMessageParams},
+        sentry_client_mock::SentryClientMock,
+    };
+
+    /// Only the connected peers receive the messages sent by PeerId.
+    #[derive(Debug)]
+    struct RecordingSentryClient {
+        connected_peers: HashSet<PeerId>,
+        sent: Arc<RwLock<Vec<PeerFilter>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl SentryClient for RecordingSentryClient {
+        async fn set_status(&mut self, _status: Status) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn penalize_peer(&mut self, _peer_id: PeerId) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn send_message(
+            &mut self,
+            _message: Message,
+            peer_filter: PeerFilter,
+        ) -> anyhow::Result<u32> {
+            let sent_peers_count = match &peer_filter {
+                PeerFilter::PeerId(peer_id) => self.connected_peers.contains(peer_id) as u32,
+                _ => 1,
+            };
+            self.sent.write().push(peer_filter);
+            Ok(sent_peers_count)
+        }
+
+        async fn receive_messages(
+            &mut self,
+            _filter_ids: &[EthMessageId],
+        ) -> anyhow::Result<MessageFromPeerStream> {
+            Ok(Box::pin(tokio_stream::empty()))
+        }
+    }
+
+    fn request() -> Message {
+        Message::GetBlockHeaders(GetBlockHeadersMessage {
+            request_id: 1,
+            params: GetBlockHeadersMessageParams {
+                start_block: super::super::block_id::BlockId::Number(0.into()),
+                limit: 1,
+                skip: 0,
+                reverse: 0,
+            },
+        })
+    }
+
+    async fn send_to_preferred(
+        connected_peers: &[PeerId],
+        preferred_peers: &[PeerId],
+    ) -> Vec<PeerFilter> {
+        let sent = Arc::new(RwLock::new(Vec::new()));
+        let mut sentry: Box<dyn SentryClient> = Box::new(RecordingSentryClient {
+            connected_peers: connected_peers.iter().copied().collect(),
+            sent: sent.clone(),
+        });
+        let command = SentryCommand::SendMessage(SendMessageParams {
+            message: request(),
+            peer_filter: PeerFilter::Random(1),
+            preferred_peers: preferred_peers.to_vec(),
+        });
+        let sent_peers_count = stream_factory::send_sentry_command(command, &mut sentry)
+            .await
+            .unwrap();
+        assert_eq!(sent_peers_count, 1);
+
+        let sent = sent.read();
+        sent.clone()
+    }
+
+    #[tokio::test]
+    async fn preferred_peers_fallback() {
+        let peer1 = PeerId::repeat_byte(1);
+        let peer2 = PeerId::repeat_byte(2);
+
+        // the first connected preferred peer gets the message
+        assert_eq!(
+            send_to_preferred(&[peer2], &[peer1, peer2]).await,
+            vec![PeerFilter::PeerId(peer1), PeerFilter::PeerId(peer2)]
+        );
+
+        // none of the preferred peers are connected
+        assert_eq!(
+            send_to_preferred(&[], &[peer1, peer2]).await,
+            vec![
+                PeerFilter::PeerId(peer1),
+                PeerFilter::PeerId(peer2),
+                PeerFilter::Random(1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn penalized_preferred_peers() {
+        let chain_config = crate::sentry::chain_config::ChainsConfig::new()
+            .unwrap()
+            .get("mainnet")
+            .unwrap();
+        let status_provider =
+            crate::downloader::sentry_status_provider::SentryStatusProvider::new(chain_config);
+        let mut sentry = SentryClientReactor::new(
+            Box::new(SentryClientConnectorTest::new(Box::new(
+                SentryClientMock::new(),
+            ))),
+            status_provider.current_status_stream(),
+        );
+
+        let peer1 = PeerId::repeat_byte(1);
+        let peer2 = PeerId::repeat_byte(2);
+        sentry.set_preferred_peers(vec![peer1, peer2]);
+        assert_eq!(sentry.preferred_peers(), vec![peer1, peer2]);
+
+        sentry.penalize_peer(peer1).await.unwrap();
+        assert_eq!(sentry.preferred_peers(), vec![peer2]);
+    }
+}
@@ -1,9 +1,19 @@
 pub mod stage;
 pub mod stages;
+pub mod sync_status;
 
-use self::stage::{Stage, StageInput, UnwindInput};
-use crate::{kv::traits::*, stagedsync::stage::ExecOutput};
+use self::{
+    stage::{Committer, ReorgTooDeepError, RetryableError, Stage, StageInput, UnwindInput},
+    stages::{StageId, UNWIND},
+};
+use crate::{
+    kv::{tables, traits::*},
+    models::BlockNumber,
+    stagedsync::stage::ExecOutput,
+};
+use async_trait::async_trait;
 use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use tracing::*;
 
 /// Staged synchronization framework
@@ -21,6 +31,12 @@ use tracing::*;
 pub struct StagedSync<'db, DB: MutableKV> {
     stages: Vec<Box<dyn Stage<'db, DB::MutableTx<'db>>>>,
     min_progress_to_commit_after_stage: u64,
+    max_retries: usize,
+    retry_backoff: Duration,
+    target_block: Option<BlockNumber>,
+    max_reorg_depth: Option<u64>,
+    current_stage_sender: watch::Sender<Option<StageId>>,
+    current_stage_receiver: watch::Receiver<Option<StageId>>,
 }
 
 impl<'db, DB: MutableKV> Default for StagedSync<'db, DB> {
@@ -31,9 +47,16 @@ impl<'db, DB: MutableKV> Default for StagedSync<'db, DB> {
 
 impl<'db, DB: MutableKV> StagedSync<'db, DB> {
     pub fn new() -> Self {
+        let (current_stage_sender, current_stage_receiver) = watch::channel(None);
         Self {
             stages: Vec::new(),
             min_progress_to_commit_after_stage: 0,
+            max_retries: 0,
+            retry_backoff: Duration::from_secs(1),
+            target_block: None,
+            max_reorg_depth: None,
+            current_stage_sender,
+            current_stage_receiver,
         }
     }
 
@@ -49,26 +72,85 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
         self
     }
 
+    /// Execute a stage again up to this many times in a row if it fails with a retryable error.
+    pub fn set_max_retries(&mut self, v: usize) -> &mut Self {
+        self.max_retries = v;
+        self
+    }
+
+    /// Delay before the first retry, doubled for each next one.
+    pub fn set_retry_backoff(&mut self, v: Duration) -> &mut Self {
+        self.retry_backoff = v;
+        self
+    }
+
+    /// Do not let the stages go past this block, and stop the sync once all of them have reached it.
+    pub fn set_target_block(&mut self, v: Option<BlockNumber>) -> &mut Self {
+        self.target_block = v;
+        self
+    }
+
+    /// Refuse the unwinds deeper than this below the highest stage progress, and fail instead,
+    /// so that a peer can't trick the node into unwinding a lot of state.
+    /// The genuine deep reorgs, e.g. on the test networks, then need a manual intervention,
+    /// like a restart with a higher limit, so it shouldn't be set too low.
+    pub fn set_max_reorg_depth(&mut self, v: Option<u64>) -> &mut Self {
+        self.max_reorg_depth = v;
+        self
+    }
+
+    /// The stage which is being executed or unwound, None between the sync cycles.
+    pub fn watch_current_stage(&self) -> watch::Receiver<Option<StageId>> {
+        self.current_stage_receiver.clone()
+    }
+
+    /// The highest progress of the stages, the head the unwinds are measured from.
+    async fn max_stage_progress<'tx, Tx: Transaction<'db>>(
+        &self,
+        tx: &'tx Tx,
+    ) -> anyhow::Result<BlockNumber> {
+        let mut max_progress = BlockNumber(0);
+        for stage in &self.stages {
+            if let Some(progress) = stage.id().get_progress(tx).await? {
+                max_progress = std::cmp::max(max_progress, progress);
+            }
+        }
+        Ok(max_progress)
+    }
+
     /// Run staged sync loop.
     /// Invokes each loaded stage, and does unwinds if necessary.
     ///
-    /// NOTE: it should never return, except if the loop or any stage fails with error.
-    pub async fn run(&self, db: &'db DB) -> anyhow::Result<!> {
+    /// NOTE: it should never return, except if the loop or any stage fails with error,
+    /// or all stages have reached the target block if it is set.
+    pub async fn run(&self, db: &'db DB) -> anyhow::Result<()> {
         let num_stages = self.stages.len();
+        let _current_stage_guard = CurrentStageGuard(&self.current_stage_sender);
 
         let mut unwind_to = None;
+        // The stage failed with a retryable error, and how many times in a row.
+        let mut retried_stage: Option<(StageId, usize)> = None;
         'run_loop: loop {
             let mut tx = db.begin_mutable().await?;
 
+            // Resume the unwind interrupted by a restart.
+            if unwind_to.is_none() {
+                unwind_to = UNWIND.get_progress(&tx).await?;
+            }
+
             // Start with unwinding if it's been requested.
             if let Some(to) = unwind_to.take() {
+                // Remembered until the unwind of all the stages is committed.
+                UNWIND.save_progress(&tx, to).await?;
+
                 // Unwind stages in reverse order.
                 for (stage_index, stage) in self.stages.iter().enumerate().rev() {
                     let stage_id = stage.id();
 
                     // Unwind magic happens here.
                     // Encapsulated into a future for tracing instrumentation.
-                    let res: anyhow::Result<()> = async {
+                    let res: anyhow::Result<_> = async {
+                        let mut tx = tx;
                         let mut stage_progress =
                             stage_id.get_progress(&tx).await?.unwrap_or_default();
 
@@ -76,6 +158,7 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                             info!("UNWINDING from {}", stage_progress);
 
                             while stage_progress > to {
+                                let _ = self.current_stage_sender.send(Some(stage_id));
                                 let unwind_output = stage
                                     .unwind(
                                         &mut tx,
@@ -89,6 +172,15 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                 stage_progress = unwind_output.stage_progress;
 
                                 stage_id.save_progress(&tx, stage_progress).await?;
+
+                                // The stage unwinds in batches, and the finished ones are committed,
+                                // so that an interrupted unwind resumes from the last one.
+                                if unwind_output.must_commit && stage_progress > to {
+                                    debug!("Commit requested");
+                                    tx.commit().await?;
+                                    debug!("Commit complete");
+                                    tx = db.begin_mutable().await?;
+                                }
                             }
 
                             info!("DONE @ {}", stage_progress);
@@ -100,7 +192,7 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                             );
                         }
 
-                        Ok(())
+                        Ok(tx)
                     }
                     .instrument(span!(
                         Level::INFO,
@@ -112,15 +204,20 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                     ))
                     .await;
 
-                    res?;
+                    tx = res?;
                 }
 
+                let _ = self.current_stage_sender.send(None);
+                tx.del(tables::SyncStage, UNWIND, None).await?;
                 tx.commit().await?;
             } else {
                 // Now that we're done with unwind, let's roll.
 
                 let mut previous_stage = None;
                 let mut timings = vec![];
+                let mut reached_target = self.target_block.is_some();
+                // The first stage which is done without having caught up, e.g. after a single batch.
+                let mut stopped_short = None;
 
                 // Execute each stage in direct order.
                 for (stage_index, stage) in self.stages.iter().enumerate() {
@@ -131,10 +228,19 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                     let start_time = Instant::now();
                     let start_progress = stage_id.get_progress(&tx).await?;
 
-                    // Re-invoke the stage until it reports `StageOutput::done`.
+                    // Re-invoke the stage until it reports `StageOutput::done`, or reaches the target block.
                     let done_progress = loop {
                         let prev_progress = stage_id.get_progress(&tx).await?;
 
+                        // Unlike the next stages, the first one is not bounded by a previous stage.
+                        if let (Some(target_block), Some(prev_progress)) =
+                            (self.target_block, prev_progress)
+                        {
+                            if prev_progress >= target_block {
+                                break prev_progress;
+                            }
+                        }
+
                         let exec_output: anyhow::Result<_> = async {
                             if restarted {
                                 debug!(
@@ -153,15 +259,18 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                             }
 
                             let invocation_start_time = Instant::now();
-                            let output = stage
-                                .execute(
-                                    &mut tx,
+                            let _ = self.current_stage_sender.send(Some(stage_id));
+                            let committer = StageCommitter { db, stage_id };
+                            let (tx, output) = stage
+                                .execute_with_committer(
+                                    tx,
                                     StageInput {
                                         restarted,
                                         first_started_at: (start_time, start_progress),
                                         previous_stage,
                                         stage_progress: prev_progress,
                                     },
+                                    &committer,
                                 )
                                 .await?;
 
@@ -169,10 +278,17 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                             match &output {
                                 ExecOutput::Progress {
                                     done,
+                                    exhausted,
                                     stage_progress,
                                     ..
                                 } => {
-                                    if *done {
+                                    if *done && !*exhausted {
+                                        info!(
+                                            "STOPPED SHORT @ {} in {}",
+                                            stage_progress,
+                                            format_duration(Instant::now() - start_time, true)
+                                        );
+                                    } else if *done {
                                         info!(
                                             "DONE @ {} in {}",
                                             stage_progress,
@@ -202,7 +318,7 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                 }
                             }
 
-                            Ok(output)
+                            Ok((tx, output))
                         }
                         .instrument(span!(
                             Level::INFO,
@@ -214,13 +330,76 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                         ))
                         .await;
 
+                        let exec_output = match exec_output {
+                            Ok((new_tx, exec_output)) => {
+                                tx = new_tx;
+                                if matches!(retried_stage, Some((id, _)) if id.0 == stage_id.0) {
+                                    retried_stage = None;
+                                }
+                                exec_output
+                            }
+                            Err(e) if e.is_retryable() => {
+                                let retries = match retried_stage {
+                                    Some((id, retries)) if id.0 == stage_id.0 => retries + 1,
+                                    _ => 1,
+                                };
+                                if retries > self.max_retries {
+                                    return Err(e);
+                                }
+                                retried_stage = Some((stage_id, retries));
+
+                                let backoff =
+                                    self.retry_backoff * 2_u32.saturating_pow(retries as u32 - 1);
+                                warn!(
+                                    "Stage {} failed, retry {}/{} in {}: {:?}",
+                                    stage_id,
+                                    retries,
+                                    self.max_retries,
+                                    format_duration(backoff, true),
+                                    e
+                                );
+                                tokio::time::sleep(backoff).await;
+
+                                // The DB transaction with the partial stage changes is aborted,
+                                // along with the changes of the previous stages since the last commit,
+                                // so the stage is retried up to the committed progress of the previous one.
+                                tx = db.begin_mutable().await?;
+                                if let Some((previous_stage_id, _)) = previous_stage {
+                                    let previous_progress = previous_stage_id
+                                        .get_progress(&tx)
+                                        .await?
+                                        .unwrap_or_default();
+                                    previous_stage = Some((
+                                        previous_stage_id,
+                                        self.target_block
+                                            .map(|target_block| {
+                                                std::cmp::min(previous_progress, target_block)
+                                            })
+                                            .unwrap_or(previous_progress),
+                                    ));
+                                }
+                                restarted = true;
+                                continue;
+                            }
+                            Err(e) => return Err(e),
+                        };
+
                         // Check how stage run went.
-                        match exec_output? {
+                        match exec_output {
                             stage::ExecOutput::Progress {
                                 stage_progress,
                                 done,
+                                exhausted,
                                 must_commit,
                             } => {
+                                // Nothing bounds the first stage, so its progress is clamped to the target block.
+                                let stage_progress = match (previous_stage, self.target_block) {
+                                    (None, Some(target_block)) => {
+                                        std::cmp::min(stage_progress, target_block)
+                                    }
+                                    _ => stage_progress,
+                                };
+
                                 stage_id.save_progress(&tx, stage_progress).await?;
 
                                 // Stage requested that we commit into database now.
@@ -236,8 +415,17 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                     tx = db.begin_mutable().await?;
                                 }
 
+                                let reached_target_block = self
+                                    .target_block
+                                    .map_or(false, |target_block| stage_progress >= target_block);
+
+                                // A partial last batch, unlike a real completion.
+                                if done && !exhausted && !reached_target_block {
+                                    stopped_short.get_or_insert((stage_id, stage_progress));
+                                }
+
                                 // Stage is "done", that is cannot make any more progress at this time.
-                                if done {
+                                if done || reached_target_block {
                                     // Break out and move to the next stage.
                                     break stage_progress;
                                 }
@@ -245,6 +433,18 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                 restarted = true
                             }
                             stage::ExecOutput::Unwind { unwind_to: to } => {
+                                if let Some(max_reorg_depth) = self.max_reorg_depth {
+                                    let head = self.max_stage_progress(&tx).await?;
+                                    if head.saturating_sub(*to) > max_reorg_depth {
+                                        return Err(ReorgTooDeepError {
+                                            head,
+                                            unwind_to: to,
+                                            max_reorg_depth,
+                                        }
+                                        .into());
+                                    }
+                                }
+
                                 // Stage has asked us to unwind.
                                 // Set unwind point and restart the whole staged sync loop.
                                 // Current DB transaction will be aborted.
@@ -255,8 +455,19 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                     };
                     timings.push((stage_id, Instant::now() - start_time));
 
-                    previous_stage = Some((stage_id, done_progress))
+                    if let Some(target_block) = self.target_block {
+                        reached_target &= done_progress >= target_block;
+                    }
+
+                    // Next stages should not go past the target block.
+                    previous_stage = Some((
+                        stage_id,
+                        self.target_block
+                            .map(|target_block| std::cmp::min(done_progress, target_block))
+                            .unwrap_or(done_progress),
+                    ))
                 }
+                let _ = self.current_stage_sender.send(None);
                 tx.commit().await?;
 
                 let t = timings
@@ -264,12 +475,56 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                     .fold(String::new(), |acc, (stage_id, time)| {
                         format!("{} {}={}", acc, stage_id, format_duration(time, true))
                     });
-                info!("Staged sync complete.{}", t);
+                if let Some((stage_id, stage_progress)) = stopped_short {
+                    info!(
+                        "Staged sync cycle complete, {} stopped short at {}.{}",
+                        stage_id, stage_progress, t
+                    );
+                } else {
+                    info!("Staged sync complete.{}", t);
+                }
+
+                if reached_target {
+                    info!("All stages reached the target block, exiting.");
+                    return Ok(());
+                }
             }
         }
     }
 }
 
+/// Resets the current stage once the sync loop exits, whether it fails or not.
+struct CurrentStageGuard<'a>(&'a watch::Sender<Option<StageId>>);
+
+impl Drop for CurrentStageGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.send(None);
+    }
+}
+
+/// Commits on behalf of a stage in the middle of its execution.
+struct StageCommitter<'db, DB: MutableKV> {
+    db: &'db DB,
+    stage_id: StageId,
+}
+
+#[async_trait]
+impl<'db, DB: MutableKV> Committer<'db, DB::MutableTx<'db>> for StageCommitter<'db, DB> {
+    async fn commit(
+        &self,
+        tx: DB::MutableTx<'db>,
+        stage_progress: BlockNumber,
+    ) -> anyhow::Result<DB::MutableTx<'db>> {
+        self.stage_id.save_progress(&tx, stage_progress).await?;
+
+        debug!("Commit requested by the stage @ {}", stage_progress);
+        tx.commit().await?;
+        debug!("Commit complete");
+
+        self.db.begin_mutable().await
+    }
+}
+
 pub fn format_duration(dur: Duration, subsec_millis: bool) -> String {
     let mut secs = dur.as_secs();
     let mut minutes = secs / 60;
@@ -289,3 +544,532 @@ pub fn format_duration(dur: Duration, subsec_millis: bool) -> String {
         }
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{stage::*, *};
+    use crate::{kv::new_mem_database, models::*};
+    use anyhow::{bail, format_err};
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[derive(Debug)]
+    struct RecordingStage {
+        id: StageId,
+        current_stage: watch::Receiver<Option<StageId>>,
+        observed: Arc<Mutex<Vec<Option<&'static str>>>>,
+        last: bool,
+    }
+
+    #[async_trait]
+    impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for RecordingStage {
+        fn id(&self) -> StageId {
+            self.id
+        }
+
+        fn description(&self) -> &'static str {
+            ""
+        }
+
+        async fn execute<'tx>(&self, _: &'tx mut RwTx, _: StageInput) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            self.observed
+                .lock()
+                .push(self.current_stage.borrow().map(|id| id.0));
+
+            if self.last {
+                bail!("stop");
+            }
+
+            Ok(ExecOutput::Progress {
+                stage_progress: BlockNumber(1),
+                done: true,
+                exhausted: true,
+                must_commit: false,
+            })
+        }
+
+        async fn unwind<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            _: UnwindInput,
+        ) -> anyhow::Result<UnwindOutput>
+        where
+            'db: 'tx,
+        {
+            unreachable!()
+        }
+    }
+
+    #[derive(Debug)]
+    struct FlakyStage {
+        id: StageId,
+        transient_failures: AtomicUsize,
+        fatal: bool,
+        executions: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for FlakyStage {
+        fn id(&self) -> StageId {
+            self.id
+        }
+
+        fn description(&self) -> &'static str {
+            ""
+        }
+
+        async fn execute<'tx>(&self, _: &'tx mut RwTx, _: StageInput) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            self.executions.fetch_add(1, Ordering::SeqCst);
+
+            if self.fatal {
+                bail!("fatal");
+            }
+            if self
+                .transient_failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| v.checked_sub(1))
+                .is_ok()
+            {
+                return Err(TransientError(format_err!("disconnected")).into());
+            }
+
+            Ok(ExecOutput::Progress {
+                stage_progress: BlockNumber(1),
+                done: true,
+                exhausted: true,
+                must_commit: false,
+            })
+        }
+
+        async fn unwind<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            _: UnwindInput,
+        ) -> anyhow::Result<UnwindOutput>
+        where
+            'db: 'tx,
+        {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_transient_errors() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync
+            .set_max_retries(2)
+            .set_retry_backoff(Duration::from_millis(1));
+
+        let first_executions = Arc::new(AtomicUsize::new(0));
+        let flaky_executions = Arc::new(AtomicUsize::new(0));
+        let last_executions = Arc::new(AtomicUsize::new(0));
+        staged_sync.push(FlakyStage {
+            id: StageId("First"),
+            transient_failures: AtomicUsize::new(0),
+            fatal: false,
+            executions: first_executions.clone(),
+        });
+        staged_sync.push(FlakyStage {
+            id: StageId("Flaky"),
+            transient_failures: AtomicUsize::new(2),
+            fatal: false,
+            executions: flaky_executions.clone(),
+        });
+        staged_sync.push(FlakyStage {
+            id: StageId("Last"),
+            transient_failures: AtomicUsize::new(0),
+            fatal: true,
+            executions: last_executions.clone(),
+        });
+
+        let error = staged_sync.run(&db).await.unwrap_err();
+        assert_eq!(error.to_string(), "fatal");
+        // the failing stage is retried in place, the ones before it aren't executed again
+        assert_eq!(first_executions.load(Ordering::SeqCst), 1);
+        assert_eq!(flaky_executions.load(Ordering::SeqCst), 3);
+        assert_eq!(last_executions.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_limit() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync
+            .set_max_retries(2)
+            .set_retry_backoff(Duration::from_millis(1));
+
+        let executions = Arc::new(AtomicUsize::new(0));
+        staged_sync.push(FlakyStage {
+            id: StageId("Flaky"),
+            transient_failures: AtomicUsize::new(usize::MAX),
+            fatal: false,
+            executions: executions.clone(),
+        });
+
+        let error = staged_sync.run(&db).await.unwrap_err();
+        assert!(error.is_retryable());
+        assert_eq!(executions.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn retryable_grpc_codes() {
+        assert!(anyhow::Error::from(tonic::Status::unavailable("restarting")).is_retryable());
+        assert!(
+            !anyhow::Error::from(tonic::Status::invalid_argument("malformed request"))
+                .is_retryable()
+        );
+    }
+
+    /// Commits its progress, and fails before returning it.
+    #[derive(Debug)]
+    struct CheckpointingStage;
+
+    #[async_trait]
+    impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for CheckpointingStage {
+        fn id(&self) -> StageId {
+            StageId("Checkpointing")
+        }
+
+        fn description(&self) -> &'static str {
+            ""
+        }
+
+        async fn execute<'tx>(&self, _: &'tx mut RwTx, _: StageInput) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            unreachable!()
+        }
+
+        async fn execute_with_committer<'tx>(
+            &self,
+            tx: RwTx,
+            _: StageInput,
+            committer: &'tx dyn Committer<'db, RwTx>,
+        ) -> anyhow::Result<(RwTx, ExecOutput)>
+        where
+            'db: 'tx,
+            RwTx: 'tx,
+        {
+            let tx = committer.commit(tx, BlockNumber(5)).await?;
+            StageId("Checkpointing")
+                .save_progress(&tx, BlockNumber(6))
+                .await?;
+            bail!("stop");
+        }
+
+        async fn unwind<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            _: UnwindInput,
+        ) -> anyhow::Result<UnwindOutput>
+        where
+            'db: 'tx,
+        {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn commit_mid_execution() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.push(CheckpointingStage);
+        staged_sync.run(&db).await.unwrap_err();
+
+        // the progress after the commit is aborted
+        let tx = db.begin().await.unwrap();
+        assert_eq!(
+            StageId("Checkpointing").get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(5))
+        );
+    }
+
+    /// Follows the previous stage, or jumps to the block 10 if it is the first one.
+    #[derive(Debug)]
+    struct FollowingStage {
+        id: StageId,
+        observed: Arc<Mutex<Vec<Option<BlockNumber>>>>,
+    }
+
+    #[async_trait]
+    impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for FollowingStage {
+        fn id(&self) -> StageId {
+            self.id
+        }
+
+        fn description(&self) -> &'static str {
+            ""
+        }
+
+        async fn execute<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            input: StageInput,
+        ) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            let previous_stage = input.previous_stage.map(|(_, b)| b);
+            self.observed.lock().push(previous_stage);
+
+            Ok(ExecOutput::Progress {
+                stage_progress: previous_stage.unwrap_or(BlockNumber(10)),
+                done: true,
+                exhausted: true,
+                must_commit: false,
+            })
+        }
+
+        async fn unwind<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            _: UnwindInput,
+        ) -> anyhow::Result<UnwindOutput>
+        where
+            'db: 'tx,
+        {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn target_block() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.set_target_block(Some(BlockNumber(5)));
+
+        let observed = Arc::new(Mutex::new(vec![]));
+        for id in ["First", "Second", "Third"] {
+            staged_sync.push(FollowingStage {
+                id: StageId(id),
+                observed: observed.clone(),
+            });
+        }
+
+        staged_sync.run(&db).await.unwrap();
+        assert_eq!(
+            *observed.lock(),
+            vec![None, Some(BlockNumber(5)), Some(BlockNumber(5))]
+        );
+
+        let tx = db.begin().await.unwrap();
+        assert_eq!(
+            StageId("Third").get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(5))
+        );
+    }
+
+    /// Never exhausted, advances by 3 blocks per invocation. Done after each one if it runs in batches.
+    #[derive(Debug)]
+    struct EndlessStage {
+        executions: Arc<AtomicUsize>,
+        batches: bool,
+    }
+
+    #[async_trait]
+    impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for EndlessStage {
+        fn id(&self) -> StageId {
+            StageId("Endless")
+        }
+
+        fn description(&self) -> &'static str {
+            ""
+        }
+
+        async fn execute<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            input: StageInput,
+        ) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            self.executions.fetch_add(1, Ordering::SeqCst);
+
+            Ok(ExecOutput::Progress {
+                stage_progress: input.stage_progress.unwrap_or_default() + 3,
+                done: self.batches,
+                exhausted: false,
+                must_commit: false,
+            })
+        }
+
+        async fn unwind<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            _: UnwindInput,
+        ) -> anyhow::Result<UnwindOutput>
+        where
+            'db: 'tx,
+        {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn target_block_stops_first_stage() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.set_target_block(Some(BlockNumber(5)));
+
+        let executions = Arc::new(AtomicUsize::new(0));
+        staged_sync.push(EndlessStage {
+            executions: executions.clone(),
+            batches: false,
+        });
+        let observed = Arc::new(Mutex::new(vec![]));
+        staged_sync.push(FollowingStage {
+            id: StageId("Following"),
+            observed: observed.clone(),
+        });
+
+        staged_sync.run(&db).await.unwrap();
+        assert_eq!(executions.load(Ordering::SeqCst), 2);
+        assert_eq!(*observed.lock(), vec![Some(BlockNumber(5))]);
+
+        // the stage at the target is not invoked again
+        staged_sync.run(&db).await.unwrap();
+        assert_eq!(executions.load(Ordering::SeqCst), 2);
+
+        let tx = db.begin().await.unwrap();
+        assert_eq!(
+            StageId("Endless").get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(5))
+        );
+    }
+
+    #[tokio::test]
+    async fn target_block_after_partial_batches() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.set_target_block(Some(BlockNumber(5)));
+
+        let executions = Arc::new(AtomicUsize::new(0));
+        staged_sync.push(EndlessStage {
+            executions: executions.clone(),
+            batches: true,
+        });
+        let observed = Arc::new(Mutex::new(vec![]));
+        staged_sync.push(FollowingStage {
+            id: StageId("Following"),
+            observed: observed.clone(),
+        });
+
+        // a stage stopping short after a batch is not at the target yet, so the cycle is repeated
+        staged_sync.run(&db).await.unwrap();
+        assert_eq!(executions.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            *observed.lock(),
+            vec![Some(BlockNumber(3)), Some(BlockNumber(5))]
+        );
+    }
+
+    /// Asks to unwind to the block 2.
+    #[derive(Debug)]
+    struct ReorgingStage;
+
+    #[async_trait]
+    impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for ReorgingStage {
+        fn id(&self) -> StageId {
+            StageId("Reorging")
+        }
+
+        fn description(&self) -> &'static str {
+            ""
+        }
+
+        async fn execute<'tx>(&self, _: &'tx mut RwTx, _: StageInput) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            Ok(ExecOutput::Unwind {
+                unwind_to: BlockNumber(2),
+            })
+        }
+
+        async fn unwind<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            _: UnwindInput,
+        ) -> anyhow::Result<UnwindOutput>
+        where
+            'db: 'tx,
+        {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn max_reorg_depth() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        StageId("Reorging")
+            .save_progress(&tx, BlockNumber(10))
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.set_max_reorg_depth(Some(5));
+        staged_sync.push(ReorgingStage);
+
+        let error = staged_sync.run(&db).await.unwrap_err();
+        assert!(!error.is_retryable());
+        assert!(matches!(
+            error.downcast_ref::<ReorgTooDeepError>(),
+            Some(ReorgTooDeepError {
+                head: BlockNumber(10),
+                unwind_to: BlockNumber(2),
+                max_reorg_depth: 5,
+            })
+        ));
+
+        // nothing was unwound
+        let tx = db.begin().await.unwrap();
+        assert_eq!(
+            StageId("Reorging").get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(10))
+        );
+    }
+
+    #[tokio::test]
+    async fn current_stage() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        let current_stage = staged_sync.watch_current_stage();
+        assert!(current_stage.borrow().is_none());
+
+        let observed = Arc::new(Mutex::new(vec![]));
+        for (id, last) in [("First", false), ("Second", true)] {
+            staged_sync.push(RecordingStage {
+                id: StageId(id),
+                current_stage: current_stage.clone(),
+                observed: observed.clone(),
+                last,
+            });
+        }
+
+        staged_sync.run(&db).await.unwrap_err();
+        assert_eq!(*observed.lock(), vec![Some("First"), Some("Second")]);
+        // reset even though the sync failed
+        assert!(current_stage.borrow().is_none());
+    }
+}
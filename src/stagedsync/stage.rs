@@ -1,8 +1,11 @@
 use super::stages::StageId;
-use crate::{kv::traits::*, models::*};
+use crate::{kv::traits::*, models::*, sentry::sentry_client_reactor::SendMessageError};
 use async_trait::async_trait;
 use auto_impl::auto_impl;
-use std::{fmt::Debug, time::Instant};
+use std::{
+    fmt::{self, Debug},
+    time::Instant,
+};
 
 #[derive(Debug, PartialEq)]
 pub enum ExecOutput {
@@ -11,7 +14,11 @@ pub enum ExecOutput {
     },
     Progress {
         stage_progress: BlockNumber,
+        /// The stage should not be invoked again in this cycle.
         done: bool,
+        /// The stage has caught up with the previous one. Unlike `done`,
+        /// not set when the stage stops early, like after one batch.
+        exhausted: bool,
         must_commit: bool,
     },
 }
@@ -37,6 +44,24 @@ pub trait Stage<'db, RwTx: MutableTransaction<'db>>: Send + Sync + Debug {
     ) -> anyhow::Result<ExecOutput>
     where
         'db: 'tx;
+    /// Called by the staged sync instead of `execute`, with the ownership of the transaction,
+    /// so that a long-running stage can commit its progress at the safe points with the committer
+    /// without returning. Calls `execute` by default.
+    async fn execute_with_committer<'tx>(
+        &self,
+        tx: RwTx,
+        input: StageInput,
+        committer: &'tx dyn Committer<'db, RwTx>,
+    ) -> anyhow::Result<(RwTx, ExecOutput)>
+    where
+        'db: 'tx,
+        RwTx: 'tx,
+    {
+        let _ = committer;
+        let mut tx = tx;
+        let output = self.execute(&mut tx, input).await?;
+        Ok((tx, output))
+    }
     /// Called when the stage should be unwound. The unwind logic should be there.
     async fn unwind<'tx>(
         &self,
@@ -47,6 +72,12 @@ pub trait Stage<'db, RwTx: MutableTransaction<'db>>: Send + Sync + Debug {
         'db: 'tx;
 }
 
+#[async_trait]
+pub trait Committer<'db, RwTx: MutableTransaction<'db>>: Send + Sync {
+    /// Saves the stage progress, commits the transaction, and begins a new one.
+    async fn commit(&self, tx: RwTx, stage_progress: BlockNumber) -> anyhow::Result<RwTx>;
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct StageInput {
     pub restarted: bool,
@@ -60,3 +91,64 @@ pub struct UnwindInput {
     pub stage_progress: BlockNumber,
     pub unwind_to: BlockNumber,
 }
+
+/// Wraps a transient stage error, e.g. a lost connection,
+/// to execute the stage again instead of aborting the sync.
+#[derive(Debug)]
+pub struct TransientError(pub anyhow::Error);
+
+impl fmt::Display for TransientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+/// An unwind deeper than the configured maximum reorg depth, refused to be done automatically.
+#[derive(Debug)]
+pub struct ReorgTooDeepError {
+    pub head: BlockNumber,
+    pub unwind_to: BlockNumber,
+    pub max_reorg_depth: u64,
+}
+
+impl fmt::Display for ReorgTooDeepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ReorgTooDeepError {}
+
+pub trait RetryableError {
+    /// Whether the failed stage can be executed again.
+    fn is_retryable(&self) -> bool;
+}
+
+impl RetryableError for anyhow::Error {
+    /// TransientError and network errors are retryable, the rest (e.g. missing data) are fatal.
+    /// Only the gRPC codes of an unavailable or overloaded server are network errors,
+    /// unlike e.g. an invalid argument, which fails again.
+    fn is_retryable(&self) -> bool {
+        self.chain().any(|error| {
+            error.is::<TransientError>()
+                || matches!(
+                    error
+                        .downcast_ref::<tonic::Status>()
+                        .map(tonic::Status::code),
+                    Some(
+                        tonic::Code::Unavailable
+                            | tonic::Code::DeadlineExceeded
+                            | tonic::Code::Aborted
+                            | tonic::Code::ResourceExhausted
+                    )
+                )
+                || error.is::<tonic::transport::Error>()
+                || matches!(
+                    error.downcast_ref::<SendMessageError>(),
+                    Some(SendMessageError::SendQueueFull)
+                )
+        })
+    }
+}
@@ -2,10 +2,14 @@ use crate::{
     kv::{tables, traits::*},
     models::*,
 };
-use std::fmt::Display;
+use anyhow::format_err;
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
 use tracing::*;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct StageId(pub &'static str);
 
 pub const HEADERS: StageId = StageId("Headers");
@@ -21,7 +25,35 @@ pub const LOG_INDEX: StageId = StageId("LogIndex");
 pub const CALL_TRACES: StageId = StageId("CallTraces");
 pub const TX_LOOKUP: StageId = StageId("TxLookup");
 pub const TX_POOL: StageId = StageId("TxPool");
+pub const PRUNE: StageId = StageId("Prune");
+pub const PRUNE_HEADERS: StageId = StageId("PruneHeaders");
 pub const FINISH: StageId = StageId("Finish");
+/// Not a stage, the progress is the point of the unwind in progress, if any.
+pub const UNWIND: StageId = StageId("Unwind");
+/// Not a stage, the progress is the block the history is pruned below, if any.
+/// The changesets of the earlier blocks are gone, see `ensure_history_available`.
+pub const PRUNED_HISTORY: StageId = StageId("PrunedHistory");
+/// Not a stage, the progress is the chain tip as estimated by the header download,
+/// saved for the processes reading the database, see `sync_status`.
+pub const ESTIMATED_TIP: StageId = StageId("EstimatedTip");
+
+/// The stages of the `akula` sync in their order, each one depends on the ones before it.
+/// The conversion from an Erigon database replaces the downloads.
+pub const SYNC_STAGES: &[StageId] = &[
+    StageId("ConvertHeaders"),
+    StageId("HeaderDownload"),
+    BLOCK_HASHES,
+    StageId("ConvertBodies"),
+    StageId("CumulativeIndex"),
+    StageId("SenderRecovery"),
+    EXECUTION,
+    HASH_STATE,
+    StageId("Interhashes"),
+    StageId("TxLookup"),
+    PRUNE,
+    PRUNE_HEADERS,
+    FINISH,
+];
 
 impl AsRef<str> for StageId {
     fn as_ref(&self) -> &str {
@@ -41,6 +73,19 @@ impl Display for StageId {
     }
 }
 
+/// Only the stages of `SYNC_STAGES` can be parsed.
+impl FromStr for StageId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SYNC_STAGES
+            .iter()
+            .find(|stage_id| stage_id.0 == s)
+            .copied()
+            .ok_or_else(|| format_err!("unknown stage: {}", s))
+    }
+}
+
 impl StageId {
     #[instrument]
     pub async fn get_progress<'db, Tx: Transaction<'db>>(
@@ -58,4 +103,287 @@ impl StageId {
     ) -> anyhow::Result<()> {
         tx.set(tables::SyncStage, *self, block).await
     }
+
+    #[instrument]
+    pub async fn clear_progress<'db, RwTx: MutableTransaction<'db>>(
+        &self,
+        tx: &RwTx,
+    ) -> anyhow::Result<()> {
+        tx.del(tables::SyncStage, *self, None).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum StageProgressError {
+    UnknownStage(StageId),
+    /// The stage applies the blocks on top of its data, which is only reverted by its unwind,
+    /// so its progress can't be changed without corrupting it, see `STATEFUL_STAGES`.
+    StatefulStage(StageId),
+    /// The stage would be ahead of the closest stage before it with any progress.
+    AheadOfPrerequisite {
+        stage: StageId,
+        block_number: BlockNumber,
+        prerequisite: StageId,
+        prerequisite_progress: BlockNumber,
+    },
+}
+
+impl fmt::Display for StageProgressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for StageProgressError {}
+
+/// The state at the block can't be reverted to, the changesets of the blocks after it are pruned.
+#[derive(Debug)]
+pub struct HistoryPrunedError {
+    pub block_number: BlockNumber,
+    pub pruned_below: BlockNumber,
+}
+
+impl fmt::Display for HistoryPrunedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "history is pruned below block {}, the state at block {} is gone",
+            self.pruned_below, self.block_number
+        )
+    }
+}
+
+impl std::error::Error for HistoryPrunedError {}
+
+/// Fails with `HistoryPrunedError` unless the changesets of all the blocks after `block_number`
+/// are kept, that is unless the current state can be reverted to the one at the block.
+pub async fn ensure_history_available<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    block_number: BlockNumber,
+) -> anyhow::Result<()> {
+    if let Some(pruned_below) = PRUNED_HISTORY.get_progress(tx).await? {
+        if block_number.0.saturating_add(1) < pruned_below.0 {
+            return Err(HistoryPrunedError {
+                block_number,
+                pruned_below,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// The stages whose next run applies the blocks on top of their data instead of overwriting it,
+/// e.g. the execution applies the state changes. Only their unwinds can lower their progress.
+pub const STATEFUL_STAGES: &[StageId] = &[EXECUTION, HASH_STATE, StageId("Interhashes")];
+
+fn ensure_stateless(stage_id: StageId) -> Result<(), StageProgressError> {
+    if STATEFUL_STAGES.contains(&stage_id) {
+        return Err(StageProgressError::StatefulStage(stage_id));
+    }
+    Ok(())
+}
+
+fn stage_index(stage_id: StageId) -> Result<usize, StageProgressError> {
+    SYNC_STAGES
+        .iter()
+        .position(|known| *known == stage_id)
+        .ok_or(StageProgressError::UnknownStage(stage_id))
+}
+
+/// The progress of every stage of `SYNC_STAGES`, None if it has never run.
+pub async fn all_progress<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+) -> anyhow::Result<Vec<(StageId, Option<BlockNumber>)>> {
+    let mut progress = Vec::with_capacity(SYNC_STAGES.len());
+    for stage_id in SYNC_STAGES {
+        progress.push((*stage_id, stage_id.get_progress(tx).await?));
+    }
+    Ok(progress)
+}
+
+/// The stages after the stage which are ahead of the block.
+async fn dependents_ahead<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    index: usize,
+    block_number: Option<BlockNumber>,
+) -> anyhow::Result<Vec<(StageId, BlockNumber)>> {
+    let mut ahead = vec![];
+    for stage_id in &SYNC_STAGES[index + 1..] {
+        if let Some(progress) = stage_id.get_progress(tx).await? {
+            if block_number.map_or(true, |block_number| progress > block_number) {
+                ahead.push((*stage_id, progress));
+            }
+        }
+    }
+    Ok(ahead)
+}
+
+/// Lowers the stages ahead to the block if `cascade` is set, and warns about them otherwise.
+/// The stateful ones are checked before anything is written.
+async fn reset_stage<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+    stage_id: StageId,
+    index: usize,
+    block_number: Option<BlockNumber>,
+    cascade: bool,
+) -> anyhow::Result<Vec<StageId>> {
+    ensure_stateless(stage_id)?;
+    let ahead = dependents_ahead(tx, index, block_number).await?;
+    if cascade {
+        for (stage_id, _) in &ahead {
+            ensure_stateless(*stage_id)?;
+        }
+    }
+
+    match block_number {
+        Some(block_number) => stage_id.save_progress(tx, block_number).await?,
+        None => stage_id.clear_progress(tx).await?,
+    }
+    for (stage_id, progress) in &ahead {
+        if cascade {
+            match block_number {
+                Some(block_number) => stage_id.save_progress(tx, block_number).await?,
+                None => stage_id.clear_progress(tx).await?,
+            }
+        } else {
+            warn!(
+                "Stage {} @ {} depends on the reset stage, and is ahead of it",
+                stage_id, progress
+            );
+        }
+    }
+
+    Ok(ahead.into_iter().map(|(stage_id, _)| stage_id).collect())
+}
+
+/// Sets the progress of the stage, e.g. to run it again from the block after a fix.
+/// Only the progress is changed, the data of the stage is overwritten by its next run,
+/// so the stateful stages are refused, see `STATEFUL_STAGES`.
+///
+/// The stage can't be moved ahead of its prerequisite. The later stages ahead of it
+/// are lowered along if `cascade` is set, unless any of them is stateful,
+/// and left with a warning otherwise. Returns these stages.
+pub async fn set_stage_progress<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+    stage_id: StageId,
+    block_number: BlockNumber,
+    cascade: bool,
+) -> anyhow::Result<Vec<StageId>> {
+    let index = stage_index(stage_id)?;
+
+    for prerequisite in SYNC_STAGES[..index].iter().rev() {
+        if let Some(prerequisite_progress) = prerequisite.get_progress(tx).await? {
+            if block_number > prerequisite_progress {
+                return Err(StageProgressError::AheadOfPrerequisite {
+                    stage: stage_id,
+                    block_number,
+                    prerequisite: *prerequisite,
+                    prerequisite_progress,
+                }
+                .into());
+            }
+            break;
+        }
+    }
+
+    reset_stage(tx, stage_id, index, Some(block_number), cascade).await
+}
+
+/// Forgets the progress of the stage, so that it runs again from the genesis.
+/// The later stages are cleared along if `cascade` is set, see `set_stage_progress`.
+pub async fn clear_stage<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+    stage_id: StageId,
+    cascade: bool,
+) -> anyhow::Result<Vec<StageId>> {
+    let index = stage_index(stage_id)?;
+
+    reset_stage(tx, stage_id, index, None, cascade).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::new_mem_database;
+
+    #[tokio::test]
+    async fn reset_stages() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let tx_lookup = StageId::from_str("TxLookup").unwrap();
+        for (stage_id, progress) in [
+            (StageId("HeaderDownload"), 100),
+            (StageId("SenderRecovery"), 90),
+            (EXECUTION, 90),
+            (tx_lookup, 90),
+            (FINISH, 90),
+        ] {
+            stage_id
+                .save_progress(&tx, BlockNumber(progress))
+                .await
+                .unwrap();
+        }
+        assert!(StageId::from_str("Unwind").is_err());
+
+        // not ahead of the execution
+        assert!(matches!(
+            set_stage_progress(&tx, tx_lookup, BlockNumber(95), false)
+                .await
+                .unwrap_err()
+                .downcast_ref::<StageProgressError>(),
+            Some(StageProgressError::AheadOfPrerequisite {
+                prerequisite_progress: BlockNumber(90),
+                ..
+            })
+        ));
+
+        // the stateful stages are only lowered by their unwinds, even along
+        let sender_recovery = StageId::from_str("SenderRecovery").unwrap();
+        for (stage_id, cascade) in [(EXECUTION, false), (sender_recovery, true)] {
+            assert!(matches!(
+                set_stage_progress(&tx, stage_id, BlockNumber(50), cascade)
+                    .await
+                    .unwrap_err()
+                    .downcast_ref::<StageProgressError>(),
+                Some(StageProgressError::StatefulStage(stateful)) if *stateful == EXECUTION
+            ));
+        }
+        assert_eq!(
+            sender_recovery.get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(90))
+        );
+
+        // the finish is left ahead without the cascade
+        assert_eq!(
+            set_stage_progress(&tx, tx_lookup, BlockNumber(50), false)
+                .await
+                .unwrap(),
+            vec![FINISH]
+        );
+        assert_eq!(
+            FINISH.get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(90))
+        );
+
+        assert_eq!(
+            clear_stage(&tx, tx_lookup, true).await.unwrap(),
+            vec![FINISH]
+        );
+        let progress = all_progress(&tx).await.unwrap();
+        for (stage_id, expected) in [
+            (StageId("HeaderDownload"), Some(BlockNumber(100))),
+            (EXECUTION, Some(BlockNumber(90))),
+            (tx_lookup, None),
+            (FINISH, None),
+        ] {
+            assert_eq!(
+                progress.iter().find(|(id, _)| *id == stage_id).unwrap().1,
+                expected
+            );
+        }
+    }
 }
@@ -0,0 +1,135 @@
+use super::stages::{StageId, ESTIMATED_TIP, EXECUTION};
+use crate::{kv::traits::*, models::*};
+
+/// Progress of the header downloader as of its last run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeaderDownloadStatus {
+    /// The headers below this block are downloaded.
+    pub max_block_num: BlockNumber,
+    /// The chain tip as estimated from the peers, if known.
+    pub estimated_top_block_num: Option<BlockNumber>,
+}
+
+/// Whether the node is synced, in the spirit of `eth_syncing`.
+#[derive(Clone, Debug)]
+pub struct SyncStatus {
+    /// The chain tip as known to the node.
+    pub highest_block: BlockNumber,
+    /// The last executed block.
+    pub current_block: BlockNumber,
+    /// False only if every stage has reached the chain tip.
+    pub is_syncing: bool,
+    pub stages: Vec<(StageId, BlockNumber)>,
+}
+
+/// Collects the progress of the `stages` and compares it to the chain tip.
+/// The tip is the highest known block among the stages and the header download estimate,
+/// either the one of the current process, or the last one saved as the `ESTIMATED_TIP` progress.
+pub async fn sync_status<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    stages: &[StageId],
+    header_download: Option<HeaderDownloadStatus>,
+) -> anyhow::Result<SyncStatus> {
+    let mut stage_progress = Vec::with_capacity(stages.len());
+    for &stage in stages {
+        stage_progress.push((stage, stage.get_progress(tx).await?.unwrap_or_default()));
+    }
+
+    let current_block = EXECUTION.get_progress(tx).await?.unwrap_or_default();
+
+    let mut highest_block = stage_progress
+        .iter()
+        .map(|&(_, progress)| progress)
+        .max()
+        .unwrap_or_default();
+    if let Some(estimated_tip) = ESTIMATED_TIP.get_progress(tx).await? {
+        highest_block = std::cmp::max(highest_block, estimated_tip);
+    }
+    if let Some(header_download) = header_download {
+        // exclusive
+        highest_block = std::cmp::max(
+            highest_block,
+            BlockNumber(header_download.max_block_num.0.saturating_sub(1)),
+        );
+        if let Some(estimated_top_block_num) = header_download.estimated_top_block_num {
+            highest_block = std::cmp::max(highest_block, estimated_top_block_num);
+        }
+    }
+
+    let is_syncing = current_block < highest_block
+        || stage_progress
+            .iter()
+            .any(|&(_, progress)| progress < highest_block);
+
+    Ok(SyncStatus {
+        highest_block,
+        current_block,
+        is_syncing,
+        stages: stage_progress,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv::new_mem_database, stagedsync::stages::*};
+
+    #[tokio::test]
+    async fn synced_only_at_tip() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let stages = [HEADERS, EXECUTION, FINISH];
+        for (stage, progress) in [(HEADERS, 100), (EXECUTION, 100), (FINISH, 100)] {
+            stage
+                .save_progress(&tx, BlockNumber(progress))
+                .await
+                .unwrap();
+        }
+
+        let status = sync_status(&tx, &stages, None).await.unwrap();
+        assert_eq!(status.highest_block, BlockNumber(100));
+        assert_eq!(status.current_block, BlockNumber(100));
+        assert!(!status.is_syncing);
+
+        // the downloader knows of newer blocks
+        let status = sync_status(
+            &tx,
+            &stages,
+            Some(HeaderDownloadStatus {
+                max_block_num: BlockNumber(101),
+                estimated_top_block_num: Some(BlockNumber(191)),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status.highest_block, BlockNumber(191));
+        assert!(status.is_syncing);
+
+        // the estimate saved by another process
+        ESTIMATED_TIP
+            .save_progress(&tx, BlockNumber(200))
+            .await
+            .unwrap();
+        let status = sync_status(&tx, &stages, None).await.unwrap();
+        assert_eq!(status.highest_block, BlockNumber(200));
+        assert!(status.is_syncing);
+        ESTIMATED_TIP
+            .save_progress(&tx, BlockNumber(100))
+            .await
+            .unwrap();
+
+        // a single lagging stage
+        FINISH.save_progress(&tx, BlockNumber(99)).await.unwrap();
+        let status = sync_status(&tx, &stages, None).await.unwrap();
+        assert!(status.is_syncing);
+        assert_eq!(
+            status
+                .stages
+                .iter()
+                .map(|&(_, progress)| progress)
+                .collect::<Vec<_>>(),
+            vec![BlockNumber(100), BlockNumber(100), BlockNumber(99)]
+        );
+    }
+}
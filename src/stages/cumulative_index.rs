@@ -77,6 +77,7 @@ where
         Ok(ExecOutput::Progress {
             stage_progress: max_block,
             done: true,
+            exhausted: true,
             must_commit: max_block > prev_progress,
         })
     }
@@ -105,3 +106,82 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accessors, kv::new_mem_database, models::*};
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn cumulative_tx_count() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        tx.set(
+            tables::CumulativeIndex,
+            BlockNumber(0),
+            CumulativeData { tx_num: 0, gas: 0 },
+        )
+        .await
+        .unwrap();
+
+        let tx_amounts = [2, 0, 5];
+        for (i, &tx_amount) in tx_amounts.iter().enumerate() {
+            let number = BlockNumber(i as u64 + 1);
+            let header = BlockHeader {
+                number,
+                gas_used: 21_000 * tx_amount as u64,
+                ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+            };
+            let hash = header.hash();
+            accessors::chain::canonical_hash::write(&tx, number, hash)
+                .await
+                .unwrap();
+            tx.set(tables::Header, (number, hash), header)
+                .await
+                .unwrap();
+            accessors::chain::storage_body::write(
+                &tx,
+                hash,
+                number,
+                &BodyForStorage {
+                    base_tx_id: TxIndex(0),
+                    tx_amount,
+                    uncles: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        CumulativeIndex
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    first_started_at: (Instant::now(), None),
+                    previous_stage: Some((
+                        crate::stagedsync::stages::BODIES,
+                        BlockNumber(tx_amounts.len() as u64),
+                    )),
+                    stage_progress: Some(BlockNumber(0)),
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut expected_tx_num = 0;
+        for (i, &tx_amount) in tx_amounts.iter().enumerate() {
+            expected_tx_num += tx_amount as u64;
+            let data = tx
+                .get(tables::CumulativeIndex, BlockNumber(i as u64 + 1))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(data.tx_num, expected_tx_num);
+            assert_eq!(data.gas, 21_000 * expected_tx_num);
+        }
+        assert_eq!(expected_tx_num, 7);
+    }
+}
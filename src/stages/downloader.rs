@@ -1,15 +1,21 @@
 use crate::{
+    accessors,
     downloader::{
-        sentry_status_provider::SentryStatusProvider, Downloader, HeaderDownloaderRunState,
+        sentry_status_provider::SentryStatusProvider, Downloader, HeaderDownloaderOptions,
+        HeaderDownloaderRunState,
     },
-    kv::traits::*,
+    kv::{tables, traits::*},
     models::BlockNumber,
-    sentry::{chain_config::ChainConfig, sentry_client_reactor::SentryClientReactorShared},
-    stagedsync::stage::*,
+    sentry::{
+        chain_config::ChainConfig, sentry_client::PeerId,
+        sentry_client_reactor::SentryClientReactorShared,
+    },
+    stagedsync::{stage::*, stages::ESTIMATED_TIP, sync_status::HeaderDownloadStatus},
     StageId,
 };
+use anyhow::{bail, format_err};
 use async_trait::async_trait;
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 use tokio::sync::Mutex as AsyncMutex;
 
 #[derive(Debug)]
@@ -17,26 +23,33 @@ pub struct HeaderDownload {
     downloader: Downloader,
     batch_size: usize,
     previous_run_state: Arc<AsyncMutex<Option<HeaderDownloaderRunState>>>,
+    status: Arc<AsyncMutex<Option<HeaderDownloadStatus>>>,
 }
 
 impl HeaderDownload {
     pub fn new(
         chain_config: ChainConfig,
-        mem_limit: usize,
+        options: &HeaderDownloaderOptions,
         batch_size: usize,
         sentry: SentryClientReactorShared,
         sentry_status_provider: SentryStatusProvider,
     ) -> anyhow::Result<Self> {
-        let downloader = Downloader::new(chain_config, mem_limit, sentry, sentry_status_provider)?;
+        let downloader = Downloader::new(chain_config, options, sentry, sentry_status_provider)?;
 
         let instance = Self {
             downloader,
             batch_size,
             previous_run_state: Arc::new(AsyncMutex::new(None)),
+            status: Arc::new(AsyncMutex::new(None)),
         };
         Ok(instance)
     }
 
+    /// Only download from these peers, see `headers::downloader::Downloader::set_peer_allowlist`.
+    pub fn set_peer_allowlist(&mut self, peer_allowlist: Option<HashSet<PeerId>>) {
+        self.downloader.set_peer_allowlist(peer_allowlist);
+    }
+
     async fn load_previous_run_state(&self) -> Option<HeaderDownloaderRunState> {
         self.previous_run_state.lock().await.clone()
     }
@@ -44,6 +57,11 @@ impl HeaderDownload {
     async fn save_run_state(&self, run_state: HeaderDownloaderRunState) {
         *self.previous_run_state.lock().await = Some(run_state);
     }
+
+    /// Progress as of the last run, None if it hasn't run yet.
+    pub async fn status(&self) -> Option<HeaderDownloadStatus> {
+        *self.status.lock().await
+    }
 }
 
 #[async_trait]
@@ -70,9 +88,32 @@ where
 
         let report = self
             .downloader
-            .run(tx, start_block_num, self.batch_size, previous_run_state)
+            .run(
+                tx,
+                start_block_num,
+                self.batch_size,
+                previous_run_state,
+                None,
+            )
             .await?;
 
+        if report.is_retry_limit_exceeded {
+            bail!(
+                "Header download stalled at block {}: too many retries",
+                report.final_block_num
+            );
+        }
+
+        // the next stages have processed the headers of the old branch
+        if let Some(common_ancestor) = report.reorg_common_ancestor {
+            if common_ancestor < past_progress {
+                self.save_run_state(report.run_state).await;
+                return Ok(ExecOutput::Unwind {
+                    unwind_to: common_ancestor,
+                });
+            }
+        }
+
         let final_block_num = report.final_block_num.0;
         let stage_progress = if final_block_num > 0 {
             BlockNumber(final_block_num - 1)
@@ -82,11 +123,23 @@ where
 
         let done = final_block_num >= report.target_final_block_num.0;
 
+        // unlike the target of the run, which is well behind the tip
+        let estimated_top_block_num = report.run_state.estimated_top_block_num;
+        if let Some(estimated_top_block_num) = estimated_top_block_num {
+            ESTIMATED_TIP
+                .save_progress(tx, estimated_top_block_num)
+                .await?;
+        }
+        *self.status.lock().await = Some(HeaderDownloadStatus {
+            max_block_num: report.final_block_num,
+            estimated_top_block_num,
+        });
         self.save_run_state(report.run_state).await;
 
         Ok(ExecOutput::Progress {
             stage_progress,
             done,
+            exhausted: done,
             must_commit: true,
         })
     }
@@ -99,8 +152,175 @@ where
     where
         'db: 'tx,
     {
-        let _ = tx;
-        let _ = input;
-        todo!()
+        // The headers stay saved, but they aren't canonical anymore,
+        // so that the next run links the heaviest branch to the unwind point.
+        let mut cursor = tx.mutable_cursor(tables::CanonicalHeader).await?;
+        while let Some((block_num, _)) = cursor.last().await? {
+            if block_num <= input.unwind_to {
+                break;
+            }
+            cursor.delete_current().await?;
+        }
+        let hash = accessors::chain::canonical_hash::read(tx, input.unwind_to)
+            .await?
+            .ok_or_else(|| format_err!("No canonical hash for block {}", input.unwind_to))?;
+        tx.set(tables::LastHeader, Default::default(), hash).await?;
+
+        Ok(UnwindOutput {
+            stage_progress: input.unwind_to,
+            must_commit: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::new_mem_database,
+        models::{BlockHeader, PartialHeader, EMPTY_LIST_HASH, EMPTY_ROOT},
+        sentry::{
+            chain_config::ChainsConfig, sentry_client_connector::SentryClientConnectorTest,
+            sentry_client_mock::SentryClientMock, sentry_client_reactor::SentryClientReactor,
+        },
+        stagedsync::sync_status::sync_status,
+    };
+    use std::time::{Duration, Instant};
+
+    fn make_stage(batch_size: usize) -> HeaderDownload {
+        let chain_config = ChainsConfig::new().unwrap().get("mainnet").unwrap();
+        let status_provider = SentryStatusProvider::new(chain_config.clone());
+        let sentry = SentryClientReactor::new(
+            Box::new(SentryClientConnectorTest::new(Box::new(
+                SentryClientMock::new(),
+            ))),
+            status_provider.current_status_stream(),
+        )
+        .into_shared();
+        let options = HeaderDownloaderOptions {
+            mem_limit: byte_unit::n_mib_bytes!(50) as usize,
+            max_in_flight_requests: None,
+            hard_mem_limit: None,
+            max_total_retries: None,
+            linear_ranges_count: 1,
+            flush_threshold: 1,
+            verify_seal: false,
+            notify_interval: Duration::ZERO,
+            window: None,
+            max_reorg_depth: None,
+        };
+        HeaderDownload::new(chain_config, &options, batch_size, sentry, status_provider).unwrap()
+    }
+
+    #[tokio::test]
+    async fn status_above_run_target() {
+        // the run doesn't reach a whole slice, so it never asks the peers
+        let stage = make_stage(100);
+        stage
+            .save_run_state(HeaderDownloaderRunState {
+                estimated_top_block_num: Some(BlockNumber(200_000)),
+            })
+            .await;
+
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+        let output = Stage::execute(
+            &stage,
+            &mut tx,
+            StageInput {
+                restarted: false,
+                first_started_at: (Instant::now(), None),
+                previous_stage: None,
+                stage_progress: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(output, ExecOutput::Progress { done: false, .. }));
+
+        // the tip, not the target of the run 90_000 blocks behind it
+        let status = stage.status().await.unwrap();
+        assert_eq!(status.estimated_top_block_num, Some(BlockNumber(200_000)));
+        assert_eq!(
+            ESTIMATED_TIP.get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(200_000))
+        );
+        let sync_status = sync_status(&tx, &[StageId("HeaderDownload")], Some(status))
+            .await
+            .unwrap();
+        assert_eq!(sync_status.highest_block, BlockNumber(200_000));
+        assert!(sync_status.is_syncing);
+    }
+
+    #[tokio::test]
+    async fn unwind_canonical_headers() {
+        let stage = make_stage(100);
+
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+        let mut headers = Vec::<BlockHeader>::new();
+        for number in 0..4 {
+            let header = BlockHeader::new(
+                PartialHeader {
+                    number: BlockNumber(number),
+                    parent_hash: headers
+                        .last()
+                        .map(|parent| parent.hash())
+                        .unwrap_or_default(),
+                    ..PartialHeader::empty()
+                },
+                EMPTY_LIST_HASH,
+                EMPTY_ROOT,
+            );
+            tx.set(
+                tables::Header,
+                (header.number, header.hash()),
+                header.clone(),
+            )
+            .await
+            .unwrap();
+            accessors::chain::canonical_hash::write(&tx, header.number, header.hash())
+                .await
+                .unwrap();
+            headers.push(header);
+        }
+        tx.set(tables::LastHeader, Default::default(), headers[3].hash())
+            .await
+            .unwrap();
+
+        // e.g. a heavier branch forks off at the block 1
+        let output = Stage::unwind(
+            &stage,
+            &mut tx,
+            UnwindInput {
+                stage_progress: BlockNumber(3),
+                unwind_to: BlockNumber(1),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.stage_progress, BlockNumber(1));
+
+        for (number, canonical_hash) in [(1, Some(headers[1].hash())), (2, None), (3, None)] {
+            assert_eq!(
+                accessors::chain::canonical_hash::read(&tx, BlockNumber(number))
+                    .await
+                    .unwrap(),
+                canonical_hash
+            );
+        }
+        // the headers of the old branch are kept
+        assert!(
+            accessors::chain::header::read(&tx, headers[3].hash(), BlockNumber(3))
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            tx.get(tables::LastHeader, Default::default())
+                .await
+                .unwrap(),
+            Some(headers[1].hash())
+        );
     }
 }
@@ -1,18 +1,35 @@
 use crate::{
-    accessors,
-    consensus::engine_factory,
+    accessors::{self, header_cache::HeaderCache},
+    consensus::{engine_factory, ValidationError},
     execution::{analysis_cache::AnalysisCache, processor::ExecutionProcessor},
     h256_to_u256,
     kv::{tables, traits::*},
     models::*,
-    stagedsync::{format_duration, stage::*, stages::EXECUTION},
-    upsert_storage_value, Buffer,
+    stagedsync::{
+        format_duration,
+        stage::*,
+        stages::{EXECUTION, PRUNED_HISTORY},
+    },
+    upsert_storage_value, Buffer, BufferConfig, StateRootCache, TouchedAddresses,
+    STATE_ROOT_CACHE_MAX_ENTRIES,
 };
-use anyhow::{format_err, Context};
+use anyhow::format_err;
 use async_trait::async_trait;
-use std::time::{Duration, Instant};
-use tokio::pin;
-use tokio_stream::StreamExt;
+use ethereum_types::H256;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
 use tracing::*;
 
 #[derive(Debug)]
@@ -22,82 +39,696 @@ pub struct Execution {
     pub exit_after_batch: bool,
     pub batch_until: Option<BlockNumber>,
     pub commit_every: Option<Duration>,
-    pub prune_from: BlockNumber,
+    /// History is not written for the blocks below. Read at the start of every batch,
+    /// so that it can be advanced by the pruning without reconstructing the stage.
+    pub prune_from: Arc<AtomicU64>,
+    /// Check the state root against the header at the end of every batch, see `state_root_cache`.
+    pub verify_state_root: bool,
+    /// Size batches as `adaptive_batch_blocks` blocks at the recent average gas limit
+    /// instead of the fixed `batch_size`.
+    pub adaptive_batch: bool,
+    pub adaptive_batch_blocks: u64,
+    /// How often to log the progress.
+    pub log_every: Duration,
+    /// Log the progress every this many blocks instead of `log_every`.
+    pub log_every_blocks: Option<u64>,
+    /// Recover the senders of this many blocks ahead of the execution in parallel,
+    /// instead of reading the ones stored by the sender recovery stage. Disabled if 0.
+    pub sender_lookahead: u64,
+    /// Threads to recover the senders ahead, the number of CPUs if 0.
+    pub sender_lookahead_threads: usize,
+    /// Built with `sender_lookahead_threads` by the first batch, and kept for the next ones.
+    pub sender_lookahead_pool: OnceCell<rayon::ThreadPool>,
+    /// Whether to trust the stored senders, or recover them again.
+    /// The sender lookahead always recovers them, and checks them in the verify mode.
+    pub sender_recovery: SenderRecoveryMode,
+    /// Log the progress as the fields of an event instead of a formatted line.
+    pub structured_progress_log: bool,
+    /// Number of the recent progress messages the smoothed Mgas/sec is averaged over.
+    pub throughput_window: usize,
+    /// Consulted for the headers of the executed blocks, and invalidated on unwind.
+    /// Best left unset for the linear sync, where every header is read only once.
+    pub header_cache: Option<Arc<HeaderCache>>,
+    /// Before executing a batch, check that the canonical headers up to its last block
+    /// link to each other by the parent hashes, e.g. to catch a stale mapping after a reorg.
+    pub verify_canonical: bool,
+    /// Size the batches by the throughput of the previous ones instead of the fixed `batch_size`.
+    pub batch_auto_tune: Option<BatchAutoTune>,
+    /// What the logged progress and the remaining time are estimated by.
+    pub progress_unit: ProgressUnit,
+    /// Refuse to unwind more blocks than this, as a guard against the bugs producing absurd unwind targets.
+    pub max_unwind_depth: Option<u64>,
+    /// Set to let the next unwind exceed `max_unwind_depth`, e.g. after confirming a deep reorg.
+    /// Cleared by that unwind.
+    pub force_deep_unwind: Arc<AtomicBool>,
+    /// Unwind at most this many blocks before committing, so that an interrupted unwind
+    /// resumes from the last commit. The reverted changesets are deleted along.
+    pub unwind_batch_size: u64,
+    /// Track the addresses and the storage slots read or written by every batch into `touched`.
+    pub track_touched: bool,
+    /// Replaced by the set of every executed batch if `track_touched` is set.
+    pub touched: Arc<Mutex<TouchedAddresses>>,
+    /// The tries of the state after the block, kept between the batches if `verify_state_root` is set.
+    /// Loaded from the database on the first batch, and again whenever it's not of the block
+    /// the batch starts after, so the whole state has to fit in memory.
+    pub state_root_cache: Arc<Mutex<Option<(BlockNumber, StateRootCache)>>>,
+    /// Bounds the state buffered within a batch, independently of `history_batch_size`,
+    /// and the history on top of it.
+    pub buffer_config: BufferConfig,
+}
+
+/// Where the execution takes the senders of the transactions from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SenderRecoveryMode {
+    /// Read the senders stored by the sender recovery stage.
+    Trust,
+    /// Recover the senders, and fail if they differ from the stored ones.
+    Verify,
+    /// Recover the senders, ignoring the stored ones.
+    Recover,
+}
+
+impl FromStr for SenderRecoveryMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "trust" => Self::Trust,
+            "verify" => Self::Verify,
+            "recover" => Self::Recover,
+            other => anyhow::bail!("unknown sender recovery mode: {}", other),
+        })
+    }
+}
+
+/// The cumulative counter of `tables::CumulativeIndex` the execution progress is measured in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressUnit {
+    /// Gas used, closer to the execution time.
+    Gas,
+    /// Transactions, not skewed by the few blocks of the DoS attacks.
+    Transactions,
+}
+
+impl ProgressUnit {
+    fn cumulative(self, data: &tables::CumulativeData) -> u64 {
+        match self {
+            Self::Gas => data.gas,
+            Self::Transactions => data.tx_num,
+        }
+    }
+}
+
+impl FromStr for ProgressUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "gas" => Self::Gas,
+            "transactions" => Self::Transactions,
+            other => anyhow::bail!("unknown progress unit: {}", other),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ExecutionStageError {
+    MissingCanonicalHash(BlockNumber),
+    MissingHeader(BlockNumber),
+    MissingBody(BlockNumber),
+    MissingCumulativeIndex(BlockNumber),
+    GasLimitExceeded(BlockNumber),
+    /// The recovered sender of the transaction differs from the stored one.
+    SenderMismatch {
+        block_number: BlockNumber,
+        index: usize,
+        stored: Option<Address>,
+        recovered: Address,
+    },
+    StateRootMismatch {
+        block_number: BlockNumber,
+        expected: H256,
+        computed: H256,
+    },
+    /// The parent hash of the canonical header differs from the canonical hash of the previous block.
+    CanonicalChainMismatch {
+        block_number: BlockNumber,
+        parent_hash: H256,
+        canonical_parent_hash: H256,
+    },
+    /// The unwind is deeper than `max_unwind_depth`, and it wasn't forced.
+    UnwindTooDeep {
+        stage_progress: BlockNumber,
+        unwind_to: BlockNumber,
+        max_unwind_depth: u64,
+    },
+    /// The changesets of the blocks after the unwind point are pruned, see `PRUNED_HISTORY`.
+    UnwindBelowPrunedHistory {
+        unwind_to: BlockNumber,
+        pruned_below: BlockNumber,
+    },
+    /// Block execution failed.
+    ProcessorError(anyhow::Error),
+    /// Database or other internal failure.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for ExecutionStageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ExecutionStageError {}
+
+impl From<anyhow::Error> for ExecutionStageError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::Other(error)
+    }
+}
+
+/// How many of the most recent headers the adaptive batch size is averaged over.
+const ADAPTIVE_BATCH_WINDOW: usize = 64;
+
+/// Batch size in gas, either fixed or following the gas limits of the recent blocks.
+#[derive(Debug)]
+struct BatchSize {
+    fixed: u64,
+    target_blocks: Option<u64>,
+    recent_gas_limits: VecDeque<u64>,
+    recent_gas_limits_sum: u64,
+}
+
+impl BatchSize {
+    fn new(fixed: u64, target_blocks: Option<u64>) -> Self {
+        Self {
+            fixed,
+            target_blocks,
+            recent_gas_limits: VecDeque::with_capacity(ADAPTIVE_BATCH_WINDOW + 1),
+            recent_gas_limits_sum: 0,
+        }
+    }
+
+    fn push_gas_limit(&mut self, gas_limit: u64) {
+        if self.target_blocks.is_none() {
+            return;
+        }
+
+        self.recent_gas_limits.push_back(gas_limit);
+        self.recent_gas_limits_sum += gas_limit;
+        if self.recent_gas_limits.len() > ADAPTIVE_BATCH_WINDOW {
+            self.recent_gas_limits_sum -= self.recent_gas_limits.pop_front().unwrap();
+        }
+    }
+
+    fn get(&self) -> u64 {
+        match self.target_blocks {
+            Some(target_blocks) if !self.recent_gas_limits.is_empty() => {
+                let average_gas_limit =
+                    self.recent_gas_limits_sum / self.recent_gas_limits.len() as u64;
+                target_blocks.saturating_mul(average_gas_limit)
+            }
+            _ => self.fixed,
+        }
+    }
+}
+
+/// Tunes the batch size in gas, so that the batches take about the target time to execute
+/// regardless of how dense the blocks are, and the commit overhead stays amortized.
+#[derive(Debug)]
+pub struct BatchAutoTune {
+    target_commit_interval: Duration,
+    min_batch_size: u64,
+    max_batch_size: u64,
+    /// The tuned size, 0 until the first batch is measured.
+    batch_size: AtomicU64,
+}
+
+impl BatchAutoTune {
+    pub fn new(target_commit_interval: Duration, min_batch_size: u64, max_batch_size: u64) -> Self {
+        Self {
+            target_commit_interval,
+            min_batch_size,
+            max_batch_size: std::cmp::max(min_batch_size, max_batch_size),
+            batch_size: AtomicU64::new(0),
+        }
+    }
+
+    /// The tuned batch size, or the initial one clamped to the bounds.
+    pub fn batch_size(&self, initial: u64) -> u64 {
+        match self.batch_size.load(Ordering::SeqCst) {
+            0 => initial.clamp(self.min_batch_size, self.max_batch_size),
+            batch_size => batch_size,
+        }
+    }
+
+    /// Moves the batch size halfway to the one that would have taken the target time
+    /// at the throughput of the batch, so that a single odd batch doesn't swing it.
+    pub fn on_batch_executed(&self, initial: u64, gas: u64, elapsed: Duration) {
+        let elapsed = elapsed.as_secs_f64();
+        if gas == 0 || elapsed <= 0_f64 {
+            return;
+        }
+
+        let gas_per_sec = gas as f64 / elapsed;
+        let target = (gas_per_sec * self.target_commit_interval.as_secs_f64()) as u64;
+        let current = self.batch_size(initial);
+        let tuned = (current / 2)
+            .saturating_add(target / 2)
+            .clamp(self.min_batch_size, self.max_batch_size);
+        self.batch_size.store(tuned, Ordering::SeqCst);
+    }
+}
+
+/// Gas throughput over the last samples, smoothing out the jitter of the single intervals.
+struct GasRate {
+    window: usize,
+    /// Time and the total gas executed by then.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl GasRate {
+    fn new(window: usize, started_at: Instant) -> Self {
+        let mut samples = VecDeque::with_capacity(window + 1);
+        samples.push_back((started_at, 0));
+        Self {
+            window: window.max(1),
+            samples,
+        }
+    }
+
+    fn push(&mut self, at: Instant, total_gas: u64) {
+        self.samples.push_back((at, total_gas));
+        // the oldest sample only marks the start of the first interval
+        if self.samples.len() > self.window + 1 {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Mgas/sec over the intervals between the samples in the window.
+    fn mgas_sec(&self) -> Option<f64> {
+        let (first_at, first_gas) = *self.samples.front()?;
+        let (last_at, last_gas) = *self.samples.back()?;
+        let elapsed = (last_at - first_at).as_secs_f64();
+        if elapsed > 0_f64 {
+            Some((last_gas - first_gas) as f64 / elapsed / 1_000_000f64)
+        } else {
+            None
+        }
+    }
+}
+
+type RecoveredBodies = Vec<(BlockNumber, BlockBodyWithSenders)>;
+
+/// The bodies of the next blocks with the senders recovered in parallel ahead of the execution,
+/// so that executing them doesn't wait for ecrecover.
+struct SenderLookahead<'a> {
+    window: u64,
+    pool: &'a rayon::ThreadPool,
+    bodies: VecDeque<(BlockNumber, BlockBodyWithSenders)>,
+    /// The window after `bodies` from its first block, recovered on the pool
+    /// while `bodies` are executed.
+    next: Option<(
+        BlockNumber,
+        oneshot::Receiver<anyhow::Result<RecoveredBodies>>,
+    )>,
+}
+
+impl<'a> SenderLookahead<'a> {
+    fn new(window: u64, pool: &'a rayon::ThreadPool) -> Self {
+        Self {
+            window,
+            pool,
+            bodies: VecDeque::new(),
+            next: None,
+        }
+    }
+
+    /// The body of the block, recovering the senders of the next blocks up to `last_block` if it's not staged.
+    async fn read<'db, Tx: Transaction<'db>>(
+        &mut self,
+        tx: &Tx,
+        block_number: BlockNumber,
+        last_block: BlockNumber,
+    ) -> Result<BlockBodyWithSenders, ExecutionStageError> {
+        if self.bodies.front().map(|(number, _)| *number) != Some(block_number) {
+            let next = match self.next.take() {
+                Some((first_block, next)) if first_block == block_number => next,
+                _ => self.spawn(tx, block_number, last_block).await?,
+            };
+            self.bodies = next
+                .await
+                .map_err(|_| format_err!("Sender recovery aborted"))??
+                .into();
+
+            if let Some(&(last_recovered, _)) = self.bodies.back() {
+                let next_block = last_recovered + 1;
+                if next_block <= last_block {
+                    self.next = Some((next_block, self.spawn(tx, next_block, last_block).await?));
+                }
+            }
+        }
+
+        match self.bodies.pop_front() {
+            Some((number, body)) if number == block_number => Ok(body),
+            _ => Err(ExecutionStageError::MissingBody(block_number)),
+        }
+    }
+
+    /// Reads the window from `first_block`, and recovers its senders on the pool in the background.
+    async fn spawn<'db, Tx: Transaction<'db>>(
+        &self,
+        tx: &Tx,
+        first_block: BlockNumber,
+        last_block: BlockNumber,
+    ) -> anyhow::Result<oneshot::Receiver<anyhow::Result<RecoveredBodies>>> {
+        // stop at the first missing block, the execution reports it when it gets there
+        let mut bodies = Vec::new();
+        let mut block_number = first_block;
+        while block_number == first_block
+            || (block_number <= last_block && (bodies.len() as u64) < self.window)
+        {
+            let block_hash = match accessors::chain::canonical_hash::read(tx, block_number).await? {
+                Some(block_hash) => block_hash,
+                None => break,
+            };
+            match accessors::chain::block_body::read_without_senders(tx, block_hash, block_number)
+                .await?
+            {
+                Some(body) => bodies.push((block_number, body)),
+                None => break,
+            }
+            block_number.0 += 1;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        self.pool.spawn(move || {
+            let bodies = bodies
+                .into_par_iter()
+                .map(|(block_number, body)| {
+                    let transactions = body
+                        .transactions
+                        .into_par_iter()
+                        .map(|transaction| {
+                            Ok(MessageWithSender {
+                                sender: transaction.recover_sender()?,
+                                message: transaction.message,
+                            })
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    Ok((
+                        block_number,
+                        BlockBodyWithSenders {
+                            transactions,
+                            ommers: body.ommers,
+                        },
+                    ))
+                })
+                .collect::<anyhow::Result<Vec<_>>>();
+            // the lookahead may have moved on
+            let _ = sender.send(bodies);
+        });
+
+        Ok(receiver)
+    }
+}
+
+/// The body of the block with the senders either read or recovered.
+async fn read_body_with_senders<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    block_hash: H256,
+    block_number: BlockNumber,
+    sender_recovery: SenderRecoveryMode,
+) -> Result<BlockBodyWithSenders, ExecutionStageError> {
+    if sender_recovery == SenderRecoveryMode::Trust {
+        return accessors::chain::block_body::read_with_senders(tx, block_hash, block_number)
+            .await?
+            .ok_or(ExecutionStageError::MissingBody(block_number));
+    }
+
+    let body = accessors::chain::block_body::read_without_senders(tx, block_hash, block_number)
+        .await?
+        .ok_or(ExecutionStageError::MissingBody(block_number))?;
+    let body = BlockBodyWithSenders {
+        transactions: body
+            .transactions
+            .into_iter()
+            .map(|transaction| {
+                Ok(MessageWithSender {
+                    sender: transaction.recover_sender()?,
+                    message: transaction.message,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        ommers: body.ommers,
+    };
+
+    if sender_recovery == SenderRecoveryMode::Verify {
+        verify_senders(tx, block_hash, block_number, &body).await?;
+    }
+
+    Ok(body)
+}
+
+/// Checks the recovered senders against the ones stored by the sender recovery stage.
+async fn verify_senders<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    block_hash: H256,
+    block_number: BlockNumber,
+    body: &BlockBodyWithSenders,
+) -> Result<(), ExecutionStageError> {
+    let stored = accessors::chain::tx_sender::read(tx, block_hash, block_number).await?;
+    for (index, transaction) in body.transactions.iter().enumerate() {
+        let stored = stored.get(index).copied();
+        if stored != Some(transaction.sender) {
+            return Err(ExecutionStageError::SenderMismatch {
+                block_number,
+                index,
+                stored,
+                recovered: transaction.sender,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Logs the progress line, or the same data as the fields of an event for the log ingestion.
+/// The progress and the remaining time are unknown once the stage is complete.
+fn log_progress(
+    structured: bool,
+    block_number: BlockNumber,
+    mgas_sec: f64,
+    windowed_mgas_sec: f64,
+    progress: Option<(f64, Duration)>,
+) {
+    if structured {
+        let (progress_pct, remaining) = progress.unwrap_or((100_f64, Duration::ZERO));
+        info!(
+            block = block_number.0,
+            mgas_sec,
+            windowed_mgas_sec,
+            progress_pct,
+            eta_secs = remaining.as_secs(),
+            "Execution progress"
+        );
+    } else if let Some((progress, remaining)) = progress {
+        info!(
+            block_number = block_number.0,
+            mgas_per_sec = mgas_sec,
+            windowed_mgas_per_sec = windowed_mgas_sec,
+            progress,
+            remaining_secs = remaining.as_secs(),
+            "Executed block {}, Mgas/sec: {:.2} ({:.2} avg), progress: {:0>2.2}%, {} remaining",
+            block_number,
+            mgas_sec,
+            windowed_mgas_sec,
+            progress,
+            format_duration(remaining, false)
+        );
+    } else {
+        info!(
+            block_number = block_number.0,
+            mgas_per_sec = mgas_sec,
+            windowed_mgas_per_sec = windowed_mgas_sec,
+            "Executed block {}, Mgas/sec: {:.2} ({:.2} avg)",
+            block_number,
+            mgas_sec,
+            windowed_mgas_sec
+        );
+    }
+}
+
+/// The outcome of `execute_batch_of_blocks`.
+#[derive(Debug)]
+struct ExecutedBatch {
+    /// The last executed block.
+    executed_to: BlockNumber,
+    /// Its hash and header, if requested.
+    last_header: Option<(H256, BlockHeader)>,
 }
 
-#[allow(clippy::too_many_arguments)]
+/// Executes a batch of blocks from `starting_block`, as sized and configured by the stage.
 async fn execute_batch_of_blocks<'db, Tx: MutableTransaction<'db>>(
     tx: &Tx,
+    stage: &Execution,
     chain_config: ChainSpec,
     max_block: BlockNumber,
-    batch_size: u64,
-    history_batch_size: u64,
-    batch_until: Option<BlockNumber>,
-    commit_every: Option<Duration>,
     starting_block: BlockNumber,
     first_started_at: (Instant, Option<BlockNumber>),
-    prune_from: BlockNumber,
-) -> anyhow::Result<BlockNumber> {
+    with_last_header: bool,
+) -> Result<ExecutedBatch, ExecutionStageError> {
+    let Execution {
+        batch_size,
+        history_batch_size,
+        batch_until,
+        commit_every,
+        verify_state_root,
+        log_every,
+        log_every_blocks,
+        sender_lookahead,
+        sender_lookahead_threads,
+        sender_recovery,
+        structured_progress_log,
+        throughput_window,
+        verify_canonical,
+        progress_unit,
+        buffer_config,
+        ..
+    } = *stage;
+    // the pruning of an earlier run is persisted, unlike `prune_from`
+    let prune_from = std::cmp::max(
+        BlockNumber(stage.prune_from.load(Ordering::SeqCst)),
+        PRUNED_HISTORY.get_progress(tx).await?.unwrap_or_default(),
+    );
+    let adaptive_batch_blocks = stage.adaptive_batch.then(|| stage.adaptive_batch_blocks);
+    let header_cache = stage.header_cache.as_deref();
+    let batch_auto_tune = stage.batch_auto_tune.as_ref();
+    let touched = stage.track_touched.then(|| &*stage.touched);
+
     let mut buffer = Buffer::new(tx, prune_from, None);
+    buffer.set_track_touched(touched.is_some());
+    buffer.set_config(buffer_config);
+    if verify_state_root {
+        let cached = stage.state_root_cache.lock().take();
+        let state_root_cache = match cached {
+            Some((block_number, state_root_cache)) if block_number.0 + 1 == starting_block.0 => {
+                state_root_cache
+            }
+            _ => {
+                info!("Loading the state into the state root cache");
+                StateRootCache::load(tx, STATE_ROOT_CACHE_MAX_ENTRIES).await?
+            }
+        };
+        buffer.set_state_root_cache(state_root_cache);
+    }
     let mut consensus_engine = engine_factory(chain_config.clone())?;
     let mut analysis_cache = AnalysisCache::default();
+    let mut block_spec_cache = BlockSpecCache::new(&chain_config);
+    let initial_batch_size = batch_size;
+    let mut batch_size = BatchSize::new(
+        batch_auto_tune.map_or(batch_size, |batch_auto_tune| {
+            batch_auto_tune.batch_size(initial_batch_size)
+        }),
+        adaptive_batch_blocks,
+    );
+    let mut sender_lookahead = if sender_lookahead > 0 {
+        let pool = stage
+            .sender_lookahead_pool
+            .get_or_try_init(|| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(sender_lookahead_threads)
+                    .build()
+            })
+            .map_err(anyhow::Error::from)?;
+        Some(SenderLookahead::new(sender_lookahead, pool))
+    } else {
+        None
+    };
+    let last_block = std::cmp::min(max_block, batch_until.unwrap_or(max_block));
+
+    if verify_canonical {
+        verify_canonical_chain(tx, starting_block, last_block).await?;
+    }
 
     let mut block_number = starting_block;
     let mut gas_since_start = 0;
     let mut gas_since_last_message = 0;
+    let mut blocks_since_last_message = 0;
     let mut gas_since_history_commit = 0;
     let batch_started_at = Instant::now();
-    let first_started_at_gas = tx
-        .get(
-            tables::CumulativeIndex,
-            first_started_at.1.unwrap_or(BlockNumber(0)),
-        )
-        .await?
-        .unwrap()
-        .gas;
+    let first_started_at_block = first_started_at.1.unwrap_or(BlockNumber(0));
+    let first_started_at_progress = progress_unit.cumulative(
+        &tx.get(tables::CumulativeIndex, first_started_at_block)
+            .await?
+            .ok_or(ExecutionStageError::MissingCumulativeIndex(
+                first_started_at_block,
+            ))?,
+    );
     let mut last_message = Instant::now();
+    let mut gas_rate = GasRate::new(throughput_window, last_message);
     let mut printed_at_least_once = false;
+    let mut last_header = None;
     loop {
         let block_hash = accessors::chain::canonical_hash::read(tx, block_number)
             .await?
-            .ok_or_else(|| format_err!("No canonical hash found for block {}", block_number))?;
-        let header = accessors::chain::header::read(tx, block_hash, block_number)
-            .await?
-            .ok_or_else(|| format_err!("Header not found: {}/{:?}", block_number, block_hash))?
-            .into();
-        let block = accessors::chain::block_body::read_with_senders(tx, block_hash, block_number)
-            .await?
-            .ok_or_else(|| {
-                format_err!("Block body not found: {}/{:?}", block_number, block_hash)
-            })?;
+            .ok_or(ExecutionStageError::MissingCanonicalHash(block_number))?;
+        let block_header =
+            accessors::chain::header::read_cached(tx, header_cache, block_hash, block_number)
+                .await?
+                .ok_or(ExecutionStageError::MissingHeader(block_number))?;
+        // only cloned if requested
+        let (header, block_header) = if with_last_header {
+            (
+                PartialHeader::from(block_header.clone()),
+                Some(block_header),
+            )
+        } else {
+            (PartialHeader::from(block_header), None)
+        };
+        let block = if let Some(sender_lookahead) = &mut sender_lookahead {
+            let block = sender_lookahead.read(tx, block_number, last_block).await?;
+            if sender_recovery == SenderRecoveryMode::Verify {
+                verify_senders(tx, block_hash, block_number, &block).await?;
+            }
+            block
+        } else {
+            read_body_with_senders(tx, block_hash, block_number, sender_recovery).await?
+        };
 
-        let block_spec = chain_config.collect_block_spec(block_number);
+        let block_spec = block_spec_cache.get(block_number);
 
-        ExecutionProcessor::new(
+        let mut processor = ExecutionProcessor::new(
             &mut buffer,
             &mut analysis_cache,
             &mut *consensus_engine,
             &header,
             &block,
-            &block_spec,
-        )
-        .execute_and_write_block()
-        .await
-        .with_context(|| {
-            format!(
+            block_spec,
+        );
+        processor.execute_and_write_block().await.map_err(|error| {
+            if let Some(ValidationError::BlockGasLimitExceeded { .. }) =
+                error.downcast_ref::<ValidationError>()
+            {
+                return ExecutionStageError::GasLimitExceeded(block_number);
+            }
+            ExecutionStageError::ProcessorError(error.context(format!(
                 "Failed to execute block #{} ({:?})",
                 block_number, block_hash
-            )
+            )))
         })?;
 
+        batch_size.push_gas_limit(header.gas_limit);
         gas_since_start += header.gas_used;
         gas_since_last_message += header.gas_used;
+        blocks_since_last_message += 1;
         gas_since_history_commit += header.gas_used;
 
-        if gas_since_history_commit >= history_batch_size {
+        if gas_since_history_commit >= history_batch_size || buffer.history_flush_due() {
             buffer.write_history().await?;
             gas_since_history_commit = 0;
         }
+        buffer.flush_if_exceeded().await?;
 
         let now = Instant::now();
 
@@ -105,70 +736,271 @@ async fn execute_batch_of_blocks<'db, Tx: MutableTransaction<'db>>(
 
         let end_of_batch = stage_complete
             || block_number >= batch_until.unwrap_or(BlockNumber(u64::MAX))
-            || gas_since_start >= batch_size
+            || gas_since_start >= batch_size.get()
             || commit_every
                 .map(|commit_every| now - batch_started_at > commit_every)
                 .unwrap_or(false);
 
         let elapsed = now - last_message;
-        if elapsed > Duration::from_secs(30) || (end_of_batch && !printed_at_least_once) {
-            let current_total_gas = tx
-                .get(tables::CumulativeIndex, block_number)
-                .await?
-                .unwrap()
-                .gas;
+        let log_due = if let Some(log_every_blocks) = log_every_blocks {
+            blocks_since_last_message >= log_every_blocks
+        } else {
+            elapsed > log_every
+        };
+        if log_due || (end_of_batch && !printed_at_least_once) {
+            let current_progress = progress_unit.cumulative(
+                &tx.get(tables::CumulativeIndex, block_number)
+                    .await?
+                    .ok_or(ExecutionStageError::MissingCumulativeIndex(block_number))?,
+            );
 
-            let total_gas = tx
-                .cursor(tables::CumulativeIndex)
-                .await?
-                .last()
-                .await?
-                .unwrap()
-                .1
-                .gas;
+            let total_progress = progress_unit.cumulative(
+                &tx.cursor(tables::CumulativeIndex)
+                    .await?
+                    .last()
+                    .await?
+                    .ok_or(ExecutionStageError::MissingCumulativeIndex(block_number))?
+                    .1,
+            );
             let mgas_sec = gas_since_last_message as f64
                 / (elapsed.as_secs() as f64 + (elapsed.subsec_millis() as f64 / 1000_f64))
                 / 1_000_000f64;
-            info!(
-                "Executed block {}, Mgas/sec: {:.2}{}",
-                block_number,
-                mgas_sec,
-                if stage_complete {
-                    String::new()
-                } else {
-                    let elapsed_since_start = now - first_started_at.0;
-                    format!(
-                        ", progress: {:0>2.2}%, {} remaining",
-                        ((current_total_gas - first_started_at_gas) as f64
-                            / (total_gas - first_started_at_gas) as f64)
-                            * 100_f64,
-                        format_duration(
-                            Duration::from_secs(
-                                (elapsed_since_start.as_secs() as f64
-                                    * ((total_gas - current_total_gas) as f64
-                                        / (current_total_gas - first_started_at_gas) as f64))
-                                    as u64
-                            ),
-                            false
-                        )
-                    )
-                }
-            );
+            gas_rate.push(now, gas_since_start);
+            let windowed_mgas_sec = gas_rate.mgas_sec().unwrap_or(mgas_sec);
+            if stage_complete {
+                log_progress(
+                    structured_progress_log,
+                    block_number,
+                    mgas_sec,
+                    windowed_mgas_sec,
+                    None,
+                );
+            } else {
+                let elapsed_since_start = now - first_started_at.0;
+                let progress = ((current_progress - first_started_at_progress) as f64
+                    / (total_progress - first_started_at_progress) as f64)
+                    * 100_f64;
+                let remaining = Duration::from_secs(
+                    (elapsed_since_start.as_secs() as f64
+                        * ((total_progress - current_progress) as f64
+                            / (current_progress - first_started_at_progress) as f64))
+                        as u64,
+                );
+                log_progress(
+                    structured_progress_log,
+                    block_number,
+                    mgas_sec,
+                    windowed_mgas_sec,
+                    Some((progress, remaining)),
+                );
+            }
             printed_at_least_once = true;
             last_message = now;
             gas_since_last_message = 0;
+            blocks_since_last_message = 0;
         }
 
         if end_of_batch {
+            if verify_state_root {
+                let state_root = buffer.compute_state_root()?;
+                if state_root != header.state_root {
+                    return Err(ExecutionStageError::StateRootMismatch {
+                        block_number,
+                        expected: header.state_root,
+                        computed: state_root,
+                    });
+                }
+            }
+
+            last_header = block_header.map(|block_header| (block_hash, block_header));
             break;
         }
 
         block_number.0 += 1;
     }
 
+    if let Some(touched) = touched {
+        *touched.lock() = buffer.take_touched().unwrap_or_default();
+    }
+
+    let state_root_cache = buffer.take_state_root_cache();
+
     buffer.write_to_db().await?;
 
-    Ok(block_number)
+    if let Some(state_root_cache) = state_root_cache {
+        *stage.state_root_cache.lock() = Some((block_number, state_root_cache));
+    }
+
+    if let Some(batch_auto_tune) = batch_auto_tune {
+        batch_auto_tune.on_batch_executed(
+            initial_batch_size,
+            gas_since_start,
+            batch_started_at.elapsed(),
+        );
+        debug!(
+            "Tuned the execution batch size to {} gas",
+            batch_auto_tune.batch_size(initial_batch_size)
+        );
+    }
+
+    Ok(ExecutedBatch {
+        executed_to: block_number,
+        last_header,
+    })
+}
+
+/// Checks that the canonical headers from `starting_block` to `last_block`
+/// link to the canonical hashes of their parents.
+async fn verify_canonical_chain<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    starting_block: BlockNumber,
+    last_block: BlockNumber,
+) -> Result<(), ExecutionStageError> {
+    let mut canonical_parent_hash = if starting_block.0 > 0 {
+        let parent_number = BlockNumber(starting_block.0 - 1);
+        Some(
+            accessors::chain::canonical_hash::read(tx, parent_number)
+                .await?
+                .ok_or(ExecutionStageError::MissingCanonicalHash(parent_number))?,
+        )
+    } else {
+        None
+    };
+
+    for block_number in starting_block.0..=last_block.0 {
+        let block_number = BlockNumber(block_number);
+        let block_hash = accessors::chain::canonical_hash::read(tx, block_number)
+            .await?
+            .ok_or(ExecutionStageError::MissingCanonicalHash(block_number))?;
+        let header = accessors::chain::header::read(tx, block_hash, block_number)
+            .await?
+            .ok_or(ExecutionStageError::MissingHeader(block_number))?;
+
+        if let Some(canonical_parent_hash) = canonical_parent_hash {
+            if header.parent_hash != canonical_parent_hash {
+                return Err(ExecutionStageError::CanonicalChainMismatch {
+                    block_number,
+                    parent_hash: header.parent_hash,
+                    canonical_parent_hash,
+                });
+            }
+        }
+        canonical_parent_hash = Some(block_hash);
+    }
+
+    Ok(())
+}
+
+/// Executes the blocks with the given hashes in order on top of the state after
+/// the parent of the first one, regardless of whether they are canonical,
+/// e.g. to validate a reorg branch. The parent has to be canonical and executed,
+/// and each block has to be the child of the previous one.
+/// Nothing is persisted: the changes are kept in a buffer that is dropped in the end.
+///
+/// Returns whether each block executed successfully. Blocks following a failed one
+/// are not executed and are reported as failed.
+pub async fn execute_block_sequence<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    chain_config: ChainSpec,
+    hashes: &[H256],
+    header_cache: Option<&HeaderCache>,
+) -> Result<Vec<bool>, ExecutionStageError> {
+    let mut headers = Vec::<(H256, BlockHeader)>::with_capacity(hashes.len());
+    for &block_hash in hashes {
+        let block_number = accessors::chain::header_number::read(tx, block_hash)
+            .await?
+            .ok_or_else(|| format_err!("Unknown block {:?}", block_hash))?;
+        let header =
+            accessors::chain::header::read_cached(tx, header_cache, block_hash, block_number)
+                .await?
+                .ok_or(ExecutionStageError::MissingHeader(block_number))?;
+        if let Some((parent_hash, parent)) = headers.last() {
+            if header.parent_hash != *parent_hash || block_number != parent.number + 1 {
+                return Err(format_err!(
+                    "Block #{} ({:?}) is not the child of the previous block #{} ({:?})",
+                    block_number,
+                    block_hash,
+                    parent.number,
+                    parent_hash
+                )
+                .into());
+            }
+        }
+        headers.push((block_hash, header));
+    }
+    let first_block = match headers.first() {
+        Some((_, header)) => header.number,
+        None => return Ok(vec![]),
+    };
+
+    // the state is read as of the start of the first block, i.e. after its parent
+    let parent_number = BlockNumber(
+        first_block
+            .0
+            .checked_sub(1)
+            .ok_or_else(|| format_err!("The genesis block can't be executed"))?,
+    );
+    let canonical_parent_hash = accessors::chain::canonical_hash::read(tx, parent_number)
+        .await?
+        .ok_or(ExecutionStageError::MissingCanonicalHash(parent_number))?;
+    if headers[0].1.parent_hash != canonical_parent_hash {
+        return Err(ExecutionStageError::CanonicalChainMismatch {
+            block_number: first_block,
+            parent_hash: headers[0].1.parent_hash,
+            canonical_parent_hash,
+        });
+    }
+    let executed_to = EXECUTION.get_progress(tx).await?.unwrap_or_default();
+    if parent_number > executed_to {
+        return Err(format_err!(
+            "The parent block #{} is not executed yet, the execution is at #{}",
+            parent_number,
+            executed_to
+        )
+        .into());
+    }
+
+    let mut buffer = Buffer::new(tx, BlockNumber(0), Some(first_block));
+    let mut consensus_engine = engine_factory(chain_config.clone())?;
+    let mut analysis_cache = AnalysisCache::default();
+    let mut block_spec_cache = BlockSpecCache::new(&chain_config);
+
+    let mut results = Vec::with_capacity(hashes.len());
+    for (block_hash, header) in headers {
+        if results.last() == Some(&false) {
+            results.push(false);
+            continue;
+        }
+
+        let block_number = header.number;
+        let header = header.into();
+        // Senders of a non-canonical block are not stored by the sender recovery stage.
+        let block =
+            read_body_with_senders(tx, block_hash, block_number, SenderRecoveryMode::Recover)
+                .await?;
+
+        let block_spec = block_spec_cache.get(block_number);
+
+        let result = ExecutionProcessor::new(
+            &mut buffer,
+            &mut analysis_cache,
+            &mut *consensus_engine,
+            &header,
+            &block,
+            block_spec,
+        )
+        .execute_and_write_block()
+        .await;
+        if let Err(error) = &result {
+            warn!(
+                "Block #{} ({:?}) failed to execute: {:?}",
+                block_number, block_hash, error
+            );
+        }
+        results.push(result.is_ok());
+    }
+
+    Ok(results)
 }
 
 #[async_trait]
@@ -202,31 +1034,30 @@ impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for Execution {
             .previous_stage.ok_or_else(|| format_err!("Execution stage cannot be executed first, but no previous stage progress specified"))?.1;
 
         Ok(if max_block >= starting_block {
-            let executed_to = execute_batch_of_blocks(
+            let ExecutedBatch { executed_to, .. } = execute_batch_of_blocks(
                 tx,
+                self,
                 chain_config,
                 max_block,
-                self.batch_size,
-                self.history_batch_size,
-                self.batch_until,
-                self.commit_every,
                 starting_block,
                 input.first_started_at,
-                self.prune_from,
+                false,
             )
             .await?;
 
-            let done = executed_to == max_block || self.exit_after_batch;
+            let exhausted = executed_to == max_block;
 
             ExecOutput::Progress {
                 stage_progress: executed_to,
-                done,
+                done: exhausted || self.exit_after_batch,
+                exhausted,
                 must_commit: true,
             }
         } else {
             ExecOutput::Progress {
                 stage_progress: prev_progress,
                 done: true,
+                exhausted: true,
                 must_commit: false,
             }
         })
@@ -240,52 +1071,1207 @@ impl<'db, RwTx: MutableTransaction<'db>> Stage<'db, RwTx> for Execution {
     where
         'db: 'tx,
     {
-        info!("Unwinding accounts");
+        if let Some(max_unwind_depth) = self.max_unwind_depth {
+            let depth = input.stage_progress.0.saturating_sub(input.unwind_to.0);
+            if depth > max_unwind_depth && !self.force_deep_unwind.load(Ordering::SeqCst) {
+                return Err(ExecutionStageError::UnwindTooDeep {
+                    stage_progress: input.stage_progress,
+                    unwind_to: input.unwind_to,
+                    max_unwind_depth,
+                }
+                .into());
+            }
+        }
+
+        if let Some(pruned_below) = PRUNED_HISTORY.get_progress(tx).await? {
+            if input.unwind_to.0.saturating_add(1) < pruned_below.0 {
+                return Err(ExecutionStageError::UnwindBelowPrunedHistory {
+                    unwind_to: input.unwind_to,
+                    pruned_below,
+                }
+                .into());
+            }
+        }
+
+        let unwind_to = std::cmp::max(
+            input.unwind_to,
+            BlockNumber(
+                input
+                    .stage_progress
+                    .0
+                    .saturating_sub(self.unwind_batch_size),
+            ),
+        );
+
+        if let Some(header_cache) = &self.header_cache {
+            header_cache.invalidate_above(unwind_to);
+        }
+        self.state_root_cache.lock().take();
+
+        // Every changeset is deleted as soon as it's reverted, in the same transaction,
+        // so that the unwind resumed after a crash doesn't revert it again.
+        info!("Unwinding accounts to {}", unwind_to);
         let mut account_cursor = tx.mutable_cursor(tables::Account).await?;
 
         let mut account_cs_cursor = tx.mutable_cursor(tables::AccountChangeSet).await?;
-        let account_walker = walk_back(&mut account_cs_cursor, None);
-        pin!(account_walker);
         while let Some((block_number, tables::AccountChange { address, account })) =
-            account_walker.try_next().await?
+            account_cs_cursor.last().await?
         {
-            if block_number == input.unwind_to {
+            if block_number <= unwind_to {
                 break;
             }
 
             if let Some(account) = account {
                 account_cursor.put(address, account).await?;
-            } else if account_cursor.seek(address).await?.is_some() {
+            } else if account_cursor.seek_exact(address).await?.is_some() {
                 account_cursor.delete_current().await?;
             }
+            account_cs_cursor.delete_current().await?;
         }
 
-        info!("Unwinding storage");
+        info!("Unwinding storage to {}", unwind_to);
         let mut storage_cursor = tx.mutable_cursor_dupsort(tables::Storage).await?;
 
         let mut storage_cs_cursor = tx.mutable_cursor(tables::StorageChangeSet).await?;
-        let storage_walker = walk_back(&mut storage_cs_cursor, None);
-        pin!(storage_walker);
-
         while let Some((
             tables::StorageChangeKey {
                 block_number,
                 address,
             },
             tables::StorageChange { location, value },
-        )) = storage_walker.try_next().await?
+        )) = storage_cs_cursor.last().await?
         {
-            if block_number == input.unwind_to {
+            if block_number <= unwind_to {
                 break;
             }
 
             upsert_storage_value(&mut storage_cursor, address, h256_to_u256(location), value)
                 .await?;
+            storage_cs_cursor.delete_current().await?;
+        }
+
+        if unwind_to == input.unwind_to {
+            self.force_deep_unwind.store(false, Ordering::SeqCst);
         }
 
         Ok(UnwindOutput {
-            stage_progress: input.unwind_to,
+            stage_progress: unwind_to,
             must_commit: true,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv::new_mem_database, res::chainspec::MAINNET};
+    use tokio::pin;
+    use tokio_stream::StreamExt;
+
+    fn stage() -> Execution {
+        Execution {
+            batch_size: u64::MAX,
+            history_batch_size: u64::MAX,
+            exit_after_batch: false,
+            batch_until: None,
+            commit_every: None,
+            prune_from: Arc::new(AtomicU64::new(0)),
+            verify_state_root: false,
+            adaptive_batch: false,
+            adaptive_batch_blocks: 0,
+            log_every: Duration::from_secs(30),
+            log_every_blocks: None,
+            sender_lookahead: 0,
+            sender_lookahead_threads: 0,
+            sender_lookahead_pool: Default::default(),
+            sender_recovery: SenderRecoveryMode::Trust,
+            structured_progress_log: false,
+            throughput_window: 8,
+            header_cache: None,
+            verify_canonical: false,
+            batch_auto_tune: None,
+            progress_unit: ProgressUnit::Gas,
+            max_unwind_depth: None,
+            force_deep_unwind: Arc::new(AtomicBool::new(false)),
+            unwind_batch_size: u64::MAX,
+            track_touched: false,
+            touched: Default::default(),
+            state_root_cache: Default::default(),
+            buffer_config: BufferConfig::default(),
+        }
+    }
+
+    async fn execute_block_1<'db, Tx: MutableTransaction<'db>>(
+        tx: &Tx,
+    ) -> Result<ExecutedBatch, ExecutionStageError> {
+        execute_to(tx, BlockNumber(1)).await
+    }
+
+    async fn execute_to<'db, Tx: MutableTransaction<'db>>(
+        tx: &Tx,
+        max_block: BlockNumber,
+    ) -> Result<ExecutedBatch, ExecutionStageError> {
+        execute_batch_of_blocks(
+            tx,
+            &stage(),
+            MAINNET.clone(),
+            max_block,
+            BlockNumber(1),
+            (Instant::now(), None),
+            true,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn missing_data() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        assert!(matches!(
+            execute_block_1(&tx).await,
+            Err(ExecutionStageError::MissingCumulativeIndex(BlockNumber(0)))
+        ));
+
+        tx.set(
+            tables::CumulativeIndex,
+            BlockNumber(0),
+            tables::CumulativeData { tx_num: 0, gas: 0 },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            execute_block_1(&tx).await,
+            Err(ExecutionStageError::MissingCanonicalHash(BlockNumber(1)))
+        ));
+
+        let header = BlockHeader {
+            number: BlockNumber(1),
+            ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+        };
+        let hash = header.hash();
+        tx.set(tables::CanonicalHeader, BlockNumber(1), hash)
+            .await
+            .unwrap();
+        assert!(matches!(
+            execute_block_1(&tx).await,
+            Err(ExecutionStageError::MissingHeader(BlockNumber(1)))
+        ));
+
+        tx.set(tables::Header, (BlockNumber(1), hash), header)
+            .await
+            .unwrap();
+        assert!(matches!(
+            execute_block_1(&tx).await,
+            Err(ExecutionStageError::MissingBody(BlockNumber(1)))
+        ));
+    }
+
+    /// Writes the genesis and empty blocks mined by `miner` up to `max_block`.
+    async fn write_empty_blocks<'db, Tx: MutableTransaction<'db>>(
+        tx: &Tx,
+        miner: Address,
+        max_block: u64,
+    ) {
+        let genesis_hash = H256::from_low_u64_be(0xdead);
+        tx.set(tables::CanonicalHeader, BlockNumber(0), genesis_hash)
+            .await
+            .unwrap();
+        tx.set(tables::Config, genesis_hash, MAINNET.clone())
+            .await
+            .unwrap();
+
+        let mut parent_hash = genesis_hash;
+        for number in 0..=max_block {
+            tx.set(
+                tables::CumulativeIndex,
+                BlockNumber(number),
+                tables::CumulativeData { tx_num: 0, gas: 0 },
+            )
+            .await
+            .unwrap();
+            if number == 0 {
+                continue;
+            }
+
+            let header = BlockHeader {
+                parent_hash,
+                beneficiary: miner,
+                number: BlockNumber(number),
+                ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+            };
+            let hash = header.hash();
+            parent_hash = hash;
+            tx.set(tables::CanonicalHeader, BlockNumber(number), hash)
+                .await
+                .unwrap();
+            tx.set(tables::Header, (BlockNumber(number), hash), header)
+                .await
+                .unwrap();
+            accessors::chain::storage_body::write(
+                tx,
+                hash,
+                number,
+                &BodyForStorage {
+                    base_tx_id: TxIndex(0),
+                    tx_amount: 0,
+                    uncles: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn last_header() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let miner = Address::from_low_u64_be(0xbeef);
+        write_empty_blocks(&tx, miner, 3).await;
+
+        let ExecutedBatch {
+            executed_to,
+            last_header,
+        } = execute_to(&tx, BlockNumber(3)).await.unwrap();
+        assert_eq!(executed_to, BlockNumber(3));
+
+        let (hash, header) = last_header.unwrap();
+        assert_eq!(
+            Some(hash),
+            accessors::chain::canonical_hash::read(&tx, BlockNumber(3))
+                .await
+                .unwrap()
+        );
+        assert_eq!(header.hash(), hash);
+        assert_eq!(header.number, BlockNumber(3));
+        assert_eq!(header.beneficiary, miner);
+    }
+
+    #[tokio::test]
+    async fn prune_from_between_batches() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let miner = Address::from_low_u64_be(0xbeef);
+        write_empty_blocks(&tx, miner, 2).await;
+
+        let prune_from = Arc::new(AtomicU64::new(0));
+        let stage = Execution {
+            batch_size: u64::MAX,
+            history_batch_size: u64::MAX,
+            exit_after_batch: false,
+            batch_until: None,
+            commit_every: None,
+            prune_from: prune_from.clone(),
+            verify_state_root: false,
+            adaptive_batch: false,
+            adaptive_batch_blocks: 0,
+            log_every: Duration::from_secs(30),
+            log_every_blocks: None,
+            sender_lookahead: 0,
+            sender_lookahead_threads: 0,
+            sender_lookahead_pool: Default::default(),
+            sender_recovery: SenderRecoveryMode::Trust,
+            structured_progress_log: false,
+            throughput_window: 8,
+            header_cache: None,
+            verify_canonical: false,
+            batch_auto_tune: None,
+            progress_unit: ProgressUnit::Gas,
+            max_unwind_depth: None,
+            force_deep_unwind: Arc::new(AtomicBool::new(false)),
+            unwind_batch_size: u64::MAX,
+            track_touched: false,
+            touched: Default::default(),
+            state_root_cache: Default::default(),
+            buffer_config: BufferConfig::default(),
+        };
+
+        for number in 1..=2 {
+            stage
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: false,
+                        first_started_at: (Instant::now(), None),
+                        previous_stage: Some((
+                            crate::stagedsync::stages::SENDERS,
+                            BlockNumber(number),
+                        )),
+                        stage_progress: Some(BlockNumber(number - 1)),
+                    },
+                )
+                .await
+                .unwrap();
+
+            // the next batch skips the history of block 2
+            prune_from.store(3, Ordering::SeqCst);
+        }
+
+        let mut cursor = tx.cursor(tables::AccountChangeSet).await.unwrap();
+        let walker = walk(&mut cursor, None);
+        pin!(walker);
+        let mut changed_blocks = vec![];
+        while let Some((block_number, change)) = walker.try_next().await.unwrap() {
+            assert_eq!(change.address, miner);
+            changed_blocks.push(block_number);
+        }
+        assert_eq!(changed_blocks, vec![BlockNumber(1)]);
+    }
+
+    #[tokio::test]
+    async fn exit_after_batch() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        write_empty_blocks(&tx, Address::from_low_u64_be(0xbeef), 3).await;
+
+        let stage = Execution {
+            batch_size: u64::MAX,
+            history_batch_size: u64::MAX,
+            exit_after_batch: true,
+            batch_until: Some(BlockNumber(2)),
+            commit_every: None,
+            prune_from: Arc::new(AtomicU64::new(0)),
+            verify_state_root: false,
+            adaptive_batch: false,
+            adaptive_batch_blocks: 0,
+            log_every: Duration::from_secs(30),
+            log_every_blocks: None,
+            sender_lookahead: 0,
+            sender_lookahead_threads: 0,
+            sender_lookahead_pool: Default::default(),
+            sender_recovery: SenderRecoveryMode::Trust,
+            structured_progress_log: false,
+            throughput_window: 8,
+            header_cache: None,
+            verify_canonical: false,
+            batch_auto_tune: None,
+            progress_unit: ProgressUnit::Gas,
+            max_unwind_depth: None,
+            force_deep_unwind: Arc::new(AtomicBool::new(false)),
+            unwind_batch_size: u64::MAX,
+            track_touched: false,
+            touched: Default::default(),
+            state_root_cache: Default::default(),
+            buffer_config: BufferConfig::default(),
+        };
+
+        for (stage_progress, expected_progress, exhausted) in [(0, 2, false), (2, 3, true)] {
+            let output = stage
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: false,
+                        first_started_at: (Instant::now(), None),
+                        previous_stage: Some((crate::stagedsync::stages::SENDERS, BlockNumber(3))),
+                        stage_progress: Some(BlockNumber(stage_progress)),
+                    },
+                )
+                .await
+                .unwrap();
+
+            // stops after a single batch, whether the previous stage is reached or not
+            assert_eq!(
+                output,
+                ExecOutput::Progress {
+                    stage_progress: BlockNumber(expected_progress),
+                    done: true,
+                    exhausted,
+                    must_commit: true,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn batch_auto_tune() {
+        let batch_auto_tune = BatchAutoTune::new(Duration::from_secs(60), 1_000, 100_000);
+        assert_eq!(batch_auto_tune.batch_size(1_000_000), 100_000);
+
+        // 100 gas/sec aims at 6000 gas per batch
+        batch_auto_tune.on_batch_executed(1_000_000, 1_000, Duration::from_secs(10));
+        assert_eq!(batch_auto_tune.batch_size(1_000_000), 53_000);
+        for _ in 0..10 {
+            batch_auto_tune.on_batch_executed(1_000_000, 1_000, Duration::from_secs(10));
+        }
+        assert!((6_000..6_100).contains(&batch_auto_tune.batch_size(1_000_000)));
+
+        // a slow chain hits the lower bound
+        for _ in 0..20 {
+            batch_auto_tune.on_batch_executed(1_000_000, 1, Duration::from_secs(10));
+        }
+        assert_eq!(batch_auto_tune.batch_size(1_000_000), 1_000);
+    }
+
+    #[tokio::test]
+    async fn verify_canonical() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let miner = Address::from_low_u64_be(0xbeef);
+        write_empty_blocks(&tx, miner, 3).await;
+
+        // a header of another branch left canonical at block 2
+        let stale_header = BlockHeader {
+            number: BlockNumber(2),
+            extra_data: vec![0xff].into(),
+            ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+        };
+        let stale_hash = stale_header.hash();
+        tx.set(tables::Header, (BlockNumber(2), stale_hash), stale_header)
+            .await
+            .unwrap();
+        tx.set(tables::CanonicalHeader, BlockNumber(2), stale_hash)
+            .await
+            .unwrap();
+
+        let stage = Execution {
+            batch_size: u64::MAX,
+            history_batch_size: u64::MAX,
+            exit_after_batch: false,
+            batch_until: None,
+            commit_every: None,
+            prune_from: Arc::new(AtomicU64::new(0)),
+            verify_state_root: false,
+            adaptive_batch: false,
+            adaptive_batch_blocks: 0,
+            log_every: Duration::from_secs(30),
+            log_every_blocks: None,
+            sender_lookahead: 0,
+            sender_lookahead_threads: 0,
+            sender_lookahead_pool: Default::default(),
+            sender_recovery: SenderRecoveryMode::Trust,
+            structured_progress_log: false,
+            throughput_window: 8,
+            header_cache: None,
+            verify_canonical: true,
+            batch_auto_tune: None,
+            progress_unit: ProgressUnit::Gas,
+            max_unwind_depth: None,
+            force_deep_unwind: Arc::new(AtomicBool::new(false)),
+            unwind_batch_size: u64::MAX,
+            track_touched: false,
+            touched: Default::default(),
+            state_root_cache: Default::default(),
+            buffer_config: BufferConfig::default(),
+        };
+
+        let error = stage
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    first_started_at: (Instant::now(), None),
+                    previous_stage: Some((crate::stagedsync::stages::SENDERS, BlockNumber(3))),
+                    stage_progress: Some(BlockNumber(0)),
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExecutionStageError>(),
+            Some(ExecutionStageError::CanonicalChainMismatch {
+                block_number: BlockNumber(2),
+                ..
+            })
+        ));
+
+        // not even the consistent block 1 was executed
+        assert!(accessors::state::account::read(&tx, miner, None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn max_unwind_depth() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        write_empty_blocks(&tx, Address::from_low_u64_be(0xbeef), 4).await;
+
+        let force_deep_unwind = Arc::new(AtomicBool::new(false));
+        let stage = Execution {
+            batch_size: u64::MAX,
+            history_batch_size: u64::MAX,
+            exit_after_batch: false,
+            batch_until: None,
+            commit_every: None,
+            prune_from: Arc::new(AtomicU64::new(0)),
+            verify_state_root: false,
+            adaptive_batch: false,
+            adaptive_batch_blocks: 0,
+            log_every: Duration::from_secs(30),
+            log_every_blocks: None,
+            sender_lookahead: 0,
+            sender_lookahead_threads: 0,
+            sender_lookahead_pool: Default::default(),
+            sender_recovery: SenderRecoveryMode::Trust,
+            structured_progress_log: false,
+            throughput_window: 8,
+            header_cache: None,
+            verify_canonical: false,
+            batch_auto_tune: None,
+            progress_unit: ProgressUnit::Gas,
+            max_unwind_depth: Some(1),
+            force_deep_unwind: force_deep_unwind.clone(),
+            unwind_batch_size: u64::MAX,
+            track_touched: false,
+            touched: Default::default(),
+            state_root_cache: Default::default(),
+            buffer_config: BufferConfig::default(),
+        };
+        stage
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    first_started_at: (Instant::now(), None),
+                    previous_stage: Some((crate::stagedsync::stages::SENDERS, BlockNumber(4))),
+                    stage_progress: Some(BlockNumber(0)),
+                },
+            )
+            .await
+            .unwrap();
+
+        let unwind = |stage_progress, unwind_to| crate::stagedsync::stage::UnwindInput {
+            stage_progress: BlockNumber(stage_progress),
+            unwind_to: BlockNumber(unwind_to),
+        };
+
+        let error = stage.unwind(&mut tx, unwind(4, 2)).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExecutionStageError>(),
+            Some(ExecutionStageError::UnwindTooDeep {
+                max_unwind_depth: 1,
+                ..
+            })
+        ));
+
+        // within the limit
+        assert_eq!(
+            stage.unwind(&mut tx, unwind(4, 3)).await.unwrap(),
+            UnwindOutput {
+                stage_progress: BlockNumber(3),
+                must_commit: true,
+            }
+        );
+
+        // forced once
+        force_deep_unwind.store(true, Ordering::SeqCst);
+        stage.unwind(&mut tx, unwind(3, 1)).await.unwrap();
+        assert!(!force_deep_unwind.load(Ordering::SeqCst));
+        assert!(stage.unwind(&mut tx, unwind(1, 0)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unwind_below_pruned_history() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        write_empty_blocks(&tx, Address::from_low_u64_be(0xbeef), 4).await;
+
+        let stage = Execution {
+            batch_size: u64::MAX,
+            history_batch_size: u64::MAX,
+            exit_after_batch: false,
+            batch_until: None,
+            commit_every: None,
+            prune_from: Arc::new(AtomicU64::new(0)),
+            verify_state_root: false,
+            adaptive_batch: false,
+            adaptive_batch_blocks: 0,
+            log_every: Duration::from_secs(30),
+            log_every_blocks: None,
+            sender_lookahead: 0,
+            sender_lookahead_threads: 0,
+            sender_lookahead_pool: Default::default(),
+            sender_recovery: SenderRecoveryMode::Trust,
+            structured_progress_log: false,
+            throughput_window: 8,
+            header_cache: None,
+            verify_canonical: false,
+            batch_auto_tune: None,
+            progress_unit: ProgressUnit::Gas,
+            max_unwind_depth: None,
+            force_deep_unwind: Default::default(),
+            unwind_batch_size: u64::MAX,
+            track_touched: false,
+            touched: Default::default(),
+            state_root_cache: Default::default(),
+            buffer_config: BufferConfig::default(),
+        };
+        stage
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    first_started_at: (Instant::now(), None),
+                    previous_stage: Some((crate::stagedsync::stages::SENDERS, BlockNumber(4))),
+                    stage_progress: Some(BlockNumber(0)),
+                },
+            )
+            .await
+            .unwrap();
+
+        // the changesets of the blocks 1 and 2 are gone
+        PRUNED_HISTORY
+            .save_progress(&tx, BlockNumber(3))
+            .await
+            .unwrap();
+
+        let unwind = |unwind_to| crate::stagedsync::stage::UnwindInput {
+            stage_progress: BlockNumber(4),
+            unwind_to: BlockNumber(unwind_to),
+        };
+
+        let error = stage.unwind(&mut tx, unwind(1)).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExecutionStageError>(),
+            Some(ExecutionStageError::UnwindBelowPrunedHistory {
+                unwind_to: BlockNumber(1),
+                pruned_below: BlockNumber(3),
+            })
+        ));
+
+        // the changesets of the blocks 3 and 4 are there
+        assert_eq!(
+            stage.unwind(&mut tx, unwind(2)).await.unwrap(),
+            UnwindOutput {
+                stage_progress: BlockNumber(2),
+                must_commit: true,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn resumable_unwind() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let miner = Address::from_low_u64_be(0xbeef);
+        write_empty_blocks(&tx, miner, 4).await;
+
+        let stage = Execution {
+            batch_size: u64::MAX,
+            history_batch_size: u64::MAX,
+            exit_after_batch: false,
+            batch_until: None,
+            commit_every: None,
+            prune_from: Arc::new(AtomicU64::new(0)),
+            verify_state_root: false,
+            adaptive_batch: false,
+            adaptive_batch_blocks: 0,
+            log_every: Duration::from_secs(30),
+            log_every_blocks: None,
+            sender_lookahead: 0,
+            sender_lookahead_threads: 0,
+            sender_lookahead_pool: Default::default(),
+            sender_recovery: SenderRecoveryMode::Trust,
+            structured_progress_log: false,
+            throughput_window: 8,
+            header_cache: None,
+            verify_canonical: false,
+            batch_auto_tune: None,
+            progress_unit: ProgressUnit::Gas,
+            max_unwind_depth: None,
+            force_deep_unwind: Arc::new(AtomicBool::new(false)),
+            unwind_batch_size: 2,
+            track_touched: false,
+            touched: Default::default(),
+            state_root_cache: Default::default(),
+            buffer_config: BufferConfig::default(),
+        };
+
+        let mut balance_at_2 = None;
+        for (stage_progress, previous_stage) in [(0, 2), (2, 4)] {
+            stage
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: false,
+                        first_started_at: (Instant::now(), None),
+                        previous_stage: Some((
+                            crate::stagedsync::stages::SENDERS,
+                            BlockNumber(previous_stage),
+                        )),
+                        stage_progress: Some(BlockNumber(stage_progress)),
+                    },
+                )
+                .await
+                .unwrap();
+            if balance_at_2.is_none() {
+                balance_at_2 = Some(
+                    accessors::state::account::read(&tx, miner, None)
+                        .await
+                        .unwrap()
+                        .unwrap()
+                        .balance,
+                );
+            }
+        }
+
+        let unwind = |stage_progress| crate::stagedsync::stage::UnwindInput {
+            stage_progress: BlockNumber(stage_progress),
+            unwind_to: BlockNumber(0),
+        };
+
+        // the first batch, then the same one again as if the progress wasn't saved
+        for _ in 0..2 {
+            assert_eq!(
+                stage.unwind(&mut tx, unwind(4)).await.unwrap(),
+                UnwindOutput {
+                    stage_progress: BlockNumber(2),
+                    must_commit: true,
+                }
+            );
+            assert_eq!(
+                accessors::state::account::read(&tx, miner, None)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .balance,
+                balance_at_2.unwrap()
+            );
+        }
+
+        assert_eq!(
+            stage
+                .unwind(&mut tx, unwind(2))
+                .await
+                .unwrap()
+                .stage_progress,
+            BlockNumber(0)
+        );
+        assert!(accessors::state::account::read(&tx, miner, None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(tx
+            .cursor(tables::AccountChangeSet)
+            .await
+            .unwrap()
+            .last()
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    /// A transfer from 0x5D6C3f4c505385f4F99057C06F0e265FFc16E829.
+    fn signed_transaction(nonce: u64) -> MessageWithSignature {
+        let secret_key = secp256k1::SecretKey::from_slice(&hex_literal::hex!(
+            "17bc08619f3b717b022728e84f5f39c3f2b3e2ad00cfecbb689e4c1f7965da5f"
+        ))
+        .unwrap();
+
+        let message = Message::Legacy {
+            chain_id: None,
+            nonce,
+            gas_price: 1.into(),
+            gas_limit: 21_000,
+            action: TransactionAction::Call(Address::zero()),
+            value: 0.into(),
+            input: Default::default(),
+        };
+        let (recovery_id, signature) = secp256k1::SECP256K1
+            .sign_recoverable(
+                &secp256k1::Message::from_slice(message.hash().as_bytes()).unwrap(),
+                &secret_key,
+            )
+            .serialize_compact();
+        MessageWithSignature {
+            message,
+            signature: MessageSignature::new(
+                recovery_id.to_i32() == 1,
+                H256::from_slice(&signature[..32]),
+                H256::from_slice(&signature[32..]),
+            )
+            .unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn sender_lookahead() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let sender = Address::from(hex_literal::hex!(
+            "5D6C3f4c505385f4F99057C06F0e265FFc16E829"
+        ));
+
+        // blocks 1 and 2 have a transaction each, the body of block 3 is missing
+        for number in 1..=3_u64 {
+            let header = BlockHeader {
+                number: BlockNumber(number),
+                ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+            };
+            let hash = header.hash();
+            accessors::chain::canonical_hash::write(&tx, number, hash)
+                .await
+                .unwrap();
+            if number == 3 {
+                continue;
+            }
+
+            accessors::chain::tx::write(&tx, number, &[signed_transaction(number)])
+                .await
+                .unwrap();
+            accessors::chain::storage_body::write(
+                &tx,
+                hash,
+                number,
+                &BodyForStorage {
+                    base_tx_id: TxIndex(number),
+                    tx_amount: 1,
+                    uncles: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let mut lookahead = SenderLookahead::new(2, &pool);
+        let body = lookahead
+            .read(&tx, BlockNumber(1), BlockNumber(3))
+            .await
+            .unwrap();
+        assert_eq!(body.transactions.len(), 1);
+        assert_eq!(body.transactions[0].sender, sender);
+        assert_eq!(lookahead.bodies.len(), 1);
+        // the window from block 3 is read ahead, and ends at its missing body
+        assert_eq!(
+            lookahead.next.as_ref().map(|(first_block, _)| *first_block),
+            Some(BlockNumber(3))
+        );
+
+        let body = lookahead
+            .read(&tx, BlockNumber(2), BlockNumber(3))
+            .await
+            .unwrap();
+        assert_eq!(body.transactions[0].sender, sender);
+        assert!(matches!(
+            body.transactions[0].message,
+            Message::Legacy { nonce: 2, .. }
+        ));
+
+        assert!(matches!(
+            lookahead.read(&tx, BlockNumber(3), BlockNumber(3)).await,
+            Err(ExecutionStageError::MissingBody(BlockNumber(3)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sender_recovery_modes() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let sender = Address::from(hex_literal::hex!(
+            "5D6C3f4c505385f4F99057C06F0e265FFc16E829"
+        ));
+        let stored_sender = Address::from_low_u64_be(0xbad);
+
+        let block_number = BlockNumber(1);
+        let hash = H256::from_low_u64_be(0xbeef);
+        accessors::chain::tx::write(&tx, 1_u64, &[signed_transaction(1)])
+            .await
+            .unwrap();
+        accessors::chain::storage_body::write(
+            &tx,
+            hash,
+            block_number,
+            &BodyForStorage {
+                base_tx_id: TxIndex(1),
+                tx_amount: 1,
+                uncles: vec![],
+            },
+        )
+        .await
+        .unwrap();
+        accessors::chain::tx_sender::write(&tx, hash, block_number, vec![stored_sender])
+            .await
+            .unwrap();
+
+        for (sender_recovery, expected_sender) in [
+            (SenderRecoveryMode::Trust, stored_sender),
+            (SenderRecoveryMode::Recover, sender),
+        ] {
+            let body = read_body_with_senders(&tx, hash, block_number, sender_recovery)
+                .await
+                .unwrap();
+            assert_eq!(body.transactions[0].sender, expected_sender);
+        }
+
+        match read_body_with_senders(&tx, hash, block_number, SenderRecoveryMode::Verify).await {
+            Err(ExecutionStageError::SenderMismatch {
+                block_number: BlockNumber(1),
+                index: 0,
+                stored,
+                recovered,
+            }) => {
+                assert_eq!(stored, Some(stored_sender));
+                assert_eq!(recovered, sender);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        accessors::chain::tx_sender::write(&tx, hash, block_number, vec![sender])
+            .await
+            .unwrap();
+        let body = read_body_with_senders(&tx, hash, block_number, SenderRecoveryMode::Verify)
+            .await
+            .unwrap();
+        assert_eq!(body.transactions[0].sender, sender);
+    }
+
+    #[tokio::test]
+    async fn non_canonical_sequence() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let miner = Address::from_low_u64_be(0xbeef);
+        let sender = Address::from(hex_literal::hex!(
+            "5D6C3f4c505385f4F99057C06F0e265FFc16E829"
+        ));
+        tx.set(
+            tables::Account,
+            sender,
+            Account {
+                balance: 21_000.into(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        // the transaction of the block 3, its sender is not stored
+        accessors::chain::tx::write(&tx, 0_u64, &[signed_transaction(0)])
+            .await
+            .unwrap();
+
+        let mut parent_hash = H256::from_low_u64_be(0xdead);
+        let mut branch = vec![];
+        for (number, gas_used, tx_amount) in [
+            (1, 0, 0),
+            (2, 0, 0),
+            (3, 21_000, 1),
+            (4, 21_000, 0),
+            (5, 0, 0),
+        ] {
+            let header = BlockHeader {
+                parent_hash,
+                beneficiary: miner,
+                number: BlockNumber(number),
+                gas_limit: 21_000,
+                gas_used,
+                ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+            };
+            let hash = header.hash();
+            tx.set(tables::HeaderNumber, hash, header.number)
+                .await
+                .unwrap();
+            tx.set(tables::Header, (header.number, hash), header)
+                .await
+                .unwrap();
+            accessors::chain::storage_body::write(
+                &tx,
+                hash,
+                number,
+                &BodyForStorage {
+                    base_tx_id: TxIndex(0),
+                    tx_amount,
+                    uncles: vec![],
+                },
+            )
+            .await
+            .unwrap();
+
+            branch.push(hash);
+            parent_hash = hash;
+        }
+
+        // the branch is not canonical, but its parent is
+        tx.set(
+            tables::CanonicalHeader,
+            BlockNumber(0),
+            H256::from_low_u64_be(0xdead),
+        )
+        .await
+        .unwrap();
+        tx.set(
+            tables::CanonicalHeader,
+            BlockNumber(1),
+            H256::from_low_u64_be(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            execute_block_sequence(&tx, MAINNET.clone(), &branch[..2], None)
+                .await
+                .unwrap(),
+            vec![true, true]
+        );
+        // block 4 claims gas for no transactions
+        assert_eq!(
+            execute_block_sequence(&tx, MAINNET.clone(), &branch, None)
+                .await
+                .unwrap(),
+            vec![true, true, true, false, false]
+        );
+        assert!(
+            execute_block_sequence(&tx, MAINNET.clone(), &[H256::from_low_u64_be(1)], None)
+                .await
+                .is_err()
+        );
+        // not linked
+        assert!(
+            execute_block_sequence(&tx, MAINNET.clone(), &[branch[0], branch[2]], None)
+                .await
+                .is_err()
+        );
+        // the parent is not canonical
+        assert!(matches!(
+            execute_block_sequence(&tx, MAINNET.clone(), &branch[1..], None).await,
+            Err(ExecutionStageError::CanonicalChainMismatch { .. })
+        ));
+
+        // nothing is persisted
+        assert_eq!(tx.get(tables::Account, miner).await.unwrap(), None);
+        assert_eq!(
+            tx.get(tables::Account, sender)
+                .await
+                .unwrap()
+                .unwrap()
+                .nonce,
+            0
+        );
+    }
+
+    fn blocks_in_batch(batch_size: &mut BatchSize, gas_limit: u64, gas_used: u64) -> u64 {
+        let mut gas_since_start = 0;
+        let mut blocks = 0;
+        loop {
+            batch_size.push_gas_limit(gas_limit);
+            gas_since_start += gas_used;
+            blocks += 1;
+            if gas_since_start >= batch_size.get() {
+                return blocks;
+            }
+        }
+    }
+
+    #[test]
+    fn adaptive_batch_size() {
+        let mut fixed = BatchSize::new(1_000_000_000, None);
+        assert_eq!(fixed.get(), 1_000_000_000);
+        fixed.push_gas_limit(30_000_000);
+        assert_eq!(fixed.get(), 1_000_000_000);
+
+        let mut adaptive = BatchSize::new(1_000_000_000, Some(100));
+        // no headers seen yet
+        assert_eq!(adaptive.get(), 1_000_000_000);
+
+        // early blocks: low gas limit, mostly empty
+        let early_blocks = blocks_in_batch(&mut adaptive, 5_000, 1_000);
+        assert_eq!(adaptive.get(), 100 * 5_000);
+        assert_eq!(early_blocks, 500);
+
+        // recent blocks: high gas limit, half full
+        let recent_blocks = blocks_in_batch(&mut adaptive, 30_000_000, 15_000_000);
+        assert_eq!(adaptive.get(), 100 * 30_000_000);
+        assert!(early_blocks > recent_blocks);
+
+        // the average follows the window of the most recent gas limits
+        for _ in 0..ADAPTIVE_BATCH_WINDOW / 2 {
+            adaptive.push_gas_limit(10_000_000);
+        }
+        assert_eq!(adaptive.get(), 100 * 20_000_000);
+    }
+
+    /// Collects the names of the fields of the logged events.
+    #[derive(Clone, Default)]
+    struct FieldNames(Arc<std::sync::Mutex<Vec<&'static str>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for FieldNames {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut names = self.0.lock().unwrap();
+            names.extend(event.metadata().fields().iter().map(|field| field.name()));
+        }
+    }
+
+    fn logged_progress_fields(structured: bool) -> Vec<&'static str> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let field_names = FieldNames::default();
+        let subscriber = tracing_subscriber::registry().with(field_names.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            log_progress(
+                structured,
+                BlockNumber(10),
+                1.5,
+                2.0,
+                Some((50_f64, Duration::from_secs(60))),
+            );
+            log_progress(structured, BlockNumber(20), 1.5, 2.0, None);
+        });
+        let names = field_names.0.lock().unwrap().clone();
+        names
+    }
+
+    #[test]
+    fn structured_progress_log() {
+        let fields = logged_progress_fields(true);
+        for name in [
+            "block",
+            "mgas_sec",
+            "windowed_mgas_sec",
+            "progress_pct",
+            "eta_secs",
+        ] {
+            // in both the progress and the completion events
+            assert_eq!(
+                fields.iter().filter(|field| **field == name).count(),
+                2,
+                "{}",
+                name
+            );
+        }
+
+        let fields = logged_progress_fields(false);
+        assert!(fields.contains(&"block_number"));
+        assert!(!fields.contains(&"progress_pct"));
+    }
+
+    #[test]
+    fn gas_rate() {
+        let started_at = Instant::now();
+        let at = |secs| started_at + Duration::from_secs(secs);
+
+        let mut rate = GasRate::new(3, started_at);
+        // no interval yet
+        assert_eq!(rate.mgas_sec(), None);
+
+        // 10 Mgas/sec, then a jittery 40 Mgas/sec
+        rate.push(at(10), 100_000_000);
+        assert_eq!(rate.mgas_sec(), Some(10_f64));
+        rate.push(at(20), 500_000_000);
+        assert_eq!(rate.mgas_sec(), Some(25_f64));
+        rate.push(at(30), 800_000_000);
+        assert!((rate.mgas_sec().unwrap() - 800_f64 / 30_f64).abs() < 1e-9);
+
+        // the first interval drops out of the window
+        rate.push(at(40), 1_100_000_000);
+        assert_eq!(rate.samples.len(), 4);
+        assert!((rate.mgas_sec().unwrap() - 1_000_f64 / 30_f64).abs() < 1e-9);
+    }
+}
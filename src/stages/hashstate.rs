@@ -196,6 +196,7 @@ where
         Ok(ExecOutput::Progress {
             stage_progress: max_block,
             done: true,
+            exhausted: true,
             must_commit: true,
         })
     }
@@ -221,7 +222,11 @@ where
 
                 if let Some(account) = account {
                     hashed_account_cur.put(hashed_address, account).await?
-                } else if hashed_account_cur.seek(hashed_address).await?.is_some() {
+                } else if hashed_account_cur
+                    .seek_exact(hashed_address)
+                    .await?
+                    .is_some()
+                {
                     hashed_account_cur.delete_current().await?
                 }
             } else {
@@ -449,6 +454,7 @@ mod tests {
             ExecOutput::Progress {
                 stage_progress: BlockNumber(3),
                 done: true,
+                exhausted: true,
                 must_commit: true,
             }
         );
@@ -631,6 +631,7 @@ where
         Ok(ExecOutput::Progress {
             stage_progress: cmp::max(max_block, past_progress),
             done: true,
+            exhausted: true,
             must_commit: true,
         })
     }
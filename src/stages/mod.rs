@@ -4,6 +4,8 @@ mod downloader;
 mod execution;
 mod hashstate;
 mod interhashes;
+mod prune;
+mod prune_headers;
 mod sender_recovery;
 mod stage_util;
 mod tx_lookup;
@@ -11,7 +13,10 @@ mod tx_lookup;
 pub use block_hashes::BlockHashes;
 pub use cumulative_index::CumulativeIndex;
 pub use downloader::HeaderDownload;
-pub use execution::Execution;
+pub use execution::{BatchAutoTune, Execution, ProgressUnit, SenderRecoveryMode};
 pub use hashstate::{promote_clean_accounts, promote_clean_storage, HashState};
 pub use interhashes::{generate_interhashes, Interhashes};
+pub use prune::Prune;
+pub use prune_headers::{PruneHeaders, MIN_KEEP_HEADERS};
 pub use sender_recovery::SenderRecovery;
+pub use tx_lookup::{migrate_tx_lookup, TxLookup};
@@ -0,0 +1,292 @@
+use crate::{
+    kv::{tables, traits::*},
+    models::*,
+    stagedsync::{
+        stage::*,
+        stages::{ESTIMATED_TIP, PRUNE, PRUNED_HISTORY, PRUNE_HEADERS, UNWIND},
+    },
+    StageId,
+};
+use anyhow::format_err;
+use async_trait::async_trait;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::pin;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+/// Deletes the account and storage changesets of the blocks older than the most recent `keep_blocks`.
+///
+/// History is never pruned above the progress of any other stage. Stage progress is the block
+/// the history has been pruned for: the changesets below `progress - keep_blocks` are gone.
+/// The block they are gone below is kept as the progress of `PRUNED_HISTORY`,
+/// so that the unwinds and the historical state readers refuse to go past it.
+#[derive(Debug)]
+pub struct Prune {
+    /// How many of the most recent blocks of history to keep.
+    pub keep_blocks: u64,
+    /// How many blocks of history to delete before committing.
+    pub batch_size: u64,
+    /// Advanced to the block the history is pruned below,
+    /// so that the execution doesn't write the history there again, e.g. after an unwind.
+    pub prune_from: Option<Arc<AtomicU64>>,
+}
+
+/// Lowest progress among all the stages other than the pruning ones.
+pub(crate) async fn min_other_stage_progress<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+) -> anyhow::Result<Option<BlockNumber>> {
+    let mut cursor = tx.cursor(tables::SyncStage.erased()).await?;
+    let walker = walk(&mut cursor, None);
+    pin!(walker);
+
+    let mut min_progress = None;
+    while let Some((stage, progress)) = walker.try_next().await? {
+        if [PRUNE, PRUNE_HEADERS, UNWIND, PRUNED_HISTORY, ESTIMATED_TIP]
+            .iter()
+            .any(|stage_id| stage == stage_id.0.as_bytes())
+        {
+            continue;
+        }
+
+        let progress = tables::ErasedTable::<tables::SyncStage>::decode_value(&progress)?;
+        min_progress = Some(min_progress.map_or(progress, |min| std::cmp::min(min, progress)));
+    }
+
+    Ok(min_progress)
+}
+
+async fn prune_changesets<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+    below: BlockNumber,
+) -> anyhow::Result<()> {
+    let mut account_cursor = tx.mutable_cursor(tables::AccountChangeSet).await?;
+    while let Some((block_number, _)) = account_cursor.first().await? {
+        if block_number >= below {
+            break;
+        }
+
+        account_cursor.delete_current().await?;
+    }
+
+    let mut storage_cursor = tx.mutable_cursor(tables::StorageChangeSet).await?;
+    while let Some((tables::StorageChangeKey { block_number, .. }, _)) =
+        storage_cursor.first().await?
+    {
+        if block_number >= below {
+            break;
+        }
+
+        storage_cursor.delete_current().await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for Prune
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        PRUNE
+    }
+
+    fn description(&self) -> &'static str {
+        "Pruning of old history"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let prev_progress = input.stage_progress.unwrap_or_default();
+        let previous_stage = input
+            .previous_stage
+            .map(|(_, v)| v)
+            .ok_or_else(|| format_err!("Cannot be the first stage"))?;
+        let max_block = std::cmp::min(
+            previous_stage,
+            min_other_stage_progress(tx)
+                .await?
+                .unwrap_or(previous_stage),
+        );
+
+        let pruned_below = BlockNumber(prev_progress.0.saturating_sub(self.keep_blocks));
+        let target = BlockNumber(max_block.0.saturating_sub(self.keep_blocks));
+        if target <= pruned_below {
+            return Ok(ExecOutput::Progress {
+                stage_progress: std::cmp::max(prev_progress, max_block),
+                done: true,
+                exhausted: true,
+                must_commit: false,
+            });
+        }
+
+        let below = std::cmp::min(
+            target,
+            BlockNumber(pruned_below.0.saturating_add(self.batch_size)),
+        );
+        info!("Pruning history below block {}", below);
+        prune_changesets(tx, below).await?;
+        let pruned_below = PRUNED_HISTORY.get_progress(tx).await?.unwrap_or_default();
+        PRUNED_HISTORY
+            .save_progress(tx, std::cmp::max(pruned_below, below))
+            .await?;
+        if let Some(prune_from) = &self.prune_from {
+            prune_from.fetch_max(below.0, Ordering::SeqCst);
+        }
+
+        let done = below == target;
+        Ok(ExecOutput::Progress {
+            stage_progress: if done {
+                max_block
+            } else {
+                below + self.keep_blocks
+            },
+            done,
+            exhausted: done,
+            must_commit: true,
+        })
+    }
+
+    async fn unwind<'tx>(
+        &self,
+        _: &'tx mut RwTx,
+        input: UnwindInput,
+    ) -> anyhow::Result<UnwindOutput>
+    where
+        'db: 'tx,
+    {
+        // Pruned history cannot be restored, the next run just has less to prune.
+        Ok(UnwindOutput {
+            stage_progress: input.unwind_to,
+            must_commit: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv::new_mem_database, stagedsync::stages::EXECUTION};
+    use ethereum_types::H256;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn prune_in_batches() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let address = Address::from_low_u64_be(0xa1);
+        for block_number in 1..=20 {
+            tx.set(
+                tables::AccountChangeSet,
+                BlockNumber(block_number),
+                tables::AccountChange {
+                    address,
+                    account: None,
+                },
+            )
+            .await
+            .unwrap();
+            tx.set(
+                tables::StorageChangeSet,
+                tables::StorageChangeKey {
+                    block_number: BlockNumber(block_number),
+                    address,
+                },
+                tables::StorageChange {
+                    location: H256::zero(),
+                    value: block_number.into(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+        EXECUTION.save_progress(&tx, BlockNumber(20)).await.unwrap();
+        // a lagging stage holds pruning back
+        StageId("Lagging")
+            .save_progress(&tx, BlockNumber(15))
+            .await
+            .unwrap();
+
+        let prune_from = Arc::new(AtomicU64::new(0));
+        let stage = Prune {
+            keep_blocks: 5,
+            batch_size: 4,
+            prune_from: Some(prune_from.clone()),
+        };
+        let mut progress = None;
+        let mut invocations = 0;
+        loop {
+            let output = stage
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: progress.is_some(),
+                        first_started_at: (Instant::now(), None),
+                        previous_stage: Some((EXECUTION, BlockNumber(20))),
+                        stage_progress: progress,
+                    },
+                )
+                .await
+                .unwrap();
+            invocations += 1;
+
+            if let ExecOutput::Progress {
+                stage_progress,
+                done,
+                ..
+            } = output
+            {
+                PRUNE.save_progress(&tx, stage_progress).await.unwrap();
+                progress = Some(stage_progress);
+                if done {
+                    break;
+                }
+            } else {
+                unreachable!()
+            }
+        }
+        assert_eq!(invocations, 3);
+        assert_eq!(progress, Some(BlockNumber(15)));
+        assert_eq!(prune_from.load(Ordering::SeqCst), 10);
+        assert_eq!(
+            PRUNED_HISTORY.get_progress(&tx).await.unwrap(),
+            Some(BlockNumber(10))
+        );
+
+        let mut cursor = tx.cursor(tables::AccountChangeSet).await.unwrap();
+        assert_eq!(cursor.first().await.unwrap().unwrap().0, BlockNumber(10));
+        let mut cursor = tx.cursor(tables::StorageChangeSet).await.unwrap();
+        assert_eq!(
+            cursor.first().await.unwrap().unwrap().0.block_number,
+            BlockNumber(10)
+        );
+
+        // nothing more to do until the other stages move on
+        assert_eq!(
+            stage
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: false,
+                        first_started_at: (Instant::now(), None),
+                        previous_stage: Some((EXECUTION, BlockNumber(20))),
+                        stage_progress: progress,
+                    },
+                )
+                .await
+                .unwrap(),
+            ExecOutput::Progress {
+                stage_progress: BlockNumber(15),
+                done: true,
+                exhausted: true,
+                must_commit: false,
+            }
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    downloader::Checkpoints,
+    kv::{tables, traits::*},
+    models::*,
+    stagedsync::{stage::*, stages::PRUNE_HEADERS},
+    stages::prune::min_other_stage_progress,
+    StageId,
+};
+use anyhow::format_err;
+use async_trait::async_trait;
+use tracing::*;
+
+/// The execution reads the hashes of up to 256 ancestors of a block,
+/// and the validation of an incoming block reads its parent and the ancestors of its ommers.
+pub const MIN_KEEP_HEADERS: u64 = 256;
+
+/// Deletes the canonical hashes and the headers older than the most recent `keep_headers`,
+/// along with their hash-to-number and total difficulty entries,
+/// for the nodes which only need the recent chain. The genesis and the checkpoints are kept.
+///
+/// Like the history, headers are never pruned above the progress of any other stage,
+/// so that the stages lagging behind still find them. Stage progress is the block
+/// the headers have been pruned for: the ones below `progress - keep_headers` are gone.
+#[derive(Debug)]
+pub struct PruneHeaders {
+    /// How many of the most recent headers to keep, at least `MIN_KEEP_HEADERS`.
+    pub keep_headers: u64,
+    /// How many blocks of headers to delete before committing.
+    pub batch_size: u64,
+    /// The headers at the checkpoint heights are kept regardless.
+    pub checkpoints: Checkpoints,
+}
+
+async fn prune_headers<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+    from: BlockNumber,
+    below: BlockNumber,
+    checkpoints: &Checkpoints,
+) -> anyhow::Result<()> {
+    let mut canonical_cursor = tx.mutable_cursor(tables::CanonicalHeader).await?;
+    let mut header_cursor = tx.mutable_cursor(tables::Header).await?;
+    for block_number in std::cmp::max(from.0, 1)..below.0 {
+        let block_number = BlockNumber(block_number);
+        if checkpoints.get(block_number).is_some() {
+            continue;
+        }
+
+        if canonical_cursor.seek_exact(block_number).await?.is_some() {
+            canonical_cursor.delete_current().await?;
+        }
+
+        // the non-canonical headers at the height along, with their numbers and total difficulties
+        while let Some(((number, hash), _)) = header_cursor.seek(block_number).await? {
+            if number != block_number {
+                break;
+            }
+
+            header_cursor.delete_current().await?;
+            tx.del(tables::HeaderNumber, hash, None).await?;
+            tx.del(tables::HeadersTotalDifficulty, (number, hash), None)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for PruneHeaders
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        PRUNE_HEADERS
+    }
+
+    fn description(&self) -> &'static str {
+        "Pruning of old headers"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let keep_headers = std::cmp::max(self.keep_headers, MIN_KEEP_HEADERS);
+        let prev_progress = input.stage_progress.unwrap_or_default();
+        let previous_stage = input
+            .previous_stage
+            .map(|(_, v)| v)
+            .ok_or_else(|| format_err!("Cannot be the first stage"))?;
+        let max_block = std::cmp::min(
+            previous_stage,
+            min_other_stage_progress(tx)
+                .await?
+                .unwrap_or(previous_stage),
+        );
+
+        let pruned_below = BlockNumber(prev_progress.0.saturating_sub(keep_headers));
+        let target = BlockNumber(max_block.0.saturating_sub(keep_headers));
+        if target <= pruned_below {
+            return Ok(ExecOutput::Progress {
+                stage_progress: std::cmp::max(prev_progress, max_block),
+                done: true,
+                exhausted: true,
+                must_commit: false,
+            });
+        }
+
+        let below = std::cmp::min(
+            target,
+            BlockNumber(pruned_below.0.saturating_add(self.batch_size)),
+        );
+        info!("Pruning headers below block {}", below);
+        prune_headers(tx, pruned_below, below, &self.checkpoints).await?;
+
+        let done = below == target;
+        Ok(ExecOutput::Progress {
+            stage_progress: if done {
+                max_block
+            } else {
+                below + keep_headers
+            },
+            done,
+            exhausted: done,
+            must_commit: true,
+        })
+    }
+
+    async fn unwind<'tx>(
+        &self,
+        _: &'tx mut RwTx,
+        input: UnwindInput,
+    ) -> anyhow::Result<UnwindOutput>
+    where
+        'db: 'tx,
+    {
+        // Pruned headers cannot be restored, the next run just has less to prune.
+        Ok(UnwindOutput {
+            stage_progress: input.unwind_to,
+            must_commit: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv::new_mem_database, stagedsync::stages::EXECUTION};
+    use ethereum_types::{H256, U256};
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn prune_below_horizon() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let header = |number, gas_limit| BlockHeader {
+            number: BlockNumber(number),
+            gas_limit,
+            ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+        };
+        for number in 0..=400 {
+            let header = header(number, 0);
+            let hash = header.hash();
+            tx.set(tables::CanonicalHeader, BlockNumber(number), hash)
+                .await
+                .unwrap();
+            tx.set(tables::HeaderNumber, hash, BlockNumber(number))
+                .await
+                .unwrap();
+            tx.set(
+                tables::HeadersTotalDifficulty,
+                (BlockNumber(number), hash),
+                U256::from(number),
+            )
+            .await
+            .unwrap();
+            tx.set(tables::Header, (BlockNumber(number), hash), header)
+                .await
+                .unwrap();
+        }
+        let fork_header = header(3, 1);
+        let fork_hash = fork_header.hash();
+        tx.set(tables::Header, (BlockNumber(3), fork_hash), fork_header)
+            .await
+            .unwrap();
+        tx.set(tables::HeaderNumber, fork_hash, BlockNumber(3))
+            .await
+            .unwrap();
+        EXECUTION
+            .save_progress(&tx, BlockNumber(400))
+            .await
+            .unwrap();
+        // a lagging stage holds pruning back
+        StageId("Lagging")
+            .save_progress(&tx, BlockNumber(350))
+            .await
+            .unwrap();
+
+        let stage = PruneHeaders {
+            // raised to the minimum
+            keep_headers: 10,
+            batch_size: 50,
+            checkpoints: Checkpoints::new(vec![(BlockNumber(64), H256::zero())]),
+        };
+        let mut progress = None;
+        let mut invocations = 0;
+        loop {
+            let output = stage
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: progress.is_some(),
+                        first_started_at: (Instant::now(), None),
+                        previous_stage: Some((EXECUTION, BlockNumber(400))),
+                        stage_progress: progress,
+                    },
+                )
+                .await
+                .unwrap();
+            invocations += 1;
+
+            if let ExecOutput::Progress {
+                stage_progress,
+                done,
+                ..
+            } = output
+            {
+                progress = Some(stage_progress);
+                if done {
+                    break;
+                }
+            } else {
+                unreachable!()
+            }
+        }
+        assert_eq!(invocations, 2);
+        assert_eq!(progress, Some(BlockNumber(350)));
+
+        for (number, kept) in [(0, true), (1, false), (64, true), (93, false), (94, true)] {
+            let hash = header(number, 0).hash();
+            assert_eq!(
+                tx.get(tables::CanonicalHeader, BlockNumber(number))
+                    .await
+                    .unwrap()
+                    .is_some(),
+                kept
+            );
+            assert_eq!(
+                tx.get(tables::Header, (BlockNumber(number), hash))
+                    .await
+                    .unwrap()
+                    .is_some(),
+                kept
+            );
+            assert_eq!(
+                tx.get(tables::HeaderNumber, hash).await.unwrap().is_some(),
+                kept
+            );
+            assert_eq!(
+                tx.get(tables::HeadersTotalDifficulty, (BlockNumber(number), hash))
+                    .await
+                    .unwrap()
+                    .is_some(),
+                kept
+            );
+        }
+        assert_eq!(
+            tx.get(tables::Header, (BlockNumber(3), header(3, 1).hash()))
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            tx.get(tables::HeaderNumber, header(3, 1).hash())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -8,6 +8,7 @@ use crate::{
     StageId,
 };
 use async_trait::async_trait;
+use ethereum_types::H256;
 use rayon::prelude::*;
 use std::time::{Duration, Instant};
 use tokio::pin;
@@ -15,7 +16,12 @@ use tokio_stream::StreamExt as _;
 use tracing::*;
 
 #[derive(Debug)]
-pub struct SenderRecovery;
+pub struct SenderRecovery {
+    /// Keep the recovered senders of the unwound blocks, and reuse them if the blocks come back.
+    /// Senders are keyed by block hash, so they stay valid as long as they are kept.
+    /// Only the last unwound block of every height is kept.
+    pub cache_senders: bool,
+}
 
 #[async_trait]
 impl<'db, RwTx> Stage<'db, RwTx> for SenderRecovery
@@ -56,6 +62,17 @@ where
             .map(|v| v.tx_num);
         let done = loop {
             while let Some(((block_number, hash), body)) = walker.try_next().await? {
+                highest_block = block_number;
+
+                if self.cache_senders
+                    && tx
+                        .get(tables::TxSender, (block_number, hash))
+                        .await?
+                        .is_some()
+                {
+                    continue;
+                }
+
                 let txs = walk(&mut tx_cur, Some(body.base_tx_id.encode().to_vec()))
                     .take(body.tx_amount)
                     .map(|res| res.map(|(_, tx)| tx))
@@ -63,8 +80,6 @@ where
                     .await?;
                 batch.push((block_number, hash, txs));
 
-                highest_block = block_number;
-
                 if batch.len() > BUFFERING_FACTOR {
                     break;
                 }
@@ -103,7 +118,12 @@ where
                 .collect::<anyhow::Result<Vec<_>>>()?;
 
             for (db_key, db_value) in recovered_senders.drain(..) {
-                senders_cur.append(db_key, db_value).await?;
+                if self.cache_senders {
+                    // cached senders of the higher blocks might be there
+                    senders_cur.put(db_key, db_value).await?;
+                } else {
+                    senders_cur.append(db_key, db_value).await?;
+                }
             }
 
             let now = Instant::now();
@@ -153,6 +173,7 @@ where
         Ok(ExecOutput::Progress {
             stage_progress: highest_block,
             done,
+            exhausted: done,
             must_commit: highest_block > original_highest_block,
         })
     }
@@ -165,6 +186,30 @@ where
     where
         'db: 'tx,
     {
+        if self.cache_senders {
+            // the senders of the unwound blocks are kept, the ones of any other block
+            // at their heights are of a branch unwound before and are invalidated
+            let mut stale = vec![];
+            let mut senders_cur = tx.cursor(tables::TxSender).await?;
+            let walker = walk(&mut senders_cur, Some((input.unwind_to + 1, H256::zero())));
+            pin!(walker);
+            while let Some((key, _)) = walker.try_next().await? {
+                let (block_number, hash) = key;
+                if tx.get(tables::CanonicalHeader, block_number).await? != Some(hash) {
+                    stale.push(key);
+                }
+            }
+
+            for key in stale {
+                tx.del(tables::TxSender, key, None).await?;
+            }
+
+            return Ok(UnwindOutput {
+                stage_progress: input.unwind_to,
+                must_commit: true,
+            });
+        }
+
         let mut senders_cur = tx.mutable_cursor(tables::TxSender).await?;
 
         while let Some(((block_number, _), _)) = senders_cur.last().await? {
@@ -356,7 +401,9 @@ mod tests {
             .await
             .unwrap();
 
-        let stage = SenderRecovery {};
+        let stage = SenderRecovery {
+            cache_senders: false,
+        };
 
         let stage_input = StageInput {
             restarted: false,
@@ -372,6 +419,7 @@ mod tests {
             ExecOutput::Progress {
                 stage_progress: 3.into(),
                 done: true,
+                exhausted: true,
                 must_commit: true,
             }
         );
@@ -384,5 +432,66 @@ mod tests {
 
         let senders3 = chain::tx_sender::read(&tx, hash3, 3);
         assert!(senders3.await.unwrap().is_empty());
+
+        // the cache keeps the senders of the unwound blocks
+        let cached_stage = SenderRecovery {
+            cache_senders: true,
+        };
+        let unwind_input = UnwindInput {
+            stage_progress: 3.into(),
+            unwind_to: 1.into(),
+        };
+        cached_stage.unwind(&mut tx, unwind_input).await.unwrap();
+
+        let senders2 = chain::tx_sender::read(&tx, hash2, 2);
+        assert_eq!(senders2.await.unwrap(), [sender1, sender2, sender2]);
+
+        // and they are not recovered again
+        tx.set(tables::TxSender, (2.into(), hash2), vec![sender2])
+            .await
+            .unwrap();
+        let stage_input = StageInput {
+            restarted: false,
+            first_started_at: (Instant::now(), Some(BlockNumber(0))),
+            previous_stage: Some((StageId("BodyDownload"), 3.into())),
+            stage_progress: Some(1.into()),
+        };
+        let output = cached_stage.execute(&mut tx, stage_input).await.unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 3.into(),
+                done: true,
+                exhausted: true,
+                must_commit: true,
+            }
+        );
+
+        let senders2 = chain::tx_sender::read(&tx, hash2, 2);
+        assert_eq!(senders2.await.unwrap(), [sender2]);
+
+        // the senders of a block unwound before are invalidated by the next unwind
+        let hash2_fork = H256::random();
+        tx.set(tables::TxSender, (2.into(), hash2_fork), vec![sender1])
+            .await
+            .unwrap();
+        cached_stage.unwind(&mut tx, unwind_input).await.unwrap();
+
+        let senders2_fork = chain::tx_sender::read(&tx, hash2_fork, 2);
+        assert!(senders2_fork.await.unwrap().is_empty());
+        let senders2 = chain::tx_sender::read(&tx, hash2, 2);
+        assert_eq!(senders2.await.unwrap(), [sender2]);
+
+        let senders1 = chain::tx_sender::read(&tx, hash1, 1);
+        assert_eq!(senders1.await.unwrap(), [sender1, sender1]);
+
+        // without the cache everything above the unwind point is deleted
+        tx.set(tables::TxSender, (3.into(), hash3), vec![sender1])
+            .await
+            .unwrap();
+        stage.unwind(&mut tx, unwind_input).await.unwrap();
+
+        let senders3 = chain::tx_sender::read(&tx, hash3, 3);
+        assert!(senders3.await.unwrap().is_empty());
     }
 }
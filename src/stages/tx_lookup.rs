@@ -5,16 +5,46 @@ use crate::{
     },
     kv::{tables, traits::*},
     models::BodyForStorage,
-    stagedsync::stage::*,
+    stagedsync::{stage::*, stages::TX_LOOKUP},
     StageId,
 };
+use anyhow::format_err;
 use async_trait::async_trait;
 use tokio::pin;
 use tokio_stream::StreamExt;
 use tracing::*;
 
+/// Marks the databases whose lookup entries hold the index of the transaction within its block.
+const TX_LOOKUP_WITH_INDEX: &[u8] = b"tx_lookup_with_index";
+
+/// The lookup entries used to hold only the block number of the transaction,
+/// and cannot be decoded as the block number and index. Those of an older database are dropped
+/// and the stage is reset to build them again. Returns whether they were dropped.
+pub async fn migrate_tx_lookup<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+) -> anyhow::Result<bool> {
+    if tx
+        .get(tables::Migration, TX_LOOKUP_WITH_INDEX.to_vec())
+        .await?
+        .is_some()
+    {
+        return Ok(false);
+    }
+
+    let reset = TX_LOOKUP.get_progress(tx).await?.is_some();
+    tx.clear_table(tables::BlockTransactionLookup).await?;
+    tx.del(tables::SyncStage, TX_LOOKUP, None).await?;
+    tx.set(tables::Migration, TX_LOOKUP_WITH_INDEX.to_vec(), vec![])
+        .await?;
+
+    Ok(reset)
+}
+
 #[derive(Debug)]
-pub struct TxLookup;
+pub struct TxLookup {
+    /// Maximum number of blocks to index before committing.
+    pub batch_size: u64,
+}
 
 #[async_trait]
 impl<'db, RwTx> Stage<'db, RwTx> for TxLookup
@@ -22,18 +52,35 @@ where
     RwTx: MutableTransaction<'db>,
 {
     fn id(&self) -> StageId {
-        StageId("TxLookup")
+        TX_LOOKUP
     }
 
     fn description(&self) -> &'static str {
-        "Generating TransactionHash => BlockNumber Mapping"
+        "Generating TransactionHash => (BlockNumber, Index) Mapping"
     }
 
     async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
     where
         'db: 'tx,
     {
-        let mut bodies_cursor = tx.mutable_cursor(tables::BlockBody).await?;
+        let prev_progress = input.stage_progress.unwrap_or_default();
+        let max_block = input
+            .previous_stage
+            .map(|(_, v)| v)
+            .ok_or_else(|| format_err!("Cannot be the first stage"))?;
+
+        if max_block <= prev_progress {
+            return Ok(ExecOutput::Progress {
+                stage_progress: prev_progress,
+                done: true,
+                exhausted: true,
+                must_commit: false,
+            });
+        }
+
+        let end_block = std::cmp::min(max_block, prev_progress + self.batch_size.max(1));
+
+        let mut bodies_cursor = tx.cursor(tables::BlockBody).await?;
         let mut tx_hash_cursor = tx
             .mutable_cursor(tables::BlockTransactionLookup.erased())
             .await?;
@@ -42,38 +89,33 @@ where
 
         let mut collector = Collector::new(OPTIMAL_BUFFER_CAPACITY);
 
-        let last_processed_block_number = tx
-            .mutable_cursor(tables::BlockTransactionLookup)
-            .await?
-            .last()
-            .await?
-            .map(|(_, v)| v.0)
-            .unwrap_or_else(|| 0.into());
-
-        let start_block_number = last_processed_block_number + 1;
-
-        let walker_block_body = walk(&mut bodies_cursor, Some(start_block_number));
+        let walker_block_body = walk(&mut bodies_cursor, Some(prev_progress + 1));
         pin!(walker_block_body);
 
         while let Some(((block_number, _), ref body_rpl)) = walker_block_body.try_next().await? {
+            if block_number > end_block {
+                break;
+            }
+
             let (tx_count, tx_base_id) = (body_rpl.tx_amount, body_rpl.base_tx_id);
 
             let walker_block_txs = walk(&mut block_txs_cursor, Some(tx_base_id)).take(tx_count);
             pin!(walker_block_txs);
 
+            let mut index = 0_u64;
             while let Some((_, tx)) = walker_block_txs.try_next().await? {
-                collector.collect(Entry::new(tx.hash(), tables::TruncateStart(block_number)));
+                collector.collect(Entry::new(tx.hash(), (block_number, index)));
+                index += 1;
             }
         }
 
         collector.load(&mut tx_hash_cursor).await?;
-        info!("Processed");
+        info!("Processed up to block {}", end_block);
+        let done = end_block == max_block;
         Ok(ExecOutput::Progress {
-            stage_progress: input
-                .previous_stage
-                .map(|(_, stage)| stage)
-                .unwrap_or_default(),
-            done: false,
+            stage_progress: end_block,
+            done,
+            exhausted: done,
             must_commit: true,
         })
     }
@@ -119,7 +161,7 @@ where
                     break;
                 }
 
-                if tx_hash_cursor.seek(tx_value.hash()).await?.is_some() {
+                if tx_hash_cursor.seek_exact(tx_value.hash()).await?.is_some() {
                     tx_hash_cursor.delete_current().await?;
                 }
                 num_txs += 1;
@@ -312,36 +354,39 @@ mod tests {
             .await
             .unwrap();
 
-        let stage = TxLookup {};
+        let stage = TxLookup { batch_size: 2 };
 
-        let stage_input = StageInput {
-            restarted: false,
-            first_started_at: (Instant::now(), Some(BlockNumber(0))),
-            previous_stage: Some((StageId("BodyDownload"), 3.into())),
-            stage_progress: Some(0.into()),
-        };
+        for (stage_progress, expected_progress, done) in [(0, 2, false), (2, 3, true)] {
+            let stage_input = StageInput {
+                restarted: false,
+                first_started_at: (Instant::now(), Some(BlockNumber(0))),
+                previous_stage: Some((StageId("BodyDownload"), 3.into())),
+                stage_progress: Some(stage_progress.into()),
+            };
 
-        let output: ExecOutput = stage.execute(&mut tx, stage_input).await.unwrap();
+            let output: ExecOutput = stage.execute(&mut tx, stage_input).await.unwrap();
 
-        assert_eq!(
-            output,
-            ExecOutput::Progress {
-                stage_progress: 3.into(),
-                done: false,
-                must_commit: true,
-            }
-        );
+            assert_eq!(
+                output,
+                ExecOutput::Progress {
+                    stage_progress: expected_progress.into(),
+                    done,
+                    exhausted: done,
+                    must_commit: true,
+                }
+            );
+        }
 
-        for (hashed_tx, block_number) in [
-            (hash1_1, 1),
-            (hash1_2, 1),
-            (hash2_1, 2),
-            (hash2_2, 2),
-            (hash2_3, 2),
+        for (hashed_tx, block_number, index) in [
+            (hash1_1, 1, 0),
+            (hash1_2, 1, 1),
+            (hash2_1, 2, 0),
+            (hash2_2, 2, 1),
+            (hash2_3, 2, 2),
         ] {
             assert_eq!(
                 dbg!(chain::tl::read(&tx, hashed_tx).await.unwrap().unwrap()),
-                block_number.into()
+                (block_number.into(), index)
             );
         }
     }
@@ -350,7 +395,7 @@ mod tests {
     async fn tx_lookup_stage_without_data() {
         let db = new_mem_database().unwrap();
         let mut tx = db.begin_mutable().await.unwrap();
-        let stage = TxLookup {};
+        let stage = TxLookup { batch_size: 1000 };
 
         let stage_input = StageInput {
             restarted: false,
@@ -365,7 +410,8 @@ mod tests {
             output,
             ExecOutput::Progress {
                 stage_progress: 3.into(),
-                done: false,
+                done: true,
+                exhausted: true,
                 must_commit: true,
             }
         );
@@ -536,12 +582,12 @@ mod tests {
             .await
             .unwrap();
 
-        chain::tl::write(&tx, hash1_1, 1.into()).await.unwrap();
-        chain::tl::write(&tx, hash1_2, 1.into()).await.unwrap();
-        chain::tl::write(&tx, hash2_1, 2.into()).await.unwrap();
-        chain::tl::write(&tx, hash2_2, 2.into()).await.unwrap();
-        chain::tl::write(&tx, hash2_3, 2.into()).await.unwrap();
-        let stage = TxLookup {};
+        chain::tl::write(&tx, hash1_1, 1.into(), 0).await.unwrap();
+        chain::tl::write(&tx, hash1_2, 1.into(), 1).await.unwrap();
+        chain::tl::write(&tx, hash2_1, 2.into(), 0).await.unwrap();
+        chain::tl::write(&tx, hash2_2, 2.into(), 1).await.unwrap();
+        chain::tl::write(&tx, hash2_3, 2.into(), 2).await.unwrap();
+        let stage = TxLookup { batch_size: 1000 };
         stage
             .unwind(
                 &mut tx,
@@ -556,17 +602,48 @@ mod tests {
         tx.commit().await.unwrap();
         let tx = db.begin_mutable().await.unwrap();
 
-        for (hashed_tx, block_number) in [
-            (hash1_1, Some(1.into())),
-            (hash1_2, Some(1.into())),
+        for (hashed_tx, location) in [
+            (hash1_1, Some((1.into(), 0))),
+            (hash1_2, Some((1.into(), 1))),
             (hash2_1, None),
             (hash2_2, None),
             (hash2_3, None),
         ] {
             assert_eq!(
                 dbg!(chain::tl::read(&tx, hashed_tx).await.unwrap()),
-                block_number
+                location
             );
         }
     }
+
+    #[tokio::test]
+    async fn migrate_block_number_only_lookup() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        // an entry holding only the truncated block number
+        let hash = H256::random();
+        tx.set(
+            tables::BlockTransactionLookup.erased(),
+            hash.as_bytes().to_vec(),
+            vec![0x01, 0x02],
+        )
+        .await
+        .unwrap();
+        TX_LOOKUP.save_progress(&tx, 258.into()).await.unwrap();
+
+        assert!(migrate_tx_lookup(&tx).await.unwrap());
+        assert_eq!(chain::tl::read(&tx, hash).await.unwrap(), None);
+        assert_eq!(TX_LOOKUP.get_progress(&tx).await.unwrap(), None);
+
+        // the new entries are kept from then on
+        chain::tl::write(&tx, hash, 1.into(), 0).await.unwrap();
+        TX_LOOKUP.save_progress(&tx, 1.into()).await.unwrap();
+        assert!(!migrate_tx_lookup(&tx).await.unwrap());
+        assert_eq!(
+            chain::tl::read(&tx, hash).await.unwrap(),
+            Some((1.into(), 0))
+        );
+        assert_eq!(TX_LOOKUP.get_progress(&tx).await.unwrap(), Some(1.into()));
+    }
 }
@@ -5,14 +5,15 @@ use crate::{
         traits::*,
     },
     models::*,
-    state::database::*,
+    state::{database::*, StateRootCache},
     u256_to_h256, State,
 };
 use async_trait::async_trait;
 use bytes::Bytes;
 use ethereum_types::{Address, H256, *};
+use parking_lot::Mutex;
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
     marker::PhantomData,
 };
 use tokio::pin;
@@ -25,6 +26,30 @@ pub type AccountChanges = BTreeMap<Address, Option<Account>>;
 // address -> location -> zeroless initial value
 pub type StorageChanges = BTreeMap<Address, BTreeMap<U256, U256>>;
 
+// address -> locations read or written
+pub type TouchedAddresses = BTreeMap<Address, BTreeSet<U256>>;
+
+/// Estimated bytes of a buffered account, and of a buffered storage slot,
+/// both in the state and in the changesets.
+const ACCOUNT_ENTRY_BYTES: usize = std::mem::size_of::<(Address, Option<Account>)>();
+const SLOT_ENTRY_BYTES: usize = std::mem::size_of::<(U256, U256)>();
+
+/// When the buffer writes the state to the transaction before `write_to_db`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// Flush the accounts, the storage and the code once they take about this many bytes,
+    /// so that a batch touching an enormous state fits in memory. Never flushed if None.
+    ///
+    /// Independent of the history: the changesets are recorded on every update, and written
+    /// by `write_history`. A flush keeps them, and drops the state only. The reads fall
+    /// through to the transaction, which has the flushed state by then.
+    pub flush_threshold: Option<usize>,
+    /// Write the history once the changesets take about this many bytes, see
+    /// `Buffer::pending_history_bytes`. Composes with the gas-based `history_batch_size`
+    /// of the execution: whichever comes first. Never by the size if None.
+    pub history_flush_threshold: Option<usize>,
+}
+
 #[derive(Default, Debug)]
 struct OverlayStorage {
     erased: bool,
@@ -53,6 +78,18 @@ where
 
     hash_to_code: BTreeMap<H256, Bytes>,
 
+    config: BufferConfig,
+    // estimated size of accounts, storage and hash_to_code
+    state_bytes: usize,
+    // estimated size of account_changes and storage_changes
+    history_bytes: usize,
+
+    // Unlike the changes, includes the reads. Behind a mutex as the reads take &self.
+    touched: Option<Mutex<TouchedAddresses>>,
+
+    // Mirrors the state updates, which survive the flushes unlike the overlay.
+    state_root_cache: Option<StateRootCache>,
+
     // Current block stuff
     block_number: BlockNumber,
     changed_storage: HashSet<Address>,
@@ -78,10 +115,91 @@ where
             account_changes: Default::default(),
             storage_changes: Default::default(),
             hash_to_code: Default::default(),
+            config: Default::default(),
+            state_bytes: 0,
+            history_bytes: 0,
+            touched: None,
+            state_root_cache: None,
             block_number: Default::default(),
             changed_storage: Default::default(),
         }
     }
+
+    pub fn set_config(&mut self, config: BufferConfig) {
+        self.config = config;
+    }
+
+    /// Estimated size of the buffered state, which is compared to `BufferConfig::flush_threshold`.
+    pub fn state_bytes(&self) -> usize {
+        self.state_bytes
+    }
+
+    /// Estimated size of the changesets not written by `write_history` yet.
+    pub fn pending_history_bytes(&self) -> usize {
+        self.history_bytes
+    }
+
+    /// Whether the changesets exceed `BufferConfig::history_flush_threshold`.
+    pub fn history_flush_due(&self) -> bool {
+        self.config
+            .history_flush_threshold
+            .map_or(false, |threshold| self.history_bytes >= threshold)
+    }
+
+    /// Whether to track the addresses and the storage slots read or written, e.g. to build access lists.
+    pub fn set_track_touched(&mut self, track_touched: bool) {
+        self.touched = if track_touched {
+            Some(Default::default())
+        } else {
+            None
+        };
+    }
+
+    /// The addresses and the storage slots touched since the tracking was enabled,
+    /// or the last call. None if not tracking.
+    pub fn take_touched(&mut self) -> Option<TouchedAddresses> {
+        self.touched
+            .as_mut()
+            .map(|touched| std::mem::take(touched.get_mut()))
+    }
+
+    /// Keeps the cache up to date with the state updates from now on, so it has to be
+    /// of the state the buffer starts from.
+    pub fn set_state_root_cache(&mut self, state_root_cache: StateRootCache) {
+        self.state_root_cache = Some(state_root_cache);
+    }
+
+    pub fn state_root_cache(&mut self) -> Option<&mut StateRootCache> {
+        self.state_root_cache.as_mut()
+    }
+
+    pub fn take_state_root_cache(&mut self) -> Option<StateRootCache> {
+        self.state_root_cache.take()
+    }
+
+    /// Computes the state root of the database state with the buffered changes on top.
+    /// The state is never loaded here, so it takes the state root cache, see `set_state_root_cache`.
+    /// Without one, the state root is only computed by the Interhashes stage.
+    pub fn compute_state_root(&mut self) -> anyhow::Result<H256> {
+        self.state_root_cache
+            .as_mut()
+            .map(StateRootCache::root)
+            .ok_or_else(|| {
+                anyhow::format_err!(
+                    "No state root cache to compute the state root with, it's computed by the Interhashes stage"
+                )
+            })
+    }
+
+    fn touch(&self, address: Address, location: Option<U256>) {
+        if let Some(touched) = &self.touched {
+            let mut touched = touched.lock();
+            let locations = touched.entry(address).or_default();
+            if let Some(location) = location {
+                locations.insert(location);
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -91,6 +209,8 @@ where
     Tx: Transaction<'db>,
 {
     async fn read_account(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        self.touch(address, None);
+
         if let Some(account) = self.accounts.get(&address) {
             return Ok(*account);
         }
@@ -112,6 +232,8 @@ where
     }
 
     async fn read_storage(&self, address: Address, location: U256) -> anyhow::Result<U256> {
+        self.touch(address, Some(location));
+
         if let Some(account_storage) = self.storage.get(&address) {
             if let Some(value) = account_storage.slots.get(&location) {
                 return Ok(*value);
@@ -124,6 +246,12 @@ where
     }
 
     async fn erase_storage(&mut self, address: Address) -> anyhow::Result<()> {
+        self.touch(address, None);
+
+        if let Some(state_root_cache) = &mut self.state_root_cache {
+            state_root_cache.erase_storage(address);
+        }
+
         let mut mark_database_as_discarded = false;
         let overlay_storage = self.storage.entry(address).or_insert_with(|| {
             // If we don't have any overlay storage, we must mark slots in database as zeroed.
@@ -143,7 +271,10 @@ where
             .or_default();
 
         for (slot, value) in overlay_storage.slots.drain() {
-            storage_changes.insert(slot, value);
+            if storage_changes.insert(slot, value).is_none() {
+                self.history_bytes += SLOT_ENTRY_BYTES;
+            }
+            self.state_bytes -= SLOT_ENTRY_BYTES;
         }
 
         if !overlay_storage.erased {
@@ -171,7 +302,10 @@ where
                 }
 
                 // Only insert slot from db if it's not in storage buffer yet.
-                storage_changes.entry(h256_to_u256(slot)).or_insert(initial);
+                if let Entry::Vacant(entry) = storage_changes.entry(h256_to_u256(slot)) {
+                    entry.insert(initial);
+                    self.history_bytes += SLOT_ENTRY_BYTES;
+                }
             }
         }
 
@@ -226,6 +360,8 @@ where
         initial: Option<Account>,
         current: Option<Account>,
     ) {
+        self.touch(address, None);
+
         let equal = current == initial;
         let account_deleted = current.is_none();
 
@@ -233,22 +369,35 @@ where
             return;
         }
 
-        if self.block_number >= self.prune_from {
-            self.account_changes
+        if self.block_number >= self.prune_from
+            && self
+                .account_changes
                 .entry(self.block_number)
                 .or_default()
-                .insert(address, initial);
+                .insert(address, initial)
+                .is_none()
+        {
+            self.history_bytes += ACCOUNT_ENTRY_BYTES;
         }
 
         if equal {
             return;
         }
 
-        self.accounts.insert(address, current);
+        if let Some(state_root_cache) = &mut self.state_root_cache {
+            state_root_cache.update_account(address, current);
+        }
+
+        if self.accounts.insert(address, current).is_none() {
+            self.state_bytes += ACCOUNT_ENTRY_BYTES;
+        }
     }
 
     async fn update_code(&mut self, code_hash: H256, code: Bytes) -> anyhow::Result<()> {
-        self.hash_to_code.insert(code_hash, code);
+        let code_len = code.len();
+        if self.hash_to_code.insert(code_hash, code).is_none() {
+            self.state_bytes += code_len;
+        }
 
         Ok(())
     }
@@ -260,25 +409,41 @@ where
         initial: U256,
         current: U256,
     ) -> anyhow::Result<()> {
+        self.touch(address, Some(location));
+
         if current == initial {
             return Ok(());
         }
 
+        if let Some(state_root_cache) = &mut self.state_root_cache {
+            state_root_cache.update_storage(address, location, current);
+        }
+
         if self.block_number >= self.prune_from {
             self.changed_storage.insert(address);
-            self.storage_changes
+            if self
+                .storage_changes
                 .entry(self.block_number)
                 .or_default()
                 .entry(address)
                 .or_default()
-                .insert(location, initial);
+                .insert(location, initial)
+                .is_none()
+            {
+                self.history_bytes += SLOT_ENTRY_BYTES;
+            }
         }
 
-        self.storage
+        if self
+            .storage
             .entry(address)
             .or_default()
             .slots
-            .insert(location, current);
+            .insert(location, current)
+            .is_none()
+        {
+            self.state_bytes += SLOT_ENTRY_BYTES;
+        }
 
         Ok(())
     }
@@ -292,6 +457,7 @@ where
     pub async fn write_history(&mut self) -> anyhow::Result<()> {
         debug!("Writing account changes");
         let mut account_change_table = self.txn.mutable_cursor(tables::AccountChangeSet).await?;
+        self.history_bytes = 0;
         for (block_number, account_entries) in std::mem::take(&mut self.account_changes) {
             for (address, account) in account_entries {
                 account_change_table
@@ -325,17 +491,43 @@ where
 
     pub async fn write_to_db(mut self) -> anyhow::Result<()> {
         self.write_history().await?;
+        self.write_state().await
+    }
+
+    /// Writes the state without the history if it exceeds `BufferConfig::flush_threshold`,
+    /// returns whether it did. Meant to be called between the blocks.
+    pub async fn flush_if_exceeded(&mut self) -> anyhow::Result<bool> {
+        match self.config.flush_threshold {
+            Some(flush_threshold) if self.state_bytes >= flush_threshold => {
+                anyhow::ensure!(
+                    self.historical_block.is_none(),
+                    "only the latest state can be flushed"
+                );
+
+                debug!("Flushing {} bytes of state", self.state_bytes);
+                self.write_state().await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes the accounts, the storage and the code to the state tables, and drops them.
+    async fn write_state(&mut self) -> anyhow::Result<()> {
+        let accounts = std::mem::take(&mut self.accounts);
+        let storage = std::mem::take(&mut self.storage);
+        let hash_to_code = std::mem::take(&mut self.hash_to_code);
+        self.state_bytes = 0;
 
-        // Write to state tables
         let mut account_table = self.txn.mutable_cursor(tables::Account).await?;
         let mut storage_table = self.txn.mutable_cursor_dupsort(tables::Storage).await?;
 
         debug!("Writing accounts");
-        let mut account_addresses = self.accounts.keys().collect::<Vec<_>>();
+        let mut account_addresses = accounts.keys().collect::<Vec<_>>();
         account_addresses.sort_unstable();
         let mut written_accounts = 0;
         for &address in account_addresses {
-            let account = self.accounts[&address];
+            let account = accounts[&address];
 
             if let Some(account) = account {
                 account_table.upsert(address, account).await?;
@@ -355,11 +547,11 @@ where
         debug!("Writing {} accounts complete", written_accounts);
 
         debug!("Writing storage");
-        let mut storage_addresses = self.storage.keys().collect::<Vec<_>>();
+        let mut storage_addresses = storage.keys().collect::<Vec<_>>();
         storage_addresses.sort_unstable();
         let mut written_slots = 0;
         for &address in storage_addresses {
-            let overlay_storage = &self.storage[&address];
+            let overlay_storage = &storage[&address];
 
             if overlay_storage.erased && storage_table.seek_exact(address).await?.is_some() {
                 storage_table.delete_current_duplicates().await?;
@@ -382,7 +574,7 @@ where
 
         debug!("Writing code");
         let mut code_table = self.txn.mutable_cursor(tables::Code).await?;
-        for (code_hash, code) in self.hash_to_code {
+        for (code_hash, code) in hash_to_code {
             code_table.upsert(code_hash, code).await?;
         }
 
@@ -393,9 +585,80 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{h256_to_u256, kv::new_mem_database};
+    use crate::{
+        consensus::engine_factory,
+        execution::{analysis_cache::AnalysisCache, processor::ExecutionProcessor},
+        h256_to_u256,
+        kv::new_mem_database,
+        res::chainspec::MAINNET,
+        state::{
+            genesis::{initialize_genesis, GenesisState},
+            STATE_ROOT_CACHE_MAX_ENTRIES,
+        },
+    };
     use hex_literal::hex;
 
+    #[tokio::test]
+    async fn state_root() {
+        let db = new_mem_database().unwrap();
+        let txn = db.begin_mutable().await.unwrap();
+        initialize_genesis(&txn, MAINNET.clone()).await.unwrap();
+
+        let genesis_state_root = H256(hex!(
+            "d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544"
+        ));
+        let mut buffer = Buffer::new(&txn, BlockNumber(0), None);
+        assert!(buffer.compute_state_root().is_err());
+        assert!(StateRootCache::load(&txn, 1000).await.is_err());
+        let mut state_root_cache = StateRootCache::load(&txn, STATE_ROOT_CACHE_MAX_ENTRIES)
+            .await
+            .unwrap();
+        assert_eq!(state_root_cache.root(), genesis_state_root);
+        buffer.set_state_root_cache(state_root_cache);
+
+        // buffered changes on top of the database
+        let genesis = GenesisState::new(MAINNET.clone());
+        let mut expected = genesis.initial_state();
+
+        let address: Address = hex!("be00000000000000000000000000000000000000").into();
+        let account = Account {
+            nonce: 1,
+            balance: 0x10.into(),
+            ..Default::default()
+        };
+        let location = 0x13.into();
+        let value = 0x6b.into();
+        let removed = expected.account_addresses().next().unwrap();
+        let removed_account = expected.read_account(removed).await.unwrap();
+
+        buffer.begin_block(BlockNumber(1));
+        buffer.update_account(address, None, Some(account));
+        buffer
+            .update_storage(address, location, U256::zero(), value)
+            .await
+            .unwrap();
+        buffer.update_account(removed, removed_account, None);
+
+        expected.begin_block(BlockNumber(1));
+        expected.update_account(address, None, Some(account));
+        expected
+            .update_storage(address, location, U256::zero(), value)
+            .await
+            .unwrap();
+        expected.update_account(removed, removed_account, None);
+
+        assert_eq!(
+            buffer.compute_state_root().unwrap(),
+            expected.state_root_hash()
+        );
+        // unlike the overlay, the cache survives the flushes
+        buffer.write_state().await.unwrap();
+        assert_eq!(
+            buffer.take_state_root_cache().unwrap().root(),
+            expected.state_root_hash()
+        );
+    }
+
     #[tokio::test]
     async fn storage_update() {
         let db = new_mem_database().unwrap();
@@ -461,4 +724,191 @@ mod tests {
         .unwrap();
         assert_eq!(db_value_b, value_b);
     }
+
+    #[tokio::test]
+    async fn touched() {
+        let db = new_mem_database().unwrap();
+        let txn = db.begin_mutable().await.unwrap();
+
+        let header = PartialHeader {
+            number: 13_000_000.into(),
+            gas_limit: 1_000_000,
+            beneficiary: hex!("4bb96091ee9d802ed039c4d1a5f6216f90f81b01").into(),
+            ..PartialHeader::empty()
+        };
+        let sender = hex!("004512399a230565b99be5c3b0030a56f3ace68c").into();
+        let caller = hex!("0000000000000000000000000000000000ca11e4").into();
+        let callee = hex!("00000000000000000000000000000000000ca11e").into();
+
+        // CALL 0x0ca11e
+        let caller_code = hex!(
+            "6000600060006000600073"
+            "00000000000000000000000000000000000ca11e"
+            "5af100"
+        );
+        // SLOAD 0x01, then SSTORE 0x2a to 0x00
+        let callee_code = hex!("60015450602a60005500");
+
+        let block = BlockBodyWithSenders {
+            transactions: vec![MessageWithSender {
+                message: Message::Legacy {
+                    chain_id: None,
+                    nonce: 0,
+                    gas_price: U256::zero(),
+                    gas_limit: 100_000,
+                    action: TransactionAction::Call(caller),
+                    value: U256::zero(),
+                    input: Bytes::new(),
+                },
+                sender,
+            }],
+            ommers: vec![],
+        };
+
+        let mut buffer = Buffer::new(&txn, BlockNumber(0), None);
+        assert_eq!(buffer.take_touched(), None);
+        buffer.set_track_touched(true);
+
+        let receipts = {
+            let mut analysis_cache = AnalysisCache::default();
+            let mut engine = engine_factory(MAINNET.clone()).unwrap();
+            let block_spec = MAINNET.collect_block_spec(header.number);
+            let mut processor = ExecutionProcessor::new(
+                &mut buffer,
+                &mut analysis_cache,
+                &mut *engine,
+                &header,
+                &block,
+                &block_spec,
+            );
+            processor
+                .state()
+                .set_code(caller, caller_code.to_vec().into())
+                .await
+                .unwrap();
+            processor
+                .state()
+                .set_code(callee, callee_code.to_vec().into())
+                .await
+                .unwrap();
+            processor.execute_block_no_post_validation().await.unwrap()
+        };
+        assert!(receipts[0].success);
+
+        let touched = buffer.take_touched().unwrap();
+        assert!(touched.contains_key(&sender));
+        assert!(touched[&caller].is_empty());
+        // the read slot is reported along with the written one
+        assert_eq!(
+            touched[&callee],
+            [U256::zero(), U256::one()].into_iter().collect()
+        );
+
+        // reset by taking
+        assert_eq!(buffer.take_touched(), Some(TouchedAddresses::new()));
+    }
+
+    #[tokio::test]
+    async fn flush_threshold() {
+        let db = new_mem_database().unwrap();
+        let txn = db.begin_mutable().await.unwrap();
+
+        let address: Address = hex!("be00000000000000000000000000000000000000").into();
+        let account = Account {
+            nonce: 1,
+            ..Default::default()
+        };
+        let location = 0x13.into();
+        let value = 0x6b.into();
+
+        let mut buffer = Buffer::new(&txn, BlockNumber(0), None);
+        buffer.set_config(BufferConfig {
+            flush_threshold: Some(ACCOUNT_ENTRY_BYTES + SLOT_ENTRY_BYTES),
+            ..Default::default()
+        });
+        buffer.begin_block(BlockNumber(1));
+        buffer.update_account(address, None, Some(account));
+        assert!(!buffer.flush_if_exceeded().await.unwrap());
+        buffer
+            .update_storage(address, location, U256::zero(), value)
+            .await
+            .unwrap();
+        assert_eq!(buffer.state_bytes(), ACCOUNT_ENTRY_BYTES + SLOT_ENTRY_BYTES);
+
+        assert!(buffer.flush_if_exceeded().await.unwrap());
+        assert_eq!(buffer.state_bytes(), 0);
+        assert_eq!(
+            txn.get(tables::Account, address).await.unwrap(),
+            Some(account)
+        );
+        // read through from the transaction
+        assert_eq!(buffer.read_storage(address, location).await.unwrap(), value);
+        // the history is left for write_history
+        assert_eq!(
+            txn.get(tables::AccountChangeSet, BlockNumber(1))
+                .await
+                .unwrap(),
+            None
+        );
+
+        buffer.write_to_db().await.unwrap();
+        assert_eq!(
+            txn.get(tables::AccountChangeSet, BlockNumber(1))
+                .await
+                .unwrap(),
+            Some(AccountChange {
+                address,
+                account: None
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn history_flush_threshold() {
+        let db = new_mem_database().unwrap();
+        let txn = db.begin_mutable().await.unwrap();
+
+        let address: Address = hex!("be00000000000000000000000000000000000000").into();
+        let slots = 16;
+
+        let mut buffer = Buffer::new(&txn, BlockNumber(0), None);
+        buffer.set_config(BufferConfig {
+            history_flush_threshold: Some(ACCOUNT_ENTRY_BYTES + slots * SLOT_ENTRY_BYTES),
+            ..Default::default()
+        });
+
+        // a cheap block touching a lot of storage, far from any gas-based batch size
+        buffer.begin_block(BlockNumber(1));
+        buffer.update_account(address, None, Some(Account::default()));
+        for location in 0..slots {
+            assert!(!buffer.history_flush_due());
+            buffer
+                .update_storage(address, location.into(), U256::zero(), 1.into())
+                .await
+                .unwrap();
+        }
+        // changed twice within the block, recorded once
+        buffer
+            .update_storage(address, 0.into(), 1.into(), 2.into())
+            .await
+            .unwrap();
+        assert_eq!(
+            buffer.pending_history_bytes(),
+            ACCOUNT_ENTRY_BYTES + slots * SLOT_ENTRY_BYTES
+        );
+        assert!(buffer.history_flush_due());
+
+        buffer.write_history().await.unwrap();
+        assert_eq!(buffer.pending_history_bytes(), 0);
+        assert!(!buffer.history_flush_due());
+        assert_eq!(
+            txn.get(tables::AccountChangeSet, BlockNumber(1))
+                .await
+                .unwrap(),
+            Some(AccountChange {
+                address,
+                account: None
+            })
+        );
+    }
 }
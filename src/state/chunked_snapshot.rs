@@ -0,0 +1,657 @@
+//! Snapshot of the state at a canonical block, split into chunks by account hash ranges.
+//!
+//! The snapshot is a directory with a manifest and `CHUNKS` chunk files. Chunk `i` holds
+//! the accounts whose `keccak256(address)` falls into the `i`-th of `CHUNKS` equal ranges,
+//! together with their storage, and the code whose hash falls into the same range,
+//! so chunks can be fetched, verified and imported independently.
+//! A chunk is a sequence of `snapshot` entries, accounts then storage then code, each sorted,
+//! terminated by the end tag.
+//!
+//! Manifest layout (all integers are big-endian):
+//! ```text
+//! "AKSNAPM" | version: u8 | block number: u64 | canonical block hash: [u8; 32]
+//! chunk count: u16 | keccak256 of every chunk file: [u8; 32] * chunk count
+//! ```
+//! The snapshot is committed to by the binary merkle root over the chunk hashes,
+//! see `SnapshotManifest::root`.
+use super::snapshot::{read_snapshot_entry, write_entry, SnapshotEntry, TAG_END};
+use crate::{
+    accessors::state::changeset::{walk_account_changes, walk_storage_changes},
+    crypto::keccak256,
+    kv::{tables, traits::*},
+    models::*,
+    stagedsync::stages::{ensure_history_available, EXECUTION},
+};
+use anyhow::{bail, ensure, format_err};
+use async_stream::try_stream;
+use ethereum_types::*;
+use futures_core::Stream;
+use sha3::{Digest, Keccak256};
+use std::{collections::BTreeMap, path::Path};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    pin,
+};
+use tokio_stream::StreamExt;
+use tracing::*;
+
+pub const MANIFEST_MAGIC: [u8; 7] = *b"AKSNAPM";
+pub const MANIFEST_VERSION: u8 = 2;
+pub const MANIFEST_FILE: &str = "manifest";
+pub const CHUNKS: usize = 16;
+
+pub fn chunk_file_name(index: usize) -> String {
+    format!("chunk-{:02}", index)
+}
+
+/// Index of the chunk holding the account and its storage.
+pub fn chunk_index(address: Address) -> usize {
+    hash_chunk_index(keccak256(address))
+}
+
+/// Index of the chunk holding the code.
+pub fn code_chunk_index(code_hash: H256) -> usize {
+    hash_chunk_index(code_hash)
+}
+
+fn hash_chunk_index(hash: H256) -> usize {
+    hash.as_bytes()[0] as usize * CHUNKS / 256
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotManifest {
+    pub block_number: BlockNumber,
+    pub block_hash: H256,
+    pub chunk_hashes: Vec<H256>,
+}
+
+impl SnapshotManifest {
+    /// Merkle root over the chunk hashes: pairs are hashed together level by level,
+    /// the odd one out is carried over to the next level as is.
+    pub fn root(&self) -> H256 {
+        let mut level = self.chunk_hashes.clone();
+        if level.is_empty() {
+            return keccak256(b"");
+        }
+
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => keccak256([left.as_bytes(), right.as_bytes()].concat()),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+
+        level[0]
+    }
+
+    async fn write<W>(&self, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_all(&MANIFEST_MAGIC).await?;
+        writer.write_u8(MANIFEST_VERSION).await?;
+        writer.write_u64(self.block_number.0).await?;
+        writer.write_all(self.block_hash.as_bytes()).await?;
+        writer
+            .write_u16(self.chunk_hashes.len().try_into()?)
+            .await?;
+        for chunk_hash in &self.chunk_hashes {
+            writer.write_all(chunk_hash.as_bytes()).await?;
+        }
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    async fn read<R>(reader: &mut R) -> anyhow::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut magic = [0; MANIFEST_MAGIC.len()];
+        reader.read_exact(&mut magic).await?;
+        if magic != MANIFEST_MAGIC {
+            bail!("not a state snapshot manifest");
+        }
+
+        let version = reader.read_u8().await?;
+        if version != MANIFEST_VERSION {
+            bail!("unsupported snapshot manifest version {}", version);
+        }
+
+        let block_number = BlockNumber(reader.read_u64().await?);
+        let mut block_hash = H256::zero();
+        reader.read_exact(block_hash.as_bytes_mut()).await?;
+
+        let mut chunk_hashes = vec![H256::zero(); reader.read_u16().await? as usize];
+        for chunk_hash in &mut chunk_hashes {
+            reader.read_exact(chunk_hash.as_bytes_mut()).await?;
+        }
+
+        Ok(Self {
+            block_number,
+            block_hash,
+            chunk_hashes,
+        })
+    }
+}
+
+struct ChunkWriter {
+    file: BufWriter<File>,
+    hasher: Keccak256,
+}
+
+impl ChunkWriter {
+    async fn write(&mut self, entry: SnapshotEntry) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        write_entry(&mut buf, entry).await?;
+        self.write_raw(&buf).await
+    }
+
+    async fn write_raw(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.hasher.update(buf);
+        self.file.write_all(buf).await?;
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> anyhow::Result<H256> {
+        self.write_raw(&[TAG_END]).await?;
+        self.file.flush().await?;
+
+        Ok(H256::from_slice(&self.hasher.finalize()))
+    }
+}
+
+async fn hash_file(path: &Path) -> anyhow::Result<H256> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Keccak256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(H256::from_slice(&hasher.finalize()))
+}
+
+/// Merges the sorted entries of a table with the sorted overrides, which take precedence.
+/// `None` overrides remove the entry.
+fn merge_overrides<'s, K, V>(
+    current: impl Stream<Item = anyhow::Result<(K, V)>> + 's,
+    overrides: BTreeMap<K, Option<V>>,
+) -> impl Stream<Item = anyhow::Result<(K, V)>> + 's
+where
+    K: Ord + Copy + 's,
+    V: Copy + 's,
+{
+    try_stream! {
+        pin!(current);
+        let mut overrides = overrides.into_iter().peekable();
+        let mut next_current = current.try_next().await?;
+        loop {
+            let (key, value) = match (next_current, overrides.peek().copied()) {
+                (None, None) => break,
+                (Some((key, value)), None) => {
+                    next_current = current.try_next().await?;
+                    (key, Some(value))
+                }
+                (Some((key, value)), Some((override_key, _))) if key < override_key => {
+                    next_current = current.try_next().await?;
+                    (key, Some(value))
+                }
+                (Some((key, _)), Some((override_key, value))) if key == override_key => {
+                    next_current = current.try_next().await?;
+                    overrides.next();
+                    (key, value)
+                }
+                (_, Some((key, value))) => {
+                    overrides.next();
+                    (key, value)
+                }
+            };
+
+            if let Some(value) = value {
+                yield (key, value);
+            }
+        }
+    }
+}
+
+/// Exports the state at the end of `block_number` into `dir`.
+/// The block must not be above the Execution stage progress, the state at earlier blocks
+/// is reconstructed from the changesets, which must not be pruned. Should be run
+/// in a read-only transaction, so that the export sees a consistent snapshot of the database.
+pub async fn export_chunked_snapshot<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    block_number: BlockNumber,
+    dir: &Path,
+) -> anyhow::Result<SnapshotManifest> {
+    let progress = EXECUTION.get_progress(tx).await?.unwrap_or_default();
+    ensure!(
+        block_number <= progress,
+        "state at block {} is not available, Execution stage is at {}",
+        block_number,
+        progress
+    );
+    ensure_history_available(tx, block_number).await?;
+    let block_hash = tx
+        .get(tables::CanonicalHeader, block_number)
+        .await?
+        .ok_or_else(|| format_err!("no canonical header for block {}", block_number))?;
+
+    // the earliest change after the block holds the value at the block
+    let mut account_overrides = BTreeMap::new();
+    let mut storage_overrides = BTreeMap::new();
+    if block_number < progress {
+        let mut cursor = tx.cursor(tables::AccountChangeSet).await?;
+        let changes = walk_account_changes(&mut cursor, block_number + 1, progress);
+        pin!(changes);
+        while let Some((_, change)) = changes.try_next().await? {
+            account_overrides
+                .entry(change.address)
+                .or_insert(change.account);
+        }
+
+        let mut cursor = tx.cursor(tables::StorageChangeSet).await?;
+        let changes = walk_storage_changes(&mut cursor, block_number + 1, progress);
+        pin!(changes);
+        while let Some((key, change)) = changes.try_next().await? {
+            storage_overrides
+                .entry((key.address, change.location))
+                .or_insert_with(|| Some(change.value).filter(|value| !value.is_zero()));
+        }
+    }
+
+    tokio::fs::create_dir_all(dir).await?;
+    let mut chunks = Vec::with_capacity(CHUNKS);
+    for index in 0..CHUNKS {
+        chunks.push(ChunkWriter {
+            file: BufWriter::new(File::create(dir.join(chunk_file_name(index))).await?),
+            hasher: Keccak256::new(),
+        });
+    }
+
+    let mut cursor = tx.cursor(tables::Account).await?;
+    let accounts = merge_overrides(walk(&mut cursor, None), account_overrides);
+    pin!(accounts);
+    while let Some((address, account)) = accounts.try_next().await? {
+        chunks[chunk_index(address)]
+            .write(SnapshotEntry::Account { address, account })
+            .await?;
+    }
+
+    let mut cursor = tx.cursor(tables::Storage).await?;
+    let storage = merge_overrides(
+        walk(&mut cursor, None)
+            .map(|entry| entry.map(|(address, (location, value))| ((address, location), value))),
+        storage_overrides,
+    );
+    pin!(storage);
+    while let Some(((address, location), value)) = storage.try_next().await? {
+        chunks[chunk_index(address)]
+            .write(SnapshotEntry::Storage {
+                address,
+                location,
+                value,
+            })
+            .await?;
+    }
+
+    // the code is never deleted, so the current table has the code of any earlier block
+    let mut cursor = tx.cursor(tables::Code).await?;
+    let walker = walk(&mut cursor, None);
+    pin!(walker);
+    while let Some((code_hash, code)) = walker.try_next().await? {
+        chunks[code_chunk_index(code_hash)]
+            .write(SnapshotEntry::Code { code_hash, code })
+            .await?;
+    }
+
+    let mut chunk_hashes = Vec::with_capacity(CHUNKS);
+    for chunk in chunks {
+        chunk_hashes.push(chunk.finish().await?);
+    }
+
+    let manifest = SnapshotManifest {
+        block_number,
+        block_hash,
+        chunk_hashes,
+    };
+    let mut manifest_file = BufWriter::new(File::create(dir.join(MANIFEST_FILE)).await?);
+    manifest.write(&mut manifest_file).await?;
+
+    info!(
+        "Exported state snapshot at block {}, root {:?}",
+        block_number,
+        manifest.root()
+    );
+
+    Ok(manifest)
+}
+
+/// Verifies the chunks in `dir` against the manifest, and the manifest against `expected_root`
+/// if given. Then seeds an empty database with the state and sets the Execution stage progress
+/// to the snapshot's block. The block must be already known as canonical locally.
+pub async fn import_chunked_snapshot<'db, RwTx: MutableTransaction<'db>>(
+    tx: &RwTx,
+    dir: &Path,
+    expected_root: Option<H256>,
+) -> anyhow::Result<BlockNumber> {
+    let manifest = SnapshotManifest::read(&mut BufReader::new(
+        File::open(dir.join(MANIFEST_FILE)).await?,
+    ))
+    .await?;
+    if let Some(expected_root) = expected_root {
+        let root = manifest.root();
+        ensure!(
+            root == expected_root,
+            "snapshot root mismatch: expected {:?}, manifest {:?}",
+            expected_root,
+            root
+        );
+    }
+    ensure!(
+        manifest.chunk_hashes.len() == CHUNKS,
+        "snapshot has {} chunks, expected {}",
+        manifest.chunk_hashes.len(),
+        CHUNKS
+    );
+
+    let block_number = manifest.block_number;
+    let canonical_hash = tx
+        .get(tables::CanonicalHeader, block_number)
+        .await?
+        .ok_or_else(|| format_err!("no canonical header for snapshot block {}", block_number))?;
+    ensure!(
+        canonical_hash == manifest.block_hash,
+        "snapshot block {} hash mismatch: local {:?}, snapshot {:?}",
+        block_number,
+        canonical_hash,
+        manifest.block_hash
+    );
+
+    ensure!(
+        tx.cursor(tables::Account).await?.first().await?.is_none()
+            && tx.cursor(tables::Storage).await?.first().await?.is_none(),
+        "state snapshot can only be imported into an empty database"
+    );
+
+    // verify everything before writing anything
+    for (index, &chunk_hash) in manifest.chunk_hashes.iter().enumerate() {
+        let hash = hash_file(&dir.join(chunk_file_name(index))).await?;
+        ensure!(
+            hash == chunk_hash,
+            "snapshot chunk {} hash mismatch: manifest {:?}, file {:?}",
+            index,
+            chunk_hash,
+            hash
+        );
+    }
+
+    let mut imported = 0_u64;
+    for index in 0..CHUNKS {
+        let mut reader = BufReader::new(File::open(dir.join(chunk_file_name(index))).await?);
+        while let Some(entry) = read_snapshot_entry(&mut reader).await? {
+            let entry_chunk_index = match entry {
+                SnapshotEntry::Account { address, account } => {
+                    tx.set(tables::Account, address, account).await?;
+                    chunk_index(address)
+                }
+                SnapshotEntry::Storage {
+                    address,
+                    location,
+                    value,
+                } => {
+                    tx.set(tables::Storage, address, (location, value)).await?;
+                    chunk_index(address)
+                }
+                SnapshotEntry::Code { code_hash, code } => {
+                    tx.set(tables::Code, code_hash, code).await?;
+                    code_chunk_index(code_hash)
+                }
+            };
+            ensure!(
+                entry_chunk_index == index,
+                "entry does not belong to snapshot chunk {}",
+                index
+            );
+
+            imported += 1;
+        }
+    }
+
+    EXECUTION.save_progress(tx, block_number).await?;
+
+    info!(
+        "Imported state snapshot at block {}, {} entries",
+        block_number, imported
+    );
+
+    Ok(block_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::new_mem_database,
+        stagedsync::stages::{HistoryPrunedError, PRUNED_HISTORY},
+    };
+    use bytes::Bytes;
+
+    async fn collect_state<'db, Tx: Transaction<'db>>(
+        tx: &Tx,
+    ) -> (
+        Vec<(Address, Account)>,
+        Vec<(Address, (H256, U256))>,
+        Vec<(H256, Bytes)>,
+    ) {
+        let mut cursor = tx.cursor(tables::Account).await.unwrap();
+        let accounts = walk(&mut cursor, None)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .await
+            .unwrap();
+        let mut cursor = tx.cursor(tables::Storage).await.unwrap();
+        let storage = walk(&mut cursor, None)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .await
+            .unwrap();
+        let mut cursor = tx.cursor(tables::Code).await.unwrap();
+        let code = walk(&mut cursor, None)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .await
+            .unwrap();
+        (accounts, storage, code)
+    }
+
+    #[tokio::test]
+    async fn export_and_import() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let account = |nonce| Account {
+            nonce,
+            ..Default::default()
+        };
+        // updated at block 2
+        let updated = Address::from_low_u64_be(0xa1);
+        // created at block 3
+        let created = Address::from_low_u64_be(0xa2);
+        // destructed at block 2
+        let destructed = Address::from_low_u64_be(0xa3);
+        let unchanged = Address::from_low_u64_be(0xa4);
+        let location = H256::from_low_u64_be(0x13);
+        let code = Bytes::from_static(&[0x60, 0x00]);
+        let code_hash = keccak256(&code);
+
+        tx.set(tables::Account, updated, account(2)).await.unwrap();
+        tx.set(tables::Account, created, account(1)).await.unwrap();
+        tx.set(tables::Account, unchanged, account(4))
+            .await
+            .unwrap();
+        tx.set(tables::Storage, updated, (location, 9.into()))
+            .await
+            .unwrap();
+        tx.set(tables::Code, code_hash, code.clone()).await.unwrap();
+        for (block_number, address, initial) in [
+            (2, updated, Some(account(1))),
+            (2, destructed, Some(account(3))),
+            (3, created, None),
+        ] {
+            tx.set(
+                tables::AccountChangeSet,
+                BlockNumber(block_number),
+                tables::AccountChange {
+                    address,
+                    account: initial,
+                },
+            )
+            .await
+            .unwrap();
+        }
+        for (block_number, address, initial) in [(2, destructed, 7), (3, updated, 5)] {
+            tx.set(
+                tables::StorageChangeSet,
+                tables::StorageChangeKey {
+                    block_number: BlockNumber(block_number),
+                    address,
+                },
+                tables::StorageChange {
+                    location,
+                    value: initial.into(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+        for block_number in 0..=3 {
+            tx.set(
+                tables::CanonicalHeader,
+                BlockNumber(block_number),
+                H256::from_low_u64_be(block_number),
+            )
+            .await
+            .unwrap();
+        }
+        EXECUTION.save_progress(&tx, BlockNumber(3)).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let current_dir = dir.path().join("current");
+        let historical_dir = dir.path().join("historical");
+
+        assert!(export_chunked_snapshot(&tx, BlockNumber(4), &current_dir)
+            .await
+            .is_err());
+        // the changesets of the block 2 are pruned
+        PRUNED_HISTORY
+            .save_progress(&tx, BlockNumber(3))
+            .await
+            .unwrap();
+        assert!(matches!(
+            export_chunked_snapshot(&tx, BlockNumber(1), &historical_dir)
+                .await
+                .unwrap_err()
+                .downcast_ref::<HistoryPrunedError>(),
+            Some(HistoryPrunedError {
+                block_number: BlockNumber(1),
+                pruned_below: BlockNumber(3),
+            })
+        ));
+        PRUNED_HISTORY.clear_progress(&tx).await.unwrap();
+        let current = export_chunked_snapshot(&tx, BlockNumber(3), &current_dir)
+            .await
+            .unwrap();
+        let historical = export_chunked_snapshot(&tx, BlockNumber(1), &historical_dir)
+            .await
+            .unwrap();
+        assert_eq!(historical.block_hash, H256::from_low_u64_be(1));
+        assert_eq!(historical.chunk_hashes.len(), CHUNKS);
+        assert_ne!(current.root(), historical.root());
+
+        // the export is deterministic
+        let again = export_chunked_snapshot(&tx, BlockNumber(1), &dir.path().join("again"))
+            .await
+            .unwrap();
+        assert_eq!(again, historical);
+
+        let db2 = new_mem_database().unwrap();
+        let tx2 = db2.begin_mutable().await.unwrap();
+        tx2.set(
+            tables::CanonicalHeader,
+            BlockNumber(1),
+            H256::from_low_u64_be(1),
+        )
+        .await
+        .unwrap();
+
+        // the root is checked before anything else
+        assert!(
+            import_chunked_snapshot(&tx2, &historical_dir, Some(current.root()))
+                .await
+                .is_err()
+        );
+
+        // a corrupted chunk
+        let chunk_path = historical_dir.join(chunk_file_name(chunk_index(updated)));
+        let chunk = std::fs::read(&chunk_path).unwrap();
+        let mut corrupted = chunk.clone();
+        corrupted[1] ^= 0xff;
+        std::fs::write(&chunk_path, &corrupted).unwrap();
+        assert!(
+            import_chunked_snapshot(&tx2, &historical_dir, Some(historical.root()))
+                .await
+                .is_err()
+        );
+        assert_eq!(collect_state(&tx2).await, (vec![], vec![], vec![]));
+        std::fs::write(&chunk_path, &chunk).unwrap();
+
+        assert_eq!(
+            import_chunked_snapshot(&tx2, &historical_dir, Some(historical.root()))
+                .await
+                .unwrap(),
+            BlockNumber(1)
+        );
+        assert_eq!(
+            EXECUTION.get_progress(&tx2).await.unwrap(),
+            Some(BlockNumber(1))
+        );
+        assert_eq!(
+            collect_state(&tx2).await,
+            (
+                vec![
+                    (updated, account(1)),
+                    (destructed, account(3)),
+                    (unchanged, account(4))
+                ],
+                vec![
+                    (updated, (location, 5.into())),
+                    (destructed, (location, 7.into()))
+                ],
+                vec![(code_hash, code)]
+            )
+        );
+
+        // the current state round trips
+        let db3 = new_mem_database().unwrap();
+        let tx3 = db3.begin_mutable().await.unwrap();
+        tx3.set(
+            tables::CanonicalHeader,
+            BlockNumber(3),
+            H256::from_low_u64_be(3),
+        )
+        .await
+        .unwrap();
+        import_chunked_snapshot(&tx3, &current_dir, Some(current.root()))
+            .await
+            .unwrap();
+        assert_eq!(collect_state(&tx3).await, collect_state(&tx).await);
+    }
+}
@@ -1,12 +1,22 @@
 use crate::{
+    chain::protocol_param::param,
+    crypto::keccak256,
     kv::{
         tables::{self, CumulativeData},
         traits::*,
     },
     models::*,
     state::*,
+    util::*,
 };
+use anyhow::bail;
+use bytes::Bytes;
 use ethereum_types::*;
+use serde::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
 
 #[derive(Clone, Debug)]
 pub struct GenesisState {
@@ -62,7 +72,304 @@ impl GenesisState {
     }
 }
 
+/// Geth-style `genesis.json`, as used to launch private chains.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GethGenesis {
+    pub config: GethChainConfig,
+    #[serde(default, deserialize_with = "deserialize_hexstr_as_u64")]
+    pub nonce: u64,
+    #[serde(default, deserialize_with = "deserialize_hexstr_as_u64")]
+    pub timestamp: u64,
+    #[serde(default, with = "hexbytes")]
+    pub extra_data: Bytes,
+    #[serde(deserialize_with = "deserialize_hexstr_as_u64")]
+    pub gas_limit: u64,
+    #[serde(deserialize_with = "deserialize_hexstr_as_u256")]
+    pub difficulty: U256,
+    #[serde(default)]
+    pub mix_hash: H256,
+    #[serde(default)]
+    pub coinbase: Address,
+    #[serde(default)]
+    pub alloc: HashMap<Address, GenesisAccount>,
+    #[serde(default, deserialize_with = "deserialize_base_fee")]
+    pub base_fee_per_gas: Option<U256>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GethChainConfig {
+    pub chain_id: ChainId,
+    #[serde(default)]
+    pub homestead_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub eip150_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub eip155_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub eip158_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub byzantium_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub constantinople_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub petersburg_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub istanbul_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub berlin_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub london_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub clique: Option<GethCliqueConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct GethCliqueConfig {
+    /// Seconds between the blocks.
+    pub period: u64,
+    pub epoch: u64,
+}
+
+/// An account allocated in the genesis block.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct GenesisAccount {
+    #[serde(deserialize_with = "deserialize_hexstr_as_u256")]
+    pub balance: U256,
+    #[serde(default, deserialize_with = "deserialize_hexstr_as_u64")]
+    pub nonce: u64,
+    #[serde(default, with = "hexbytes")]
+    pub code: Bytes,
+    #[serde(default)]
+    pub storage: HashMap<H256, H256>,
+}
+
+fn deserialize_base_fee<'de, D>(deserializer: D) -> Result<Option<U256>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    deserialize_hexstr_as_u256(deserializer).map(Some)
+}
+
+const CLIQUE_VANITY_LENGTH: usize = 32;
+const CLIQUE_SEAL_LENGTH: usize = 65;
+
+impl GethGenesis {
+    /// Chain spec of the private chain. Ethash chains get the mainnet block rewards and no
+    /// difficulty bomb.
+    pub fn chain_spec(&self) -> anyhow::Result<ChainSpec> {
+        let config = &self.config;
+
+        let (seal_verification, seal) = if let Some(clique) = config.clique {
+            let extra_data = &self.extra_data;
+            if extra_data.len() < CLIQUE_VANITY_LENGTH + CLIQUE_SEAL_LENGTH
+                || (extra_data.len() - CLIQUE_VANITY_LENGTH - CLIQUE_SEAL_LENGTH) % ADDRESS_LENGTH
+                    != 0
+            {
+                bail!("invalid clique extra data length {}", extra_data.len());
+            }
+            let signers_end = extra_data.len() - CLIQUE_SEAL_LENGTH;
+            if extra_data[signers_end..].iter().any(|&b| b != 0) {
+                bail!("clique extra data of the genesis must not be sealed");
+            }
+
+            let score = if self.difficulty == U256::from(BlockScore::NoTurn as u8) {
+                BlockScore::NoTurn
+            } else if self.difficulty == U256::from(BlockScore::InTurn as u8) {
+                BlockScore::InTurn
+            } else {
+                bail!("invalid clique difficulty {}", self.difficulty);
+            };
+
+            (
+                SealVerificationParams::Clique {
+                    period: Duration::from_secs(clique.period),
+                    epoch: clique.epoch,
+                },
+                Seal::Clique {
+                    vanity: H256::from_slice(&extra_data[..CLIQUE_VANITY_LENGTH]),
+                    score,
+                    signers: extra_data[CLIQUE_VANITY_LENGTH..signers_end]
+                        .chunks(ADDRESS_LENGTH)
+                        .map(Address::from_slice)
+                        .collect(),
+                },
+            )
+        } else {
+            let mut block_reward = BTreeMap::new();
+            block_reward.insert(BlockNumber(0), 5_000_000_000_000_000_000_u64.into());
+            if let Some(byzantium) = config.byzantium_block {
+                block_reward.insert(byzantium, 3_000_000_000_000_000_000_u64.into());
+            }
+            if let Some(constantinople) = config.constantinople_block {
+                block_reward.insert(constantinople, 2_000_000_000_000_000_000_u64.into());
+            }
+
+            (
+                SealVerificationParams::Ethash {
+                    duration_limit: 13,
+                    block_reward,
+                    homestead_formula: config.homestead_block,
+                    byzantium_formula: config.byzantium_block,
+                    difficulty_bomb: None,
+                    skip_pow_verification: false,
+                },
+                Seal::Ethash {
+                    vanity: self.extra_data.clone(),
+                    difficulty: self.difficulty,
+                    nonce: H64::from_low_u64_be(self.nonce),
+                    mix_hash: self.mix_hash,
+                },
+            )
+        };
+
+        let mut balances = BTreeMap::new();
+        if !self.alloc.is_empty() {
+            balances.insert(
+                BlockNumber(0),
+                self.alloc
+                    .iter()
+                    .map(|(&address, account)| (address, account.balance))
+                    .collect(),
+            );
+        }
+
+        Ok(ChainSpec {
+            name: "Private".into(),
+            consensus: ConsensusParams {
+                seal_verification,
+                eip1559_block: config.london_block,
+            },
+            upgrades: Upgrades {
+                homestead: config.homestead_block,
+                tangerine: config.eip150_block,
+                spurious: config.eip158_block.or(config.eip155_block),
+                byzantium: config.byzantium_block,
+                constantinople: config.constantinople_block,
+                // Geth enables Petersburg along with Constantinople unless told otherwise
+                petersburg: config.petersburg_block.or(config.constantinople_block),
+                istanbul: config.istanbul_block,
+                berlin: config.berlin_block,
+                london: config.london_block,
+            },
+            params: Params {
+                chain_id: config.chain_id,
+                network_id: NetworkId(config.chain_id.0),
+                min_gas_limit: 5000,
+            },
+            genesis: Genesis {
+                number: BlockNumber(0),
+                author: self.coinbase,
+                gas_limit: self.gas_limit,
+                timestamp: self.timestamp,
+                seal,
+            },
+            contracts: Default::default(),
+            balances,
+            p2p: P2PParams {
+                bootnodes: vec![],
+                preverified_hashes: vec![],
+            },
+        })
+    }
+}
+
 pub async fn initialize_genesis<'db, Tx>(txn: &Tx, chainspec: ChainSpec) -> anyhow::Result<bool>
+where
+    Tx: MutableTransaction<'db>,
+{
+    let alloc = chainspec
+        .balances
+        .get(&chainspec.genesis.number)
+        .map(|balances| {
+            balances
+                .iter()
+                .map(|(&address, &balance)| {
+                    (
+                        address,
+                        GenesisAccount {
+                            balance,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    write_genesis(txn, chainspec, &alloc, None).await
+}
+
+/// Initializes the database with the genesis of a private chain.
+pub async fn initialize_geth_genesis<'db, Tx>(
+    txn: &Tx,
+    genesis: &GethGenesis,
+) -> anyhow::Result<bool>
+where
+    Tx: MutableTransaction<'db>,
+{
+    let chainspec = genesis.chain_spec()?;
+
+    write_genesis(txn, chainspec, &genesis.alloc, genesis.base_fee_per_gas).await
+}
+
+/// The genesis header of `initialize_geth_genesis`, computed without a database,
+/// e.g. to check that the database was initialized with the same genesis.
+pub async fn geth_genesis_header(genesis: &GethGenesis) -> anyhow::Result<BlockHeader> {
+    let chainspec = genesis.chain_spec()?;
+
+    let mut state = InMemoryState::new();
+    state.begin_block(chainspec.genesis.number);
+    for (&address, account) in &genesis.alloc {
+        let mut current = Account {
+            nonce: account.nonce,
+            balance: account.balance,
+            ..Default::default()
+        };
+        if !account.code.is_empty() {
+            current.code_hash = keccak256(&account.code);
+            state
+                .update_code(current.code_hash, account.code.clone())
+                .await?;
+        }
+        state.update_account(address, None, Some(current));
+
+        for (location, value) in &account.storage {
+            state
+                .update_storage(
+                    address,
+                    h256_to_u256(location),
+                    U256::zero(),
+                    h256_to_u256(value),
+                )
+                .await?;
+        }
+    }
+
+    let base_fee_per_gas = genesis_base_fee_per_gas(&chainspec, genesis.base_fee_per_gas);
+    let number = chainspec.genesis.number;
+    Ok(BlockHeader {
+        number,
+        base_fee_per_gas,
+        ..GenesisState::new(chainspec).header(&state)
+    })
+}
+
+fn genesis_base_fee_per_gas(chainspec: &ChainSpec, base_fee_per_gas: Option<U256>) -> Option<U256> {
+    if switch_is_active(chainspec.consensus.eip1559_block, chainspec.genesis.number) {
+        Some(base_fee_per_gas.unwrap_or_else(|| param::INITIAL_BASE_FEE.into()))
+    } else {
+        None
+    }
+}
+
+async fn write_genesis<'db, Tx>(
+    txn: &Tx,
+    chainspec: ChainSpec,
+    alloc: &HashMap<Address, GenesisAccount>,
+    base_fee_per_gas: Option<U256>,
+) -> anyhow::Result<bool>
 where
     Tx: MutableTransaction<'db>,
 {
@@ -74,16 +381,29 @@ where
     let mut state_buffer = Buffer::new(txn, genesis, None);
     state_buffer.begin_block(genesis);
     // Allocate accounts
-    if let Some(balances) = chainspec.balances.get(&genesis) {
-        for (&address, &balance) in balances {
-            state_buffer.update_account(
-                address,
-                None,
-                Some(Account {
-                    balance,
-                    ..Default::default()
-                }),
-            );
+    for (&address, account) in alloc {
+        let mut current = Account {
+            nonce: account.nonce,
+            balance: account.balance,
+            ..Default::default()
+        };
+        if !account.code.is_empty() {
+            current.code_hash = keccak256(&account.code);
+            state_buffer
+                .update_code(current.code_hash, account.code.clone())
+                .await?;
+        }
+        state_buffer.update_account(address, None, Some(current));
+
+        for (location, value) in &account.storage {
+            state_buffer
+                .update_storage(
+                    address,
+                    h256_to_u256(location),
+                    U256::zero(),
+                    h256_to_u256(value),
+                )
+                .await?;
         }
     }
 
@@ -93,6 +413,8 @@ where
     crate::stages::promote_clean_storage(txn).await?;
     let state_root = crate::stages::generate_interhashes(txn).await?;
 
+    let base_fee_per_gas = genesis_base_fee_per_gas(&chainspec, base_fee_per_gas);
+
     let header = BlockHeader {
         parent_hash: H256::zero(),
         beneficiary: chainspec.genesis.author,
@@ -106,7 +428,7 @@ where
         extra_data: chainspec.genesis.seal.extra_data(),
         mix_hash: chainspec.genesis.seal.mix_hash(),
         nonce: chainspec.genesis.seal.nonce(),
-        base_fee_per_gas: None,
+        base_fee_per_gas,
 
         receipts_root: EMPTY_ROOT,
         ommers_hash: EMPTY_LIST_HASH,
@@ -181,6 +503,167 @@ mod tests {
         // );
     }
 
+    const CLIQUE_GENESIS: &str = r#"{
+        "config": {
+            "chainId": 1337,
+            "homesteadBlock": 0,
+            "eip150Block": 0,
+            "eip155Block": 0,
+            "eip158Block": 0,
+            "byzantiumBlock": 0,
+            "constantinopleBlock": 0,
+            "istanbulBlock": 0,
+            "berlinBlock": 0,
+            "londonBlock": 10,
+            "clique": {
+                "period": 5,
+                "epoch": 30000
+            }
+        },
+        "difficulty": "1",
+        "gasLimit": "0x1c9c380",
+        "extraData": "0x0000000000000000000000000000000000000000000000000000000000000000a94f5374fce5edbc8e2a8697c15331677e6ebf0b0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "alloc": {
+            "0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b": {
+                "balance": "1000000000000000000000"
+            },
+            "0x00000000000000000000000000000000000000c0": {
+                "balance": "0x0",
+                "nonce": "0x1",
+                "code": "0x60016000f3",
+                "storage": {
+                    "0x0000000000000000000000000000000000000000000000000000000000000000": "0x000000000000000000000000000000000000000000000000000000000000002a"
+                }
+            }
+        }
+    }"#;
+
+    const ETHASH_GENESIS: &str = r#"{
+        "config": {
+            "chainId": 4242,
+            "homesteadBlock": 0,
+            "byzantiumBlock": 5,
+            "londonBlock": 0
+        },
+        "nonce": "0x0000000000000042",
+        "difficulty": "0x20000",
+        "gasLimit": "0x47b760",
+        "alloc": {}
+    }"#;
+
+    async fn init_geth_genesis<'db, RwTx: MutableTransaction<'db>>(tx: &RwTx, json: &str) -> H256 {
+        let genesis = serde_json::from_str::<GethGenesis>(json).unwrap();
+        assert!(initialize_geth_genesis(tx, &genesis).await.unwrap());
+        tx.get(tables::CanonicalHeader, 0.into())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn init_clique_geth_genesis() {
+        let genesis = serde_json::from_str::<GethGenesis>(CLIQUE_GENESIS).unwrap();
+        let chain_spec = genesis.chain_spec().unwrap();
+        assert_eq!(
+            chain_spec.consensus.seal_verification,
+            SealVerificationParams::Clique {
+                period: Duration::from_secs(5),
+                epoch: 30000,
+            }
+        );
+        assert_eq!(
+            chain_spec.genesis.seal,
+            Seal::Clique {
+                vanity: H256::zero(),
+                score: BlockScore::NoTurn,
+                signers: vec![hex!("a94f5374fce5edbc8e2a8697c15331677e6ebf0b").into()],
+            }
+        );
+        assert_eq!(chain_spec.genesis.seal.extra_data(), genesis.extra_data);
+        // implied by Constantinople
+        assert_eq!(chain_spec.upgrades.petersburg, Some(BlockNumber(0)));
+        assert_eq!(chain_spec.upgrades.london, Some(BlockNumber(10)));
+
+        // same file, same genesis
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        let genesis_hash = init_geth_genesis(&tx, CLIQUE_GENESIS).await;
+        assert_eq!(
+            geth_genesis_header(&genesis).await.unwrap().hash(),
+            genesis_hash
+        );
+
+        let other_db = new_mem_database().unwrap();
+        let other_tx = other_db.begin_mutable().await.unwrap();
+        assert_eq!(
+            init_geth_genesis(&other_tx, CLIQUE_GENESIS).await,
+            genesis_hash
+        );
+
+        let header = tx
+            .get(tables::Header, (0.into(), genesis_hash))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.difficulty, 1.into());
+        assert_eq!(header.gas_limit, 0x1c9c380);
+        assert_eq!(header.base_fee_per_gas, None);
+
+        let contract = Address::from_low_u64_be(0xc0);
+        let account = crate::accessors::state::account::read(&tx, contract, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.nonce, 1);
+        assert_eq!(
+            tx.get(tables::Code, account.code_hash).await.unwrap(),
+            Some(hex!("60016000f3").to_vec().into())
+        );
+        assert_eq!(
+            crate::accessors::state::storage::read(&tx, contract, 0.into(), None)
+                .await
+                .unwrap(),
+            0x2a.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn init_ethash_geth_genesis() {
+        let genesis = serde_json::from_str::<GethGenesis>(ETHASH_GENESIS).unwrap();
+        let chain_spec = genesis.chain_spec().unwrap();
+        assert!(chain_spec.balances.is_empty());
+        assert_eq!(chain_spec.params.chain_id, ChainId(4242));
+        assert_eq!(
+            chain_spec.gather_forks(),
+            [BlockNumber(5)].into_iter().collect()
+        );
+
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        let genesis_hash = init_geth_genesis(&tx, ETHASH_GENESIS).await;
+        assert_eq!(
+            geth_genesis_header(&genesis).await.unwrap().hash(),
+            genesis_hash
+        );
+
+        let header = tx
+            .get(tables::Header, (0.into(), genesis_hash))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.state_root, EMPTY_ROOT);
+        assert_eq!(header.nonce, H64::from_low_u64_be(0x42));
+        // London is active from the genesis
+        assert_eq!(
+            header.base_fee_per_gas,
+            Some(param::INITIAL_BASE_FEE.into())
+        );
+        assert_eq!(
+            tx.get(tables::Config, genesis_hash).await.unwrap(),
+            Some(chain_spec)
+        );
+    }
+
     #[tokio::test]
     async fn init_mainnet_genesis() {
         let db = new_mem_database().unwrap();
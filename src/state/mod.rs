@@ -1,4 +1,5 @@
 mod buffer;
+pub mod chunked_snapshot;
 mod database;
 mod delta;
 pub mod genesis;
@@ -6,7 +7,10 @@ mod in_memory_state;
 mod interface;
 mod intra_block_state;
 mod object;
+pub mod snapshot;
+mod trie_cache;
 
 pub use self::{
     buffer::*, database::*, in_memory_state::*, interface::*, intra_block_state::*, object::*,
+    trie_cache::*,
 };
@@ -0,0 +1,465 @@
+//! Flat, deterministic snapshot of the plain state.
+//!
+//! Layout (all integers are big-endian):
+//! ```text
+//! "AKSNAP" | version: u8 | block number: u64 | canonical block hash: [u8; 32]
+//! 0x01 | address: [u8; 20] | len: u8 | account: [u8; len]    -- accounts, sorted by address
+//! 0x02 | address: [u8; 20] | location: [u8; 32] | value: [u8; 32]
+//!                                                           -- storage, sorted by (address, location)
+//! 0x03 | code hash: [u8; 32] | len: u32 | code: [u8; len]   -- code, sorted by code hash
+//! 0x00                                                      -- end of snapshot
+//! ```
+//! Accounts use the same encoding as `tables::Account`.
+use crate::{
+    crypto::keccak256,
+    kv::{tables, traits::*},
+    models::*,
+    stagedsync::stages::EXECUTION,
+    u256_to_h256,
+};
+use anyhow::{bail, ensure, format_err};
+use bytes::Bytes;
+use ethereum_types::*;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    pin,
+};
+use tokio_stream::StreamExt;
+use tracing::*;
+
+pub const SNAPSHOT_MAGIC: [u8; 6] = *b"AKSNAP";
+pub const SNAPSHOT_VERSION: u8 = 3;
+
+pub(crate) const TAG_END: u8 = 0x00;
+const TAG_ACCOUNT: u8 = 0x01;
+const TAG_STORAGE: u8 = 0x02;
+const TAG_CODE: u8 = 0x03;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SnapshotHeader {
+    pub block_number: BlockNumber,
+    /// Zero if the canonical header was not known at export.
+    pub block_hash: H256,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotEntry {
+    Account {
+        address: Address,
+        account: Account,
+    },
+    Storage {
+        address: Address,
+        location: H256,
+        value: U256,
+    },
+    Code {
+        code_hash: H256,
+        code: Bytes,
+    },
+}
+
+pub(crate) async fn write_entry<W>(writer: &mut W, entry: SnapshotEntry) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    match entry {
+        SnapshotEntry::Account { address, account } => {
+            let encoded = account.encode_for_storage();
+            writer.write_u8(TAG_ACCOUNT).await?;
+            writer.write_all(address.as_bytes()).await?;
+            writer.write_u8(encoded.len().try_into()?).await?;
+            writer.write_all(&encoded).await?;
+        }
+        SnapshotEntry::Storage {
+            address,
+            location,
+            value,
+        } => {
+            writer.write_u8(TAG_STORAGE).await?;
+            writer.write_all(address.as_bytes()).await?;
+            writer.write_all(location.as_bytes()).await?;
+            writer.write_all(u256_to_h256(value).as_bytes()).await?;
+        }
+        SnapshotEntry::Code { code_hash, code } => {
+            writer.write_u8(TAG_CODE).await?;
+            writer.write_all(code_hash.as_bytes()).await?;
+            writer.write_u32(code.len().try_into()?).await?;
+            writer.write_all(&code).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams the plain state (`tables::Account`, `tables::Storage` and `tables::Code`) into `writer`.
+/// Returns the block number recorded in the snapshot header, i.e. the Execution stage progress.
+pub async fn export_state_snapshot<'db, Tx, W>(
+    tx: &Tx,
+    writer: &mut W,
+) -> anyhow::Result<BlockNumber>
+where
+    Tx: Transaction<'db>,
+    W: AsyncWrite + Unpin + Send,
+{
+    let block_number = EXECUTION.get_progress(tx).await?.unwrap_or_default();
+    let block_hash = tx
+        .get(tables::CanonicalHeader, block_number)
+        .await?
+        .unwrap_or_default();
+
+    writer.write_all(&SNAPSHOT_MAGIC).await?;
+    writer.write_u8(SNAPSHOT_VERSION).await?;
+    writer.write_u64(block_number.0).await?;
+    writer.write_all(block_hash.as_bytes()).await?;
+
+    let mut account_cursor = tx.cursor(tables::Account).await?;
+    let walker = walk(&mut account_cursor, None);
+    pin!(walker);
+    while let Some((address, account)) = walker.try_next().await? {
+        write_entry(writer, SnapshotEntry::Account { address, account }).await?;
+    }
+
+    let mut storage_cursor = tx.cursor(tables::Storage).await?;
+    let walker = walk(&mut storage_cursor, None);
+    pin!(walker);
+    while let Some((address, (location, value))) = walker.try_next().await? {
+        write_entry(
+            writer,
+            SnapshotEntry::Storage {
+                address,
+                location,
+                value,
+            },
+        )
+        .await?;
+    }
+
+    let mut code_cursor = tx.cursor(tables::Code).await?;
+    let walker = walk(&mut code_cursor, None);
+    pin!(walker);
+    while let Some((code_hash, code)) = walker.try_next().await? {
+        write_entry(writer, SnapshotEntry::Code { code_hash, code }).await?;
+    }
+
+    writer.write_u8(TAG_END).await?;
+    writer.flush().await?;
+
+    Ok(block_number)
+}
+
+/// Reads the snapshot header describing the block the snapshot was taken at.
+pub async fn read_snapshot_header<R>(reader: &mut R) -> anyhow::Result<SnapshotHeader>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut magic = [0; SNAPSHOT_MAGIC.len()];
+    reader.read_exact(&mut magic).await?;
+    if magic != SNAPSHOT_MAGIC {
+        bail!("not a state snapshot");
+    }
+
+    let version = reader.read_u8().await?;
+    if version != SNAPSHOT_VERSION {
+        bail!("unsupported snapshot version {}", version);
+    }
+
+    let block_number = BlockNumber(reader.read_u64().await?);
+    let mut block_hash = H256::zero();
+    reader.read_exact(block_hash.as_bytes_mut()).await?;
+
+    Ok(SnapshotHeader {
+        block_number,
+        block_hash,
+    })
+}
+
+/// Reads the next entry, returns `None` at the end of the snapshot.
+pub async fn read_snapshot_entry<R>(reader: &mut R) -> anyhow::Result<Option<SnapshotEntry>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut address = Address::zero();
+
+    Ok(match reader.read_u8().await? {
+        TAG_END => None,
+        TAG_ACCOUNT => {
+            reader.read_exact(address.as_bytes_mut()).await?;
+            let mut encoded = vec![0; reader.read_u8().await? as usize];
+            reader.read_exact(&mut encoded).await?;
+            let account = Account::decode_for_storage(&encoded)?
+                .ok_or_else(|| format_err!("empty account {:?} in snapshot", address))?;
+
+            Some(SnapshotEntry::Account { address, account })
+        }
+        TAG_STORAGE => {
+            let mut location = H256::zero();
+            let mut value = H256::zero();
+            reader.read_exact(address.as_bytes_mut()).await?;
+            reader.read_exact(location.as_bytes_mut()).await?;
+            reader.read_exact(value.as_bytes_mut()).await?;
+
+            Some(SnapshotEntry::Storage {
+                address,
+                location,
+                value: U256::from_big_endian(value.as_bytes()),
+            })
+        }
+        TAG_CODE => {
+            let mut code_hash = H256::zero();
+            reader.read_exact(code_hash.as_bytes_mut()).await?;
+            let mut code = vec![0; reader.read_u32().await? as usize];
+            reader.read_exact(&mut code).await?;
+            ensure!(
+                keccak256(&code) == code_hash,
+                "code {:?} in snapshot does not match its hash",
+                code_hash
+            );
+
+            Some(SnapshotEntry::Code {
+                code_hash,
+                code: code.into(),
+            })
+        }
+        other => bail!("unknown snapshot entry tag {}", other),
+    })
+}
+
+/// Seeds an empty database with the state from a snapshot
+/// and sets the Execution stage progress to the snapshot's block.
+/// The block must be already known as canonical locally.
+///
+/// Commits every `batch_entries` entries, and sets the progress only with the last commit,
+/// so an interrupted import leaves the state without the progress behind, and has to be
+/// restarted from an empty database.
+pub async fn import_state_snapshot<DB, R>(
+    db: &DB,
+    reader: &mut R,
+    batch_entries: u64,
+) -> anyhow::Result<BlockNumber>
+where
+    DB: MutableKV,
+    R: AsyncRead + Unpin + Send,
+{
+    let SnapshotHeader {
+        block_number,
+        block_hash,
+    } = read_snapshot_header(reader).await?;
+
+    let mut tx = db.begin_mutable().await?;
+    let canonical_hash = tx
+        .get(tables::CanonicalHeader, block_number)
+        .await?
+        .ok_or_else(|| format_err!("no canonical header for snapshot block {}", block_number))?;
+    ensure!(
+        canonical_hash == block_hash,
+        "snapshot block {} hash mismatch: local {:?}, snapshot {:?}",
+        block_number,
+        canonical_hash,
+        block_hash
+    );
+
+    ensure!(
+        tx.cursor(tables::Account).await?.first().await?.is_none()
+            && tx.cursor(tables::Storage).await?.first().await?.is_none(),
+        "state snapshot can only be imported into an empty database"
+    );
+
+    let batch_entries = std::cmp::max(batch_entries, 1);
+    let mut imported = 0_u64;
+    let mut next_entry = read_snapshot_entry(reader).await?;
+    while next_entry.is_some() {
+        {
+            let mut account_cursor = tx.mutable_cursor(tables::Account).await?;
+            let mut storage_cursor = tx.mutable_cursor_dupsort(tables::Storage).await?;
+            let mut code_cursor = tx.mutable_cursor(tables::Code).await?;
+
+            // Entries are sorted, so they are appended in bulk.
+            while let Some(entry) = next_entry.take() {
+                match entry {
+                    SnapshotEntry::Account { address, account } => {
+                        account_cursor.append(address, account).await?;
+                    }
+                    SnapshotEntry::Storage {
+                        address,
+                        location,
+                        value,
+                    } => {
+                        storage_cursor
+                            .append_dup(address, (location, value))
+                            .await?;
+                    }
+                    // the genesis might have written some of the code already
+                    SnapshotEntry::Code { code_hash, code } => {
+                        code_cursor.upsert(code_hash, code).await?;
+                    }
+                }
+
+                imported += 1;
+                next_entry = read_snapshot_entry(reader).await?;
+                if imported % batch_entries == 0 {
+                    break;
+                }
+            }
+        }
+
+        if next_entry.is_some() {
+            tx.commit().await?;
+            tx = db.begin_mutable().await?;
+            info!("Imported {} snapshot entries", imported);
+        }
+    }
+
+    EXECUTION.save_progress(&tx, block_number).await?;
+    tx.commit().await?;
+
+    info!(
+        "Imported state snapshot at block {}, {} entries",
+        block_number, imported
+    );
+
+    Ok(block_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::new_mem_database;
+    use hex_literal::hex;
+
+    async fn collect_state<'db, Tx: Transaction<'db>>(tx: &Tx) -> Vec<SnapshotEntry> {
+        let mut out = vec![];
+        export_state_snapshot(tx, &mut out).await.unwrap();
+
+        let mut reader = &out[..];
+        read_snapshot_header(&mut reader).await.unwrap();
+        let mut entries = vec![];
+        while let Some(entry) = read_snapshot_entry(&mut reader).await.unwrap() {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn export_and_import() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let address1 = Address::from(hex!("00000000000000000000000000000000000000a1"));
+        let address2 = Address::from(hex!("00000000000000000000000000000000000000b2"));
+        let account1 = Account {
+            nonce: 1,
+            balance: 0x10.into(),
+            code_hash: EMPTY_HASH,
+        };
+        let code = Bytes::from_static(&hex!("600035600055"));
+        let code_hash = keccak256(&code);
+        let account2 = Account {
+            nonce: 0,
+            balance: 0.into(),
+            code_hash,
+        };
+        let location1 = H256::from_low_u64_be(3);
+        let location2 = H256::from_low_u64_be(4);
+        let block_hash = H256::repeat_byte(0x07);
+
+        // inserted out of order on purpose
+        tx.set(tables::Account, address2, account2).await.unwrap();
+        tx.set(tables::Account, address1, account1).await.unwrap();
+        tx.set(tables::Storage, address2, (location2, 0x01.into()))
+            .await
+            .unwrap();
+        tx.set(tables::Storage, address2, (location1, 0x2a.into()))
+            .await
+            .unwrap();
+        tx.set(tables::Code, code_hash, code.clone()).await.unwrap();
+        tx.set(tables::CanonicalHeader, BlockNumber(7), block_hash)
+            .await
+            .unwrap();
+        EXECUTION.save_progress(&tx, BlockNumber(7)).await.unwrap();
+
+        let mut out = vec![];
+        assert_eq!(
+            export_state_snapshot(&tx, &mut out).await.unwrap(),
+            BlockNumber(7)
+        );
+
+        let mut expected = vec![];
+        expected.extend_from_slice(b"AKSNAP");
+        expected.push(SNAPSHOT_VERSION);
+        expected.extend_from_slice(&7_u64.to_be_bytes());
+        expected.extend_from_slice(block_hash.as_bytes());
+        for (address, account) in [(address1, account1), (address2, account2)] {
+            let encoded = account.encode_for_storage();
+            expected.push(TAG_ACCOUNT);
+            expected.extend_from_slice(address.as_bytes());
+            expected.push(encoded.len() as u8);
+            expected.extend_from_slice(&encoded);
+        }
+        for (location, value) in [(location1, 0x2a), (location2, 0x01)] {
+            expected.push(TAG_STORAGE);
+            expected.extend_from_slice(address2.as_bytes());
+            expected.extend_from_slice(location.as_bytes());
+            expected.extend_from_slice(H256::from_low_u64_be(value).as_bytes());
+        }
+        expected.push(TAG_CODE);
+        expected.extend_from_slice(code_hash.as_bytes());
+        expected.extend_from_slice(&(code.len() as u32).to_be_bytes());
+        expected.extend_from_slice(&code);
+        expected.push(TAG_END);
+        assert_eq!(out, expected);
+
+        // the code doesn't match its hash
+        let mut corrupted = out.clone();
+        let code_end = corrupted.len() - 2;
+        corrupted[code_end] ^= 0xff;
+        let mut reader = &corrupted[..];
+        read_snapshot_header(&mut reader).await.unwrap();
+        for _ in 0..4 {
+            read_snapshot_entry(&mut reader).await.unwrap().unwrap();
+        }
+        assert!(read_snapshot_entry(&mut reader).await.is_err());
+
+        // the block is not known to the importing node
+        let db2 = new_mem_database().unwrap();
+        assert!(import_state_snapshot(&db2, &mut &out[..], 1).await.is_err());
+
+        // a different block at the same height
+        let tx2 = db2.begin_mutable().await.unwrap();
+        tx2.set(
+            tables::CanonicalHeader,
+            BlockNumber(7),
+            H256::repeat_byte(0x08),
+        )
+        .await
+        .unwrap();
+        tx2.commit().await.unwrap();
+        assert!(import_state_snapshot(&db2, &mut &out[..], 1).await.is_err());
+
+        let tx2 = db2.begin_mutable().await.unwrap();
+        tx2.set(tables::CanonicalHeader, BlockNumber(7), block_hash)
+            .await
+            .unwrap();
+        tx2.commit().await.unwrap();
+        // committed entry by entry
+        assert_eq!(
+            import_state_snapshot(&db2, &mut &out[..], 1).await.unwrap(),
+            BlockNumber(7)
+        );
+        let tx2 = db2.begin().await.unwrap();
+        assert_eq!(
+            EXECUTION.get_progress(&tx2).await.unwrap(),
+            Some(BlockNumber(7))
+        );
+        assert_eq!(collect_state(&tx).await, collect_state(&tx2).await);
+        assert_eq!(collect_state(&tx2).await.len(), 5);
+        assert_eq!(
+            tx2.get(tables::Code, code_hash).await.unwrap(),
+            Some(code.clone())
+        );
+        drop(tx2);
+
+        // the database is not empty anymore
+        assert!(import_state_snapshot(&db2, &mut &out[..], 1).await.is_err());
+    }
+}
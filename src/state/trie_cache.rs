@@ -0,0 +1,536 @@
+use crate::{
+    crypto::keccak256,
+    h256_to_u256,
+    kv::{tables, traits::*},
+    models::*,
+    u256_to_h256,
+    util::zeroless_view,
+};
+use anyhow::bail;
+use ethereum_types::*;
+use rlp::RlpStream;
+use std::collections::{HashMap, HashSet};
+use tokio::pin;
+use tokio_stream::StreamExt;
+
+/// Splits the key into the nibbles of its path in the trie.
+fn to_nibbles(key: H256) -> Vec<u8> {
+    key.as_bytes()
+        .iter()
+        .flat_map(|b| [b >> 4, b & 0x0f])
+        .collect()
+}
+
+/// Hex-prefix encoding of a partial path.
+fn hex_prefix(path: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 0x20 } else { 0x00 };
+
+    let mut out = Vec::with_capacity(path.len() / 2 + 1);
+    let rest = if path.len() % 2 == 1 {
+        out.push(flag | 0x10 | path[0]);
+        &path[1..]
+    } else {
+        out.push(flag);
+        path
+    };
+    for pair in rest.chunks(2) {
+        out.push((pair[0] << 4) | pair[1]);
+    }
+    out
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+fn concat(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    out.extend_from_slice(a);
+    out.extend_from_slice(b);
+    out
+}
+
+/// Embeds the child into its parent: inline if its encoding is shorter than a hash.
+fn append_reference(s: &mut RlpStream, reference: &[u8]) {
+    if reference.len() == H256::len_bytes() {
+        s.append(&reference);
+    } else {
+        s.append_raw(reference, 1);
+    }
+}
+
+// All keys are of the same length, so no key is a prefix of another, and branches have no values.
+#[derive(Clone, Debug)]
+enum NodeKind {
+    Leaf { path: Vec<u8>, value: Vec<u8> },
+    Extension { path: Vec<u8>, child: Box<Node> },
+    Branch { children: [Option<Box<Node>>; 16] },
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    kind: NodeKind,
+    /// Hash of the RLP of the node, or the RLP itself if it's shorter than a hash.
+    /// None if the node has changed since it was last encoded.
+    reference: Option<Vec<u8>>,
+}
+
+impl Node {
+    fn new(kind: NodeKind) -> Box<Self> {
+        Box::new(Self {
+            kind,
+            reference: None,
+        })
+    }
+
+    fn leaf(path: &[u8], value: Vec<u8>) -> Box<Self> {
+        Self::new(NodeKind::Leaf {
+            path: path.to_vec(),
+            value,
+        })
+    }
+
+    /// The node under the extension, or the node itself if the path is empty.
+    fn extension(path: &[u8], child: Box<Self>) -> Box<Self> {
+        if path.is_empty() {
+            child
+        } else {
+            Self::new(NodeKind::Extension {
+                path: path.to_vec(),
+                child,
+            })
+        }
+    }
+
+    /// Branch with two nodes under the different nibbles.
+    fn branch(a: (u8, Box<Self>), b: (u8, Box<Self>)) -> Box<Self> {
+        let mut children: [Option<Box<Self>>; 16] = Default::default();
+        children[a.0 as usize] = Some(a.1);
+        children[b.0 as usize] = Some(b.1);
+        Self::new(NodeKind::Branch { children })
+    }
+
+    /// The node with the path prepended.
+    fn prepend_path(self: Box<Self>, prefix: &[u8]) -> Box<Self> {
+        match self.kind {
+            NodeKind::Leaf { path, value } => Self::leaf(&concat(prefix, &path), value),
+            NodeKind::Extension { path, child } => Self::extension(&concat(prefix, &path), child),
+            NodeKind::Branch { .. } => Self::extension(prefix, self),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        match &self.kind {
+            NodeKind::Leaf { path, value } => (path == key).then(|| value.as_slice()),
+            NodeKind::Extension { path, child } => key
+                .strip_prefix(path.as_slice())
+                .and_then(|key| child.get(key)),
+            NodeKind::Branch { children } => children[key[0] as usize]
+                .as_ref()
+                .and_then(|child| child.get(&key[1..])),
+        }
+    }
+
+    fn insert(node: Option<Box<Self>>, key: &[u8], value: Vec<u8>) -> Box<Self> {
+        let mut node = match node {
+            Some(node) => node,
+            None => return Self::leaf(key, value),
+        };
+
+        node.reference = None;
+        match node.kind {
+            NodeKind::Leaf {
+                ref path,
+                value: ref mut leaf_value,
+            } if path == key => {
+                *leaf_value = value;
+                node
+            }
+            NodeKind::Leaf {
+                path,
+                value: leaf_value,
+            } => {
+                let n = common_prefix_len(&path, key);
+                Self::extension(
+                    &key[..n],
+                    Self::branch(
+                        (path[n], Self::leaf(&path[n + 1..], leaf_value)),
+                        (key[n], Self::leaf(&key[n + 1..], value)),
+                    ),
+                )
+            }
+            NodeKind::Extension { path, child } => {
+                let n = common_prefix_len(&path, key);
+                if n == path.len() {
+                    Self::extension(&path, Self::insert(Some(child), &key[n..], value))
+                } else {
+                    Self::extension(
+                        &key[..n],
+                        Self::branch(
+                            (path[n], Self::extension(&path[n + 1..], child)),
+                            (key[n], Self::leaf(&key[n + 1..], value)),
+                        ),
+                    )
+                }
+            }
+            NodeKind::Branch { ref mut children } => {
+                let child = &mut children[key[0] as usize];
+                *child = Some(Self::insert(child.take(), &key[1..], value));
+                node
+            }
+        }
+    }
+
+    /// Removes the key, which must be present in the trie.
+    fn remove(mut self: Box<Self>, key: &[u8]) -> Option<Box<Self>> {
+        self.reference = None;
+        match self.kind {
+            NodeKind::Leaf { .. } => None,
+            NodeKind::Extension { path, child } => child
+                .remove(&key[path.len()..])
+                .map(|child| child.prepend_path(&path)),
+            NodeKind::Branch { ref mut children } => {
+                let index = key[0] as usize;
+                children[index] = children[index].take().unwrap().remove(&key[1..]);
+
+                // Branch with a single child is replaced with the child.
+                if children.iter().filter(|child| child.is_some()).count() == 1 {
+                    let index = children.iter().position(Option::is_some).unwrap();
+                    let child = children[index].take().unwrap();
+                    Some(child.prepend_path(&[index as u8]))
+                } else {
+                    Some(self)
+                }
+            }
+        }
+    }
+
+    fn reference(&mut self) -> &[u8] {
+        if self.reference.is_none() {
+            let rlp = match &mut self.kind {
+                NodeKind::Leaf { path, value } => {
+                    let mut s = RlpStream::new_list(2);
+                    s.append(&hex_prefix(path, true));
+                    s.append(value);
+                    s.out()
+                }
+                NodeKind::Extension { path, child } => {
+                    let mut s = RlpStream::new_list(2);
+                    s.append(&hex_prefix(path, false));
+                    append_reference(&mut s, child.reference());
+                    s.out()
+                }
+                NodeKind::Branch { children } => {
+                    let mut s = RlpStream::new_list(17);
+                    for child in children.iter_mut() {
+                        if let Some(child) = child {
+                            append_reference(&mut s, child.reference());
+                        } else {
+                            s.append_empty_data();
+                        }
+                    }
+                    s.append_empty_data();
+                    s.out()
+                }
+            };
+
+            self.reference = Some(if rlp.len() < H256::len_bytes() {
+                rlp.to_vec()
+            } else {
+                keccak256(&rlp).as_bytes().to_vec()
+            });
+        }
+
+        self.reference.as_ref().unwrap()
+    }
+}
+
+/// Merkle Patricia trie in memory which keeps the hashes of its nodes.
+/// Updates only discard the hashes along the changed paths, so the root
+/// is recomputed in time proportional to the number of changes rather than the size of the trie.
+#[derive(Clone, Debug, Default)]
+pub struct TrieCache {
+    root: Option<Box<Node>>,
+    len: usize,
+}
+
+impl TrieCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: H256) -> Option<&[u8]> {
+        self.root.as_ref()?.get(&to_nibbles(key))
+    }
+
+    pub fn insert(&mut self, key: H256, value: Vec<u8>) {
+        if self.get(key).is_none() {
+            self.len += 1;
+        }
+        self.root = Some(Node::insert(self.root.take(), &to_nibbles(key), value));
+    }
+
+    /// Returns whether the key was present.
+    pub fn remove(&mut self, key: H256) -> bool {
+        if self.get(key).is_none() {
+            return false;
+        }
+
+        self.len -= 1;
+        self.root = self.root.take().unwrap().remove(&to_nibbles(key));
+        true
+    }
+
+    pub fn root(&mut self) -> H256 {
+        match &mut self.root {
+            None => EMPTY_ROOT,
+            Some(root) => {
+                let reference = root.reference();
+                if reference.len() == H256::len_bytes() {
+                    H256::from_slice(reference)
+                } else {
+                    keccak256(reference)
+                }
+            }
+        }
+    }
+}
+
+/// The most accounts and storage slots `StateRootCache::load` keeps in memory, which is plenty
+/// for the test and development chains, and far from the mainnet state.
+pub const STATE_ROOT_CACHE_MAX_ENTRIES: usize = 10_000_000;
+
+/// State root maintained incrementally: the account trie and the storage trie of every account
+/// are kept in memory, and only the changed accounts are encoded again.
+#[derive(Debug, Default)]
+pub struct StateRootCache {
+    accounts: HashMap<Address, Account>,
+    storage: HashMap<Address, TrieCache>,
+    account_trie: TrieCache,
+    changed: HashSet<Address>,
+}
+
+impl StateRootCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the tries of the latest state in the database, which takes the whole state in memory.
+    /// Fails as soon as the state has more than `max_entries` accounts and storage slots,
+    /// see `STATE_ROOT_CACHE_MAX_ENTRIES`.
+    pub async fn load<'db, Tx: Transaction<'db>>(
+        tx: &Tx,
+        max_entries: usize,
+    ) -> anyhow::Result<Self> {
+        let mut cache = Self::new();
+        let mut entries = 0;
+        let mut count_entry = || {
+            entries += 1;
+            if entries > max_entries {
+                bail!(
+                    "State has more than {} accounts and storage slots to keep in memory",
+                    max_entries
+                );
+            }
+            Ok(())
+        };
+
+        let mut account_table = tx.cursor(tables::Account).await?;
+        let walker = walk(&mut account_table, None);
+        pin!(walker);
+        while let Some((address, account)) = walker.try_next().await? {
+            count_entry()?;
+            cache.update_account(address, Some(account));
+        }
+
+        let mut storage_table = tx.cursor_dup_sort(tables::Storage).await?;
+        let walker = walk(&mut storage_table, None);
+        pin!(walker);
+        while let Some((address, (location, value))) = walker.try_next().await? {
+            count_entry()?;
+            cache.update_storage(address, h256_to_u256(location), value);
+        }
+
+        Ok(cache)
+    }
+
+    /// Deleting the account also erases its storage.
+    pub fn update_account(&mut self, address: Address, account: Option<Account>) {
+        if let Some(account) = account {
+            self.accounts.insert(address, account);
+        } else {
+            self.accounts.remove(&address);
+            self.storage.remove(&address);
+        }
+        self.changed.insert(address);
+    }
+
+    pub fn update_storage(&mut self, address: Address, location: U256, value: U256) {
+        let key = keccak256(u256_to_h256(location));
+        let storage = self.storage.entry(address).or_default();
+        if value.is_zero() {
+            storage.remove(key);
+        } else {
+            let value = u256_to_h256(value);
+            storage.insert(key, rlp::encode(&zeroless_view(&value)).to_vec());
+        }
+        self.changed.insert(address);
+    }
+
+    pub fn erase_storage(&mut self, address: Address) {
+        self.storage.remove(&address);
+        self.changed.insert(address);
+    }
+
+    pub fn root(&mut self) -> H256 {
+        for address in self.changed.drain() {
+            let key = keccak256(address);
+            if let Some(account) = self.accounts.get(&address) {
+                let storage_root = self
+                    .storage
+                    .get_mut(&address)
+                    .map(TrieCache::root)
+                    .unwrap_or(EMPTY_ROOT);
+                self.account_trie
+                    .insert(key, rlp::encode(&account.to_rlp(storage_root)).to_vec());
+            } else {
+                self.account_trie.remove(key);
+            }
+        }
+
+        self.account_trie.root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::trie_root;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    fn random_key(rng: &mut StdRng) -> H256 {
+        let n = rng.gen_range(0..300_u64);
+        // small keys share long prefixes, so that short nodes get inlined
+        if rng.gen() {
+            H256::from_low_u64_be(n)
+        } else {
+            keccak256(H256::from_low_u64_be(n))
+        }
+    }
+
+    #[test]
+    fn random_updates() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut trie = TrieCache::new();
+        let mut expected = BTreeMap::new();
+
+        assert_eq!(trie.root(), EMPTY_ROOT);
+
+        for round in 0..50 {
+            for _ in 0..rng.gen_range(1..40) {
+                let key = random_key(&mut rng);
+                if rng.gen_ratio(1, 3) {
+                    assert_eq!(trie.remove(key), expected.remove(&key).is_some());
+                } else {
+                    let value = (0..rng.gen_range(1..40))
+                        .map(|_| rng.gen())
+                        .collect::<Vec<u8>>();
+                    trie.insert(key, value.clone());
+                    expected.insert(key, value);
+                }
+            }
+
+            assert_eq!(trie.len(), expected.len());
+            assert_eq!(
+                trie.root(),
+                trie_root(expected.iter().map(|(k, v)| (*k, v.clone()))),
+                "round {}",
+                round
+            );
+        }
+
+        for key in expected.keys() {
+            assert_eq!(trie.get(*key), Some(expected[key].as_slice()));
+            assert!(trie.remove(*key));
+        }
+        assert!(trie.is_empty());
+        assert_eq!(trie.root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn state_root() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut cache = StateRootCache::new();
+        let mut accounts = HashMap::<Address, Account>::new();
+        let mut storage = HashMap::<Address, HashMap<U256, U256>>::new();
+
+        for _ in 0..20 {
+            for _ in 0..rng.gen_range(1..20) {
+                let address = Address::from_low_u64_be(rng.gen_range(0..20));
+                match rng.gen_range(0..10) {
+                    0 => {
+                        cache.update_account(address, None);
+                        accounts.remove(&address);
+                        storage.remove(&address);
+                    }
+                    1 => {
+                        cache.erase_storage(address);
+                        storage.remove(&address);
+                    }
+                    2..=4 => {
+                        let account = Account {
+                            nonce: rng.gen_range(0..100),
+                            balance: rng.gen_range(0..1_000_000_u64).into(),
+                            ..Default::default()
+                        };
+                        cache.update_account(address, Some(account));
+                        accounts.insert(address, account);
+                    }
+                    _ => {
+                        let location = U256::from(rng.gen_range(0..10_u64));
+                        let value = U256::from(rng.gen_range(0..3_u64));
+                        cache.update_storage(address, location, value);
+                        if value.is_zero() {
+                            storage.entry(address).or_default().remove(&location);
+                        } else {
+                            storage.entry(address).or_default().insert(location, value);
+                        }
+                    }
+                }
+            }
+
+            let expected = if accounts.is_empty() {
+                EMPTY_ROOT
+            } else {
+                trie_root(accounts.iter().map(|(address, account)| {
+                    let storage_root = match storage.get(address) {
+                        Some(slots) if !slots.is_empty() => {
+                            trie_root(slots.iter().map(|(location, value)| {
+                                let value = u256_to_h256(*value);
+                                (
+                                    keccak256(u256_to_h256(*location)),
+                                    rlp::encode(&zeroless_view(&value)),
+                                )
+                            }))
+                        }
+                        _ => EMPTY_ROOT,
+                    };
+                    (
+                        keccak256(address),
+                        rlp::encode(&account.to_rlp(storage_root)),
+                    )
+                }))
+            };
+            assert_eq!(cache.root(), expected);
+        }
+    }
+}
@@ -0,0 +1,781 @@
+use crate::{
+    chain::intrinsic_gas::intrinsic_gas,
+    consensus::{expected_base_fee_per_gas, pre_validate_transaction, ValidationError},
+    crypto::is_valid_signature,
+    models::*,
+    State,
+};
+use ethereum_types::*;
+use evmodin::Revision;
+use std::{
+    cmp::{min, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    fmt::Display,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct TxPoolConfig {
+    /// Maximum number of transactions of a single sender, pending and queued.
+    pub max_per_sender: usize,
+    /// Maximum number of transactions in the pool. Once reached, a new transaction evicts
+    /// the one with the lowest effective tip if it tips more.
+    pub max_size: usize,
+    /// How much both fees of a replacement must exceed the fees of the replaced transaction.
+    pub price_bump_percent: u64,
+}
+
+impl Default for TxPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_per_sender: 16,
+            max_size: 4096,
+            price_bump_percent: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TxPoolError {
+    AlreadyKnown,
+    Invalid(ValidationError),
+    ReplacementUnderpriced,
+    SenderLimitReached,
+    PoolFull,
+    Other(anyhow::Error),
+}
+
+impl From<ValidationError> for TxPoolError {
+    fn from(e: ValidationError) -> Self {
+        Self::Invalid(e)
+    }
+}
+
+impl From<anyhow::Error> for TxPoolError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Other(e)
+    }
+}
+
+impl Display for TxPoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for TxPoolError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    /// Executable on top of the latest state.
+    Pending,
+    /// Waits for the transactions with the missing nonces of its sender.
+    Queued,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PooledTransaction {
+    pub hash: H256,
+    pub sender: Address,
+    pub transaction: MessageWithSignature,
+}
+
+#[derive(Debug)]
+struct SenderTransactions {
+    /// Nonce of the sender in the latest state.
+    state_nonce: u64,
+    by_nonce: BTreeMap<u64, PooledTransaction>,
+}
+
+impl SenderTransactions {
+    /// Nonce after the last of the consecutive transactions starting at the state nonce.
+    fn pending_end(&self) -> u64 {
+        let mut nonce = self.state_nonce;
+        while self.by_nonce.contains_key(&nonce) {
+            nonce += 1;
+        }
+        nonce
+    }
+
+    fn status(&self, nonce: u64) -> TransactionStatus {
+        if nonce < self.pending_end() {
+            TransactionStatus::Pending
+        } else {
+            TransactionStatus::Queued
+        }
+    }
+
+    /// Drops the transactions mined with the nonces below the new state nonce.
+    fn set_state_nonce(&mut self, state_nonce: u64, by_hash: &mut HashMap<H256, (Address, u64)>) {
+        let kept = self.by_nonce.split_off(&state_nonce);
+        for mined in std::mem::replace(&mut self.by_nonce, kept).into_values() {
+            by_hash.remove(&mined.hash);
+        }
+        self.state_nonce = state_nonce;
+    }
+}
+
+/// Local pool of the transactions not yet included into the chain.
+///
+/// Transactions are validated against the state after the latest executed block
+/// and the rules of the next block.
+/// A transaction is pending if all the nonces of its sender before it are taken,
+/// and queued otherwise.
+#[derive(Debug)]
+pub struct TxPool {
+    config: TxPoolConfig,
+    chain_spec: ChainSpec,
+    head_hash: H256,
+    block_spec: BlockExecutionSpec,
+    base_fee_per_gas: Option<U256>,
+    block_gas_limit: u64,
+    senders: HashMap<Address, SenderTransactions>,
+    by_hash: HashMap<H256, (Address, u64)>,
+}
+
+impl TxPool {
+    pub fn new(config: TxPoolConfig, chain_spec: ChainSpec, head: &BlockHeader) -> Self {
+        let mut pool = Self {
+            config,
+            block_spec: chain_spec.collect_block_spec(head.number),
+            chain_spec,
+            head_hash: H256::zero(),
+            base_fee_per_gas: None,
+            block_gas_limit: 0,
+            senders: HashMap::new(),
+            by_hash: HashMap::new(),
+        };
+        pool.set_next_block(head);
+        pool
+    }
+
+    fn set_next_block(&mut self, head: &BlockHeader) {
+        let next = BlockHeader {
+            number: BlockNumber(head.number.0 + 1),
+            ..head.clone()
+        };
+        self.block_spec = self.chain_spec.collect_block_spec(next.number);
+        self.base_fee_per_gas =
+            expected_base_fee_per_gas(&next, head, self.chain_spec.consensus.eip1559_block);
+        self.block_gas_limit = head.gas_limit;
+        self.head_hash = head.hash();
+    }
+
+    /// Hash of the block the pool is on top of, see `set_head`.
+    pub fn head_hash(&self) -> H256 {
+        self.head_hash
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_hash.is_empty()
+    }
+
+    pub fn get(&self, hash: H256) -> Option<&PooledTransaction> {
+        let (sender, nonce) = self.by_hash.get(&hash)?;
+        self.senders[sender].by_nonce.get(nonce)
+    }
+
+    pub fn status(&self, hash: H256) -> Option<TransactionStatus> {
+        let (sender, nonce) = self.by_hash.get(&hash)?;
+        Some(self.senders[sender].status(*nonce))
+    }
+
+    pub fn remove(&mut self, hash: H256) -> Option<PooledTransaction> {
+        let (sender, nonce) = self.by_hash.remove(&hash)?;
+        let txs = self.senders.get_mut(&sender).unwrap();
+        let removed = txs.by_nonce.remove(&nonce);
+        if txs.by_nonce.is_empty() {
+            self.senders.remove(&sender);
+        }
+        removed
+    }
+
+    /// Tip the transaction pays per gas in the next block.
+    fn effective_tip(&self, message: &Message) -> U256 {
+        match self.base_fee_per_gas {
+            Some(base_fee_per_gas) => min(
+                message.max_priority_fee_per_gas(),
+                message.max_fee_per_gas().saturating_sub(base_fee_per_gas),
+            ),
+            None => message.max_fee_per_gas(),
+        }
+    }
+
+    /// The transaction with the lowest effective tip, the first to go when the pool is full.
+    fn cheapest(&self) -> Option<(U256, H256)> {
+        self.senders
+            .values()
+            .flat_map(|txs| txs.by_nonce.values())
+            .map(|tx| (self.effective_tip(&tx.transaction), tx.hash))
+            .min()
+    }
+
+    /// The base fee may have risen above the max fee of a transaction admitted earlier.
+    fn is_includable(&self, message: &Message) -> bool {
+        self.base_fee_per_gas.map_or(true, |base_fee_per_gas| {
+            message.max_fee_per_gas() >= base_fee_per_gas
+        })
+    }
+
+    fn is_underpriced_replacement(&self, old: &Message, new: &Message) -> bool {
+        let bumped = |fee: U256| fee.full_mul(U256::from(100 + self.config.price_bump_percent));
+        let scaled = |fee: U256| fee.full_mul(U256::from(100));
+
+        scaled(new.max_fee_per_gas()) < bumped(old.max_fee_per_gas())
+            || scaled(new.max_priority_fee_per_gas()) < bumped(old.max_priority_fee_per_gas())
+    }
+
+    /// Validates the transaction and returns its sender and the sender's nonce in the latest state.
+    async fn validate<S: State>(
+        &self,
+        state: &S,
+        transaction: &MessageWithSignature,
+    ) -> Result<(Address, u64), TxPoolError> {
+        let revision = self.block_spec.revision;
+
+        let supported = match transaction.tx_type() {
+            TxType::Legacy => true,
+            TxType::EIP2930 => revision >= Revision::Berlin,
+            TxType::EIP1559 => revision >= Revision::London,
+        };
+        if !supported {
+            return Err(ValidationError::UnsupportedTransactionType.into());
+        }
+
+        if !is_valid_signature(transaction.r(), transaction.s())
+            || (revision >= Revision::Homestead && !transaction.signature.is_low_s())
+        {
+            return Err(ValidationError::InvalidSignature.into());
+        }
+        let sender = transaction
+            .recover_sender()
+            .map_err(|_| ValidationError::InvalidSignature)?;
+
+        pre_validate_transaction(
+            &transaction.message,
+            self.block_spec.params.chain_id,
+            revision >= Revision::Spurious,
+            self.base_fee_per_gas,
+        )?;
+
+        let g0 = intrinsic_gas(
+            &transaction.message,
+            revision >= Revision::Homestead,
+            revision >= Revision::Istanbul,
+        );
+        if u128::from(transaction.gas_limit()) < g0 {
+            return Err(ValidationError::IntrinsicGas.into());
+        }
+
+        if transaction.gas_limit() > self.block_gas_limit {
+            return Err(ValidationError::BlockGasLimitExceeded {
+                available: self.block_gas_limit,
+                required: transaction.gas_limit(),
+            }
+            .into());
+        }
+
+        let account = state.read_account(sender).await?.unwrap_or_default();
+
+        if account.code_hash != EMPTY_HASH {
+            return Err(ValidationError::SenderNoEOA { sender }.into());
+        }
+
+        if transaction.nonce() < account.nonce {
+            return Err(ValidationError::WrongNonce {
+                account: sender,
+                expected: account.nonce,
+                got: transaction.nonce(),
+            }
+            .into());
+        }
+
+        // only the transaction itself is checked, not the total cost of the sender's transactions
+        let max_gas_cost =
+            U512::from(transaction.gas_limit()) * U512::from(transaction.max_fee_per_gas());
+        let v0 = max_gas_cost + transaction.value();
+        let available_balance = U512::from(account.balance);
+        if available_balance < v0 {
+            return Err(ValidationError::InsufficientFunds {
+                account: sender,
+                available: available_balance,
+                required: v0,
+            }
+            .into());
+        }
+
+        Ok((sender, account.nonce))
+    }
+
+    pub async fn add_transaction<S: State>(
+        &mut self,
+        state: &S,
+        transaction: MessageWithSignature,
+    ) -> Result<TransactionStatus, TxPoolError> {
+        let hash = transaction.hash();
+        if self.by_hash.contains_key(&hash) {
+            return Err(TxPoolError::AlreadyKnown);
+        }
+
+        let (sender, state_nonce) = self.validate(state, &transaction).await?;
+        let nonce = transaction.nonce();
+
+        // the transactions mined since don't count toward the limits
+        if let Some(txs) = self.senders.get_mut(&sender) {
+            txs.set_state_nonce(state_nonce, &mut self.by_hash);
+        }
+
+        match self
+            .senders
+            .get(&sender)
+            .and_then(|txs| txs.by_nonce.get(&nonce))
+        {
+            Some(existing) => {
+                if self.is_underpriced_replacement(&existing.transaction, &transaction) {
+                    return Err(TxPoolError::ReplacementUnderpriced);
+                }
+            }
+            None => {
+                let sender_count = self
+                    .senders
+                    .get(&sender)
+                    .map_or(0, |txs| txs.by_nonce.len());
+                if sender_count >= self.config.max_per_sender {
+                    return Err(TxPoolError::SenderLimitReached);
+                }
+                if self.len() >= self.config.max_size {
+                    // only a transaction tipping more than the cheapest one takes its place
+                    let tip = self.effective_tip(&transaction);
+                    let (_, cheapest_hash) = self
+                        .cheapest()
+                        .filter(|(cheapest_tip, _)| *cheapest_tip < tip)
+                        .ok_or(TxPoolError::PoolFull)?;
+                    self.remove(cheapest_hash);
+                }
+            }
+        }
+
+        let txs = self
+            .senders
+            .entry(sender)
+            .or_insert_with(|| SenderTransactions {
+                state_nonce,
+                by_nonce: BTreeMap::new(),
+            });
+
+        if let Some(replaced) = txs.by_nonce.insert(
+            nonce,
+            PooledTransaction {
+                hash,
+                sender,
+                transaction,
+            },
+        ) {
+            self.by_hash.remove(&replaced.hash);
+        }
+        self.by_hash.insert(hash, (sender, nonce));
+
+        Ok(txs.status(nonce))
+    }
+
+    /// Adds a batch, e.g. received from a peer, with a result for each of the transactions.
+    pub async fn add_transactions<S: State>(
+        &mut self,
+        state: &S,
+        transactions: Vec<MessageWithSignature>,
+    ) -> Vec<Result<TransactionStatus, TxPoolError>> {
+        let mut results = Vec::with_capacity(transactions.len());
+        for transaction in transactions {
+            results.push(self.add_transaction(state, transaction).await);
+        }
+        results
+    }
+
+    /// Pending transactions, the highest effective tip first,
+    /// while the transactions of each sender stay in the order of their nonces.
+    pub fn pending(&self) -> Vec<&PooledTransaction> {
+        let mut heap = BinaryHeap::new();
+        let push = |heap: &mut BinaryHeap<_>, txs: &SenderTransactions, nonce: u64| {
+            if let Some(tx) = txs.by_nonce.get(&nonce) {
+                if self.is_includable(&tx.transaction) {
+                    heap.push((
+                        self.effective_tip(&tx.transaction),
+                        Reverse(tx.hash),
+                        tx.sender,
+                        nonce,
+                    ));
+                }
+            }
+        };
+
+        for txs in self.senders.values() {
+            push(&mut heap, txs, txs.state_nonce);
+        }
+
+        let mut pending = Vec::new();
+        while let Some((_, _, sender, nonce)) = heap.pop() {
+            let txs = &self.senders[&sender];
+            pending.push(&txs.by_nonce[&nonce]);
+            push(&mut heap, txs, nonce + 1);
+        }
+        pending
+    }
+
+    /// Moves the pool on top of the new head: the rules and the base fee of the next block
+    /// are updated, and the transactions mined since are dropped.
+    pub async fn set_head<S: State>(
+        &mut self,
+        head: &BlockHeader,
+        state: &S,
+    ) -> anyhow::Result<()> {
+        self.set_next_block(head);
+
+        for (sender, txs) in &mut self.senders {
+            let state_nonce = state
+                .read_account(*sender)
+                .await?
+                .map_or(0, |account| account.nonce);
+            txs.set_state_nonce(state_nonce, &mut self.by_hash);
+        }
+        self.senders.retain(|_, txs| !txs.by_nonce.is_empty());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::keccak256, res::chainspec::MAINNET, InMemoryState};
+    use secp256k1::{PublicKey, SecretKey, SECP256K1};
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn secret_key(n: u8) -> SecretKey {
+        SecretKey::from_slice(&[n; 32]).unwrap()
+    }
+
+    fn address(secret_key: &SecretKey) -> Address {
+        let public = PublicKey::from_secret_key(SECP256K1, secret_key);
+        Address::from_slice(&keccak256(&public.serialize_uncompressed()[1..]).as_bytes()[12..])
+    }
+
+    fn sign(message: Message, secret_key: &SecretKey) -> MessageWithSignature {
+        let (recovery_id, signature) = SECP256K1
+            .sign_recoverable(
+                &secp256k1::Message::from_slice(message.hash().as_bytes()).unwrap(),
+                secret_key,
+            )
+            .serialize_compact();
+        MessageWithSignature {
+            message,
+            signature: MessageSignature::new(
+                recovery_id.to_i32() == 1,
+                H256::from_slice(&signature[..32]),
+                H256::from_slice(&signature[32..]),
+            )
+            .unwrap(),
+        }
+    }
+
+    fn message(nonce: u64, tip_gwei: u64, fee_gwei: u64, gas_limit: u64) -> Message {
+        Message::EIP1559 {
+            chain_id: ChainId(1),
+            nonce,
+            max_priority_fee_per_gas: U256::from(tip_gwei * GWEI),
+            max_fee_per_gas: U256::from(fee_gwei * GWEI),
+            gas_limit,
+            action: TransactionAction::Call(Address::zero()),
+            value: 0.into(),
+            input: Default::default(),
+            access_list: vec![],
+        }
+    }
+
+    fn transaction(
+        secret_key: &SecretKey,
+        nonce: u64,
+        tip_gwei: u64,
+        fee_gwei: u64,
+    ) -> MessageWithSignature {
+        sign(message(nonce, tip_gwei, fee_gwei, 21_000), secret_key)
+    }
+
+    fn head(number: u64) -> BlockHeader {
+        // the gas used is at the target, so the base fee stays the same
+        BlockHeader {
+            number: BlockNumber(number),
+            gas_limit: 30_000_000,
+            gas_used: 15_000_000,
+            base_fee_per_gas: Some(U256::from(10 * GWEI)),
+            ..BlockHeader::new(PartialHeader::empty(), EMPTY_LIST_HASH, EMPTY_ROOT)
+        }
+    }
+
+    fn fund(state: &mut InMemoryState, secret_key: &SecretKey, nonce: u64) {
+        state.update_account(
+            address(secret_key),
+            None,
+            Some(Account {
+                nonce,
+                balance: U256::exp10(18),
+                ..Default::default()
+            }),
+        );
+    }
+
+    fn setup(config: TxPoolConfig) -> (InMemoryState, TxPool) {
+        let mut state = InMemoryState::new();
+        state.begin_block(BlockNumber(13_000_000));
+        for n in 1..=2 {
+            fund(&mut state, &secret_key(n), 0);
+        }
+        let pool = TxPool::new(config, MAINNET.clone(), &head(13_000_000));
+        (state, pool)
+    }
+
+    #[tokio::test]
+    async fn validation() {
+        let (mut state, mut pool) = setup(TxPoolConfig::default());
+        let key = secret_key(1);
+
+        let tx = transaction(&key, 0, 2, 20);
+        let hash = tx.hash();
+        assert_eq!(
+            pool.add_transaction(&state, tx.clone()).await.unwrap(),
+            TransactionStatus::Pending
+        );
+        assert!(matches!(
+            pool.add_transaction(&state, tx).await,
+            Err(TxPoolError::AlreadyKnown)
+        ));
+        assert_eq!(pool.get(hash).unwrap().sender, address(&key));
+
+        let wrong_chain = sign(
+            Message::Legacy {
+                chain_id: Some(ChainId(5)),
+                nonce: 1,
+                gas_price: U256::from(20 * GWEI),
+                gas_limit: 21_000,
+                action: TransactionAction::Call(Address::zero()),
+                value: 0.into(),
+                input: Default::default(),
+            },
+            &key,
+        );
+        assert!(matches!(
+            pool.add_transaction(&state, wrong_chain).await,
+            Err(TxPoolError::Invalid(ValidationError::WrongChainId))
+        ));
+
+        assert!(matches!(
+            pool.add_transaction(&state, transaction(&key, 1, 2, 5))
+                .await,
+            Err(TxPoolError::Invalid(ValidationError::MaxFeeLessThanBase))
+        ));
+
+        assert!(matches!(
+            pool.add_transaction(&state, sign(message(1, 2, 20, 20_000), &key))
+                .await,
+            Err(TxPoolError::Invalid(ValidationError::IntrinsicGas))
+        ));
+
+        assert!(matches!(
+            pool.add_transaction(&state, sign(message(1, 2, 20, 40_000_000), &key))
+                .await,
+            Err(TxPoolError::Invalid(
+                ValidationError::BlockGasLimitExceeded { .. }
+            ))
+        ));
+
+        assert!(matches!(
+            pool.add_transaction(&state, transaction(&secret_key(3), 0, 2, 20))
+                .await,
+            Err(TxPoolError::Invalid(
+                ValidationError::InsufficientFunds { .. }
+            ))
+        ));
+
+        fund(&mut state, &key, 5);
+        assert!(matches!(
+            pool.add_transaction(&state, transaction(&key, 4, 2, 20))
+                .await,
+            Err(TxPoolError::Invalid(ValidationError::WrongNonce {
+                expected: 5,
+                got: 4,
+                ..
+            }))
+        ));
+
+        // the transaction mined meanwhile is dropped
+        assert_eq!(
+            pool.add_transaction(&state, transaction(&key, 5, 2, 20))
+                .await
+                .unwrap(),
+            TransactionStatus::Pending
+        );
+        assert!(pool.get(hash).is_none());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn nonce_gap() {
+        let (state, mut pool) = setup(TxPoolConfig::default());
+        let key = secret_key(1);
+
+        let queued = transaction(&key, 1, 2, 20);
+        let queued_hash = queued.hash();
+        assert_eq!(
+            pool.add_transaction(&state, queued).await.unwrap(),
+            TransactionStatus::Queued
+        );
+        assert!(pool.pending().is_empty());
+
+        assert_eq!(
+            pool.add_transaction(&state, transaction(&key, 0, 2, 20))
+                .await
+                .unwrap(),
+            TransactionStatus::Pending
+        );
+        assert_eq!(pool.status(queued_hash), Some(TransactionStatus::Pending));
+        assert_eq!(pool.pending().len(), 2);
+
+        let removed = pool.remove(pool.pending()[0].hash).unwrap();
+        assert_eq!(removed.transaction.nonce(), 0);
+        assert_eq!(pool.status(queued_hash), Some(TransactionStatus::Queued));
+    }
+
+    #[tokio::test]
+    async fn replacement() {
+        let (state, mut pool) = setup(TxPoolConfig::default());
+        let key = secret_key(1);
+
+        let original = transaction(&key, 0, 20, 200);
+        let original_hash = original.hash();
+        pool.add_transaction(&state, original).await.unwrap();
+
+        // both fees must be bumped by 10%
+        for (tip, fee) in [(21, 220), (22, 210)] {
+            assert!(matches!(
+                pool.add_transaction(&state, transaction(&key, 0, tip, fee))
+                    .await,
+                Err(TxPoolError::ReplacementUnderpriced)
+            ));
+        }
+
+        let replacement = transaction(&key, 0, 22, 220);
+        let replacement_hash = replacement.hash();
+        pool.add_transaction(&state, replacement).await.unwrap();
+
+        assert_eq!(pool.len(), 1);
+        assert!(pool.get(original_hash).is_none());
+        assert_eq!(
+            pool.status(replacement_hash),
+            Some(TransactionStatus::Pending)
+        );
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let (mut state, mut pool) = setup(TxPoolConfig {
+            max_per_sender: 2,
+            max_size: 3,
+            ..Default::default()
+        });
+        let (a, b) = (secret_key(1), secret_key(2));
+
+        for nonce in 0..2 {
+            pool.add_transaction(&state, transaction(&a, nonce, 2, 20))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            pool.add_transaction(&state, transaction(&a, 2, 2, 20))
+                .await,
+            Err(TxPoolError::SenderLimitReached)
+        ));
+
+        // replacements are allowed at the limit
+        pool.add_transaction(&state, transaction(&a, 1, 3, 30))
+            .await
+            .unwrap();
+
+        let cheapest = transaction(&b, 0, 1, 20);
+        let cheapest_hash = cheapest.hash();
+        let results = pool
+            .add_transactions(&state, vec![cheapest, transaction(&b, 1, 1, 20)])
+            .await;
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(TxPoolError::PoolFull)));
+        assert_eq!(pool.len(), 3);
+
+        // a higher tip evicts the cheapest transaction
+        let c = secret_key(3);
+        fund(&mut state, &c, 0);
+        let evicting = transaction(&c, 0, 5, 50);
+        let evicting_hash = evicting.hash();
+        pool.add_transaction(&state, evicting).await.unwrap();
+        assert_eq!(pool.len(), 3);
+        assert!(pool.get(cheapest_hash).is_none());
+        assert_eq!(pool.status(evicting_hash), Some(TransactionStatus::Pending));
+
+        // the mined transactions of the sender are dropped before the limits are checked,
+        // so neither the sender limit nor the eviction kicks in
+        fund(&mut state, &a, 2);
+        pool.add_transaction(&state, transaction(&a, 2, 2, 20))
+            .await
+            .unwrap();
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.status(evicting_hash), Some(TransactionStatus::Pending));
+    }
+
+    #[tokio::test]
+    async fn ordering() {
+        let (state, mut pool) = setup(TxPoolConfig::default());
+        let (a, b) = (secret_key(1), secret_key(2));
+
+        let a0 = transaction(&a, 0, 1, 20);
+        let a1 = transaction(&a, 1, 5, 20);
+        let a3 = transaction(&a, 3, 9, 20);
+        // the effective tip is capped by the max fee above the base fee of 10 gwei
+        let b0 = transaction(&b, 0, 9, 13);
+        let expected = vec![b0.hash(), a0.hash(), a1.hash()];
+
+        for tx in [a3, a1, b0, a0] {
+            pool.add_transaction(&state, tx).await.unwrap();
+        }
+
+        assert_eq!(
+            pool.pending()
+                .into_iter()
+                .map(|tx| tx.hash)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn set_head() {
+        let (mut state, mut pool) = setup(TxPoolConfig::default());
+        let key = secret_key(1);
+
+        let mined = transaction(&key, 0, 2, 20);
+        let mined_hash = mined.hash();
+        let next = transaction(&key, 1, 2, 20);
+        let next_hash = next.hash();
+        pool.add_transactions(&state, vec![mined, next]).await;
+
+        state.begin_block(BlockNumber(13_000_001));
+        fund(&mut state, &key, 1);
+        pool.set_head(&head(13_000_001), &state).await.unwrap();
+
+        assert_eq!(pool.head_hash(), head(13_000_001).hash());
+        assert_eq!(pool.len(), 1);
+        assert!(pool.get(mined_hash).is_none());
+        assert_eq!(pool.status(next_hash), Some(TransactionStatus::Pending));
+        assert_eq!(pool.pending()[0].hash, next_hash);
+    }
+}
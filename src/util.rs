@@ -68,6 +68,22 @@ where
     Ok(d)
 }
 
+pub fn deserialize_hexstr_as_u256<'de, D>(deserializer: D) -> Result<U256, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+
+    let d = if let Some(stripped) = s.strip_prefix("0x") {
+        U256::from_str_radix(stripped, 16).map_err(|e| format!("{:?}", e))
+    } else {
+        U256::from_dec_str(&s).map_err(|e| format!("{:?}", e))
+    }
+    .map_err(|e| de::Error::custom(format!("{}/{}", e, s)))?;
+
+    Ok(d)
+}
+
 pub mod hexbytes {
     use serde::Serializer;
 